@@ -8,8 +8,13 @@ use crate::{
 	info, log,
 	state::{LauncherPaths, PersistedState},
 	trace,
+	tunnels::{connectivity, security_events::SecurityMonitor},
 	util::{
-		errors::{wrap, AnyError, RefreshTokenNotAvailableError, StatusError, WrappedError},
+		errors::{
+			wrap, AnyError, InvalidAccessTokenFile, InvalidProfileName, LoginTimedOut,
+			RefreshTokenNotAvailableError, StatusError, WrappedError,
+		},
+		http,
 		input::prompt_options,
 	},
 	warning,
@@ -19,7 +24,7 @@ use chrono::{DateTime, Duration, Utc};
 use gethostname::gethostname;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{cell::Cell, fmt::Display, path::PathBuf, sync::Arc};
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 use tunnels::{
 	contracts::PROD_FIRST_PARTY_APP_ID,
 	management::{Authorization, AuthorizationProvider, HttpError},
@@ -31,7 +36,41 @@ struct DeviceCodeResponse {
 	user_code: String,
 	message: Option<String>,
 	verification_uri: String,
+	#[serde(default)]
+	verification_uri_complete: Option<String>,
 	expires_in: i64,
+	/// Seconds to wait between polls for the login to be completed.
+	#[serde(default)]
+	interval: Option<u64>,
+}
+
+/// A device code to be entered on another device, as printed with
+/// `--output json`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceCodePrompt<'a> {
+	provider: &'static str,
+	user_code: &'a str,
+	verification_uri: &'a str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	verification_uri_complete: Option<&'a str>,
+	expires_at: DateTime<Utc>,
+}
+
+/// Seconds between polls for a device code login, if the provider doesn't say.
+const DEFAULT_DEVICE_CODE_INTERVAL: u64 = 5;
+
+/// How device code logins are shown and waited for, so they can be completed
+/// on a second device when the CLI runs where there's no browser.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceCodeOptions {
+	/// Print the code as a JSON line on stdout, instead of a message.
+	pub json: bool,
+	/// Also print a QR code of the verification URL.
+	pub qr_code: bool,
+	/// How long to wait for the login, rather than issuing new codes as old
+	/// ones expire until it's completed.
+	pub timeout: Option<std::time::Duration>,
 }
 
 #[derive(Deserialize)]
@@ -41,7 +80,7 @@ struct AuthenticationResponse {
 	expires_in: Option<i64>,
 }
 
-#[derive(clap::ArgEnum, Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(clap::ArgEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AuthProvider {
 	Microsoft,
 	Github,
@@ -57,6 +96,14 @@ impl Display for AuthProvider {
 }
 
 impl AuthProvider {
+	/// Gets the name of the provider, as given to `--provider`.
+	pub fn id(&self) -> &'static str {
+		match self {
+			AuthProvider::Microsoft => "microsoft",
+			AuthProvider::Github => "github",
+		}
+	}
+
 	pub fn client_id(&self) -> &'static str {
 		match self {
 			AuthProvider::Microsoft => "aebc6443-996d-45c2-90f0-388ff96faa56",
@@ -104,11 +151,13 @@ pub struct StoredCredential {
 }
 
 impl StoredCredential {
-	pub async fn is_expired(&self, client: &reqwest::Client) -> bool {
+	/// Gets whether the credential has expired, or is about to. `now` is the
+	/// current time, corrected for any skew in the local clock.
+	pub async fn is_expired(&self, client: &reqwest::Client, now: DateTime<Utc>) -> bool {
 		match self.provider {
 			AuthProvider::Microsoft => self
 				.expires_at
-				.map(|e| Utc::now() + chrono::Duration::minutes(5) > e)
+				.map(|e| now + chrono::Duration::minutes(5) > e)
 				.unwrap_or(false),
 
 			// Make an auth request to Github. Mark the credential as expired
@@ -125,18 +174,114 @@ impl StoredCredential {
 		}
 	}
 
-	fn from_response(auth: AuthenticationResponse, provider: AuthProvider) -> Self {
+	/// Gets the name of the account the credential is for, if it can be found.
+	async fn account_name(&self, client: &reqwest::Client) -> Option<String> {
+		#[derive(Deserialize)]
+		struct Claims {
+			preferred_username: Option<String>,
+			upn: Option<String>,
+			oid: Option<String>,
+		}
+
+		#[derive(Deserialize)]
+		struct GithubUser {
+			login: String,
+		}
+
+		match self.provider {
+			AuthProvider::Microsoft => {
+				let payload = self.access_token.split('.').nth(1)?;
+				let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+				let claims: Claims = serde_json::from_slice(&payload).ok()?;
+				claims.preferred_username.or(claims.upn).or(claims.oid)
+			}
+			AuthProvider::Github => {
+				let response = client
+					.get("https://api.github.com/user")
+					.header("Authorization", format!("token {}", self.access_token))
+					.header("User-Agent", get_default_user_agent())
+					.send()
+					.await
+					.ok()?;
+				let user: GithubUser = response.json().await.ok()?;
+				Some(user.login)
+			}
+		}
+	}
+
+	/// Gets the authorization to send to the tunnel service.
+	fn authorization(self) -> Authorization {
+		match self.provider {
+			AuthProvider::Microsoft => Authorization::Bearer(self.access_token),
+			AuthProvider::Github => Authorization::Github(format!(
+				"client_id={} {}",
+				self.provider.client_id(),
+				self.access_token
+			)),
+		}
+	}
+
+	fn from_response(
+		auth: AuthenticationResponse,
+		provider: AuthProvider,
+		now: DateTime<Utc>,
+	) -> Self {
 		StoredCredential {
 			provider,
 			access_token: auth.access_token,
 			refresh_token: auth.refresh_token,
-			expires_at: auth.expires_in.map(|e| Utc::now() + Duration::seconds(e)),
+			expires_at: auth.expires_in.map(|e| now + Duration::seconds(e)),
+		}
+	}
+}
+
+/// Differences between the local clock and a server's that are within this
+/// many seconds are put down to latency and ignored.
+const CLOCK_SKEW_TOLERANCE_SECS: i64 = 60;
+
+/// How far the local clock is behind the clocks of the services the CLI
+/// talks to. Token lifetimes are given relative to the server's clock, so a
+/// skewed local clock makes valid tokens look expired, or expired tokens look
+/// valid, which surfaces as intermittent authentication failures.
+#[derive(Clone, Default)]
+struct ClockSkew(Arc<std::sync::Mutex<Option<Duration>>>);
+
+impl ClockSkew {
+	/// Gets the current time on the server's clock, as best as it's known.
+	fn now(&self) -> DateTime<Utc> {
+		Utc::now() + self.0.lock().unwrap().unwrap_or_else(Duration::zero)
+	}
+
+	fn is_measured(&self) -> bool {
+		self.0.lock().unwrap().is_some()
+	}
+
+	/// Records the skew given the server's time at `local_time`. Returns the
+	/// skew if it's significant and wasn't already known.
+	fn record(&self, server_time: DateTime<Utc>, local_time: DateTime<Utc>) -> Option<Duration> {
+		let skew = server_time - local_time;
+		let skew = if skew.num_seconds().abs() > CLOCK_SKEW_TOLERANCE_SECS {
+			skew
+		} else {
+			Duration::zero()
+		};
+
+		let previous = self.0.lock().unwrap().replace(skew);
+		let changed = match previous {
+			Some(p) => (p - skew).num_seconds().abs() > CLOCK_SKEW_TOLERANCE_SECS,
+			None => true,
+		};
+
+		if changed && skew != Duration::zero() {
+			Some(skew)
+		} else {
+			None
 		}
 	}
 }
 
 struct StorageWithLastRead {
-	storage: Box<dyn StorageImplementation>,
+	storage: Box<dyn SecretStore>,
 	last_read: Cell<Result<Option<StoredCredential>, WrappedError>>,
 }
 
@@ -145,10 +290,19 @@ pub struct Auth {
 	client: reqwest::Client,
 	log: log::Logger,
 	file_storage_path: PathBuf,
+	keyring_prefix: String,
 	storage: Arc<std::sync::Mutex<Option<StorageWithLastRead>>>,
+	clock_skew: ClockSkew,
+	provider: Option<AuthProvider>,
+	device_code: DeviceCodeOptions,
+	security: SecurityMonitor,
 }
 
-trait StorageImplementation: Send + Sync {
+/// Where credentials are persisted. The OS keychain is preferred: the Keychain
+/// on macOS, the Credential Manager on Windows, and the Secret Service (as
+/// used by libsecret) on Linux. Where it's unavailable, such as on headless
+/// Linux machines, credentials are kept in a file in the CLI data directory.
+trait SecretStore: Send + Sync {
 	fn read(&mut self) -> Result<Option<StoredCredential>, WrappedError>;
 	fn store(&mut self, value: StoredCredential) -> Result<(), WrappedError>;
 	fn clear(&mut self) -> Result<(), WrappedError>;
@@ -184,11 +338,20 @@ const KEYCHAIN_ENTRY_LIMIT: usize = 128 * 1024;
 
 const CONTINUE_MARKER: &str = "<MORE>";
 
-#[derive(Default)]
 struct KeyringStorage {
 	// keywring storage can be split into multiple entries due to entry length limits
 	// on Windows https://github.com/microsoft/vscode-cli/issues/358
 	entries: Vec<keyring::Entry>,
+	prefix: String,
+}
+
+impl KeyringStorage {
+	fn new(prefix: &str) -> Self {
+		KeyringStorage {
+			entries: vec![],
+			prefix: prefix.to_string(),
+		}
+	}
 }
 
 macro_rules! get_next_entry {
@@ -196,7 +359,7 @@ macro_rules! get_next_entry {
 		match $self.entries.get($i) {
 			Some(e) => e,
 			None => {
-				let e = keyring::Entry::new("vscode-cli", &format!("vscode-cli-{}", $i));
+				let e = keyring::Entry::new("vscode-cli", &format!("{}-{}", $self.prefix, $i));
 				$self.entries.push(e);
 				$self.entries.last().unwrap()
 			}
@@ -204,7 +367,7 @@ macro_rules! get_next_entry {
 	};
 }
 
-impl StorageImplementation for KeyringStorage {
+impl SecretStore for KeyringStorage {
 	fn read(&mut self) -> Result<Option<StoredCredential>, WrappedError> {
 		let mut str = String::new();
 
@@ -266,7 +429,7 @@ impl StorageImplementation for KeyringStorage {
 
 struct FileStorage(PersistedState<Option<String>>);
 
-impl StorageImplementation for FileStorage {
+impl SecretStore for FileStorage {
 	fn read(&mut self) -> Result<Option<StoredCredential>, WrappedError> {
 		Ok(self.0.load().and_then(|s| unseal(&s)))
 	}
@@ -280,13 +443,83 @@ impl StorageImplementation for FileStorage {
 	}
 }
 
+/// Moves any credential in one store to another, such as from the file a
+/// login was kept in before the keychain was available. The credential is
+/// only removed from the old store once the new one holds it.
+fn migrate_secret(
+	from: &mut dyn SecretStore,
+	to: &mut dyn SecretStore,
+) -> Result<Option<StoredCredential>, WrappedError> {
+	let value = match from.read()? {
+		Some(v) => v,
+		None => return Ok(None),
+	};
+
+	to.store(value.clone())?;
+	from.clear()?;
+	Ok(Some(value))
+}
+
 impl Auth {
 	pub fn new(paths: &LauncherPaths, log: log::Logger) -> Auth {
 		Auth {
-			log,
 			client: reqwest::Client::new(),
 			file_storage_path: paths.root().join("token.json"),
+			keyring_prefix: "vscode-cli".to_string(),
 			storage: Arc::new(std::sync::Mutex::new(None)),
+			clock_skew: ClockSkew::default(),
+			provider: None,
+			device_code: DeviceCodeOptions::default(),
+			security: SecurityMonitor::new(log.clone(), paths),
+			log,
+		}
+	}
+
+	/// Sets how device code logins are shown and waited for.
+	pub fn with_device_code_options(mut self, options: DeviceCodeOptions) -> Auth {
+		self.device_code = options;
+		self
+	}
+
+	/// Uses the credentials stored under the named profile, from `--as-profile`,
+	/// rather than the default ones. Logging in or out only affects that profile.
+	pub fn with_profile(mut self, profile: &str) -> Auth {
+		self.file_storage_path
+			.set_file_name(format!("token-{}.json", profile));
+		self.keyring_prefix = format!("vscode-cli-profile-{}", profile);
+		self
+	}
+
+	/// Logs in with the given provider, rather than asking which to use.
+	/// Stored credentials from other providers are not used.
+	pub fn with_provider(mut self, provider: AuthProvider) -> Auth {
+		self.provider = Some(provider);
+		self
+	}
+
+	/// Records how far the local clock is from the server's, given the time
+	/// the server reported, and warns if it's off by enough to cause problems.
+	fn record_server_time(&self, server_time: DateTime<Utc>) {
+		if let Some(skew) = self.clock_skew.record(server_time, Utc::now()) {
+			warning!(
+				self.log,
+				"This machine's clock is {} {} the server time. Token lifetimes will be adjusted, but you should sync the clock to avoid authentication failures.",
+				format_skew(skew),
+				if skew > Duration::zero() { "behind" } else { "ahead of" }
+			);
+		}
+	}
+
+	/// Checks the local clock against the management API, if it hasn't been
+	/// checked already.
+	async fn check_clock_skew(&self) {
+		if self.clock_skew.is_measured() {
+			return;
+		}
+
+		match connectivity::get_management_api_time(&self.client).await {
+			Some(t) => self.record_server_time(t),
+			None => trace!(self.log, "Could not get the time from the management API"),
 		}
 	}
 
@@ -299,7 +532,7 @@ impl Auth {
 			return op(s);
 		}
 
-		let mut keyring_storage = KeyringStorage::default();
+		let mut keyring_storage = KeyringStorage::new(&self.keyring_prefix);
 		let mut file_storage = FileStorage(PersistedState::new(self.file_storage_path.clone()));
 
 		let keyring_storage_result = match std::env::var("VSCODE_CLI_USE_FILE_KEYCHAIN") {
@@ -308,6 +541,28 @@ impl Auth {
 		};
 
 		let mut storage = match keyring_storage_result {
+			Ok(None) => match migrate_secret(&mut file_storage, &mut keyring_storage) {
+				Ok(v) => {
+					if v.is_some() {
+						info!(self.log, "Moved the stored login into the OS keychain");
+					}
+					StorageWithLastRead {
+						last_read: Cell::new(Ok(v)),
+						storage: Box::new(keyring_storage),
+					}
+				}
+				Err(e) => {
+					warning!(
+						self.log,
+						"Could not move the stored login into the OS keychain, keeping it in a file: {}",
+						e
+					);
+					StorageWithLastRead {
+						last_read: Cell::new(file_storage.read()),
+						storage: Box::new(file_storage),
+					}
+				}
+			},
 			Ok(v) => StorageWithLastRead {
 				last_read: Cell::new(Ok(v)),
 				storage: Box::new(keyring_storage),
@@ -325,17 +580,7 @@ impl Auth {
 
 	/// Gets a tunnel Authentication for use in the tunnel management API.
 	pub async fn get_tunnel_authentication(&self) -> Result<Authorization, AnyError> {
-		let cred = self.get_credential().await?;
-		let auth = match cred.provider {
-			AuthProvider::Microsoft => Authorization::Bearer(cred.access_token),
-			AuthProvider::Github => Authorization::Github(format!(
-				"client_id={} {}",
-				cred.provider.client_id(),
-				cred.access_token
-			)),
-		};
-
-		Ok(auth)
+		Ok(self.get_credential().await?.authorization())
 	}
 
 	/// Reads the current details from the keyring.
@@ -377,6 +622,7 @@ impl Auth {
 			None => self.do_device_code_flow_with_provider(provider).await?,
 		};
 
+		self.check_account(&credentials).await;
 		self.store_credentials(credentials.clone());
 		Ok(credentials)
 	}
@@ -384,6 +630,16 @@ impl Auth {
 	/// Gets the currently stored credentials, or asks the user to log in.
 	pub async fn get_credential(&self) -> Result<StoredCredential, AnyError> {
 		let entry = match self.get_current_credential() {
+			Ok(Some(old_creds)) if matches!(self.provider, Some(p) if p != old_creds.provider) => {
+				trace!(
+					self.log,
+					"Token in keyring is for another provider, getting a new one"
+				);
+				let creds = self.do_device_code_flow().await?;
+				self.store_credentials(creds.clone());
+				creds
+			}
+
 			Ok(Some(old_creds)) => {
 				trace!(self.log, "Found token in keyring");
 				match self.get_refreshed_token(&old_creds).await {
@@ -425,6 +681,15 @@ impl Auth {
 		Ok(entry)
 	}
 
+	/// Reports if newly logged-in credentials are for an account that wasn't
+	/// used on this machine before.
+	async fn check_account(&self, creds: &StoredCredential) {
+		match creds.account_name(&self.client).await {
+			Some(name) => self.security.check_account(creds.provider.id(), &name),
+			None => trace!(self.log, "Could not get the name of the logged in account"),
+		}
+	}
+
 	/// Stores credentials, logging a warning if it fails.
 	fn store_credentials(&self, creds: StoredCredential) {
 		self.with_storage(|storage| {
@@ -445,7 +710,11 @@ impl Auth {
 		&self,
 		creds: &StoredCredential,
 	) -> Result<Option<StoredCredential>, AnyError> {
-		if !creds.is_expired(&self.client).await {
+		if creds.expires_at.is_some() {
+			self.check_clock_skew().await;
+		}
+
+		if !creds.is_expired(&self.client, self.clock_skew.now()).await {
 			return Ok(None);
 		}
 
@@ -480,12 +749,21 @@ impl Auth {
 			.send()
 			.await?;
 
+		if let Some(t) = connectivity::get_response_time(&response) {
+			self.record_server_time(t);
+		}
+
 		if !response.status().is_success() {
 			return Err(StatusError::from_res(response).await?.into());
 		}
 
-		let body = response.json::<AuthenticationResponse>().await?;
-		Ok(StoredCredential::from_response(body, provider))
+		let body =
+			http::read_json::<AuthenticationResponse>(provider.grant_uri(), response).await?;
+		Ok(StoredCredential::from_response(
+			body,
+			provider,
+			self.clock_skew.now(),
+		))
 	}
 
 	/// Implements the device code flow, returning the credentials upon success.
@@ -495,6 +773,10 @@ impl Auth {
 	}
 
 	async fn prompt_for_provider(&self) -> Result<AuthProvider, AnyError> {
+		if let Some(provider) = self.provider {
+			return Ok(provider);
+		}
+
 		if std::env::var("VSCODE_CLI_ALLOW_MS_AUTH").is_err() {
 			return Ok(AuthProvider::Github);
 		}
@@ -511,6 +793,7 @@ impl Auth {
 		&self,
 		provider: AuthProvider,
 	) -> Result<StoredCredential, AnyError> {
+		let deadline = self.device_code.timeout.map(|t| Instant::now() + t);
 		loop {
 			let init_code = self
 				.client
@@ -528,16 +811,17 @@ impl Auth {
 				return Err(StatusError::from_res(init_code).await?.into());
 			}
 
-			let init_code_json = init_code.json::<DeviceCodeResponse>().await?;
+			let init_code_json =
+				http::read_json::<DeviceCodeResponse>(provider.code_uri(), init_code).await?;
 			let expires_at = Utc::now() + chrono::Duration::seconds(init_code_json.expires_in);
 
-			match &init_code_json.message {
-				Some(m) => self.log.result(m),
-				None => self.log.result(&format!(
-					"To grant access to the server, please log into {} and use code {}",
-					init_code_json.verification_uri, init_code_json.user_code
-				)),
-			};
+			self.show_device_code(provider, &init_code_json, expires_at);
+			let interval = std::time::Duration::from_secs(
+				init_code_json
+					.interval
+					.unwrap_or(DEFAULT_DEVICE_CODE_INTERVAL)
+					.max(1),
+			);
 
 			let body = format!(
                 "client_id={}&grant_type=urn:ietf:params:oauth:grant-type:device_code&device_code={}",
@@ -546,10 +830,20 @@ impl Auth {
             );
 
 			while Utc::now() < expires_at {
-				sleep(std::time::Duration::from_secs(5)).await;
+				if let Some(deadline) = deadline {
+					if Instant::now() >= deadline {
+						let timeout = self.device_code.timeout.unwrap_or_default();
+						return Err(LoginTimedOut(timeout.as_secs()).into());
+					}
+				}
+
+				sleep(interval).await;
 
 				match self.do_grant(provider, body.clone()).await {
-					Ok(creds) => return Ok(creds),
+					Ok(creds) => {
+						self.check_account(&creds).await;
+						return Ok(creds);
+					}
 					Err(e) => {
 						trace!(self.log, "refresh poll failed, retrying: {}", e);
 					}
@@ -559,6 +853,66 @@ impl Auth {
 	}
 }
 
+impl Auth {
+	/// Shows the device code for the user to enter, as set by the options.
+	fn show_device_code(
+		&self,
+		provider: AuthProvider,
+		code: &DeviceCodeResponse,
+		expires_at: DateTime<Utc>,
+	) {
+		let qr_uri = code
+			.verification_uri_complete
+			.as_deref()
+			.unwrap_or(&code.verification_uri);
+		let qr_code = self
+			.device_code
+			.qr_code
+			.then(|| render_qr_code(qr_uri))
+			.flatten();
+
+		if self.device_code.json {
+			let prompt = DeviceCodePrompt {
+				provider: provider.id(),
+				user_code: &code.user_code,
+				verification_uri: &code.verification_uri,
+				verification_uri_complete: code.verification_uri_complete.as_deref(),
+				expires_at,
+			};
+			println!("{}", serde_json::to_string(&prompt).unwrap());
+			// keep stdout to JSON lines
+			if let Some(qr_code) = qr_code {
+				eprintln!("{}", qr_code);
+			}
+			return;
+		}
+
+		match &code.message {
+			Some(m) => self.log.result(m),
+			None => self.log.result(format!(
+				"To grant access to the server, please log into {} and use code {}",
+				code.verification_uri, code.user_code
+			)),
+		};
+		if let Some(qr_code) = qr_code {
+			self.log.result(&qr_code);
+		}
+	}
+}
+
+/// Renders a QR code of the data for a terminal, light on dark.
+fn render_qr_code(data: &str) -> Option<String> {
+	use qrcode::render::unicode::Dense1x2;
+
+	let code = qrcode::QrCode::new(data.as_bytes()).ok()?;
+	Some(
+		code.render::<Dense1x2>()
+			.dark_color(Dense1x2::Light)
+			.light_color(Dense1x2::Dark)
+			.build(),
+	)
+}
+
 #[async_trait]
 impl AuthorizationProvider for Auth {
 	async fn get_authorization(&self) -> Result<Authorization, HttpError> {
@@ -568,6 +922,100 @@ impl AuthorizationProvider for Auth {
 	}
 }
 
+/// Checks that a name given to `--as-profile` can be used in the names its
+/// credentials are stored under.
+pub fn validate_profile_name(name: &str) -> Result<(), InvalidProfileName> {
+	let valid = !name.is_empty()
+		&& name
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+	if valid {
+		Ok(())
+	} else {
+		Err(InvalidProfileName(name.to_string()))
+	}
+}
+
+/// Source of the credentials used with the tunnel service.
+#[async_trait]
+pub trait CredentialProvider: Send + Sync {
+	/// Gets credentials, logging in first if the provider needs to.
+	async fn get_credential(&self) -> Result<StoredCredential, AnyError>;
+}
+
+#[async_trait]
+impl CredentialProvider for Auth {
+	async fn get_credential(&self) -> Result<StoredCredential, AnyError> {
+		Auth::get_credential(self).await
+	}
+}
+
+/// A personal access token read from a file, for machines where no one is
+/// around to complete a device code login. The file is read for each request,
+/// so the token can be rotated while the CLI runs; it's never refreshed.
+#[derive(Clone, Debug)]
+pub struct AccessTokenFile {
+	provider: AuthProvider,
+	path: PathBuf,
+}
+
+impl AccessTokenFile {
+	pub fn new(provider: AuthProvider, path: PathBuf) -> AccessTokenFile {
+		AccessTokenFile { provider, path }
+	}
+
+	fn read(&self) -> Result<String, InvalidAccessTokenFile> {
+		let contents = std::fs::read_to_string(&self.path).map_err(|e| {
+			InvalidAccessTokenFile(format!("error reading {}: {}", self.path.display(), e))
+		})?;
+
+		match contents.trim() {
+			"" => Err(InvalidAccessTokenFile(format!(
+				"{} is empty",
+				self.path.display()
+			))),
+			token => Ok(token.to_string()),
+		}
+	}
+}
+
+#[async_trait]
+impl CredentialProvider for AccessTokenFile {
+	async fn get_credential(&self) -> Result<StoredCredential, AnyError> {
+		Ok(StoredCredential {
+			provider: self.provider,
+			access_token: self.read()?,
+			refresh_token: None,
+			expires_at: None,
+		})
+	}
+}
+
+/// Authorizes requests to the tunnel service with credentials from any
+/// `CredentialProvider`, so the management client needn't know which is used.
+#[derive(Clone)]
+pub struct TunnelAuthorization(Arc<dyn CredentialProvider>);
+
+impl TunnelAuthorization {
+	pub fn new(provider: impl CredentialProvider + 'static) -> TunnelAuthorization {
+		TunnelAuthorization(Arc::new(provider))
+	}
+
+	pub async fn get_credential(&self) -> Result<StoredCredential, AnyError> {
+		self.0.get_credential().await
+	}
+}
+
+#[async_trait]
+impl AuthorizationProvider for TunnelAuthorization {
+	async fn get_authorization(&self) -> Result<Authorization, HttpError> {
+		self.get_credential()
+			.await
+			.map(StoredCredential::authorization)
+			.map_err(|e| HttpError::AuthorizationError(e.to_string()))
+	}
+}
+
 lazy_static::lazy_static! {
 	static ref HOSTNAME: Vec<u8> = gethostname().to_string_lossy().bytes().collect();
 }
@@ -592,3 +1040,115 @@ fn encrypt(value: &str) -> String {
 fn decrypt(value: &str) -> Option<String> {
 	Some(value.to_owned())
 }
+
+/// Formats a clock skew as a rough, human-readable duration.
+fn format_skew(skew: Duration) -> String {
+	let secs = skew.num_seconds().abs();
+	match secs {
+		s if s < 60 * 60 => format!("{} minutes", s / 60),
+		s if s < 60 * 60 * 24 => format!("{} hours", s / (60 * 60)),
+		s => format!("{} days", s / (60 * 60 * 24)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_records_significant_skew_once() {
+		let skew = ClockSkew::default();
+		let local = Utc::now();
+
+		assert!(skew.record(local + Duration::seconds(5), local).is_none());
+		assert!(skew.is_measured());
+
+		assert_eq!(
+			skew.record(local + Duration::minutes(10), local),
+			Some(Duration::minutes(10))
+		);
+		assert!(skew
+			.record(local + Duration::minutes(10) + Duration::seconds(2), local)
+			.is_none());
+		assert!(skew.now() > Utc::now() + Duration::minutes(9));
+	}
+
+	#[test]
+	fn test_formats_skew() {
+		assert_eq!(format_skew(Duration::minutes(-10)), "10 minutes");
+		assert_eq!(format_skew(Duration::hours(3)), "3 hours");
+		assert_eq!(format_skew(Duration::days(400)), "400 days");
+	}
+
+	#[tokio::test]
+	async fn test_reads_access_token_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("token");
+		let provider = AccessTokenFile::new(AuthProvider::Github, path.clone());
+		assert!(provider.get_credential().await.is_err());
+
+		std::fs::write(&path, "  \n").unwrap();
+		assert!(provider.get_credential().await.is_err());
+
+		std::fs::write(&path, "ghp_first\n").unwrap();
+		let auth = TunnelAuthorization::new(provider);
+		assert!(matches!(
+			auth.get_authorization().await,
+			Ok(Authorization::Github(a)) if a.ends_with(" ghp_first")
+		));
+
+		// a rotated token is picked up without restarting
+		std::fs::write(&path, "ghp_second").unwrap();
+		let cred = auth.get_credential().await.unwrap();
+		assert_eq!(cred.access_token, "ghp_second");
+	}
+
+	#[test]
+	fn test_migrates_secret() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut from = FileStorage(PersistedState::new(dir.path().join("from.json")));
+		let mut to = FileStorage(PersistedState::new(dir.path().join("to.json")));
+		assert!(migrate_secret(&mut from, &mut to).unwrap().is_none());
+
+		from.store(StoredCredential {
+			provider: AuthProvider::Github,
+			access_token: "token".to_string(),
+			refresh_token: None,
+			expires_at: None,
+		})
+		.unwrap();
+
+		let moved = migrate_secret(&mut from, &mut to).unwrap().unwrap();
+		assert_eq!(moved.access_token, "token");
+		assert!(from.read().unwrap().is_none());
+		assert_eq!(to.read().unwrap().unwrap().access_token, "token");
+	}
+
+	#[test]
+	fn test_renders_device_code_prompt() {
+		let prompt = DeviceCodePrompt {
+			provider: AuthProvider::Github.id(),
+			user_code: "ABCD-1234",
+			verification_uri: "https://github.com/login/device",
+			verification_uri_complete: None,
+			expires_at: DateTime::parse_from_rfc3339("2024-01-01T00:15:00Z")
+				.unwrap()
+				.with_timezone(&Utc),
+		};
+		assert_eq!(
+			serde_json::to_string(&prompt).unwrap(),
+			r#"{"provider":"github","userCode":"ABCD-1234","verificationUri":"https://github.com/login/device","expiresAt":"2024-01-01T00:15:00Z"}"#
+		);
+
+		let qr = render_qr_code("https://github.com/login/device").unwrap();
+		assert!(qr.lines().count() > 10);
+	}
+
+	#[test]
+	fn test_profile_names() {
+		assert!(validate_profile_name("build-bot_2").is_ok());
+		assert!(validate_profile_name("").is_err());
+		assert!(validate_profile_name("../token").is_err());
+		assert!(validate_profile_name("bot.json").is_err());
+	}
+}
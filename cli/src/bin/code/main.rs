@@ -8,9 +8,11 @@ use std::process::Command;
 
 use clap::Parser;
 use cli::{
-	commands::{args, tunnels, update, version, CommandContext},
+	auth,
+	commands::{args, output, serve_web, tunnels, update, version, CommandContext},
 	desktop, log as own_log,
 	state::LauncherPaths,
+	tunnels::connectivity,
 	util::{
 		errors::{wrap, AnyError},
 		is_integrated_cli,
@@ -23,6 +25,10 @@ use opentelemetry::trace::TracerProvider;
 
 use log::{Level, Metadata, Record};
 
+/// Exit code used when the user cancels an operation with Ctrl+C, following
+/// the shell convention for processes terminated by SIGINT.
+const CANCELLED_EXIT_CODE: i32 = 130;
+
 #[tokio::main]
 async fn main() -> Result<(), std::convert::Infallible> {
 	let raw_args = std::env::args_os().collect::<Vec<_>>();
@@ -37,17 +43,53 @@ async fn main() -> Result<(), std::convert::Infallible> {
 		});
 
 	let core = parsed.core();
+	let tracer = SdkTracerProvider::builder().build().tracer("codecli");
+	let level = if core.global_options.verbose {
+		own_log::Level::Trace
+	} else {
+		core.global_options.log.unwrap_or(own_log::Level::Info)
+	};
+	let json_output = core.global_options.output == Some(args::ResultFormat::Json);
+	let mut log = if core.global_options.structured_progress {
+		own_log::Logger::new_structured(tracer, level)
+	} else if json_output {
+		own_log::Logger::new_stderr(tracer, level)
+	} else {
+		own_log::Logger::new(tracer, level)
+	};
+	if let Some(log_file) = &core.global_options.log_file {
+		match own_log::FileLogSink::new(level, log_file) {
+			Ok(sink) => log = log.tee(sink),
+			Err(e) => own_log::emit(
+				own_log::Level::Warn,
+				"",
+				&format!("could not open log file {}: {}", log_file.display(), e),
+			),
+		}
+	}
+
+	// CLI processes started from this one are part of the same invocation
+	std::env::set_var(own_log::CORRELATION_ID_ENV, own_log::correlation_id());
+	if let Err(e) = connectivity::configure_proxy(
+		core.global_options.proxy_url.as_deref(),
+		core.global_options.no_proxy.as_deref(),
+	) {
+		print_and_exit(e);
+	}
+	if let Some(profile) = &core.global_options.as_profile {
+		if let Err(e) = auth::validate_profile_name(profile) {
+			print_and_exit(e);
+		}
+	}
+	log.emit(
+		own_log::Level::Debug,
+		&format!("Correlation ID: {}", own_log::correlation_id()),
+	);
+
 	let context = CommandContext {
 		http: reqwest::Client::new(),
 		paths: LauncherPaths::new(&core.global_options.cli_data_dir).unwrap(),
-		log: own_log::Logger::new(
-			SdkTracerProvider::builder().build().tracer("codecli"),
-			if core.global_options.verbose {
-				own_log::Level::Trace
-			} else {
-				core.global_options.log.unwrap_or(own_log::Level::Info)
-			},
-		),
+		log,
 		args: core.clone(),
 	};
 
@@ -88,24 +130,78 @@ async fn main() -> Result<(), std::convert::Infallible> {
 				args::VersionSubcommand::Show => version::show(context).await,
 			},
 
+			Some(args::Commands::ServeWeb(serve_web_args)) => {
+				serve_web::serve_web(context, serve_web_args).await
+			}
+
 			Some(args::Commands::Tunnel(tunnel_args)) => match tunnel_args.subcommand {
-				Some(args::TunnelSubcommand::Prune) => tunnels::prune(context).await,
-				Some(args::TunnelSubcommand::Unregister) => tunnels::unregister(context).await,
+				Some(args::TunnelSubcommand::Prune(prune_args)) => {
+					tunnels::prune(context, prune_args).await
+				}
+				Some(args::TunnelSubcommand::Setup) => tunnels::setup(context).await,
+				Some(args::TunnelSubcommand::Unregister(unregister_args)) => {
+					tunnels::unregister(context, unregister_args).await
+				}
+				Some(args::TunnelSubcommand::List(list_args)) => {
+					tunnels::list(context, list_args).await
+				}
+				Some(args::TunnelSubcommand::VerifyHost(verify_args)) => {
+					tunnels::verify_host(context, verify_args).await
+				}
+				Some(args::TunnelSubcommand::Attach) => tunnels::attach(context).await,
+				Some(args::TunnelSubcommand::Watch(watch_args)) => {
+					tunnels::watch(context, watch_args).await
+				}
+				Some(args::TunnelSubcommand::Status) => tunnels::status(context).await,
+				Some(args::TunnelSubcommand::Forward(forward_args)) => {
+					tunnels::forward(context, forward_args).await
+				}
+				Some(args::TunnelSubcommand::Restart(restart_args)) => {
+					tunnels::restart(context, restart_args).await
+				}
+				Some(args::TunnelSubcommand::Pause) => tunnels::pause(context).await,
+				Some(args::TunnelSubcommand::Resume) => tunnels::resume(context).await,
+				Some(args::TunnelSubcommand::Guest(guest_args)) => {
+					tunnels::guest(context, guest_args).await
+				}
+				Some(args::TunnelSubcommand::PingClusters(ping_args)) => {
+					tunnels::ping_clusters(context, ping_args).await
+				}
+				Some(args::TunnelSubcommand::RelayTest) => tunnels::relay_test(context).await,
+				Some(args::TunnelSubcommand::Doctor) => tunnels::doctor(context).await,
+				Some(args::TunnelSubcommand::SelfTest) => tunnels::self_test(context).await,
 				Some(args::TunnelSubcommand::Rename(rename_args)) => {
 					tunnels::rename(context, rename_args).await
 				}
 				Some(args::TunnelSubcommand::User(user_command)) => {
 					tunnels::user(context, user_command).await
 				}
+				Some(args::TunnelSubcommand::Config(args::TunnelConfigSubCommands::Show(
+					show_args,
+				))) => tunnels::config_show(context, tunnel_args.serve_args, show_args).await,
 				Some(args::TunnelSubcommand::Service(service_args)) => {
 					tunnels::service(context, service_args).await
 				}
+				Some(args::TunnelSubcommand::Usage(usage_args)) => {
+					tunnels::usage(context, usage_args).await
+				}
 				None => tunnels::serve(context, tunnel_args.serve_args).await,
 			},
 		},
 	};
 
 	match result {
+		Err(e) if json_output => {
+			output::print_json_error(&e);
+			std::process::exit(match e {
+				AnyError::OperationCancelled(_) => CANCELLED_EXIT_CODE,
+				_ => 1,
+			});
+		}
+		Err(AnyError::OperationCancelled(e)) => {
+			own_log::emit(own_log::Level::Warn, "", &format!("{}", e));
+			std::process::exit(CANCELLED_EXIT_CODE);
+		}
 		Err(e) => print_and_exit(e),
 		Ok(code) => std::process::exit(code),
 	}
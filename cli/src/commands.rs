@@ -4,8 +4,11 @@
  *--------------------------------------------------------------------------------------------*/
 
 mod context;
+mod tunnel_config;
 
 pub mod args;
+pub mod output;
+pub mod serve_web;
 pub mod tunnels;
 pub mod update;
 pub mod version;
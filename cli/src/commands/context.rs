@@ -3,9 +3,9 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use crate::{log, state::LauncherPaths};
+use crate::{auth::Auth, log, state::LauncherPaths};
 
-use super::args::CliCore;
+use super::args::{CliCore, ResultFormat};
 
 pub struct CommandContext {
 	pub log: log::Logger,
@@ -13,3 +13,27 @@ pub struct CommandContext {
 	pub args: CliCore,
 	pub http: reqwest::Client,
 }
+
+impl CommandContext {
+	/// Gets whether results should be printed as JSON, from `--output json`.
+	pub fn json_output(&self) -> bool {
+		self.args.global_options.output == Some(ResultFormat::Json)
+	}
+
+	/// Gets the login to use, which is the one stored under `--as-profile`
+	/// if it's given.
+	pub fn auth(&self) -> Auth {
+		with_profile(
+			Auth::new(&self.paths, self.log.clone()),
+			self.args.global_options.as_profile.as_deref(),
+		)
+	}
+}
+
+/// Makes the login use the credentials stored under the profile, if any.
+pub fn with_profile(auth: Auth, profile: Option<&str>) -> Auth {
+	match profile {
+		Some(p) => auth.with_profile(p),
+		None => auth,
+	}
+}
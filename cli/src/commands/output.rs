@@ -7,8 +7,62 @@ use std::fmt::Display;
 
 use std::io::{BufWriter, Write};
 
+use serde::Serialize;
+
+use crate::util::errors::AnyError;
+
 use super::args::OutputFormat;
 
+/// Result of a command printed with `--output json`. The fields of the data
+/// are alongside `success`.
+#[derive(Serialize)]
+struct JsonResult<'a, T: Serialize> {
+	success: bool,
+	#[serde(flatten)]
+	data: &'a T,
+}
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+	success: bool,
+	error: JsonErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct JsonErrorDetail<'a> {
+	code: &'a str,
+	message: String,
+}
+
+/// Prints the result of a command that succeeded as a JSON object.
+pub fn print_json_result<T: Serialize>(data: &T) {
+	println!("{}", json_result(data));
+}
+
+/// Prints the error a command failed with as a JSON object.
+pub fn print_json_error(err: &AnyError) {
+	println!("{}", json_error(err));
+}
+
+fn json_result<T: Serialize>(data: &T) -> String {
+	let result = JsonResult {
+		success: true,
+		data,
+	};
+	serde_json::to_string(&result).unwrap()
+}
+
+fn json_error(err: &AnyError) -> String {
+	let result = JsonError {
+		success: false,
+		error: JsonErrorDetail {
+			code: err.code(),
+			message: err.to_string(),
+		},
+	};
+	serde_json::to_string(&result).unwrap()
+}
+
 pub struct Column {
 	max_width: usize,
 	heading: &'static str,
@@ -35,6 +89,7 @@ impl OutputFormat {
 		match *self {
 			OutputFormat::Json => JsonTablePrinter().print(table, &mut std::io::stdout()),
 			OutputFormat::Text => TextTablePrinter().print(table, &mut std::io::stdout()),
+			OutputFormat::Csv => CsvTablePrinter().print(table, &mut std::io::stdout()),
 		}
 	}
 }
@@ -73,11 +128,15 @@ impl TablePrinter for JsonTablePrinter {
 				} else {
 					bw.write_all(b"{")?;
 				}
-				for col in &table.cols {
+				for (j, col) in table.cols.iter().enumerate() {
+					if j > 0 {
+						bw.write_all(b",")?;
+					}
 					serde_json::to_writer(&mut bw, col.heading)?;
 					bw.write_all(b":")?;
 					serde_json::to_writer(&mut bw, &col.data[i])?;
 				}
+				bw.write_all(b"}")?;
 			}
 		}
 
@@ -119,6 +178,46 @@ impl TablePrinter for TextTablePrinter {
 	}
 }
 
+/// Type that prints the output as comma-separated values, with a header row.
+pub struct CsvTablePrinter();
+
+impl TablePrinter for CsvTablePrinter {
+	fn print(
+		&self,
+		table: OutputTable,
+		out: &mut dyn std::io::Write,
+	) -> Result<(), std::io::Error> {
+		let mut bw = BufWriter::new(out);
+
+		write_csv_row(&mut bw, table.cols.iter().map(|c| c.heading))?;
+		if !table.cols.is_empty() {
+			let data_len = table.cols[0].data.len();
+			for i in 0..data_len {
+				write_csv_row(&mut bw, table.cols.iter().map(|c| c.data[i].as_str()))?;
+			}
+		}
+
+		bw.flush()
+	}
+}
+
+fn write_csv_row<'a>(
+	mut w: impl Write,
+	cols: impl Iterator<Item = &'a str>,
+) -> Result<(), std::io::Error> {
+	for (i, col) in cols.enumerate() {
+		if i > 0 {
+			w.write_all(b",")?;
+		}
+		if col.contains([',', '"', '\r', '\n']) {
+			write!(w, "\"{}\"", col.replace('"', "\"\""))?;
+		} else {
+			w.write_all(col.as_bytes())?;
+		}
+	}
+	w.write_all(b"\r\n")
+}
+
 fn write_columns<T>(
 	mut w: impl Write,
 	cols: impl Iterator<Item = T>,
@@ -133,3 +232,29 @@ where
 	}
 	w.write_all(b"\r\n")
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::util::errors::TunnelNameRequired;
+
+	#[derive(Serialize)]
+	struct Tunnel {
+		name: &'static str,
+	}
+
+	#[test]
+	fn test_prints_json_results_and_errors() {
+		assert_eq!(
+			json_result(&Tunnel { name: "my-tunnel" }),
+			r#"{"success":true,"name":"my-tunnel"}"#
+		);
+
+		let err: AnyError = TunnelNameRequired().into();
+		let value: serde_json::Value = serde_json::from_str(&json_error(&err)).unwrap();
+		assert_eq!(value["success"], false);
+		assert_eq!(value["error"]["code"], "TunnelNameRequired");
+		assert_eq!(value["error"]["message"], err.to_string());
+	}
+}
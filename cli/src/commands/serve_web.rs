@@ -0,0 +1,94 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use crate::{
+	constants::{VSCODE_CLI_COMMIT, VSCODE_CLI_QUALITY},
+	log,
+	options::Quality,
+	tunnels::{
+		code_server::{CodeServerArgs, ServerBuilder, ServerParamsRaw, WebUiMatcher},
+		dev_tunnels::DevTunnels,
+		legal,
+		port_access::PortPrivacy,
+		registry::TunnelRegistry,
+		web_ui,
+	},
+	util::{
+		errors::{wrap, AnyError},
+		prereqs::PreReqChecker,
+	},
+};
+
+use super::{args::ServeWebArgs, CommandContext};
+
+pub async fn serve_web(ctx: CommandContext, args: ServeWebArgs) -> Result<i32, AnyError> {
+	legal::require_consent(&ctx.paths, args.accept_server_license_terms)?;
+	let platform = spanf!(
+		ctx.log,
+		ctx.log.span("prereq"),
+		PreReqChecker::new().verify()
+	)?;
+
+	let mut csa: CodeServerArgs = (&ctx.args).into();
+	csa.host = Some(args.host.clone());
+	csa.port = Some(args.port);
+	csa.connection_token = args.connection_token.clone();
+	csa.without_connection_token = args.without_connection_token;
+	csa.server_base_path = args.server_base_path.clone();
+
+	let resolved = ServerParamsRaw {
+		commit_id: VSCODE_CLI_COMMIT.map(|c| c.to_string()),
+		quality: VSCODE_CLI_QUALITY
+			.and_then(|q| Quality::try_from(q).ok())
+			.unwrap_or(Quality::Stable),
+		code_server_args: csa,
+		headless: false,
+		platform,
+	}
+	.resolve(&ctx.log)
+	.await?;
+
+	let sb = ServerBuilder::new(&ctx.log, &resolved, &ctx.paths);
+	sb.setup().await?;
+	let (mut origin, listening) = sb.start_opaque_with_args::<WebUiMatcher, _>(&[]).await?;
+
+	let local_url = tokio::select! {
+		url = listening => url.map_err(|e| wrap(e, "error waiting for the web UI to start"))?,
+		_ = origin.wait_for_exit() => {
+			error!(ctx.log, "Server exited before the web UI was available");
+			return Ok(1);
+		}
+	};
+
+	let mut registry = None;
+	if let Some(name) = &args.tunnel {
+		let auth = ctx.auth();
+		let mut r = TunnelRegistry::new(DevTunnels::new(&ctx.log, auth, &ctx.paths), &ctx.paths);
+		let port = local_url.port_or_known_default().unwrap_or(args.port);
+
+		let tunnel = r.host(name).await?;
+		tunnel.add_port_tcp(port, PortPrivacy::Private).await?;
+		let url = web_ui::public_url(&local_url, &tunnel.get_port_uri(port).await?)?;
+		ctx.log.result(format!(
+			"Web UI published through tunnel {} at {}",
+			name, url
+		));
+		registry = Some(r);
+	}
+
+	tokio::select! {
+		_ = tokio::signal::ctrl_c() => {},
+		_ = origin.wait_for_exit() => {
+			info!(ctx.log, "Server exited");
+		}
+	}
+
+	if let (Some(mut r), Some(name)) = (registry, &args.tunnel) {
+		r.close(name).await?;
+	}
+	origin.kill().await;
+
+	Ok(0)
+}
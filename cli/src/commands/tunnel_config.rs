@@ -0,0 +1,384 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::ffi::OsString;
+use std::fmt;
+
+use crate::{
+	state::LauncherPaths,
+	tunnels::{connectivity, dev_tunnels},
+	util::prereqs::PreReqChecker,
+};
+
+use super::{
+	args::{GlobalOptions, TunnelServeArgs},
+	output::{Column, OutputTable},
+};
+
+/// Where the value of a setting came from, from highest to lowest precedence.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+	Flag(&'static str),
+	Environment(&'static str),
+	State,
+	Detected,
+	Default,
+}
+
+impl ValueSource {
+	/// Gets whether the value was explicitly configured by the user.
+	pub fn is_explicit(&self) -> bool {
+		matches!(self, ValueSource::Flag(_) | ValueSource::Environment(_))
+	}
+}
+
+impl fmt::Display for ValueSource {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ValueSource::Flag(flag) => write!(f, "flag --{}", flag),
+			ValueSource::Environment(var) => write!(f, "environment {}", var),
+			ValueSource::State => write!(f, "saved state"),
+			ValueSource::Detected => write!(f, "detected"),
+			ValueSource::Default => write!(f, "default"),
+		}
+	}
+}
+
+pub struct ConfigValue {
+	pub setting: &'static str,
+	pub value: String,
+	pub source: ValueSource,
+}
+
+/// Flags given on the command line. Clap merges flags with their environment
+/// variables, so this is used to tell which one a value came from.
+pub struct ProvidedFlags(Vec<String>);
+
+impl ProvidedFlags {
+	pub fn from_args(args: impl IntoIterator<Item = OsString>) -> Self {
+		ProvidedFlags(
+			args.into_iter()
+				.filter_map(|a| a.into_string().ok())
+				.filter_map(|a| {
+					a.strip_prefix("--")
+						.map(|f| f.split('=').next().unwrap_or_default().to_string())
+				})
+				.collect(),
+		)
+	}
+
+	pub fn contains(&self, flag: &str) -> bool {
+		self.0.iter().any(|f| f == flag)
+	}
+
+	/// Gets the source of a value that was set by a flag or, if the flag has
+	/// one, its environment variable.
+	fn source_of(&self, flag: &'static str, env_var: Option<&'static str>) -> ValueSource {
+		match env_var {
+			Some(var) if !self.contains(flag) && std::env::var_os(var).is_some() => {
+				ValueSource::Environment(var)
+			}
+			_ => ValueSource::Flag(flag),
+		}
+	}
+
+	fn optional(
+		&self,
+		setting: &'static str,
+		value: &Option<String>,
+		flag: &'static str,
+		env_var: Option<&'static str>,
+	) -> ConfigValue {
+		match value {
+			Some(v) => ConfigValue {
+				setting,
+				value: v.clone(),
+				source: self.source_of(flag, env_var),
+			},
+			None => ConfigValue {
+				setting,
+				value: "(none)".to_string(),
+				source: ValueSource::Default,
+			},
+		}
+	}
+
+	/// Gets a value that has a default, which was used if `is_set` is false.
+	fn or_default(
+		&self,
+		setting: &'static str,
+		value: String,
+		is_set: bool,
+		flag: &'static str,
+		env_var: Option<&'static str>,
+	) -> ConfigValue {
+		ConfigValue {
+			setting,
+			value,
+			source: if is_set {
+				self.source_of(flag, env_var)
+			} else {
+				ValueSource::Default
+			},
+		}
+	}
+}
+
+/// Resolves the configuration the tunnel would run with.
+pub async fn resolve(
+	paths: &LauncherPaths,
+	global: &GlobalOptions,
+	serve: &TunnelServeArgs,
+	flags: &ProvidedFlags,
+) -> Vec<ConfigValue> {
+	let mut values = vec![ConfigValue {
+		setting: "data directory",
+		value: paths.root().display().to_string(),
+		source: match global.cli_data_dir {
+			Some(_) => flags.source_of("cli-data-dir", Some("VSCODE_CLI_DATA_DIR")),
+			None => ValueSource::Default,
+		},
+	}];
+
+	values.push(match (global.verbose, global.log) {
+		(true, _) => ConfigValue {
+			setting: "log level",
+			value: "trace".to_string(),
+			source: ValueSource::Flag("verbose"),
+		},
+		(false, Some(level)) => ConfigValue {
+			setting: "log level",
+			value: level.to_string(),
+			source: ValueSource::Flag("log"),
+		},
+		(false, None) => ConfigValue {
+			setting: "log level",
+			value: "info".to_string(),
+			source: ValueSource::Default,
+		},
+	});
+
+	values.push(flags.optional(
+		"log file",
+		&global.log_file.as_ref().map(|p| p.display().to_string()),
+		"log-file",
+		Some("VSCODE_CLI_LOG_FILE"),
+	));
+
+	let persisted = dev_tunnels::get_persisted_tunnel(paths);
+	values.push(match (&serve.name, persisted, serve.random_name) {
+		(Some(name), _, _) => ConfigValue {
+			setting: "tunnel name",
+			value: name.clone(),
+			source: ValueSource::Flag("name"),
+		},
+		(None, Some(t), _) => ConfigValue {
+			setting: "tunnel name",
+			value: t.name,
+			source: ValueSource::State,
+		},
+		(None, None, true) => ConfigValue {
+			setting: "tunnel name",
+			value: "(random)".to_string(),
+			source: ValueSource::Flag("random-name"),
+		},
+		(None, None, false) => ConfigValue {
+			setting: "tunnel name",
+			value: "(prompted on first run)".to_string(),
+			source: ValueSource::Default,
+		},
+	});
+
+	values.push(ConfigValue {
+		setting: "auto update",
+		value: serve.auto_update.to_string(),
+		source: if serve.auto_update {
+			ValueSource::Flag("auto-update")
+		} else {
+			ValueSource::Default
+		},
+	});
+
+	values.push(match serve.update_channel {
+		Some(c) => ConfigValue {
+			setting: "update channel",
+			value: format!("{:?}", c).to_lowercase(),
+			source: ValueSource::Flag("update-channel"),
+		},
+		None => ConfigValue {
+			setting: "update channel",
+			value: "(same as the CLI)".to_string(),
+			source: ValueSource::Default,
+		},
+	});
+
+	values.push(match connectivity::get_proxy_env_var() {
+		// the flag is applied by setting the proxy variables at startup
+		Some(_) if global.proxy_url.is_some() => ConfigValue {
+			setting: "proxy",
+			value: connectivity::get_configured_proxy().unwrap_or_default(),
+			source: flags.source_of("proxy-url", Some("VSCODE_CLI_PROXY_URL")),
+		},
+		Some(var) => ConfigValue {
+			setting: "proxy",
+			value: connectivity::get_configured_proxy().unwrap_or_default(),
+			source: ValueSource::Environment(var),
+		},
+		None => ConfigValue {
+			setting: "proxy",
+			value: "(none)".to_string(),
+			source: ValueSource::Default,
+		},
+	});
+
+	values.push(flags.optional(
+		"no proxy",
+		&global.no_proxy,
+		"no-proxy",
+		Some("VSCODE_CLI_NO_PROXY"),
+	));
+
+	let network = &serve.server_network;
+	values.push(flags.optional(
+		"server proxy",
+		&network.server_proxy,
+		"server-proxy",
+		Some("VSCODE_CLI_SERVER_PROXY"),
+	));
+	values.push(flags.optional(
+		"server no proxy",
+		&network.server_no_proxy,
+		"server-no-proxy",
+		Some("VSCODE_CLI_SERVER_NO_PROXY"),
+	));
+	values.push(flags.optional(
+		"extensions gallery",
+		&network.extensions_gallery_url,
+		"extensions-gallery-url",
+		Some("VSCODE_CLI_EXTENSIONS_GALLERY_URL"),
+	));
+
+	let reconnect = &serve.reconnect;
+	let backoff = reconnect.to_config();
+	values.push(flags.or_default(
+		"reconnect base delay",
+		format!("{}s", backoff.base.as_secs()),
+		reconnect.reconnect_base_delay.is_some(),
+		"reconnect-base-delay",
+		Some("VSCODE_CLI_RECONNECT_BASE_DELAY"),
+	));
+	values.push(flags.or_default(
+		"reconnect max delay",
+		format!("{}s", backoff.max.as_secs()),
+		reconnect.reconnect_max_delay.is_some(),
+		"reconnect-max-delay",
+		Some("VSCODE_CLI_RECONNECT_MAX_DELAY"),
+	));
+	values.push(flags.or_default(
+		"reconnect strategy",
+		backoff.strategy.to_string(),
+		reconnect.reconnect_strategy.is_some(),
+		"reconnect-strategy",
+		Some("VSCODE_CLI_RECONNECT_STRATEGY"),
+	));
+	values.push(
+		flags.or_default(
+			"reconnect max retries",
+			backoff
+				.max_retries
+				.map(|r| r.to_string())
+				.unwrap_or_else(|| "(unlimited)".to_string()),
+			reconnect.reconnect_max_retries.is_some(),
+			"reconnect-max-retries",
+			Some("VSCODE_CLI_RECONNECT_MAX_RETRIES"),
+		),
+	);
+
+	let platform = PreReqChecker::new()
+		.with_overrides(serve.server_platform, serve.server_arch)
+		.with_libc_overrides(serve.libc, serve.use_legacy_server)
+		.verify()
+		.await;
+	values.push(ConfigValue {
+		setting: "server platform",
+		value: match platform {
+			Ok(p) => p.headless(),
+			Err(e) => format!("(unsupported: {})", e),
+		},
+		source: if serve.server_platform.is_some() {
+			ValueSource::Flag("server-platform")
+		} else if serve.server_arch.is_some() {
+			ValueSource::Flag("server-arch")
+		} else if serve.libc.is_some() {
+			ValueSource::Flag("libc")
+		} else if serve.use_legacy_server {
+			ValueSource::Flag("use-legacy-server")
+		} else {
+			ValueSource::Detected
+		},
+	});
+
+	values
+}
+
+pub fn to_table(values: Vec<ConfigValue>) -> OutputTable {
+	let mut setting = Column::new("setting");
+	let mut value = Column::new("value");
+	let mut source = Column::new("source");
+	for v in values {
+		setting.add_row(v.setting.to_string());
+		value.add_row(v.value);
+		source.add_row(v.source.to_string());
+	}
+
+	OutputTable::new(vec![setting, value, source])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn flags(args: &[&str]) -> ProvidedFlags {
+		ProvidedFlags::from_args(args.iter().map(OsString::from))
+	}
+
+	#[test]
+	fn test_provided_flags() {
+		let f = flags(&[
+			"code",
+			"tunnel",
+			"--name=foo",
+			"--server-proxy",
+			"http://p",
+			"-v",
+		]);
+		assert!(f.contains("name"));
+		assert!(f.contains("server-proxy"));
+		assert!(!f.contains("http://p"));
+		assert!(!f.contains("v"));
+	}
+
+	#[test]
+	fn test_flag_takes_precedence_over_environment() {
+		let f = flags(&["code", "--cli-data-dir", "/tmp"]);
+		assert_eq!(
+			f.source_of("cli-data-dir", Some("PATH")),
+			ValueSource::Flag("cli-data-dir")
+		);
+
+		let f = flags(&["code"]);
+		assert_eq!(
+			f.source_of("cli-data-dir", Some("PATH")),
+			ValueSource::Environment("PATH")
+		);
+	}
+
+	#[test]
+	fn test_unset_optional_is_default() {
+		let v = flags(&["code"]).optional("log file", &None, "log-file", None);
+		assert_eq!(v.source, ValueSource::Default);
+		assert!(!v.source.is_explicit());
+	}
+}
@@ -4,34 +4,103 @@
  *--------------------------------------------------------------------------------------------*/
 
 use async_trait::async_trait;
-use std::str::FromStr;
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use sysinfo::{Pid, SystemExt};
+use std::fs::File;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::SystemTime;
 use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+use tunnels::contracts::Tunnel;
 
 use super::{
 	args::{
-		AuthProvider, CliCore, ExistingTunnelArgs, TunnelRenameArgs, TunnelServeArgs,
-		TunnelServiceSubCommands, TunnelUserSubCommands,
+		AuthProvider, CliCore, ExistingTunnelArgs, GlobalOptions, OutputFormat,
+		TunnelConfigShowArgs, TunnelForwardArgs, TunnelGuestArgs, TunnelHookArgs, TunnelListArgs,
+		TunnelPingClustersArgs, TunnelPruneArgs, TunnelRenameArgs, TunnelRestartArgs,
+		TunnelServeArgs, TunnelServiceSubCommands, TunnelUnregisterArgs, TunnelUsageSubCommands,
+		TunnelUserSubCommands, TunnelVerifyHostArgs, TunnelWatchArgs,
 	},
-	CommandContext,
+	context::with_profile,
+	output::{print_json_result, Column, OutputTable},
+	tunnel_config, CommandContext,
 };
 
 use crate::{
-	auth::Auth,
-	log::{self, Logger},
+	auth::{AccessTokenFile, Auth, DeviceCodeOptions, TunnelAuthorization},
+	log::{self, BroadcastLogSink, Logger},
+	options::{TelemetryLevel, UpdateChannel},
+	self_update,
 	state::LauncherPaths,
 	tunnels::{
-		code_server::CodeServerArgs, create_service_manager, dev_tunnels, legal,
-		paths::get_all_servers, ServiceContainer, ServiceManager,
+		attestation::{self, HostAttestation},
+		auto_forward::{AutoForwardFilter, AutoForwarder},
+		backend::TunnelBackend,
+		bandwidth_budget::{format_size, BandwidthBudget},
+		capabilities::HostCapabilities,
+		client_auth::AuthorizedClientKeys,
+		code_server::CodeServerArgs,
+		connectivity,
+		container::DevContainer,
+		create_service_manager,
+		declared_ports::DeclaredPort,
+		dev_tunnels,
+		extension_filter::ExtensionFilter,
+		failover::FailoverOptions,
+		guest,
+		handoff::{Handoff, HANDOFF_TIMEOUT},
+		hooks::{HookSink, TunnelHooks},
+		legal,
+		local_forwarding::ForwardTargets,
+		local_relay::{LocalRelayBackend, LocalRelayOptions, DEFAULT_LOCAL_HOST},
+		paths::get_all_servers,
+		port_access::PortAccessRules,
+		port_metrics,
+		port_stats::{self, PortStatsSink, PortTraffic},
+		registry::TunnelRegistry,
+		self_test,
+		setup::TunnelSetup,
+		singleton::{self, acquire_singleton},
+		status::{self, StatusSink},
+		usage::{self, SessionRecord, UsageMonth, UsageSink},
+		warm_server::WarmServerOptions,
+		PortForwardingProcessor, ServiceContainer, ServiceManager, UpdateOptions,
 	},
+	update_service::Platform,
 	util::{
-		errors::{wrap, AnyError},
-		prereqs::PreReqChecker,
+		errors::{
+			wrap, AnyError, HostNotAttested, InvalidIdleTimeout, TunnelAlreadyRunning,
+			TunnelHostFailed, TunnelOwnedByOtherAccount, TunnelRestartFailed,
+		},
+		input::{prompt_options, prompt_placeholder, prompt_yn},
+		machine::wait_until_process_exits,
+		os_permissions::{self, PermissionStatus},
+		power::{self, PowerPolicy},
+		prereqs::{detect_host_arch, PreReqChecker},
+		sync::cancellable,
 	},
 };
 
+/// How long a respawned, updated CLI has to start listening before the
+/// update is rolled back.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often a running launcher tunnel checks that it's still registered
+/// with the tunnel service.
+const TUNNEL_REGISTRATION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often to check whether the machine is on battery or a metered
+/// connection, with `--pause-on-battery` or `--pause-on-metered`.
+const POWER_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Log file for tunnels started with `--detach`, in the data directory.
+const DETACHED_LOG_FILE_NAME: &str = "tunnel.log";
+
 impl From<AuthProvider> for crate::auth::AuthProvider {
 	fn from(auth_provider: AuthProvider) -> Self {
 		match auth_provider {
@@ -58,6 +127,19 @@ impl From<ExistingTunnelArgs> for Option<dev_tunnels::ExistingTunnel> {
 	}
 }
 
+impl From<TunnelHookArgs> for TunnelHooks {
+	fn from(a: TunnelHookArgs) -> TunnelHooks {
+		TunnelHooks {
+			on_connect: a.on_connect,
+			on_disconnect: a.on_disconnect,
+			on_reconnect: a.on_reconnect,
+			on_port_forwarded: a.on_port_forwarded,
+			on_security_event: a.on_security_event,
+			security_webhook: a.security_webhook,
+		}
+	}
+}
+
 struct TunnelServiceContainer {
 	args: CliCore,
 }
@@ -84,6 +166,7 @@ impl ServiceContainer for TunnelServiceContainer {
 				random_name: true, // avoid prompting
 				..Default::default()
 			},
+			self.args.global_options.as_profile.clone(),
 			csa,
 			Some(shutdown_rx),
 		)
@@ -96,6 +179,22 @@ pub enum ShutdownSignal {
 	CtrlC,
 	ParentProcessKilled,
 	ServiceStopped,
+	ShutdownRequested,
+	PrimaryHostOnline,
+	GuestTunnelExpired,
+	TunnelDeleted,
+	MainTunnelStopped,
+	PowerConstrained,
+	IdleTimeout(Duration),
+	/// Hand the tunnel over to a new process, started from the binary if
+	/// one's given. Not a shutdown, unless the new process takes over.
+	RestartRequested(Option<PathBuf>),
+	/// Take the tunnel off the relay until hosting's resumed. Not a shutdown.
+	PauseRequested,
+	/// Resume hosting after it was paused.
+	ResumeRequested,
+	/// The tunnel was renamed by another process. Not a shutdown.
+	Renamed(String),
 }
 
 impl fmt::Display for ShutdownSignal {
@@ -104,6 +203,25 @@ impl fmt::Display for ShutdownSignal {
 			ShutdownSignal::CtrlC => write!(f, "Ctrl-C received"),
 			ShutdownSignal::ParentProcessKilled => write!(f, "Parent process no longer exists"),
 			ShutdownSignal::ServiceStopped => write!(f, "Service stopped"),
+			ShutdownSignal::ShutdownRequested => {
+				write!(f, "Shutdown requested by another tunnel instance")
+			}
+			ShutdownSignal::PrimaryHostOnline => write!(f, "The primary host is back online"),
+			ShutdownSignal::GuestTunnelExpired => write!(f, "The guest tunnel expired"),
+			ShutdownSignal::TunnelDeleted => {
+				write!(f, "The tunnel was deleted from the tunnel service")
+			}
+			ShutdownSignal::MainTunnelStopped => write!(f, "The main tunnel stopped"),
+			ShutdownSignal::PowerConstrained => {
+				write!(f, "The machine switched to battery or a metered connection")
+			}
+			ShutdownSignal::IdleTimeout(d) => {
+				write!(f, "No clients were connected for {}s", d.as_secs())
+			}
+			ShutdownSignal::RestartRequested(_) => write!(f, "Restart requested"),
+			ShutdownSignal::PauseRequested => write!(f, "Pause requested"),
+			ShutdownSignal::ResumeRequested => write!(f, "Resume requested"),
+			ShutdownSignal::Renamed(name) => write!(f, "The tunnel was renamed to {}", name),
 		}
 	}
 }
@@ -116,31 +234,29 @@ pub async fn service(
 	match service_args {
 		TunnelServiceSubCommands::Install => {
 			// ensure logged in, otherwise subsequent serving will fail
-			Auth::new(&ctx.paths, ctx.log.clone())
-				.get_credential()
-				.await?;
+			ctx.auth().get_credential().await?;
 
 			// likewise for license consent
 			legal::require_consent(&ctx.paths, false)?;
 
-			let current_exe =
-				std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
-
-			manager.register(
-				current_exe,
-				&[
-					"--cli-data-dir",
-					ctx.paths.root().as_os_str().to_string_lossy().as_ref(),
-					"tunnel",
-					"service",
-					"internal-run",
-				],
-			)?;
-			ctx.log.result("Service successfully installed! You can use `code tunnel service log` to monitor it, and `code tunnel service uninstall` to remove it.");
+			// the service can't ask for permissions once it's running, so
+			// missing ones are pointed out while someone's here to grant them
+			if let Ok(exe) = std::env::current_exe() {
+				for check in os_permissions::check_permissions(&exe).await {
+					if check.status == PermissionStatus::Missing {
+						warning!(ctx.log, "{} is not granted. {}", check.name, check.remedy);
+					}
+				}
+			}
+
+			install_service(&ctx, &manager)?;
 		}
 		TunnelServiceSubCommands::Uninstall => {
 			manager.unregister()?;
 		}
+		TunnelServiceSubCommands::Log => {
+			manager.show_logs(&ctx.paths)?;
+		}
 		TunnelServiceSubCommands::InternalRun => {
 			manager.run(ctx.paths.clone(), TunnelServiceContainer::new(ctx.args))?;
 		}
@@ -149,24 +265,123 @@ pub async fn service(
 	Ok(0)
 }
 
+fn install_service(ctx: &CommandContext, manager: &impl ServiceManager) -> Result<(), AnyError> {
+	let current_exe = std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+
+	let data_dir = ctx.paths.root().as_os_str().to_string_lossy();
+	let mut args = vec!["--cli-data-dir", data_dir.as_ref()];
+	// the service hosts the tunnel with the same login it was installed with
+	if let Some(profile) = &ctx.args.global_options.as_profile {
+		args.extend(["--as-profile", profile.as_str()]);
+	}
+	args.extend(["tunnel", "service", "internal-run"]);
+
+	manager.register(current_exe, &args)?;
+	ctx.log.result("Service successfully installed! You can use `code tunnel service log` to monitor it, and `code tunnel service uninstall` to remove it.");
+
+	Ok(())
+}
+
+/// Walks through logging in, naming the machine, and choosing the defaults
+/// the tunnel is served with, and then optionally installs it as a service.
+pub async fn setup(ctx: CommandContext) -> Result<i32, AnyError> {
+	legal::require_consent(&ctx.paths, false)?;
+
+	let auth = ctx.auth();
+	if !matches!(auth.get_current_credential(), Ok(Some(_))) {
+		ctx.log
+			.result("First, sign in to the account to host tunnels with.");
+		cancellable(auth.login(None, None)).await?;
+	}
+
+	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+	let current_name = dev_tunnels::get_persisted_tunnel(&ctx.paths).map(|t| t.name);
+	let keep_name = match &current_name {
+		Some(name) => prompt_yn(&format!("Keep calling this machine '{}'?", name))?,
+		None => false,
+	};
+	if !keep_name {
+		let name = dt.get_name_for_tunnel(None, false).await?;
+		dt.rename_tunnel(&name).await?;
+		ctx.log
+			.result(format!("This machine will be called '{}'", name));
+	}
+
+	let mut setup = TunnelSetup::load(&ctx.paths);
+	setup.declared_ports = loop {
+		let ports = prompt_placeholder(
+			"Ports to forward when the tunnel connects, separated by spaces, as <port>[:<protocol>][:public] (or 'none')",
+			&if setup.declared_ports.is_empty() {
+				"none".to_string()
+			} else {
+				setup.declared_ports.join(" ")
+			},
+		)?;
+		let ports: Vec<String> = ports
+			.split_whitespace()
+			.filter(|p| *p != "none")
+			.map(|p| p.to_string())
+			.collect();
+		match ports.iter().find_map(|p| p.parse::<DeclaredPort>().err()) {
+			Some(e) => ctx.log.result(e.to_string()),
+			None => break ports,
+		}
+	};
+
+	let levels = [
+		TelemetryLevel::All,
+		TelemetryLevel::Error,
+		TelemetryLevel::Crash,
+		TelemetryLevel::Off,
+	];
+	setup.telemetry_level = Some(prompt_options(
+		"What telemetry should the server send?",
+		&levels,
+	)?);
+
+	setup.save(&ctx.paths)?;
+	ctx.log
+		.result("Saved your choices. Flags passed to `code tunnel` take precedence over them.");
+
+	let manager = create_service_manager(ctx.log.clone());
+	if prompt_yn("Install the tunnel as a service, so it starts when you log in?")? {
+		install_service(&ctx, &manager)?;
+	} else {
+		ctx.log
+			.result("Setup is complete! Run `code tunnel` to start hosting.");
+	}
+
+	Ok(0)
+}
+
 pub async fn user(ctx: CommandContext, user_args: TunnelUserSubCommands) -> Result<i32, AnyError> {
-	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let auth = ctx.auth();
 	match user_args {
 		TunnelUserSubCommands::Login(login_args) => {
-			auth.login(
+			let auth = auth.with_device_code_options(DeviceCodeOptions {
+				json: ctx.json_output(),
+				qr_code: login_args.qr_code,
+				timeout: login_args.timeout.map(Duration::from_secs),
+			});
+			cancellable(auth.login(
 				login_args.provider.map(|p| p.into()),
 				login_args.access_token.to_owned(),
-			)
+			))
 			.await?;
 		}
 		TunnelUserSubCommands::Logout => {
 			auth.clear_credentials()?;
 		}
 		TunnelUserSubCommands::Show => {
-			if let Ok(Some(_)) = auth.get_current_credential() {
+			let logged_in = matches!(auth.get_current_credential(), Ok(Some(_)));
+			if ctx.json_output() {
+				print_json_result(&UserResult { logged_in });
+			} else if logged_in {
 				ctx.log.result("logged in");
 			} else {
 				ctx.log.result("not logged in");
+			}
+			if !logged_in {
 				return Ok(1);
 			}
 		}
@@ -177,39 +392,744 @@ pub async fn user(ctx: CommandContext, user_args: TunnelUserSubCommands) -> Resu
 
 /// Remove the tunnel used by this gateway, if any.
 pub async fn rename(ctx: CommandContext, rename_args: TunnelRenameArgs) -> Result<i32, AnyError> {
-	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let auth = ctx.auth();
 	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
 	dt.rename_tunnel(&rename_args.name).await?;
-	ctx.log.result(&format!(
-		"Successfully renamed this gateway to {}",
-		&rename_args.name
+	// a running tunnel learns its new name without restarting
+	if singleton::running_pid(&ctx.paths).is_some() {
+		if let Err(e) = singleton::notify_renamed(&ctx.paths, &rename_args.name).await {
+			warning!(
+				ctx.log,
+				"Could not tell the running tunnel it was renamed, it will use the new name once restarted: {}",
+				e
+			);
+		}
+	}
+	if ctx.json_output() {
+		let tunnel = dev_tunnels::get_persisted_tunnel(&ctx.paths).map(TunnelResult::from);
+		print_json_result(&tunnel);
+	} else {
+		ctx.log.result(format!(
+			"Successfully renamed this gateway to {}",
+			&rename_args.name
+		));
+	}
+
+	Ok(0)
+}
+
+/// Adds or removes a forwarded port on the tunnel running on this machine.
+pub async fn forward(
+	ctx: CommandContext,
+	forward_args: TunnelForwardArgs,
+) -> Result<i32, AnyError> {
+	let uri = if forward_args.remove {
+		singleton::unforward_port(&ctx.paths, forward_args.port).await?;
+		None
+	} else {
+		Some(
+			singleton::forward_port(&ctx.paths, forward_args.port, forward_args.port_visibility)
+				.await?,
+		)
+	};
+
+	if ctx.json_output() {
+		print_json_result(&PortResult {
+			port: forward_args.port,
+			forwarded: uri.is_some(),
+			uri,
+		});
+	} else if let Some(uri) = uri {
+		ctx.log.result(uri);
+	} else {
+		ctx.log
+			.result(format!("Stopped forwarding port {}", forward_args.port));
+	}
+
+	Ok(0)
+}
+
+/// Restarts the tunnel running on this machine in place, and waits for the
+/// new process to take over.
+pub async fn restart(
+	ctx: CommandContext,
+	restart_args: TunnelRestartArgs,
+) -> Result<i32, AnyError> {
+	// the running tunnel resolves relative paths from its own directory
+	let exe = restart_args
+		.exec
+		.map(|p| {
+			std::fs::canonicalize(&p).map_err(|e| wrap(e, format!("error reading {}", p.display())))
+		})
+		.transpose()?;
+
+	if singleton::status(&ctx.paths).await?.status.paused.is_some() {
+		return Err(TunnelRestartFailed("hosting is paused, resume it first".to_string()).into());
+	}
+
+	let pid = singleton::request_restart(&ctx.paths, exe).await?;
+	ctx.log.result(format!(
+		"Restarting the tunnel running in process {}...",
+		pid
+	));
+
+	let new_pid = cancellable(singleton::wait_for_takeover(
+		&ctx.paths,
+		pid,
+		HANDOFF_TIMEOUT + Duration::from_secs(10),
+	))
+	.await?;
+	ctx.log.result(format!(
+		"Process {} took the tunnel over. Connected clients will reconnect to it.",
+		new_pid
 	));
 
 	Ok(0)
 }
 
-/// Remove the tunnel used by this gateway, if any.
-pub async fn unregister(ctx: CommandContext) -> Result<i32, AnyError> {
-	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+/// Takes the running tunnel off the relay until it's resumed.
+pub async fn pause(ctx: CommandContext) -> Result<i32, AnyError> {
+	let pid = singleton::request_pause(&ctx.paths).await?;
+	ctx.log.result(format!(
+		"Paused hosting of the tunnel running in process {}. Run `code tunnel resume` to resume it.",
+		pid
+	));
+	Ok(0)
+}
+
+/// Puts the running tunnel back on the relay after it was paused.
+pub async fn resume(ctx: CommandContext) -> Result<i32, AnyError> {
+	let pid = singleton::request_resume(&ctx.paths).await?;
+	ctx.log.result(format!(
+		"Resuming hosting of the tunnel running in process {}",
+		pid
+	));
+	Ok(0)
+}
+
+/// Lists the launcher tunnels of all machines on the account.
+pub async fn list(ctx: CommandContext, list_args: TunnelListArgs) -> Result<i32, AnyError> {
+	let auth = ctx.auth();
+	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+	let mut tunnels = dt.list_all_server_tunnels().await?;
+	tunnels.sort_by(|a, b| a.tags.first().cmp(&b.tags.first()));
+
+	table_format(&ctx, list_args.format.format)
+		.print_table(tunnels_table(tunnels))
+		.map_err(|e| wrap(e, "error printing tunnels"))?;
+
+	Ok(0)
+}
+
+/// Checks that a machine's tunnel was registered by a host attested with one
+/// of the trusted keys.
+pub async fn verify_host(
+	ctx: CommandContext,
+	verify_args: TunnelVerifyHostArgs,
+) -> Result<i32, AnyError> {
+	let keys = attestation::read_trusted_keys(&verify_args.trusted_keys)?;
+	let auth = ctx.auth();
+	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+	let name = verify_args.name;
+	let tunnel = dt
+		.list_all_server_tunnels()
+		.await?
+		.into_iter()
+		.find(|t| t.tags.iter().any(|tag| tag == &name))
+		.ok_or_else(|| HostNotAttested(format!("no machine named {} was found", name)))?;
+	let tunnel_id = tunnel.tunnel_id.as_deref().unwrap_or_default();
+	let attestation = HostAttestation::from_tags(tunnel_id, &tunnel.tags)
+		.ok_or_else(|| HostNotAttested(format!("{} does not publish an attestation", name)))?;
+	let key = attestation.verify(&keys)?;
+
+	if ctx.json_output() {
+		print_json_result(&VerifyHostResult {
+			name,
+			key,
+			attestation,
+		});
+	} else {
+		ctx.log
+			.result(format!("{} is attested by the trusted key {}", name, key));
+		ctx.log
+			.result(format!("  Machine fingerprint: {}", attestation.machine));
+		ctx.log.result(format!(
+			"  Platform: {} {}",
+			attestation.os, attestation.arch
+		));
+	}
+
+	Ok(0)
+}
+
+fn tunnels_table(tunnels: Vec<Tunnel>) -> OutputTable {
+	let mut name = Column::new("name");
+	let mut cluster = Column::new("cluster");
+	let mut hosts = Column::new("host_connections");
+	let mut last_connected = Column::new("last_host_connection");
+	for t in tunnels {
+		let status = t.status.unwrap_or_default();
+		name.add_row(t.tags.first().cloned().unwrap_or_default());
+		cluster.add_row(t.cluster_id.unwrap_or_default());
+		hosts.add_row(
+			status
+				.host_connection_count
+				.map(|c| c.get_count())
+				.unwrap_or(0)
+				.to_string(),
+		);
+		last_connected.add_row(
+			status
+				.last_host_connection_time
+				.map(|t| t.to_rfc3339())
+				.unwrap_or_default(),
+		);
+	}
+
+	OutputTable::new(vec![name, cluster, hosts, last_connected])
+}
+
+/// Remove the tunnel used by this gateway, if any. With `--force`, each
+/// step of the cleanup is reported, and the exit code is 1 if any failed.
+pub async fn unregister(
+	ctx: CommandContext,
+	unregister_args: TunnelUnregisterArgs,
+) -> Result<i32, AnyError> {
+	let auth = ctx.auth();
 	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
-	dt.remove_tunnel().await?;
+	let removed = dev_tunnels::get_persisted_tunnel(&ctx.paths);
+	let steps = if unregister_args.force {
+		dt.remove_tunnel_forced().await?
+	} else {
+		dt.remove_tunnel().await?;
+		vec![]
+	};
+
+	let failed = steps.iter().filter(|s| s.error.is_some()).count();
+	if ctx.json_output() {
+		print_json_result(&UnregisterResult {
+			removed: removed.is_some(),
+			tunnel: removed.map(TunnelResult::from),
+			steps: steps.into_iter().map(CleanupStepResult::from).collect(),
+		});
+	} else {
+		for step in &steps {
+			match &step.error {
+				Some(e) => ctx
+					.log
+					.result(format!("Failed to {}: {}", step.description, e)),
+				None => ctx.log.result(format!("Done: {}", step.description)),
+			}
+		}
+		if failed > 0 {
+			ctx.log.result(format!(
+				"{} of {} cleanup steps failed. The tunnel was forgotten on this machine; anything left of it on the service can be deleted later with `code tunnel prune --tunnels`.",
+				failed,
+				steps.len()
+			));
+		}
+	}
+
+	Ok(if failed > 0 { 1 } else { 0 })
+}
+
+/// Streams the output of the tunnel running for this data directory.
+pub async fn attach(ctx: CommandContext) -> Result<i32, AnyError> {
+	cancellable(singleton::attach(&ctx.log, &ctx.paths)).await?;
 	Ok(0)
 }
 
-/// Removes unused servers.
-pub async fn prune(ctx: CommandContext) -> Result<i32, AnyError> {
+/// Prints the status of the tunnel running for this data directory as a line
+/// of JSON, once per interval, until it shuts down.
+pub async fn watch(ctx: CommandContext, watch_args: TunnelWatchArgs) -> Result<i32, AnyError> {
+	let period = Duration::from_secs(watch_args.interval.max(1));
+	cancellable(singleton::watch(&ctx.paths, period, |status| {
+		println!("{}", serde_json::to_string(&status).unwrap());
+	}))
+	.await?;
+	Ok(0)
+}
+
+/// Prints the status of the tunnel running for this data directory.
+pub async fn status(ctx: CommandContext) -> Result<i32, AnyError> {
+	let status = singleton::status(&ctx.paths).await?;
+	if ctx.json_output() {
+		let tunnel = dev_tunnels::get_persisted_tunnel(&ctx.paths);
+		let today = Utc::now().date_naive();
+		print_json_result(&StatusResult {
+			tunnel_id: tunnel.as_ref().map(|t| t.id.clone()),
+			cluster: tunnel.map(|t| t.cluster),
+			status,
+			port_stats: port_stats::load(&ctx.paths)
+				.into_iter()
+				.map(|(port, stats)| {
+					let recent = stats.recent(today);
+					(
+						port,
+						PortStatsResult {
+							lifetime: stats.lifetime,
+							recent,
+						},
+					)
+				})
+				.collect(),
+		});
+		return Ok(0);
+	}
+
+	let s = &status.status;
+	ctx.log.result(format!(
+		"Tunnel {} is running in process {}",
+		s.name.as_deref().unwrap_or("(unnamed)"),
+		status.pid
+	));
+	if let Some(uri) = &s.uri {
+		ctx.log.result(format!("  URI: {}", uri));
+	}
+
+	match &s.connection {
+		Some(c) => {
+			ctx.log.result(format!("  Connection: {}", c));
+			ctx.log.result(format!("  Uptime: {}s", c.uptime_secs));
+			ctx.log.result(format!("  Reconnects: {}", c.reconnects));
+			if let Some(host_id) = &c.host_id {
+				ctx.log.result(format!("  Host ID: {}", host_id));
+			}
+			if let Some(relay) = &c.relay_uri {
+				ctx.log.result(format!("  Relay: {}", relay));
+			}
+			if let Some(e) = &c.last_error {
+				ctx.log.result(format!("  Last error: {}", e));
+			}
+		}
+		None => ctx.log.result("  Connection: not started"),
+	}
+	if let Some(reason) = &s.paused {
+		ctx.log.result(format!("  Hosting paused, as {}", reason));
+	} else if let Some(message) = &s.budget_exceeded {
+		ctx.log.result(format!("  Paused: {}", message));
+	}
+	for (subsystem, reason) in &s.disabled_subsystems {
+		ctx.log
+			.result(format!("  Turned off {}, as {}", subsystem, reason));
+	}
+	for event in &s.security_events {
+		ctx.log.result(format!(
+			"  Security notice at {}: {}",
+			event.at.format("%Y-%m-%d %H:%M:%S UTC"),
+			event.detail
+		));
+	}
+
+	for (port, uri) in &s.ports {
+		ctx.log.result(format!("  Port {}: {}", port, uri));
+	}
+	for (port, m) in &s.port_metrics {
+		ctx.log.result(format!(
+			"  Traffic on port {}: {} active, {} total connections, {} in, {} out",
+			port,
+			m.active_connections,
+			m.connects,
+			format_size(m.bytes_received),
+			format_size(m.bytes_sent)
+		));
+	}
+
+	let today = Utc::now().date_naive();
+	for (port, stats) in port_stats::load(&ctx.paths) {
+		ctx.log.result(format!(
+			"  All-time traffic on port {}: {}; last {} days: {}",
+			port,
+			describe_traffic(&stats.lifetime),
+			port_stats::RECENT_DAYS,
+			describe_traffic(&stats.recent(today))
+		));
+	}
+
+	Ok(0)
+}
+
+fn describe_traffic(t: &PortTraffic) -> String {
+	format!(
+		"{} connections, {} in, {} out",
+		t.connections,
+		format_size(t.bytes_received),
+		format_size(t.bytes_sent)
+	)
+}
+
+/// Tunnel of this machine, in results printed with `--output json`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TunnelResult {
+	name: String,
+	tunnel_id: String,
+	cluster: String,
+}
+
+impl From<dev_tunnels::PersistedTunnel> for TunnelResult {
+	fn from(t: dev_tunnels::PersistedTunnel) -> Self {
+		TunnelResult {
+			name: t.name,
+			tunnel_id: t.id,
+			cluster: t.cluster,
+		}
+	}
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserResult {
+	logged_in: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PortResult {
+	port: u16,
+	forwarded: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	uri: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UnregisterResult {
+	removed: bool,
+	#[serde(flatten)]
+	tunnel: Option<TunnelResult>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	steps: Vec<CleanupStepResult>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CleanupStepResult {
+	step: String,
+	succeeded: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+}
+
+impl From<dev_tunnels::CleanupStep> for CleanupStepResult {
+	fn from(s: dev_tunnels::CleanupStep) -> Self {
+		CleanupStepResult {
+			step: s.description,
+			succeeded: s.error.is_none(),
+			error: s.error,
+		}
+	}
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyHostResult {
+	name: String,
+	key: String,
+	#[serde(flatten)]
+	attestation: HostAttestation,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StatusResult {
+	#[serde(flatten)]
+	status: singleton::InstanceStatus,
+	tunnel_id: Option<String>,
+	cluster: Option<String>,
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	port_stats: BTreeMap<u16, PortStatsResult>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PortStatsResult {
+	lifetime: PortTraffic,
+	recent: PortTraffic,
+}
+
+/// Gets the format to print a table in, which is JSON with `--output json`.
+fn table_format(ctx: &CommandContext, format: OutputFormat) -> OutputFormat {
+	if ctx.json_output() {
+		OutputFormat::Json
+	} else {
+		format
+	}
+}
+
+/// Forwards the ports that were forwarded on a closed tunnel on the tunnel
+/// that replaces it, with the same privacy.
+async fn forward_ports_again(
+	log: &log::Logger,
+	tunnel: &mut dev_tunnels::ActiveTunnel,
+	closed: &dev_tunnels::ActiveTunnel,
+) {
+	for port in closed.forwarded_ports() {
+		if tunnel.has_port(port) {
+			continue;
+		}
+		if let Err(e) = tunnel.add_port_tcp(port, closed.port_privacy(port)).await {
+			warning!(log, "Could not forward port {} again: {}", port, e);
+		}
+	}
+}
+
+/// Tests connectivity to each service the tunnel uses.
+pub async fn relay_test(ctx: CommandContext) -> Result<i32, AnyError> {
+	let proxy = connectivity::get_configured_proxy();
+	match &proxy {
+		Some(p) => ctx.log.result(format!("Connecting through proxy {}", p)),
+		None => ctx
+			.log
+			.result("Connecting directly, no proxy is configured"),
+	}
+
+	let cluster = dev_tunnels::get_persisted_cluster(&ctx.paths);
+	let mut failed = false;
+	for endpoint in connectivity::get_required_endpoints(cluster.as_deref()) {
+		match connectivity::probe(&ctx.http, &endpoint, proxy.is_some()).await {
+			Ok(s) => ctx
+				.log
+				.result(format!("[ok] {} ({}): {}", endpoint.name, endpoint.url, s)),
+			Err(e) => {
+				failed = true;
+				ctx.log.result(format!(
+					"[failed] {} ({}): {}\n    {}",
+					endpoint.name,
+					endpoint.url,
+					e,
+					e.likely_cause(&endpoint.host())
+				));
+			}
+		}
+	}
+
+	Ok(if failed { 1 } else { 0 })
+}
+
+/// Prints whether the OS grants the permissions hosting needs, and how to
+/// grant those it doesn't.
+pub async fn doctor(ctx: CommandContext) -> Result<i32, AnyError> {
+	let current_exe = std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+	let checks = os_permissions::check_permissions(&current_exe).await;
+	if checks.is_empty() {
+		ctx.log
+			.result("No OS permissions are needed to host tunnels on this platform");
+		return Ok(0);
+	}
+
+	let mut failed = false;
+	for check in &checks {
+		let label = match check.status {
+			PermissionStatus::Granted => "ok",
+			PermissionStatus::Missing => "missing",
+			PermissionStatus::Unknown => "unknown",
+		};
+		failed |= check.status == PermissionStatus::Missing;
+		match check.status {
+			PermissionStatus::Granted => ctx.log.result(format!("[{}] {}", label, check.name)),
+			_ => ctx
+				.log
+				.result(format!("[{}] {}\n    {}", label, check.name, check.remedy)),
+		}
+	}
+
+	Ok(if failed { 1 } else { 0 })
+}
+
+/// Hosts a disposable tunnel and connects to it through the relay, printing
+/// the outcome and timing of each stage.
+pub async fn self_test(ctx: CommandContext) -> Result<i32, AnyError> {
+	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, ctx.auth(), &ctx.paths);
+	let stages = self_test::run(&ctx.log, &mut dt, &ctx.http).await;
+
+	let mut failed = false;
+	for stage in &stages {
+		let ms = stage.duration.as_millis();
+		match &stage.error {
+			None => ctx.log.result(format!("[ok] {} ({}ms)", stage.name, ms)),
+			Some(e) => {
+				failed = true;
+				ctx.log
+					.result(format!("[failed] {} ({}ms): {}", stage.name, ms, e));
+			}
+		}
+	}
+
+	let total: Duration = stages.iter().map(|s| s.duration).sum();
+	ctx.log.result(format!(
+		"Self-test {} in {}ms",
+		if failed { "failed" } else { "passed" },
+		total.as_millis()
+	));
+	Ok(if failed { 1 } else { 0 })
+}
+
+/// Prints the latency to each of the service's clusters, closest first.
+pub async fn ping_clusters(
+	ctx: CommandContext,
+	ping_args: TunnelPingClustersArgs,
+) -> Result<i32, AnyError> {
+	let metadata = TunnelSetup::load(&ctx.paths).request_metadata;
+	let clusters = connectivity::list_relay_clusters(&ctx.http, &metadata)
+		.await
+		.map_err(|e| wrap(e, "error listing clusters"))?;
+	let latencies = futures::future::join_all(
+		clusters
+			.iter()
+			.map(|c| connectivity::ping_cluster(&ctx.http, &c.cluster_id)),
+	)
+	.await;
+
+	let mut pinged: Vec<_> = clusters.into_iter().zip(latencies).collect();
+	pinged.sort_by_key(|(_, l)| *l.as_ref().unwrap_or(&Duration::MAX));
+
+	let mut cluster = Column::new("cluster");
+	let mut location = Column::new("location");
+	let mut latency = Column::new("latency_ms");
+	for (c, l) in &pinged {
+		cluster.add_row(c.cluster_id.clone());
+		location.add_row(c.azure_location.clone().unwrap_or_default());
+		latency.add_row(match l {
+			Ok(d) => d.as_millis().to_string(),
+			Err(e) => format!("unreachable: {}", e),
+		});
+	}
+
+	table_format(&ctx, ping_args.format.format)
+		.print_table(OutputTable::new(vec![cluster, location, latency]))
+		.map_err(|e| wrap(e, "error printing clusters"))?;
+
+	if !ctx.json_output() {
+		if let Some((closest, Ok(_))) = pinged.first() {
+			ctx.log.result(format!(
+				"The closest cluster is {}. Run `code tunnel --cluster {}` to host tunnels in it.",
+				closest.cluster_id, closest.cluster_id
+			));
+		}
+	}
+
+	Ok(0)
+}
+
+/// Prints the tunnel's configuration and where each value came from.
+pub async fn config_show(
+	ctx: CommandContext,
+	serve_args: TunnelServeArgs,
+	show_args: TunnelConfigShowArgs,
+) -> Result<i32, AnyError> {
+	let flags = tunnel_config::ProvidedFlags::from_args(std::env::args_os());
+	let mut values =
+		tunnel_config::resolve(&ctx.paths, &ctx.args.global_options, &serve_args, &flags).await;
+	if !show_args.effective {
+		values.retain(|v| v.source.is_explicit());
+	}
+
+	show_args
+		.format
+		.format
+		.print_table(tunnel_config::to_table(values))
+		.map_err(|e| wrap(e, "error printing configuration"))?;
+
+	Ok(0)
+}
+
+pub async fn usage(
+	ctx: CommandContext,
+	usage_args: TunnelUsageSubCommands,
+) -> Result<i32, AnyError> {
+	match usage_args {
+		TunnelUsageSubCommands::Export(export_args) => {
+			let month = UsageMonth::parse(&export_args.month)?;
+			let sessions = usage::read_sessions(&ctx.paths, month)
+				.map_err(|e| wrap(e, "error reading usage history"))?;
+			table_format(&ctx, export_args.format.format)
+				.print_table(usage_table(sessions))
+				.map_err(|e| wrap(e, "error printing usage"))?;
+		}
+	}
+
+	Ok(0)
+}
+
+fn usage_table(sessions: Vec<SessionRecord>) -> OutputTable {
+	let mut tunnel = Column::new("tunnel");
+	let mut started_at = Column::new("started_at");
+	let mut ended_at = Column::new("ended_at");
+	let mut duration = Column::new("duration_secs");
+	let mut received = Column::new("bytes_received");
+	let mut sent = Column::new("bytes_sent");
+	for s in sessions {
+		duration.add_row(s.duration_secs().to_string());
+		tunnel.add_row(s.tunnel_name);
+		started_at.add_row(s.started_at.to_rfc3339());
+		ended_at.add_row(s.ended_at.to_rfc3339());
+		received.add_row(s.bytes_received.to_string());
+		sent.add_row(s.bytes_sent.to_string());
+	}
+
+	OutputTable::new(vec![tunnel, started_at, ended_at, duration, received, sent])
+}
+
+/// Removes unused servers, and stale tunnels if requested.
+pub async fn prune(ctx: CommandContext, prune_args: TunnelPruneArgs) -> Result<i32, AnyError> {
+	let verb = if prune_args.dry_run {
+		"Would delete"
+	} else {
+		"Deleted"
+	};
+
 	get_all_servers(&ctx.paths)
 		.into_iter()
 		.map(|s| s.server_paths(&ctx.paths))
 		.filter(|s| s.get_running_pid().is_none())
 		.try_for_each(|s| {
 			ctx.log
-				.result(&format!("Deleted {}", s.server_dir.display()));
-			s.delete()
+				.result(format!("{} {}", verb, s.server_dir.display()));
+			match prune_args.dry_run {
+				true => Ok(()),
+				false => s.delete(),
+			}
 		})
 		.map_err(AnyError::from)?;
 
-	ctx.log.result("Successfully removed all unused servers");
+	let mut failed = 0;
+	if prune_args.tunnels {
+		let auth = ctx.auth();
+		let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+		let max_age = Duration::from_secs(prune_args.older_than.saturating_mul(24 * 60 * 60));
+		let stale = dt.find_stale_tunnels(max_age).await?;
+		if prune_args.dry_run {
+			for tunnel in &stale {
+				ctx.log.result(format!(
+					"{} tunnel {}",
+					verb,
+					tunnel.tags.first().map(|t| t.as_str()).unwrap_or_default()
+				));
+			}
+		} else {
+			for step in dt.delete_tunnels(&stale).await {
+				match &step.error {
+					Some(e) => {
+						failed += 1;
+						ctx.log
+							.result(format!("Failed to {}: {}", step.description, e));
+					}
+					None => ctx.log.result(format!("Done: {}", step.description)),
+				}
+			}
+		}
+	}
+
+	if failed > 0 {
+		ctx.log
+			.result(format!("{} stale tunnels could not be deleted", failed));
+		return Ok(1);
+	}
+
+	if !prune_args.dry_run {
+		ctx.log.result("Successfully removed all unused servers");
+	}
 
 	Ok(0)
 }
@@ -222,73 +1142,1042 @@ pub async fn serve(ctx: CommandContext, gateway_args: TunnelServeArgs) -> Result
 
 	legal::require_consent(&paths, gateway_args.accept_server_license_terms)?;
 
+	if gateway_args.detach {
+		return serve_detached(&log, &paths, &gateway_args, &args.global_options).await;
+	}
+
+	let mut csa: CodeServerArgs = (&args).into();
+	gateway_args.server_network.apply_to(&mut csa);
+	csa.extension_filter = ExtensionFilter {
+		allowed: gateway_args.allowed_initial_extensions.clone(),
+		denied: gateway_args.denied_initial_extensions.clone(),
+	};
+	let profile = args.global_options.as_profile;
+	serve_with_csa(paths, log, gateway_args, profile, csa, None).await
+}
+
+/// Hosts a guest tunnel until its TTL is up or it's interrupted, and then
+/// deletes it.
+pub async fn guest(ctx: CommandContext, guest_args: TunnelGuestArgs) -> Result<i32, AnyError> {
+	let CommandContext {
+		log, paths, args, ..
+	} = ctx;
+
+	legal::require_consent(&paths, guest_args.accept_server_license_terms)?;
+	let ttl = guest::parse_ttl(&guest_args.ttl)?;
 	let csa = (&args).into();
-	serve_with_csa(paths, log, gateway_args, csa, None).await
+	let platform = spanf!(log, log.span("prereq"), PreReqChecker::new().verify())?;
+
+	let auth = with_profile(
+		Auth::new(&paths, log.clone()),
+		args.global_options.as_profile.as_deref(),
+	);
+	let mut dt = dev_tunnels::DevTunnels::new(&log, auth, &paths);
+	for name in dt.delete_expired_guest_tunnels().await? {
+		info!(log, "Deleted expired guest tunnel {}", name);
+	}
+
+	let log = log
+		.tee(UsageSink::new(&paths))
+		.tee(PortStatsSink::new(&paths));
+	let expires_at = SystemTime::now() + ttl;
+	let (tunnel, persisted) = dt
+		.start_guest_tunnel(
+			guest_args.name,
+			expires_at,
+			guest::access_control(&guest_args.allowed_identities),
+		)
+		.await?;
+	info!(
+		log,
+		"Guest tunnel {} will be deleted in {}s",
+		persisted.name,
+		ttl.as_secs()
+	);
+
+	let (tx, mut rx) = mpsc::channel::<ShutdownSignal>(2);
+	let ctrl_c_tx = tx.clone();
+	tokio::spawn(async move {
+		tokio::signal::ctrl_c().await.ok();
+		ctrl_c_tx.send(ShutdownSignal::CtrlC).await.ok();
+	});
+	tokio::spawn(async move {
+		sleep(ttl).await;
+		tx.send(ShutdownSignal::GuestTunnelExpired).await.ok();
+	});
+
+	let mut served = crate::tunnels::serve(
+		&log,
+		tunnel,
+		&paths,
+		&csa,
+		platform,
+		BroadcastLogSink::new(),
+		UpdateOptions {
+			auto_update: false,
+			channel: None,
+			handoff_exe: None,
+		},
+		&mut PortForwardingProcessor::new(),
+		&mut rx,
+	)
+	.await;
+
+	if let Ok(r) = &mut served {
+		r.tunnel.close().await.ok();
+	}
+
+	// the tunnel's expiry tag lets a later run clean it up if this fails
+	dt.delete_guest_tunnel(&persisted).await?;
+	info!(log, "Deleted guest tunnel {}", persisted.name);
+
+	served.map(|_| 0)
 }
 
-async fn serve_with_csa(
+/// Starts the tunnel in a background process with the current arguments, and
+/// returns once it's listening.
+async fn serve_detached(
+	log: &Logger,
+	paths: &LauncherPaths,
+	gateway_args: &TunnelServeArgs,
+	global_options: &GlobalOptions,
+) -> Result<i32, AnyError> {
+	// the background process can't prompt, so make sure we're logged in first
+	if gateway_args.local.local_port.is_none() {
+		let profile = global_options.as_profile.as_deref();
+		tunnel_authorization(serve_auth(paths, log, profile, gateway_args), gateway_args)
+			.get_credential()
+			.await?;
+	}
+
+	if let Some(pid) = singleton::running_pid(paths) {
+		if !gateway_args.force {
+			return Err(TunnelAlreadyRunning(pid).into());
+		}
+	}
+
+	let current_exe = std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+	let mut args = std::env::args_os()
+		.skip(1)
+		.filter(|a| a != OsStr::new("--detach"))
+		.collect::<Vec<OsString>>();
+	if gateway_args.name.is_none() && !gateway_args.random_name {
+		args.push("--random-name".into());
+	}
+
+	let mut cmd = Command::new(current_exe);
+	cmd.args(args).stdin(Stdio::null());
+
+	// pick the local access token here, so it can be shown to the user
+	if gateway_args.local.local_port.is_some() && gateway_args.local.local_token.is_none() {
+		let token = LocalRelayOptions::generate_token();
+		log.result(format!("Clients connect with the access token {}", token));
+		cmd.env("VSCODE_CLI_LOCAL_TOKEN", token);
+	}
+
+	// The child inherits `--log-file` and writes its logs there itself.
+	// Otherwise, capture its output into the data directory.
+	let log_path = match &global_options.log_file {
+		Some(path) => {
+			cmd.stdout(Stdio::null()).stderr(Stdio::null());
+			path.clone()
+		}
+		None => {
+			let path = paths.root().join(DETACHED_LOG_FILE_NAME);
+			let stdout = File::create(&path)
+				.map_err(|e| wrap(e, format!("error creating {}", path.display())))?;
+			let stderr = stdout
+				.try_clone()
+				.map_err(|e| wrap(e, format!("error opening {}", path.display())))?;
+			cmd.stdout(stdout).stderr(stderr);
+			path
+		}
+	};
+
+	detach_from_terminal(&mut cmd);
+
+	let mut child = cmd
+		.spawn()
+		.map_err(|e| wrap(e, "error starting the tunnel in the background"))?;
+	singleton::write_pid_file(paths, child.id())?;
+
+	info!(
+		log,
+		"Started the tunnel in process {}, waiting for it to connect...",
+		child.id()
+	);
+
+	let (name, uri) = cancellable(wait_for_child_listening(paths, &mut child))
+		.await
+		.map_err(|e| match e {
+			AnyError::TunnelHostFailed(e) => {
+				TunnelHostFailed(format!("{}, see {} for details", e, log_path.display())).into()
+			}
+			e => e,
+		})?;
+
+	log.result(format!(
+		"Tunnel {} is running in the background (process {}).\n\nOpen this link in your browser {}\n\nLogs are written to {}. Run `code tunnel attach` to follow them.",
+		name,
+		child.id(),
+		uri,
+		log_path.display()
+	));
+
+	Ok(0)
+}
+
+/// Waits for the tunnel started in the child process to take the singleton
+/// lock and begin listening, returning its name and URI.
+async fn wait_for_child_listening(
+	paths: &LauncherPaths,
+	child: &mut Child,
+) -> Result<(String, String), AnyError> {
+	let exited = |status: ExitStatus| TunnelHostFailed(format!("The tunnel exited ({})", status));
+
+	// wait for the child to take the singleton lock, so we attach to it and
+	// not to any tunnel it's taking over from
+	while singleton::running_pid(paths) != Some(child.id()) {
+		if let Ok(Some(status)) = child.try_wait() {
+			return Err(exited(status).into());
+		}
+		sleep(Duration::from_millis(250)).await;
+	}
+
+	let listening = singleton::wait_for_listening(paths).await;
+	if let Ok(Some(status)) = child.try_wait() {
+		return Err(exited(status).into());
+	}
+
+	listening
+}
+
+#[cfg(unix)]
+fn detach_from_terminal(cmd: &mut Command) {
+	use std::os::unix::process::CommandExt;
+	// Start a new session so the tunnel isn't killed when the terminal closes.
+	unsafe {
+		cmd.pre_exec(|| {
+			libc::setsid();
+			Ok(())
+		});
+	}
+}
+
+#[cfg(windows)]
+fn detach_from_terminal(cmd: &mut Command) {
+	use std::os::windows::process::CommandExt;
+	const DETACHED_PROCESS: u32 = 0x00000008;
+	const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+	cmd.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+}
+
+#[derive(Clone, Copy)]
+enum OtherAccountChoice {
+	Login,
+	Recreate,
+	Abort,
+}
+
+impl fmt::Display for OtherAccountChoice {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			OtherAccountChoice::Login => write!(f, "Log in with the account that owns it"),
+			OtherAccountChoice::Recreate => write!(f, "Create a new tunnel under this account"),
+			OtherAccountChoice::Abort => write!(f, "Cancel"),
+		}
+	}
+}
+
+/// Gets whether the user can be prompted while serving the tunnel.
+fn is_interactive(gateway_args: &TunnelServeArgs) -> bool {
+	!gateway_args.non_interactive && atty::is(atty::Stream::Stdin)
+}
+
+/// Gets the login used to serve the tunnel, which doesn't ask for a
+/// provider if `--auth-provider` is given.
+fn serve_auth(
+	paths: &LauncherPaths,
+	log: &Logger,
+	profile: Option<&str>,
+	gateway_args: &TunnelServeArgs,
+) -> Auth {
+	let auth = with_profile(Auth::new(paths, log.clone()), profile);
+	match gateway_args.auth_provider {
+		Some(provider) => auth.with_provider(provider.into()),
+		None => auth,
+	}
+}
+
+/// Gets what the tunnel authorizes with: the token in `--access-token-file`
+/// if it's given, or otherwise the login.
+fn tunnel_authorization(auth: Auth, gateway_args: &TunnelServeArgs) -> TunnelAuthorization {
+	match &gateway_args.access_token_file {
+		Some(path) => TunnelAuthorization::new(AccessTokenFile::new(
+			gateway_args
+				.auth_provider
+				.map(Into::into)
+				.unwrap_or(crate::auth::AuthProvider::Github),
+			path.clone(),
+		)),
+		None => TunnelAuthorization::new(auth),
+	}
+}
+
+/// Starts the launcher tunnel. If the tunnel from an earlier run belongs to
+/// another account, asks whether to log in with that account or create a
+/// new tunnel, rather than silently leaving the old one orphaned.
+async fn start_launcher_tunnel(
+	log: &Logger,
+	auth: &Auth,
+	dt: &mut dev_tunnels::DevTunnels,
+	gateway_args: &TunnelServeArgs,
+) -> Result<dev_tunnels::ActiveTunnel, AnyError> {
+	let mut name = gateway_args.name.clone();
+	loop {
+		let owned_by_other = match dt
+			.start_new_launcher_tunnel(name.clone(), gateway_args.random_name)
+			.await
+		{
+			Err(AnyError::TunnelOwnedByOtherAccount(e)) => e,
+			r => return r,
+		};
+
+		// logging in again won't change the account of an access token
+		if !is_interactive(gateway_args) || gateway_args.access_token_file.is_some() {
+			return Err(owned_by_other.into());
+		}
+
+		let choice = prompt_options(
+			&format!("{}. What would you like to do?", owned_by_other),
+			&[
+				OtherAccountChoice::Login,
+				OtherAccountChoice::Recreate,
+				OtherAccountChoice::Abort,
+			],
+		)?;
+
+		match choice {
+			OtherAccountChoice::Login => {
+				auth.clear_credentials()?;
+				cancellable(auth.login(None, None)).await?;
+			}
+			OtherAccountChoice::Recreate => {
+				let TunnelOwnedByOtherAccount(old_name) = owned_by_other;
+				warning!(
+					log,
+					"The tunnel '{}' is still registered to the other account. Log in with it and run `code tunnel unregister` to remove it.",
+					old_name
+				);
+				dt.forget_tunnel()?;
+				name = name.or(Some(old_name));
+			}
+			OtherAccountChoice::Abort => return Err(owned_by_other.into()),
+		}
+	}
+}
+
+/// Serves an additional named tunnel in the background, until a signal is
+/// sent on the returned channel. Clients can't update the CLI through it,
+/// since that's handled by the main tunnel.
+fn serve_additional_tunnel(
+	log: Logger,
+	tunnel: dev_tunnels::ActiveTunnel,
+	paths: LauncherPaths,
+	csa: CodeServerArgs,
+	platform: Platform,
+	log_broadcast: BroadcastLogSink,
+	update_channel: Option<UpdateChannel>,
+) -> (mpsc::Sender<ShutdownSignal>, JoinHandle<()>) {
+	let (tx, mut rx) = mpsc::channel::<ShutdownSignal>(1);
+	let serving = tokio::spawn(async move {
+		let update_options = UpdateOptions {
+			auto_update: false,
+			channel: update_channel,
+			handoff_exe: None,
+		};
+		match crate::tunnels::serve(
+			&log,
+			tunnel,
+			&paths,
+			&csa,
+			platform,
+			log_broadcast,
+			update_options,
+			&mut PortForwardingProcessor::new(),
+			&mut rx,
+		)
+		.await
+		{
+			Ok(mut r) => {
+				if r.respawn {
+					warning!(log, "Updates can only be installed through the main tunnel");
+				}
+				r.tunnel.close().await.ok();
+			}
+			Err(e) => error!(log, "Error serving tunnel: {}", e),
+		}
+	});
+
+	(tx, serving)
+}
+
+/// Gets options for hosting on the local network, if `--local-port` is given.
+fn local_relay_options(
+	gateway_args: &TunnelServeArgs,
+) -> Result<Option<LocalRelayOptions>, AnyError> {
+	let local = &gateway_args.local;
+	let port = match local.local_port {
+		Some(p) => p,
+		None => return Ok(None),
+	};
+
+	let tls = match (&local.local_cert, &local.local_key) {
+		(Some(cert), Some(key)) => Some(LocalRelayOptions::load_tls(cert, key)?),
+		_ => None,
+	};
+
+	let options = LocalRelayOptions {
+		host: local
+			.local_host
+			.clone()
+			.unwrap_or_else(|| DEFAULT_LOCAL_HOST.to_string()),
+		port,
+		token: local
+			.local_token
+			.clone()
+			.unwrap_or_else(LocalRelayOptions::generate_token),
+		tls,
+	};
+	options.ensure_secure(local.local_insecure)?;
+	Ok(Some(options))
+}
+
+/// Hosts the tunnel on a backend other than the tunnel service, such as a
+/// listener on the local network. Features of the tunnel service, like
+/// recreating deleted tunnels and failing over, aren't available.
+async fn serve_on_backend(
 	paths: LauncherPaths,
 	log: Logger,
 	gateway_args: TunnelServeArgs,
 	csa: CodeServerArgs,
+	platform: Platform,
+	make_backend: impl FnOnce(&Logger) -> Box<dyn TunnelBackend>,
+	shutdown_rx: Option<mpsc::Receiver<ShutdownSignal>>,
+) -> Result<i32, AnyError> {
+	let mut singleton = acquire_singleton(&log, &paths, gateway_args.force).await?;
+	let log_broadcast = BroadcastLogSink::new();
+	let status = StatusSink::new();
+	status::report_to_supervisor(status.clone());
+	let log = log
+		.tee(log_broadcast.clone())
+		.tee(status.clone())
+		.tee(UsageSink::new(&paths))
+		.tee(PortStatsSink::new(&paths));
+	if let Some(port) = gateway_args.metrics_port {
+		port_metrics::serve_prometheus(log.clone(), port, status.clone()).await?;
+	}
+	let hooks = TunnelHooks::from(gateway_args.hooks.clone());
+	let log = if hooks.is_empty() {
+		log
+	} else {
+		log.tee(HookSink::new(log.clone(), hooks))
+	};
+
+	let mut backend = make_backend(&log);
+	backend
+		.create(gateway_args.name.clone(), gateway_args.random_name)
+		.await?;
+	let mut tunnel = backend.host().await?;
+	singleton.write_metadata(&tunnel.name)?;
+	tunnel.set_port_access(PortAccessRules::parse(&gateway_args.port_access)?);
+	status.set_connection(tunnel.connection_tracker());
+
+	let (tx, mut rx) = mpsc::channel::<ShutdownSignal>(2);
+	let mut forwarding = PortForwardingProcessor::new();
+	forwarding.check_ports(log.clone(), !gateway_args.forward_unbound_ports);
+	singleton.serve(
+		log.clone(),
+		log_broadcast.clone(),
+		status.clone(),
+		forwarding.handle(),
+		tx.clone(),
+	);
+	forward_shutdown_signals(&log, &gateway_args, shutdown_rx, &tx);
+
+	// updates aren't downloaded, since the machine may be offline
+	let update_options = UpdateOptions {
+		auto_update: false,
+		channel: gateway_args.update_channel,
+		handoff_exe: None,
+	};
+	let mut r = crate::tunnels::serve(
+		&log,
+		tunnel,
+		&paths,
+		&csa,
+		platform,
+		log_broadcast,
+		update_options,
+		&mut forwarding,
+		&mut rx,
+	)
+	.await?;
+	r.tunnel.close().await.ok();
+
+	Ok(0)
+}
+
+async fn serve_with_csa(
+	paths: LauncherPaths,
+	log: Logger,
+	gateway_args: TunnelServeArgs,
+	profile: Option<String>,
+	mut csa: CodeServerArgs,
 	shutdown_rx: Option<mpsc::Receiver<ShutdownSignal>>,
 ) -> Result<i32, AnyError> {
 	// Intentionally read before starting the server. If the server updated and
 	// respawn is requested, the old binary will get renamed, and then
 	// current_exe will point to the wrong path.
 	let current_exe = std::env::current_exe().unwrap();
-	let platform = spanf!(log, log.span("prereq"), PreReqChecker::new().verify())?;
+	let (server_os, server_arch) = match &gateway_args.in_container {
+		Some(target) => {
+			let container = DevContainer::start(&log, target).await?;
+			let (os, arch) = container.server_platform().await?;
+			csa.container = Some(container);
+			(Some(os), Some(arch))
+		}
+		None => (gateway_args.server_platform, gateway_args.server_arch),
+	};
+	let platform = spanf!(
+		log,
+		log.span("prereq"),
+		PreReqChecker::new()
+			.with_overrides(server_os, server_arch)
+			.with_libc_overrides(gateway_args.libc, gateway_args.use_legacy_server)
+			.verify()
+	)?;
+	if platform.is_legacy() && !gateway_args.use_legacy_server {
+		warning!(
+			log,
+			"This machine's glibc is older than the current VS Code Server requires, using the legacy server build instead. Pass --use-legacy-server to hide this warning."
+		);
+	}
+	if let (None, Some(arch)) = (&csa.container, server_arch) {
+		match detect_host_arch().await {
+			Ok(host) if host != arch => {
+				info!(
+					log,
+					"Serving the {} server on this {} machine, so it will run under emulation",
+					arch,
+					host
+				);
+				csa.host_arch = Some(host);
+			}
+			_ => {}
+		}
+	}
+	let port_access = PortAccessRules::parse(&gateway_args.port_access)?;
+	let auto_forward_filter = AutoForwardFilter::parse(
+		&gateway_args.auto_forward_include,
+		&gateway_args.auto_forward_exclude,
+	)?;
+	let forward_targets = ForwardTargets::parse(
+		gateway_args.forward_host.as_deref(),
+		&gateway_args.forward_targets,
+	)?;
+	let setup = TunnelSetup::load(&paths);
+	forward_targets.ensure_allowed(setup.forward_allowlist(&gateway_args.forward_allowlist))?;
+	if csa.telemetry_level.is_none() {
+		csa.telemetry_level = setup.telemetry_level;
+	}
+	if csa.extension_filter.is_empty() {
+		csa.extension_filter = setup.extension_filter.clone();
+	}
+	if csa.extensions_gallery.is_none() {
+		csa.extensions_gallery = setup.extensions_gallery.clone();
+	}
+	csa.bandwidth_budget = BandwidthBudget::parse(
+		gateway_args.daily_transfer_budget.as_deref(),
+		gateway_args.monthly_transfer_budget.as_deref(),
+	)?;
+	csa.authorized_keys = gateway_args
+		.authorized_keys
+		.as_ref()
+		.map(AuthorizedClientKeys::load)
+		.transpose()?;
+	csa.attestation_key = gateway_args.attestation_key.clone();
+	if let Some(quality) = gateway_args.warm_server {
+		if csa.container.is_some() {
+			warning!(
+				log,
+				"--warm-server is not supported with --in-container, ignoring it"
+			);
+		} else {
+			csa.warm_server = Some(WarmServerOptions {
+				quality,
+				recycle_after: Duration::from_secs(gateway_args.warm_server_recycle.max(1) * 3600),
+				max_memory_mb: gateway_args.warm_server_max_memory,
+			});
+		}
+	}
+	if let Some(options) = local_relay_options(&gateway_args)? {
+		if options.ensure_secure(false).is_err() {
+			warning!(
+				log,
+				"Serving on the local network without TLS, so traffic can be read by others on the network. Pass --local-cert and --local-key to encrypt it."
+			);
+		}
+		log.result(format!(
+			"Clients connect with the access token {}",
+			options.token
+		));
 
-	let auth = Auth::new(&paths, log.clone());
-	let mut dt = dev_tunnels::DevTunnels::new(&log, auth, &paths);
-	let tunnel = if let Some(d) = gateway_args.tunnel.clone().into() {
-		dt.start_existing_tunnel(d).await
+		let backoff = gateway_args.reconnect.to_config();
+		let forward_buffer_size = gateway_args.forward_buffer_size.max(1) * 1024;
+		return serve_on_backend(
+			paths,
+			log,
+			gateway_args,
+			csa,
+			platform,
+			|log| {
+				Box::new(LocalRelayBackend::new(
+					log.clone(),
+					options,
+					backoff,
+					forward_buffer_size,
+					forward_targets,
+				))
+			},
+			shutdown_rx,
+		)
+		.await;
+	}
+	let declared_ports = setup
+		.declared_ports(&gateway_args.declared_ports)
+		.iter()
+		.map(|p| {
+			p.parse::<DeclaredPort>()
+				.map(|p| p.to_tunnel_port(&port_access))
+		})
+		.collect::<Result<Vec<_>, _>>()?;
+	// a process taking the tunnel over from a restarting one connects first,
+	// so clients have a host to reconnect to, and takes the lock after
+	let handoff = Handoff::take(&paths);
+	let early_singleton = match &handoff {
+		Some(_) => None,
+		None => Some(acquire_singleton(&log, &paths, gateway_args.force).await?),
+	};
+	let log_broadcast = BroadcastLogSink::new();
+	let status = StatusSink::new();
+	status::report_to_supervisor(status.clone());
+	let base_log = log.clone();
+	let log = log
+		.tee(log_broadcast.clone())
+		.tee(status.clone())
+		.tee(UsageSink::new(&paths))
+		.tee(PortStatsSink::new(&paths));
+	let hooks = TunnelHooks::from(gateway_args.hooks.clone());
+	let log = if hooks.is_empty() {
+		log
 	} else {
-		dt.start_new_launcher_tunnel(gateway_args.name, gateway_args.random_name)
-			.await
-	}?;
+		log.tee(HookSink::new(log.clone(), hooks))
+	};
 
-	let shutdown_tx = if let Some(tx) = shutdown_rx {
-		tx
+	let auth = serve_auth(&paths, &log, profile.as_deref(), &gateway_args);
+	let mut dt = dev_tunnels::DevTunnels::new(
+		&log,
+		tunnel_authorization(auth.clone(), &gateway_args),
+		&paths,
+	);
+	dt.set_interactive(is_interactive(&gateway_args));
+	dt.add_reserved_ports(gateway_args.reserved_ports.iter().copied());
+	dt.declare_ports(declared_ports);
+	dt.set_forward_buffer_size(gateway_args.forward_buffer_size.max(1) * 1024);
+	dt.set_forward_targets(forward_targets);
+	let idle_timeout = match gateway_args.idle_timeout.as_deref() {
+		Some(t) => Some(guest::parse_ttl(t).map_err(|_| InvalidIdleTimeout(t.to_string()))?),
+		None => None,
+	};
+	// a sleeping tunnel wakes for clients, rather than shutting down
+	let shutdown_when_idle = if gateway_args.sleep_when_idle {
+		dt.set_sleep_when_idle(idle_timeout);
+		None
 	} else {
-		let (tx, rx) = mpsc::channel::<ShutdownSignal>(2);
-		if let Some(process_id) = gateway_args.parent_process_id {
-			match Pid::from_str(&process_id) {
-				Ok(pid) => {
+		idle_timeout
+	};
+	dt.set_host_capabilities(&HostCapabilities::detect());
+	dt.set_preferred_cluster(gateway_args.tunnel.cluster.clone());
+	if let Some(key) = &gateway_args.attestation_key {
+		dt.set_attestation_key(key.clone());
+	}
+	dt.set_backoff(gateway_args.reconnect.to_config());
+	let standby = match &gateway_args.name {
+		Some(name) if gateway_args.standby => Some(dt.find_launcher_tunnel(name).await?),
+		_ => None,
+	};
+
+	// a standby only starts hosting once the primary host goes offline
+	let existing_tunnel: Option<dev_tunnels::ExistingTunnel> = gateway_args.tunnel.clone().into();
+	let mut next_tunnel = match &standby {
+		Some(_) => None,
+		None => Some(if let Some(d) = existing_tunnel.clone() {
+			dt.start_existing_tunnel(d).await
+		} else {
+			start_launcher_tunnel(&log, &auth, &mut dt, &gateway_args).await
+		}?),
+	};
+	let mut singleton = match early_singleton {
+		Some(singleton) => singleton,
+		None => acquire_singleton(&log, &paths, true).await?,
+	};
+	if let (Some(handoff), Some(tunnel)) = (&handoff, next_tunnel.as_mut()) {
+		info!(
+			log,
+			"Took the tunnel over from process {}",
+			handoff.from_pid()
+		);
+		handoff.forward_ports(&log, tunnel).await;
+	}
+	if let Some(port) = gateway_args.metrics_port {
+		port_metrics::serve_prometheus(log.clone(), port, status.clone()).await?;
+	}
+	match (&next_tunnel, &standby) {
+		(Some(tunnel), _) => singleton.write_metadata(&tunnel.name)?,
+		(None, Some(persisted)) => singleton.write_metadata(&persisted.name)?,
+		(None, None) => {}
+	}
+
+	let mut registry = TunnelRegistry::new(dt.clone(), &paths);
+	let mut additional_tunnels = vec![];
+	for name in &gateway_args.additional_names {
+		registry.host(name).await?;
+		let mut tunnel = registry.take(name).unwrap();
+		tunnel.set_port_access(port_access.clone());
+		additional_tunnels.push(serve_additional_tunnel(
+			base_log
+				.prefixed(&format!("[{}]", name))
+				.tee(log_broadcast.clone())
+				.tee(UsageSink::new(&paths)),
+			tunnel,
+			paths.clone(),
+			csa.clone(),
+			platform,
+			log_broadcast.clone(),
+			gateway_args.update_channel,
+		));
+	}
+
+	let (tx, mut rx) = mpsc::channel::<ShutdownSignal>(2);
+	// outlives each tunnel that's served, so `code tunnel forward` can reach
+	// whichever one is current
+	let mut forwarding = PortForwardingProcessor::new();
+	forwarding.check_ports(log.clone(), !gateway_args.forward_unbound_ports);
+	if let Some(privacy) = gateway_args.port_visibility {
+		forwarding.set_default_privacy(privacy);
+	}
+	singleton.serve(
+		log.clone(),
+		log_broadcast.clone(),
+		status.clone(),
+		forwarding.handle(),
+		tx.clone(),
+	);
+	let _auto_forward = gateway_args.auto_forward_ports.then(|| {
+		AutoForwarder::start(
+			log.clone(),
+			paths.clone(),
+			forwarding.handle(),
+			auto_forward_filter,
+		)
+	});
+
+	forward_shutdown_signals(&log, &gateway_args, shutdown_rx, &tx);
+
+	let update_options = UpdateOptions {
+		auto_update: gateway_args.auto_update,
+		channel: gateway_args.update_channel,
+		// a standby's new process would wait for it to go offline, rather
+		// than take over while it's hosting
+		handoff_exe: match gateway_args.standby {
+			true => None,
+			false => Some(current_exe.clone()),
+		},
+	};
+	let failover_options = FailoverOptions::default();
+	let power_policy = PowerPolicy {
+		pause_on_battery: gateway_args.pause_on_battery,
+		pause_on_metered: gateway_args.pause_on_metered,
+	};
+	// only the launcher tunnel is ours to recreate
+	let recreate_deleted = !gateway_args.no_recreate && existing_tunnel.is_none();
+	let r = 'serving: loop {
+		let mut failover_watcher = None;
+		let mut deletion_watcher = None;
+		let mut power_watcher = None;
+		let mut idle_watcher = None;
+		let mut tunnel = match (next_tunnel.take(), &standby) {
+			(Some(tunnel), None) if recreate_deleted => {
+				if let Some(persisted) = dev_tunnels::get_persisted_tunnel(&paths) {
+					let mut dt = dt.clone();
 					let tx = tx.clone();
-					info!(log, "checking for parent process {}", process_id);
-					tokio::spawn(async move {
-						let mut s = sysinfo::System::new();
-						while s.refresh_process(pid) {
-							sleep(Duration::from_millis(2000)).await;
-						}
-						tx.send(ShutdownSignal::ParentProcessKilled).await.ok();
-					});
+					deletion_watcher = Some(tokio::spawn(async move {
+						dt.wait_for_tunnel_deleted(&persisted, TUNNEL_REGISTRATION_CHECK_INTERVAL)
+							.await;
+						tx.send(ShutdownSignal::TunnelDeleted).await.ok();
+					}));
 				}
-				Err(_) => {
-					info!(log, "invalid parent process id: {}", process_id);
+
+				tunnel
+			}
+			(Some(tunnel), _) => tunnel,
+			(None, Some(persisted)) => {
+				info!(
+					log,
+					"Waiting for the primary host of {} to go offline", persisted.name
+				);
+				tokio::select! {
+					r = dt.wait_for_host_offline(persisted, &failover_options) => r?,
+					Some(s) = rx.recv() => {
+						info!(log, "Shutting down: {}", s);
+						break 'serving None;
+					}
 				}
+
+				info!(log, "The primary host is offline, taking over the tunnel");
+				let (mut tunnel, offline_host_ids) = dt.start_standby_tunnel(persisted).await?;
+				let host_id = tunnel.host_id().await?;
+
+				let mut dt = dt.clone();
+				let persisted = persisted.clone();
+				let failover_options = failover_options.clone();
+				let tx = tx.clone();
+				failover_watcher = Some(tokio::spawn(async move {
+					if dt
+						.wait_for_other_host(
+							&persisted,
+							&host_id,
+							&offline_host_ids,
+							&failover_options,
+						)
+						.await
+						.is_ok()
+					{
+						tx.send(ShutdownSignal::PrimaryHostOnline).await.ok();
+					}
+				}));
+
+				tunnel
 			}
+			(None, None) => unreachable!("expected a tunnel or a standby"),
+		};
+
+		if power_policy.is_enabled() {
+			let tx = tx.clone();
+			power_watcher = Some(tokio::spawn(async move {
+				power::wait_for_power_state(POWER_POLL_INTERVAL, |s| {
+					power_policy.pause_reason(s).is_some()
+				})
+				.await;
+				tx.send(ShutdownSignal::PowerConstrained).await.ok();
+			}));
 		}
-		tokio::spawn(async move {
-			tokio::signal::ctrl_c().await.ok();
-			tx.send(ShutdownSignal::CtrlC).await.ok();
-		});
-		rx
+
+		if let Some(timeout) = shutdown_when_idle {
+			let activity = tunnel.activity();
+			let tx = tx.clone();
+			idle_watcher = Some(tokio::spawn(async move {
+				activity.wait_until_idle(timeout).await;
+				tx.send(ShutdownSignal::IdleTimeout(timeout)).await.ok();
+			}));
+		}
+
+		tunnel.set_port_access(port_access.clone());
+		status.set_connection(tunnel.connection_tracker());
+		let mut r = crate::tunnels::serve(
+			&log,
+			tunnel,
+			&paths,
+			&csa,
+			platform,
+			log_broadcast.clone(),
+			update_options.clone(),
+			&mut forwarding,
+			&mut rx,
+		)
+		.await?;
+		r.tunnel.close().await.ok();
+
+		for w in [
+			failover_watcher,
+			deletion_watcher,
+			power_watcher,
+			idle_watcher,
+		]
+		.into_iter()
+		.flatten()
+		{
+			w.abort();
+		}
+
+		if matches!(r.shutdown, Some(ShutdownSignal::TunnelDeleted)) {
+			warning!(
+				log,
+				"The tunnel {} was deleted from the tunnel service, creating it again",
+				r.tunnel.name
+			);
+			let mut tunnel = dt
+				.start_new_launcher_tunnel(Some(r.tunnel.name.clone()), false)
+				.await?;
+			tunnel.set_port_access(port_access.clone());
+			forward_ports_again(&log, &mut tunnel, &r.tunnel).await;
+
+			log.progress(log::ProgressFrame::TunnelRecreated { name: &tunnel.name });
+			next_tunnel = Some(tunnel);
+			continue;
+		}
+
+		let mut requested = matches!(r.shutdown, Some(ShutdownSignal::PauseRequested));
+		let mut constrained = matches!(r.shutdown, Some(ShutdownSignal::PowerConstrained));
+		if requested || constrained {
+			let reason = if requested {
+				"it was requested with `code tunnel pause`"
+			} else {
+				power_policy
+					.pause_reason(&power::get_power_state().await)
+					.unwrap_or("of the machine's power state")
+			};
+			info!(log, "Paused hosting because {}", reason);
+			log.progress(log::ProgressFrame::HostingPaused { reason });
+
+			// the warm server in `r` is kept running until hosting resumes
+			while requested || constrained {
+				tokio::select! {
+					_ = power::wait_for_power_state(POWER_POLL_INTERVAL, |s| {
+						power_policy.pause_reason(s).is_none()
+					}), if constrained => constrained = false,
+					Some(s) = rx.recv() => match s {
+						ShutdownSignal::PauseRequested => requested = true,
+						ShutdownSignal::PowerConstrained => constrained = true,
+						ShutdownSignal::Renamed(name) => r.tunnel.name = name,
+						ShutdownSignal::ResumeRequested if constrained => {
+							info!(log, "Hosting will resume once the machine's power state allows it");
+							requested = false;
+						}
+						ShutdownSignal::ResumeRequested => requested = false,
+						ShutdownSignal::RestartRequested(_) => {
+							warning!(log, "Not restarting, as hosting is paused. Resume it first.");
+						}
+						s => {
+							info!(log, "Shutting down: {}", s);
+							break 'serving Some(r);
+						}
+					}
+				}
+			}
+
+			info!(log, "Resuming hosting");
+			log.progress(log::ProgressFrame::HostingResumed);
+			// a standby goes back to waiting for the primary host to go offline
+			if standby.is_none() {
+				// ports that are still forwarded are kept on the service
+				let closed = &r.tunnel;
+				dt.set_desired_ports(
+					closed
+						.forwarded_ports()
+						.into_iter()
+						.map(|p| (p, closed.port_privacy(p))),
+				);
+				let mut tunnel = match existing_tunnel.clone() {
+					Some(d) => dt.start_existing_tunnel(d).await?,
+					None => {
+						dt.start_new_launcher_tunnel(Some(r.tunnel.name.clone()), false)
+							.await?
+					}
+				};
+				tunnel.set_port_access(port_access.clone());
+				forward_ports_again(&log, &mut tunnel, &r.tunnel).await;
+				next_tunnel = Some(tunnel);
+			}
+			continue;
+		}
+
+		if matches!(r.shutdown, Some(ShutdownSignal::PrimaryHostOnline)) {
+			info!(
+				log,
+				"The primary host is back online, handing the tunnel back"
+			);
+			continue;
+		}
+
+		break Some(r);
 	};
+	drop(singleton);
 
-	let mut r = crate::tunnels::serve(&log, tunnel, &paths, &csa, platform, shutdown_tx).await?;
-	r.tunnel.close().await.ok();
+	for (tx, serving) in additional_tunnels {
+		tx.send(ShutdownSignal::MainTunnelStopped).await.ok();
+		serving.await.ok();
+	}
+
+	// a standby that shut down before taking over never served the tunnel
+	let r = match r {
+		Some(r) => r,
+		None => return Ok(0),
+	};
+
+	if let Some(mut child) = r.handed_off {
+		// the new process connected, so an update it's running is good
+		if self_update::has_rollback(&current_exe) {
+			self_update::discard_rollback(&current_exe);
+		}
+
+		let exit = child
+			.wait()
+			.await
+			.map_err(|e| wrap(e, "error waiting for child"))?;
+
+		return Ok(exit.code().unwrap_or(1));
+	}
 
 	if r.respawn {
 		warning!(log, "respawn requested, starting new server");
 		// reuse current args, but specify no-forward since tunnels will
 		// already be running in this process, and we cannot do a login
 		let args = std::env::args().skip(1).collect::<Vec<String>>();
-		let exit = std::process::Command::new(current_exe)
-			.args(args)
-			.spawn()
-			.map_err(|e| wrap(e, "error respawning after update"))?
+		let respawn = || {
+			Command::new(&current_exe)
+				.args(&args)
+				.spawn()
+				.map_err(|e| wrap(e, "error respawning after update"))
+		};
+
+		let mut child = respawn()?;
+
+		// If the new CLI doesn't come up, put the previous one back.
+		if self_update::has_rollback(&current_exe) {
+			let started = timeout(
+				RESTART_TIMEOUT,
+				wait_for_child_listening(&paths, &mut child),
+			)
+			.await
+			.unwrap_or_else(|_| {
+				Err(TunnelHostFailed("The updated tunnel did not start in time".to_string()).into())
+			});
+
+			match started {
+				Ok(_) => self_update::discard_rollback(&current_exe),
+				Err(e) => {
+					error!(log, "Updated CLI failed to start, rolling back: {}", e);
+					child.kill().ok();
+					child.wait().ok();
+					self_update::rollback(&current_exe)?;
+					child = respawn()?;
+				}
+			}
+		}
+
+		let exit = child
 			.wait()
 			.map_err(|e| wrap(e, "error waiting for child"))?;
 
@@ -297,3 +2186,76 @@ async fn serve_with_csa(
 
 	Ok(0)
 }
+
+/// Sends signals to shut the tunnel down on `tx`: those from `shutdown_rx` if
+/// it's given, or otherwise Ctrl+C and the parent process exiting.
+fn forward_shutdown_signals(
+	log: &Logger,
+	gateway_args: &TunnelServeArgs,
+	shutdown_rx: Option<mpsc::Receiver<ShutdownSignal>>,
+	tx: &mpsc::Sender<ShutdownSignal>,
+) {
+	if let Some(mut shutdown_rx) = shutdown_rx {
+		let tx = tx.clone();
+		tokio::spawn(async move {
+			if let Some(s) = shutdown_rx.recv().await {
+				tx.send(s).await.ok();
+			}
+		});
+	} else {
+		match gateway_args.parent_pid {
+			Some(pid) if gateway_args.no_parent_watch => {
+				info!(log, "not watching parent process {}", pid);
+			}
+			Some(pid) => {
+				let tx = tx.clone();
+				let log = log.clone();
+				let poll_interval = Duration::from_secs(gateway_args.parent_poll_interval.max(1));
+				let grace_period = Duration::from_secs(gateway_args.parent_grace_period);
+				info!(log, "checking for parent process {}", pid);
+				tokio::spawn(async move {
+					wait_until_process_exits(pid, poll_interval).await;
+					if !grace_period.is_zero() {
+						info!(
+							log,
+							"parent process {} exited, shutting down in {}s",
+							pid,
+							grace_period.as_secs()
+						);
+						sleep(grace_period).await;
+					}
+					tx.send(ShutdownSignal::ParentProcessKilled).await.ok();
+				});
+			}
+			None => {}
+		}
+		let tx = tx.clone();
+		tokio::spawn(async move {
+			tokio::signal::ctrl_c().await.ok();
+			tx.send(ShutdownSignal::CtrlC).await.ok();
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_reports_detached_child_exiting() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+
+		// a child that exits without ever taking the singleton lock
+		let mut child = Command::new(std::env::current_exe().unwrap())
+			.arg("--list")
+			.stdout(Stdio::null())
+			.spawn()
+			.unwrap();
+
+		match wait_for_child_listening(&paths, &mut child).await {
+			Err(AnyError::TunnelHostFailed(e)) => assert!(e.0.starts_with("The tunnel exited")),
+			r => panic!("unexpected result {:?}", r),
+		}
+	}
+}
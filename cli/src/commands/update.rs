@@ -32,6 +32,16 @@ pub async fn update(ctx: CommandContext, args: StandaloneUpdateArgs) -> Result<i
 		return Ok(0);
 	}
 
+	if !args.force_standalone {
+		if let Err(e) = update_service.install_source()?.ensure_standalone() {
+			ctx.log.result(format!(
+				"{}. To replace it in place anyway, run `code update --force-standalone`.",
+				e
+			));
+			return Ok(1);
+		}
+	}
+
 	let pb = ProgressBar::new(1);
 	pb.set_message("Downloading...");
 	update_service
@@ -10,17 +10,26 @@ use lazy_static::lazy_static;
 use crate::options::Quality;
 
 pub const CONTROL_PORT: u16 = 31545;
-pub const PROTOCOL_VERSION: u32 = 1;
+pub const PROTOCOL_VERSION: u32 = 3;
 
 pub const VSCODE_CLI_VERSION: Option<&'static str> = option_env!("VSCODE_CLI_VERSION");
 pub const VSCODE_CLI_AI_KEY: Option<&'static str> = option_env!("VSCODE_CLI_AI_KEY");
 pub const VSCODE_CLI_AI_ENDPOINT: Option<&'static str> = option_env!("VSCODE_CLI_AI_ENDPOINT");
 pub const VSCODE_CLI_QUALITY: Option<&'static str> = option_env!("VSCODE_CLI_QUALITY");
 pub const VSCODE_CLI_COMMIT: Option<&'static str> = option_env!("VSCODE_CLI_COMMIT");
+/// Minisign public key, in base64, that CLI updates must be signed with.
+pub const VSCODE_CLI_UPDATE_PUBLIC_KEY: Option<&'static str> =
+	option_env!("VSCODE_CLI_UPDATE_PUBLIC_KEY");
 pub const VSCODE_CLI_UPDATE_ENDPOINT: Option<&'static str> =
 	option_env!("VSCODE_CLI_UPDATE_ENDPOINT");
+/// URL the tunnel service's current limits, such as name rules, are fetched from.
+pub const VSCODE_CLI_TUNNEL_LIMITS_URL: Option<&'static str> =
+	option_env!("VSCODE_CLI_TUNNEL_LIMITS_URL");
 
 pub const TUNNEL_SERVICE_USER_AGENT_ENV_VAR: &str = "TUNNEL_SERVICE_USER_AGENT";
+/// Overrides, or sets for builds without one, the URL of the tunnel service's
+/// current limits.
+pub const TUNNEL_LIMITS_URL_ENV_VAR: &str = "VSCODE_CLI_TUNNEL_LIMITS_URL";
 
 // JSON map of quality names to arrays of app IDs used for them, for example, `{"stable":["ABC123"]}`
 const VSCODE_CLI_WIN32_APP_IDS: Option<&'static str> = option_env!("VSCODE_CLI_WIN32_APP_IDS");
@@ -41,6 +50,11 @@ lazy_static! {
 			Ok(ua) if !ua.is_empty() => format!("{} {}", ua, get_default_user_agent()),
 			_ => get_default_user_agent(),
 		};
+	pub static ref TUNNEL_LIMITS_URL: Option<String> =
+		match std::env::var(TUNNEL_LIMITS_URL_ENV_VAR) {
+			Ok(url) if !url.is_empty() => Some(url),
+			_ => VSCODE_CLI_TUNNEL_LIMITS_URL.map(|u| u.to_string()),
+		};
 	pub static ref WIN32_APP_IDS: Option<HashMap<Quality, Vec<String>>> =
 		VSCODE_CLI_WIN32_APP_IDS.and_then(|s| serde_json::from_str(s).unwrap());
 	pub static ref QUALITY_DOWNLOAD_URIS: Option<HashMap<Quality, String>> =
@@ -4,19 +4,41 @@
  *--------------------------------------------------------------------------------------------*/
 
 use chrono::Local;
+use lazy_static::lazy_static;
 use opentelemetry::{
 	sdk::trace::{Tracer, TracerProvider},
 	trace::{SpanBuilder, Tracer as TraitTracer, TracerProvider as TracerProviderTrait},
 };
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::{env, path::Path, sync::Arc};
 use std::{
 	io::Write,
 	sync::atomic::{AtomicU32, Ordering},
+	sync::Mutex,
 };
+use tokio::sync::broadcast;
 
 const NO_COLOR_ENV: &str = "NO_COLOR";
 
+/// Environment variable that CLI processes started by this one, such as a
+/// respawned tunnel, read their correlation ID from.
+pub const CORRELATION_ID_ENV: &str = "VSCODE_CLI_CORRELATION_ID";
+
+lazy_static! {
+	static ref CORRELATION_ID: String = env::var(CORRELATION_ID_ENV)
+		.ok()
+		.filter(|id| !id.is_empty())
+		.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+}
+
+/// Gets the ID that correlates the spans, log files, and tunnel service
+/// requests of this CLI invocation, or of the session that started it, so
+/// they can be matched with the service's traces.
+pub fn correlation_id() -> &'static str {
+	&CORRELATION_ID
+}
+
 static INSTANCE_COUNTER: AtomicU32 = AtomicU32::new(0);
 
 // Gets a next incrementing number that can be used in logs
@@ -127,6 +149,140 @@ where
 pub trait LogSink: LogSinkClone + Sync + Send {
 	fn write_log(&self, level: Level, prefix: &str, message: &str);
 	fn write_result(&self, message: &str);
+
+	/// Writes a structured progress frame. Only sinks that talk to a machine
+	/// consumer care about these, so they're ignored by default.
+	fn write_progress(&self, _frame: &ProgressFrame) {}
+}
+
+/// Structured progress and status frames. These are written as JSON lines
+/// when the CLI is spawned by the VS Code desktop client, so that it can
+/// render real progress instead of parsing log text.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ProgressFrame<'a> {
+	#[serde(rename_all = "camelCase")]
+	Log {
+		level: &'a str,
+		message: &'a str,
+	},
+	#[serde(rename_all = "camelCase")]
+	Result {
+		message: &'a str,
+	},
+	#[serde(rename_all = "camelCase")]
+	Download {
+		name: &'a str,
+		bytes_so_far: u64,
+		total_bytes: u64,
+	},
+	#[serde(rename_all = "camelCase")]
+	TunnelState {
+		state: TunnelProgressState,
+		name: Option<&'a str>,
+		uri: Option<&'a str>,
+	},
+	#[serde(rename_all = "camelCase")]
+	PortUri {
+		port: u16,
+		uri: &'a str,
+	},
+	#[serde(rename_all = "camelCase")]
+	PortUnforwarded {
+		port: u16,
+	},
+	/// A different process started serving a forwarded port.
+	#[serde(rename_all = "camelCase")]
+	PortOwnerChanged {
+		port: u16,
+		pid: Option<u32>,
+		previous_pid: Option<u32>,
+	},
+	/// The tunnel was deleted from the tunnel service while running, and was
+	/// created again under the same name.
+	#[serde(rename_all = "camelCase")]
+	TunnelRecreated {
+		name: &'a str,
+	},
+	ClientConnected,
+	/// New client connections are refused, since a data transfer budget is
+	/// used up.
+	#[serde(rename_all = "camelCase")]
+	BudgetExceeded {
+		message: &'a str,
+	},
+	/// Client connections are accepted again after a budget was used up.
+	BudgetAvailable,
+	/// Hosting is paused: the tunnel is unregistered from the relay while the
+	/// process and server keep running.
+	#[serde(rename_all = "camelCase")]
+	HostingPaused {
+		reason: &'a str,
+	},
+	/// Hosting resumed after it was paused.
+	HostingResumed,
+	/// An optional subsystem was turned off after failing too often.
+	#[serde(rename_all = "camelCase")]
+	SubsystemDisabled {
+		subsystem: &'a str,
+		reason: &'a str,
+	},
+	#[serde(rename_all = "camelCase")]
+	ClientDisconnected {
+		bytes_received: u64,
+		bytes_sent: u64,
+		duration_ms: u64,
+	},
+	/// A client connected to a forwarded port.
+	#[serde(rename_all = "camelCase")]
+	PortConnectionOpened {
+		port: u16,
+	},
+	/// A client's connection to a forwarded port closed.
+	#[serde(rename_all = "camelCase")]
+	PortConnectionClosed {
+		port: u16,
+		bytes_received: u64,
+		bytes_sent: u64,
+	},
+	/// Something changed that could mean the account or tunnel is misused.
+	#[serde(rename_all = "camelCase")]
+	SecurityEvent {
+		kind: SecurityEventKind,
+		detail: &'a str,
+	},
+}
+
+/// Kinds of security-relevant changes reported in `SecurityEvent` frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SecurityEventKind {
+	/// A host token was issued to this machine for the tunnel.
+	HostTokenIssued,
+	/// Who can access the tunnel changed since it was last hosted here.
+	AccessControlChanged,
+	/// The CLI logged in with an account not used on this machine before.
+	NewAccount,
+}
+
+impl SecurityEventKind {
+	pub fn name(&self) -> &'static str {
+		match self {
+			SecurityEventKind::HostTokenIssued => "host-token-issued",
+			SecurityEventKind::AccessControlChanged => "access-control-changed",
+			SecurityEventKind::NewAccount => "new-account",
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TunnelProgressState {
+	Connecting,
+	Connected,
+	Reconnecting,
+	Listening,
+	Closed,
 }
 
 impl Clone for Box<dyn LogSink> {
@@ -154,6 +310,163 @@ impl LogSink for StdioLogSink {
 	}
 }
 
+/// Log sink that writes log text to stderr, leaving stdout for results that
+/// commands print as JSON.
+#[derive(Clone)]
+pub struct StderrLogSink {
+	level: Level,
+}
+
+impl LogSink for StderrLogSink {
+	fn write_log(&self, level: Level, prefix: &str, message: &str) {
+		if level < self.level {
+			return;
+		}
+
+		eprint!("{}", format(level, prefix, message));
+	}
+
+	fn write_result(&self, _message: &str) {}
+}
+
+/// Log sink that writes everything as structured JSON lines on stdout.
+#[derive(Clone)]
+pub struct StructuredStdioLogSink {
+	level: Level,
+}
+
+impl StructuredStdioLogSink {
+	fn write_frame(&self, frame: &ProgressFrame) {
+		if let Ok(s) = serde_json::to_string(frame) {
+			println!("{}", s);
+		}
+	}
+}
+
+impl LogSink for StructuredStdioLogSink {
+	fn write_log(&self, level: Level, prefix: &str, message: &str) {
+		if level < self.level {
+			return;
+		}
+
+		if let Some(name) = level.name() {
+			self.write_frame(&ProgressFrame::Log {
+				level: name,
+				message: &format!("{}{}", prefix, message),
+			});
+		}
+	}
+
+	fn write_result(&self, message: &str) {
+		self.write_frame(&ProgressFrame::Result { message });
+	}
+
+	fn write_progress(&self, frame: &ProgressFrame) {
+		self.write_frame(frame);
+	}
+}
+
+/// Log sink that republishes everything as JSON-serialized progress frames on
+/// a broadcast channel, for clients attached to a running tunnel.
+#[derive(Clone)]
+pub struct BroadcastLogSink {
+	tx: broadcast::Sender<String>,
+	last_state: Arc<Mutex<Option<String>>>,
+}
+
+impl Default for BroadcastLogSink {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl BroadcastLogSink {
+	pub fn new() -> Self {
+		let (tx, _) = broadcast::channel(64);
+		Self {
+			tx,
+			last_state: Arc::new(Mutex::new(None)),
+		}
+	}
+
+	/// Subscribes to frames. Returns the most recent tunnel state frame, if
+	/// any, so that new subscribers know where things stand.
+	pub fn subscribe(&self) -> (Option<String>, broadcast::Receiver<String>) {
+		let state = self.last_state.lock().unwrap();
+		(state.clone(), self.tx.subscribe())
+	}
+
+	fn has_subscribers(&self) -> bool {
+		self.tx.receiver_count() > 0
+	}
+
+	fn write_frame(&self, frame: &ProgressFrame) {
+		let is_state = matches!(frame, ProgressFrame::TunnelState { .. });
+		// the latest state is kept for later subscribers; anything else is only
+		// worth serializing if someone is listening
+		if !is_state && !self.has_subscribers() {
+			return;
+		}
+
+		if let Ok(s) = serde_json::to_string(frame) {
+			if is_state {
+				self.last_state.lock().unwrap().replace(s.clone());
+			}
+
+			// an error here just means there are no subscribers
+			self.tx.send(s).ok();
+		}
+	}
+}
+
+impl LogSink for BroadcastLogSink {
+	fn write_log(&self, level: Level, prefix: &str, message: &str) {
+		if !self.has_subscribers() {
+			return;
+		}
+
+		if let Some(name) = level.name() {
+			self.write_frame(&ProgressFrame::Log {
+				level: name,
+				message: &format!("{}{}", prefix, message),
+			});
+		}
+	}
+
+	fn write_result(&self, message: &str) {
+		self.write_frame(&ProgressFrame::Result { message });
+	}
+
+	fn write_progress(&self, frame: &ProgressFrame) {
+		self.write_frame(frame);
+	}
+}
+
+/// A frame received from a `BroadcastLogSink`. Only the frames that
+/// subscribers display are parsed; anything else is `Other`.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BroadcastFrame {
+	Log {
+		level: String,
+		message: String,
+	},
+	Result {
+		message: String,
+	},
+	TunnelState {
+		state: String,
+		name: Option<String>,
+		uri: Option<String>,
+	},
+	PortUri {
+		port: u16,
+		uri: String,
+	},
+	#[serde(other)]
+	Other,
+}
+
 #[derive(Clone)]
 pub struct FileLogSink {
 	level: Level,
@@ -176,7 +489,8 @@ impl LogSink for FileLogSink {
 			return;
 		}
 
-		let line = format(level, prefix, message);
+		let prefix = format!("[{}] {}", correlation_id(), prefix);
+		let line = format(level, &prefix, message);
 
 		// ignore any errors, not much we can do if logging fails...
 		self.file.lock().unwrap().write_all(line.as_bytes()).ok();
@@ -202,6 +516,26 @@ impl Logger {
 		}
 	}
 
+	/// Creates a logger that writes structured progress frames to stdout
+	/// rather than human-readable text.
+	pub fn new_structured(tracer: Tracer, level: Level) -> Self {
+		Self {
+			tracer,
+			sink: vec![Box::new(StructuredStdioLogSink { level })],
+			prefix: None,
+		}
+	}
+
+	/// Creates a logger that writes log text to stderr and drops results, for
+	/// commands that print their results as JSON.
+	pub fn new_stderr(tracer: Tracer, level: Level) -> Self {
+		Self {
+			tracer,
+			sink: vec![Box::new(StderrLogSink { level })],
+			prefix: None,
+		}
+	}
+
 	pub fn span(&self, name: &str) -> SpanBuilder {
 		self.tracer.span_builder(format!("serverlauncher/{}", name))
 	}
@@ -223,6 +557,12 @@ impl Logger {
 		}
 	}
 
+	pub fn progress(&self, frame: ProgressFrame) {
+		for sink in &self.sink {
+			sink.write_progress(&frame);
+		}
+	}
+
 	pub fn prefixed(&self, prefix: &str) -> Logger {
 		Logger {
 			prefix: Some(match &self.prefix {
@@ -262,6 +602,12 @@ pub struct DownloadLogger<'a> {
 
 impl<'a> crate::util::io::ReportCopyProgress for DownloadLogger<'a> {
 	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
+		self.logger.progress(ProgressFrame::Download {
+			name: self.prefix,
+			bytes_so_far,
+			total_bytes,
+		});
+
 		if total_bytes > 0 {
 			self.logger.emit(
 				Level::Trace,
@@ -364,6 +710,10 @@ macro_rules! span {
 
 		let span = $span.start($logger.tracer());
 		let cx = opentelemetry::Context::current_with_span(span);
+		cx.span().set_attribute(opentelemetry::KeyValue::new(
+			"correlation_id",
+			$crate::log::correlation_id(),
+		));
 		let guard = cx.clone().attach();
 		let t = $func;
 
@@ -384,6 +734,10 @@ macro_rules! spanf {
 
 		let span = $span.start($logger.tracer());
 		let cx = opentelemetry::Context::current_with_span(span);
+		cx.span().set_attribute(opentelemetry::KeyValue::new(
+			"correlation_id",
+			$crate::log::correlation_id(),
+		));
 		let t = $func.with_context(cx.clone()).await;
 
 		if let Err(e) = &t {
@@ -395,3 +749,91 @@ macro_rules! spanf {
 		t
 	}};
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_writes_log_file_at_level() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("cli.log");
+		let log = Logger::test().tee(FileLogSink::new(Level::Info, &path).unwrap());
+
+		log.emit(Level::Debug, "too verbose");
+		log.emit(Level::Info, "written");
+
+		let contents = std::fs::read_to_string(&path).unwrap();
+		assert!(contents.contains("written"));
+		assert!(!contents.contains("too verbose"));
+		assert!(contents.contains(&format!("[{}]", correlation_id())));
+	}
+
+	#[test]
+	fn test_serializes_progress_frames() {
+		let frame = ProgressFrame::Download {
+			name: "server",
+			bytes_so_far: 10,
+			total_bytes: 20,
+		};
+		assert_eq!(
+			serde_json::to_string(&frame).unwrap(),
+			r#"{"type":"download","name":"server","bytesSoFar":10,"totalBytes":20}"#
+		);
+
+		let frame = ProgressFrame::TunnelState {
+			state: TunnelProgressState::Listening,
+			name: Some("my-tunnel"),
+			uri: None,
+		};
+		assert_eq!(
+			serde_json::to_string(&frame).unwrap(),
+			r#"{"type":"tunnelState","state":"listening","name":"my-tunnel","uri":null}"#
+		);
+	}
+
+	#[test]
+	fn test_logger_sends_progress_to_sinks() {
+		let sink = BroadcastLogSink::new();
+		let (_, mut rx) = sink.subscribe();
+		let log = Logger::test().tee(sink);
+
+		log.progress(ProgressFrame::PortUri {
+			port: 8080,
+			uri: "https://example.com",
+		});
+		match serde_json::from_str(&rx.try_recv().unwrap()).unwrap() {
+			BroadcastFrame::PortUri { port, uri } => {
+				assert_eq!(port, 8080);
+				assert_eq!(uri, "https://example.com");
+			}
+			f => panic!("unexpected frame {:?}", f),
+		}
+	}
+
+	#[test]
+	fn test_broadcast_only_keeps_state_without_subscribers() {
+		let sink = BroadcastLogSink::new();
+		sink.write_log(Level::Info, "", "before");
+		sink.write_progress(&ProgressFrame::TunnelState {
+			state: TunnelProgressState::Listening,
+			name: Some("my-tunnel"),
+			uri: None,
+		});
+
+		let (state, mut rx) = sink.subscribe();
+		match serde_json::from_str(&state.unwrap()).unwrap() {
+			BroadcastFrame::TunnelState { name, .. } => {
+				assert_eq!(name.as_deref(), Some("my-tunnel"))
+			}
+			f => panic!("unexpected frame {:?}", f),
+		}
+
+		sink.write_log(Level::Info, "", "after");
+		match serde_json::from_str(&rx.try_recv().unwrap()).unwrap() {
+			BroadcastFrame::Log { message, .. } => assert_eq!(message, "after"),
+			f => panic!("unexpected frame {:?}", f),
+		}
+		assert!(rx.try_recv().is_err());
+	}
+}
@@ -92,6 +92,108 @@ impl TryFrom<&str> for Quality {
 	}
 }
 
+/// Channel the CLI updates itself from.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UpdateChannel {
+	Stable,
+	Insiders,
+	/// Never update, for machines whose CLI is managed some other way.
+	Never,
+}
+
+impl UpdateChannel {
+	/// Quality to update to, or None if updates are disabled.
+	pub fn quality(&self) -> Option<Quality> {
+		match self {
+			UpdateChannel::Stable => Some(Quality::Stable),
+			UpdateChannel::Insiders => Some(Quality::Insiders),
+			UpdateChannel::Never => None,
+		}
+	}
+}
+
+/// Operating system of a VS Code Server build.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ServerOs {
+	Linux,
+	/// Linux distributions using musl, such as Alpine.
+	Alpine,
+	Darwin,
+	Win32,
+}
+
+impl fmt::Display for ServerOs {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ServerOs::Linux => write!(f, "linux"),
+			ServerOs::Alpine => write!(f, "alpine"),
+			ServerOs::Darwin => write!(f, "darwin"),
+			ServerOs::Win32 => write!(f, "win32"),
+		}
+	}
+}
+
+/// CPU architecture of a VS Code Server build.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ServerArch {
+	X64,
+	X86,
+	Arm64,
+	Armhf,
+	Riscv64,
+}
+
+impl ServerArch {
+	/// Gets the architecture the CLI was built for.
+	pub fn build_default() -> Option<ServerArch> {
+		if cfg!(target_arch = "x86_64") {
+			Some(ServerArch::X64)
+		} else if cfg!(target_arch = "x86") {
+			Some(ServerArch::X86)
+		} else if cfg!(target_arch = "aarch64") {
+			Some(ServerArch::Arm64)
+		} else if cfg!(target_arch = "arm") {
+			Some(ServerArch::Armhf)
+		} else if cfg!(target_arch = "riscv64") {
+			Some(ServerArch::Riscv64)
+		} else {
+			None
+		}
+	}
+
+	/// Parses an architecture as named by the OS, e.g. in `uname -m` or
+	/// `PROCESSOR_ARCHITECTURE`.
+	pub fn from_machine_name(name: &str) -> Option<ServerArch> {
+		match name.to_lowercase().as_str() {
+			"x86_64" | "amd64" | "x64" => Some(ServerArch::X64),
+			"i386" | "i686" | "x86" => Some(ServerArch::X86),
+			"aarch64" | "arm64" | "armv8b" | "armv8l" => Some(ServerArch::Arm64),
+			"armv7l" | "armhf" | "arm" => Some(ServerArch::Armhf),
+			"riscv64" => Some(ServerArch::Riscv64),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for ServerArch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ServerArch::X64 => write!(f, "x64"),
+			ServerArch::X86 => write!(f, "x86"),
+			ServerArch::Arm64 => write!(f, "arm64"),
+			ServerArch::Armhf => write!(f, "armhf"),
+			ServerArch::Riscv64 => write!(f, "riscv64"),
+		}
+	}
+}
+
+/// C library that a Linux VS Code Server build links against.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Libc {
+	Glibc,
+	Musl,
+}
+
 #[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TelemetryLevel {
 	Off,
@@ -110,3 +212,17 @@ impl fmt::Display for TelemetryLevel {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use clap::ArgEnum;
+
+	#[test]
+	fn test_update_channel_quality() {
+		let channel = |s| UpdateChannel::from_str(s, true).unwrap();
+		assert_eq!(channel("stable").quality(), Some(Quality::Stable));
+		assert_eq!(channel("insiders").quality(), Some(Quality::Insiders));
+		assert_eq!(channel("never").quality(), None);
+	}
+}
@@ -3,20 +3,99 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{fs, path::Path, process::Command};
+use minisign_verify::{PublicKey, Signature};
+use std::{
+	fmt, fs,
+	path::{Path, PathBuf},
+	process::Command,
+};
 use tempfile::tempdir;
 
 use crate::{
-	constants::{VSCODE_CLI_COMMIT, VSCODE_CLI_QUALITY},
+	constants::{VSCODE_CLI_COMMIT, VSCODE_CLI_QUALITY, VSCODE_CLI_UPDATE_PUBLIC_KEY},
 	options::Quality,
 	update_service::{unzip_downloaded_release, Platform, Release, TargetKind, UpdateService},
 	util::{
-		errors::{wrap, AnyError, CorruptDownload, UpdatesNotConfigured},
+		errors::{
+			wrap, AnyError, CorruptDownload, ManagedInstall, UpdateSignatureInvalid,
+			UpdatesNotConfigured,
+		},
 		http,
 		io::{ReportCopyProgress, SilentCopyProgress},
 	},
 };
 
+/// Name of the file that packages place next to the CLI executable to record
+/// how it was installed, e.g. containing `apt` or `homebrew`.
+const INSTALL_SOURCE_FILE_NAME: &str = "install-source";
+
+/// How the CLI was installed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstallSource {
+	Standalone,
+	Apt,
+	Rpm,
+	Homebrew,
+	Winget,
+}
+
+impl InstallSource {
+	/// Reads the install source recorded next to the given executable. CLIs
+	/// without a recorded source are standalone.
+	pub fn detect(exe: &Path) -> Self {
+		let contents = match exe
+			.parent()
+			.map(|dir| fs::read_to_string(dir.join(INSTALL_SOURCE_FILE_NAME)))
+		{
+			Some(Ok(c)) => c,
+			_ => return InstallSource::Standalone,
+		};
+
+		match contents.trim().to_lowercase().as_str() {
+			"apt" | "deb" => InstallSource::Apt,
+			"rpm" | "yum" | "dnf" => InstallSource::Rpm,
+			"homebrew" | "brew" => InstallSource::Homebrew,
+			"winget" => InstallSource::Winget,
+			_ => InstallSource::Standalone,
+		}
+	}
+
+	/// Gets the command the user should run to update a managed install.
+	pub fn update_command(&self) -> Option<&'static str> {
+		match self {
+			InstallSource::Standalone => None,
+			InstallSource::Apt => Some("sudo apt-get update && sudo apt-get install code"),
+			InstallSource::Rpm => Some("sudo dnf upgrade code"),
+			InstallSource::Homebrew => Some("brew upgrade --cask visual-studio-code"),
+			InstallSource::Winget => Some("winget upgrade Microsoft.VisualStudioCode"),
+		}
+	}
+
+	/// Returns an error if files of this install are owned by a package
+	/// manager, and so should not be replaced by the CLI.
+	pub fn ensure_standalone(&self) -> Result<(), ManagedInstall> {
+		match self.update_command() {
+			None => Ok(()),
+			Some(cmd) => Err(ManagedInstall {
+				source: self.to_string(),
+				update_command: cmd.to_string(),
+			}),
+		}
+	}
+}
+
+impl fmt::Display for InstallSource {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			InstallSource::Standalone => write!(f, "standalone"),
+			InstallSource::Apt => write!(f, "apt"),
+			InstallSource::Rpm => write!(f, "rpm"),
+			InstallSource::Homebrew => write!(f, "Homebrew"),
+			InstallSource::Winget => write!(f, "winget"),
+		}
+	}
+}
+
 pub struct SelfUpdate<'a> {
 	commit: &'static str,
 	quality: Quality,
@@ -45,6 +124,12 @@ impl<'a> SelfUpdate<'a> {
 		})
 	}
 
+	/// Updates from the given quality, rather than the one this CLI was built as.
+	pub fn with_quality(mut self, quality: Quality) -> Self {
+		self.quality = quality;
+		self
+	}
+
 	/// Gets the current release
 	pub async fn get_current_release(&self) -> Result<Release, AnyError> {
 		self.update_service
@@ -52,6 +137,12 @@ impl<'a> SelfUpdate<'a> {
 			.await
 	}
 
+	/// Gets how the running CLI was installed.
+	pub fn install_source(&self) -> Result<InstallSource, AnyError> {
+		let exe = std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+		Ok(InstallSource::detect(&exe))
+	}
+
 	/// Gets whether the given release is what this CLI is built against
 	pub fn is_up_to_date_with(&self, release: &Release) -> bool {
 		release.commit == self.commit
@@ -62,12 +153,33 @@ impl<'a> SelfUpdate<'a> {
 		&self,
 		release: &Release,
 		progress: impl ReportCopyProgress,
+	) -> Result<(), AnyError> {
+		self.install(release, progress, false).await
+	}
+
+	/// Updates the CLI to the given release, keeping the previous binary so
+	/// that the update can be undone with `rollback()`.
+	pub async fn do_update_with_rollback(
+		&self,
+		release: &Release,
+		progress: impl ReportCopyProgress,
+	) -> Result<(), AnyError> {
+		self.install(release, progress, true).await
+	}
+
+	async fn install(
+		&self,
+		release: &Release,
+		progress: impl ReportCopyProgress,
+		keep_previous: bool,
 	) -> Result<(), AnyError> {
 		// 1. Download the archive into a temporary directory
 		let tempdir = tempdir().map_err(|e| wrap(e, "Failed to create temp dir"))?;
 		let archive_path = tempdir.path().join("archive");
 		let stream = self.update_service.get_download_stream(release).await?;
+		let signature = self.update_service.get_download_signature(&stream).await?;
 		http::download_into_file(&archive_path, progress, stream).await?;
+		verify_signature(VSCODE_CLI_UPDATE_PUBLIC_KEY, &archive_path, &signature)?;
 
 		// 2. Unzip the archive and get the binary
 		let target_path =
@@ -83,10 +195,14 @@ impl<'a> SelfUpdate<'a> {
 			.map_err(|e| wrap(e, "failed to set file permissions"))?;
 		validate_cli_is_good(&staging_path)?;
 
-		// Try to rename the old CLI to the tempdir, where it can get cleaned up by the
+		// If asked to keep the old CLI, move it next to the install path. Otherwise
+		// try to rename the old CLI to the tempdir, where it can get cleaned up by the
 		// OS later. However, this can fail if the tempdir is on a different drive
 		// than the installation dir. In this case just rename it to ".old".
-		if fs::rename(&target_path, &tempdir.path().join("old-code-cli")).is_err() {
+		if keep_previous {
+			fs::rename(&target_path, rollback_path(&target_path))
+				.map_err(|e| wrap(e, "failed to back up old CLI"))?;
+		} else if fs::rename(&target_path, &tempdir.path().join("old-code-cli")).is_err() {
 			fs::rename(&target_path, &target_path.with_extension(".old"))
 				.map_err(|e| wrap(e, "failed to rename old CLI"))?;
 		}
@@ -98,6 +214,51 @@ impl<'a> SelfUpdate<'a> {
 	}
 }
 
+fn rollback_path(exe: &Path) -> PathBuf {
+	exe.with_extension("rollback")
+}
+
+/// Gets whether a previous CLI was kept by `do_update_with_rollback()`.
+pub fn has_rollback(exe: &Path) -> bool {
+	rollback_path(exe).exists()
+}
+
+/// Restores the CLI kept by `do_update_with_rollback()`.
+pub fn rollback(exe: &Path) -> Result<(), AnyError> {
+	fs::rename(rollback_path(exe), exe).map_err(|e| wrap(e, "failed to restore previous CLI"))?;
+	Ok(())
+}
+
+/// Deletes the CLI kept by `do_update_with_rollback()`, once the update is known good.
+pub fn discard_rollback(exe: &Path) {
+	fs::remove_file(rollback_path(exe)).ok();
+}
+
+/// Checks the archive against its detached signature, using the public key
+/// the CLI was built with. Updates are refused if there is no key.
+fn verify_signature(
+	public_key: Option<&str>,
+	archive_path: &Path,
+	signature: &str,
+) -> Result<(), AnyError> {
+	let public_key = public_key
+		.ok_or_else(|| UpdateSignatureInvalid("no update signing key is configured".to_string()))
+		.and_then(|k| {
+			PublicKey::from_base64(k)
+				.map_err(|e| UpdateSignatureInvalid(format!("invalid signing key: {}", e)))
+		})?;
+
+	let signature = Signature::decode(signature)
+		.map_err(|e| UpdateSignatureInvalid(format!("invalid signature: {}", e)))?;
+
+	let archive = fs::read(archive_path).map_err(|e| wrap(e, "could not read update archive"))?;
+	public_key
+		.verify(&archive, &signature, false)
+		.map_err(|e| UpdateSignatureInvalid(e.to_string()))?;
+
+	Ok(())
+}
+
 fn validate_cli_is_good(exe_path: &Path) -> Result<(), AnyError> {
 	let o = Command::new(exe_path)
 		.args(["--version"])
@@ -161,3 +322,77 @@ fn copy_file_metadata(from: &Path, to: &Path) -> Result<(), std::io::Error> {
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_detects_install_source() {
+		let dir = tempfile::tempdir().unwrap();
+		let exe = dir.path().join("code");
+		assert_eq!(InstallSource::detect(&exe), InstallSource::Standalone);
+		assert!(InstallSource::detect(&exe).ensure_standalone().is_ok());
+
+		fs::write(dir.path().join(INSTALL_SOURCE_FILE_NAME), "deb\n").unwrap();
+		assert_eq!(InstallSource::detect(&exe), InstallSource::Apt);
+		let e = InstallSource::detect(&exe).ensure_standalone().unwrap_err();
+		assert_eq!(e.source, "apt");
+		assert!(e.update_command.contains("apt-get"));
+
+		fs::write(dir.path().join(INSTALL_SOURCE_FILE_NAME), "Homebrew").unwrap();
+		assert_eq!(InstallSource::detect(&exe), InstallSource::Homebrew);
+
+		fs::write(dir.path().join(INSTALL_SOURCE_FILE_NAME), "unknown").unwrap();
+		assert_eq!(InstallSource::detect(&exe), InstallSource::Standalone);
+	}
+
+	#[test]
+	fn test_rolls_back_to_previous_cli() {
+		let dir = tempfile::tempdir().unwrap();
+		let exe = dir.path().join("code");
+		fs::write(&exe, "updated").unwrap();
+		assert!(!has_rollback(&exe));
+
+		fs::write(rollback_path(&exe), "previous").unwrap();
+		assert!(has_rollback(&exe));
+		rollback(&exe).unwrap();
+		assert_eq!(fs::read_to_string(&exe).unwrap(), "previous");
+		assert!(!has_rollback(&exe));
+
+		fs::write(rollback_path(&exe), "previous").unwrap();
+		discard_rollback(&exe);
+		assert!(!has_rollback(&exe));
+		assert_eq!(fs::read_to_string(&exe).unwrap(), "previous");
+	}
+
+	#[test]
+	fn test_verifies_update_signature() {
+		const PUBLIC_KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+		const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1633700835\tfile:test\tprehashed
+wLMDjy9FLAuxZ3q4NlEvkgtyhrr0gtTu6KC4KBJdITbbOeAi1zBIYo0v4iTgt8jJpIidRJnp94ABQkJAgAooBQ==";
+
+		let dir = tempfile::tempdir().unwrap();
+		let archive = dir.path().join("archive");
+		fs::write(&archive, "test").unwrap();
+		verify_signature(Some(PUBLIC_KEY), &archive, SIGNATURE).unwrap();
+
+		let invalid =
+			|r: Result<(), AnyError>| matches!(r, Err(AnyError::UpdateSignatureInvalid(_)));
+		assert!(invalid(verify_signature(None, &archive, SIGNATURE)));
+		assert!(invalid(verify_signature(
+			Some(PUBLIC_KEY),
+			&archive,
+			"garbage"
+		)));
+
+		fs::write(&archive, "tampered").unwrap();
+		assert!(invalid(verify_signature(
+			Some(PUBLIC_KEY),
+			&archive,
+			SIGNATURE
+		)));
+	}
+}
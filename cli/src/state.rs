@@ -6,12 +6,14 @@
 extern crate dirs;
 
 use std::{
-	fs::{create_dir, read_to_string, remove_dir_all, write},
+	ffi::OsString,
+	fs::{copy, create_dir, metadata, read_to_string, remove_dir_all, remove_file, rename, write},
 	path::{Path, PathBuf},
 	sync::{Arc, Mutex},
+	time::SystemTime,
 };
 
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::util::errors::{wrap, AnyError, NoHomeForLauncherError, WrappedError};
 
@@ -22,42 +24,165 @@ pub struct LauncherPaths {
 	root: PathBuf,
 }
 
+/// Converts persisted state, as JSON, from the shape of one schema version
+/// to that of the next. Migrations are given in order, the first taking
+/// version 1 to version 2.
+pub type Migration = fn(serde_json::Value) -> Result<serde_json::Value, String>;
+
+/// What's written to disk once a state has migrations: the state, tagged with
+/// its schema version. Version 1 is saved bare, as it was before versioning,
+/// so older CLIs can still read it.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct VersionedState<S> {
+	schema_version: u32,
+	state: S,
+}
+
+enum LoadError {
+	/// The file can't be read as any version of the state.
+	Corrupt,
+	/// The file was written by a newer CLI, with a schema this one doesn't
+	/// know.
+	Newer,
+}
+
 struct PersistedStateContainer<T>
 where
 	T: Clone + Serialize + DeserializeOwned + Default,
 {
 	path: PathBuf,
 	state: Option<T>,
+	migrations: &'static [Migration],
+	/// Whether the file was backed up, which is done once per process before
+	/// it's first rewritten.
+	backed_up: bool,
+	/// Stamp of the file the state was last read from or saved to. The state
+	/// is read again once the file's changed, such as by another process.
+	stamp: Option<(SystemTime, u64)>,
 }
 
 impl<T> PersistedStateContainer<T>
 where
 	T: Clone + Serialize + DeserializeOwned + Default,
 {
+	fn schema_version(&self) -> u32 {
+		self.migrations.len() as u32 + 1
+	}
+
+	/// Gets when the file was last modified, and its size, to notice when
+	/// another process has changed it.
+	fn file_stamp(&self) -> Option<(SystemTime, u64)> {
+		let metadata = metadata(&self.path).ok()?;
+		Some((metadata.modified().ok()?, metadata.len()))
+	}
+
 	fn load_or_get(&mut self) -> T {
+		let stamp = self.file_stamp();
 		if let Some(state) = &self.state {
-			return state.clone();
+			if stamp == self.stamp {
+				return state.clone();
+			}
 		}
 
-		let state = if let Ok(s) = read_to_string(&self.path) {
-			serde_json::from_str::<T>(&s).unwrap_or_default()
-		} else {
-			T::default()
+		self.stamp = stamp;
+		// saves are atomic, but older CLIs write the file in place, so it's
+		// read again before deciding it's corrupt rather than half-written
+		let read = match self.read() {
+			Err(LoadError::Corrupt) => self.read(),
+			r => r,
+		};
+		let state = match read {
+			Ok(state) => state,
+			Err(LoadError::Corrupt) => {
+				// set aside, so it can still be looked at or recovered
+				rename(&self.path, path_with_suffix(&self.path, ".corrupt")).ok();
+				T::default()
+			}
+			// left as it is, since saving refuses to overwrite it
+			Err(LoadError::Newer) => T::default(),
 		};
 
 		self.state = Some(state.clone());
 		state
 	}
 
+	/// Reads the state from the file, or the default if there's none.
+	fn read(&self) -> Result<T, LoadError> {
+		match read_to_string(&self.path) {
+			Ok(s) => self.decode(&s),
+			Err(_) => Ok(T::default()),
+		}
+	}
+
+	/// Reads the state from the file's contents, migrating it from the schema
+	/// version it was saved with.
+	fn decode(&self, s: &str) -> Result<T, LoadError> {
+		let (version, mut value) = split_version(s).ok_or(LoadError::Corrupt)?;
+		if version == 0 {
+			return Err(LoadError::Corrupt);
+		}
+		if version > self.schema_version() {
+			return Err(LoadError::Newer);
+		}
+		for migrate in &self.migrations[version as usize - 1..] {
+			value = migrate(value).map_err(|_| LoadError::Corrupt)?;
+		}
+
+		serde_json::from_value(value).map_err(|_| LoadError::Corrupt)
+	}
+
 	fn save(&mut self, state: T) -> Result<(), WrappedError> {
-		let s = serde_json::to_string(&state).unwrap();
-		self.state = Some(state);
-		write(&self.path, s).map_err(|e| {
-			wrap(
-				e,
+		let on_disk = read_to_string(&self.path)
+			.ok()
+			.and_then(|s| split_version(&s))
+			.map(|(version, _)| version);
+		if let Some(version) = on_disk.filter(|v| *v > self.schema_version()) {
+			return Err(wrap(
+				format!(
+					"it was written by a newer version of the CLI (schema {})",
+					version
+				),
 				format!("error saving launcher state into {}", self.path.display()),
-			)
-		})
+			));
+		}
+
+		let s = match self.schema_version() {
+			1 => serde_json::to_string(&state),
+			schema_version => serde_json::to_string(&VersionedState {
+				schema_version,
+				state: &state,
+			}),
+		}
+		.unwrap();
+		self.state = Some(state);
+
+		if !self.backed_up {
+			self.backed_up = true;
+			if self.path.exists() {
+				copy(&self.path, path_with_suffix(&self.path, ".bak")).map_err(|e| {
+					wrap(
+						e,
+						format!("error backing up launcher state in {}", self.path.display()),
+					)
+				})?;
+			}
+		}
+
+		// written aside and moved into place, so other processes never read
+		// a partly written file
+		let tmp = path_with_suffix(&self.path, &format!(".{}.tmp", std::process::id()));
+		write(&tmp, s)
+			.and_then(|_| rename(&tmp, &self.path))
+			.map_err(|e| {
+				remove_file(&tmp).ok();
+				wrap(
+					e,
+					format!("error saving launcher state into {}", self.path.display()),
+				)
+			})?;
+		self.stamp = self.file_stamp();
+		Ok(())
 	}
 }
 
@@ -76,8 +201,22 @@ where
 {
 	/// Creates a new state container that persists to the given path.
 	pub fn new(path: PathBuf) -> PersistedState<T> {
+		Self::with_migrations(path, &[])
+	}
+
+	/// Creates a new state container that persists to the given path, whose
+	/// schema has changed over time. State saved with an earlier schema is
+	/// migrated as it's loaded, and the current schema version is one more
+	/// than the number of migrations.
+	pub fn with_migrations(path: PathBuf, migrations: &'static [Migration]) -> PersistedState<T> {
 		PersistedState {
-			container: Arc::new(Mutex::new(PersistedStateContainer { path, state: None })),
+			container: Arc::new(Mutex::new(PersistedStateContainer {
+				path,
+				state: None,
+				migrations,
+				backed_up: false,
+				stamp: None,
+			})),
 		}
 	}
 
@@ -104,6 +243,23 @@ where
 	}
 }
 
+/// Splits saved state into its schema version and the state itself, or returns
+/// None if it isn't JSON.
+fn split_version(s: &str) -> Option<(u32, serde_json::Value)> {
+	let value: serde_json::Value = serde_json::from_str(s).ok()?;
+	match serde_json::from_value::<VersionedState<serde_json::Value>>(value.clone()) {
+		Ok(v) => Some((v.schema_version, v.state)),
+		Err(_) => Some((1, value)),
+	}
+}
+
+/// Gets the path with the suffix added to its file name, like `a.json.bak`.
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+	let mut p = OsString::from(path);
+	p.push(suffix);
+	PathBuf::from(p)
+}
+
 impl LauncherPaths {
 	pub fn new(root: &Option<String>) -> Result<LauncherPaths, AnyError> {
 		let root = root.as_deref().unwrap_or("~/.vscode-cli");
@@ -150,3 +306,125 @@ impl LauncherPaths {
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+	struct Named {
+		name: String,
+	}
+
+	/// Version 1 saved the name as `title`.
+	fn rename_title(mut v: serde_json::Value) -> Result<serde_json::Value, String> {
+		let obj = v.as_object_mut().ok_or("expected an object")?;
+		let title = obj.remove("title").ok_or("missing title")?;
+		obj.insert("name".to_string(), title);
+		Ok(v)
+	}
+
+	#[test]
+	fn test_migrates_unversioned_state() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.json");
+		write(&path, r#"{"title":"my-machine"}"#).unwrap();
+
+		let state = PersistedState::<Named>::with_migrations(path.clone(), &[rename_title]);
+		assert_eq!(state.load().name, "my-machine");
+
+		state
+			.save(Named {
+				name: "other".to_string(),
+			})
+			.unwrap();
+		assert_eq!(
+			read_to_string(&path).unwrap(),
+			r#"{"schemaVersion":2,"state":{"name":"other"}}"#
+		);
+		// the file as it was before being rewritten is kept
+		assert_eq!(
+			read_to_string(dir.path().join("state.json.bak")).unwrap(),
+			r#"{"title":"my-machine"}"#
+		);
+
+		let state = PersistedState::<Named>::with_migrations(path, &[rename_title]);
+		assert_eq!(state.load().name, "other");
+	}
+
+	#[test]
+	fn test_saves_version_1_bare() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.json");
+
+		// as saved before versioning, and by older CLIs
+		write(&path, r#"{"name":"old"}"#).unwrap();
+		let state = PersistedState::<Named>::new(path.clone());
+		assert_eq!(state.load().name, "old");
+
+		state
+			.save(Named {
+				name: "new".to_string(),
+			})
+			.unwrap();
+		assert_eq!(read_to_string(&path).unwrap(), r#"{"name":"new"}"#);
+
+		// nothing's left from writing the file aside
+		let mut files = std::fs::read_dir(dir.path())
+			.unwrap()
+			.map(|e| e.unwrap().file_name())
+			.collect::<Vec<_>>();
+		files.sort();
+		assert_eq!(files, ["state.json", "state.json.bak"]);
+	}
+
+	#[test]
+	fn test_reads_versioned_state() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.json");
+
+		write(&path, r#"{"schemaVersion":1,"state":{"name":"tagged"}}"#).unwrap();
+		let state = PersistedState::<Named>::new(path.clone());
+		assert_eq!(state.load().name, "tagged");
+
+		write(&path, r#"{"schemaVersion":2,"state":{"name":"tagged"}}"#).unwrap();
+		let state = PersistedState::<Named>::with_migrations(path, &[rename_title]);
+		assert_eq!(state.load().name, "tagged");
+	}
+
+	#[test]
+	fn test_reads_changes_from_other_processes() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.json");
+		let ours = PersistedState::<Named>::new(path.clone());
+		assert_eq!(ours.load(), Named::default());
+
+		// as if another process saved it
+		PersistedState::<Named>::new(path)
+			.save(Named {
+				name: "renamed".to_string(),
+			})
+			.unwrap();
+		assert_eq!(ours.load().name, "renamed");
+	}
+
+	#[test]
+	fn test_sets_aside_corrupt_state() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.json");
+		write(&path, r#"{"schemaVersion":1,"state":{"na"#).unwrap();
+
+		let state = PersistedState::<Named>::new(path.clone());
+		assert_eq!(state.load(), Named::default());
+		assert!(!path.exists());
+		assert!(dir.path().join("state.json.corrupt").exists());
+
+		// state from a newer CLI is left alone, and not overwritten
+		let newer = r#"{"schemaVersion":3,"state":{"name":"a"}}"#;
+		write(&path, newer).unwrap();
+		let state = PersistedState::<Named>::new(path.clone());
+		assert_eq!(state.load(), Named::default());
+		assert!(state.save(Named::default()).is_err());
+		assert_eq!(read_to_string(&path).unwrap(), newer);
+	}
+}
@@ -3,23 +3,76 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+pub mod activity;
+pub mod attestation;
+pub mod auto_forward;
+pub mod backend;
+pub mod backoff;
+pub mod bandwidth_budget;
+pub mod capabilities;
+pub mod client_auth;
 pub mod code_server;
+pub mod connectivity;
+pub mod container;
+pub mod declared_ports;
 pub mod dev_tunnels;
+pub mod error_budget;
+pub mod extension_filter;
+pub mod failover;
+pub mod guest;
+pub mod handoff;
+pub mod hooks;
 pub mod legal;
+pub mod local_forwarding;
+pub mod local_relay;
 pub mod paths;
+pub mod port_access;
+pub mod port_connection;
+pub mod port_metrics;
+pub mod port_owner;
+pub mod port_stats;
+pub mod rate_limit;
+pub mod registry;
+pub mod security_events;
+pub mod self_test;
+pub mod service_limits;
+pub mod setup;
+pub mod singleton;
+pub mod status;
+pub mod tunnel_service;
+pub mod usage;
+pub mod warm_server;
+pub mod web_ui;
+
+#[cfg(feature = "tunnel-api")]
+pub mod api;
+#[cfg(any(test, feature = "tunnel-emulator"))]
+pub mod cassette;
+#[cfg(any(test, feature = "tunnel-emulator"))]
+pub mod emulator;
+#[cfg(feature = "tunnel-api")]
+mod port_streams;
 
 mod control_server;
 mod name_generator;
 mod port_forwarder;
 mod protocol;
+mod rpc_compression;
 #[cfg_attr(unix, path = "tunnels/server_bridge_unix.rs")]
 #[cfg_attr(windows, path = "tunnels/server_bridge_windows.rs")]
 mod server_bridge;
 mod service;
+#[cfg(target_os = "linux")]
+mod service_linux;
+#[cfg(target_os = "macos")]
+mod service_macos;
 #[cfg(target_os = "windows")]
 mod service_windows;
+#[cfg(test)]
+mod testing;
 
-pub use control_server::serve;
+pub use control_server::{serve, UpdateOptions};
+pub use port_forwarder::{PortForwarding, PortForwardingProcessor};
 pub use service::{
 	create_service_manager, ServiceContainer, ServiceManager, SERVICE_LOG_FILE_NAME,
 };
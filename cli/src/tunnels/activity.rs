@@ -0,0 +1,180 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Tracks client connections to a tunnel's ports, including the control port
+//! editors connect to, so hosting can be stopped, or put to sleep until a
+//! client tries to connect, once nobody's used the tunnel for a while, rather
+//! than left running on a machine indefinitely.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+use super::port_connection::{PortConnection, PortReadHalf, PortWriteHalf};
+
+struct Activity {
+	/// Number of client connections currently open.
+	open: usize,
+	/// When the last connection closed, or when tracking started.
+	last_active: Instant,
+}
+
+/// Tracks when clients last used a tunnel. Clones share the same state.
+#[derive(Clone)]
+pub struct ActivityTracker(Arc<Mutex<Activity>>);
+
+impl Default for ActivityTracker {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl ActivityTracker {
+	pub fn new() -> Self {
+		Self(Arc::new(Mutex::new(Activity {
+			open: 0,
+			last_active: Instant::now(),
+		})))
+	}
+
+	/// Gets how long the tunnel has had no connections open, or None while
+	/// any are.
+	pub fn idle_for(&self) -> Option<Duration> {
+		let a = self.0.lock().unwrap();
+		match a.open {
+			0 => Some(a.last_active.elapsed()),
+			_ => None,
+		}
+	}
+
+	/// Waits until the tunnel's had no connections open for the timeout.
+	pub async fn wait_until_idle(&self, timeout: Duration) {
+		loop {
+			let wait = match self.idle_for() {
+				Some(idle) if idle >= timeout => return,
+				Some(idle) => timeout - idle,
+				// connections are checked on again after a full timeout, as
+				// the idle period only starts once they close
+				None => timeout,
+			};
+			tokio::time::sleep(wait).await;
+		}
+	}
+
+	/// Wraps the connections to a port, so each counts as activity while
+	/// it's open.
+	pub fn track(
+		&self,
+		mut connections: mpsc::UnboundedReceiver<PortConnection>,
+	) -> mpsc::UnboundedReceiver<PortConnection> {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let tracker = self.clone();
+		tokio::spawn(async move {
+			while let Some(conn) = connections.recv().await {
+				let tracked = TrackedConnection::new(tracker.clone(), conn);
+				if tx.send(PortConnection::Local(Box::new(tracked))).is_err() {
+					return;
+				}
+			}
+		});
+		rx
+	}
+
+	/// Counts as activity now, such as when a client wakes the tunnel.
+	pub fn touch(&self) {
+		self.0.lock().unwrap().last_active = Instant::now();
+	}
+
+	fn opened(&self) {
+		self.0.lock().unwrap().open += 1;
+	}
+
+	fn closed(&self) {
+		let mut a = self.0.lock().unwrap();
+		a.open -= 1;
+		a.last_active = Instant::now();
+	}
+}
+
+/// A connection that's counted as open until it's dropped.
+struct TrackedConnection {
+	tracker: ActivityTracker,
+	read: PortReadHalf,
+	write: PortWriteHalf,
+}
+
+impl TrackedConnection {
+	fn new(tracker: ActivityTracker, conn: PortConnection) -> Self {
+		tracker.opened();
+		let (write, read) = conn.into_split();
+		TrackedConnection {
+			tracker,
+			read,
+			write,
+		}
+	}
+}
+
+impl Drop for TrackedConnection {
+	fn drop(&mut self) {
+		self.tracker.closed();
+	}
+}
+
+impl AsyncRead for TrackedConnection {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().read).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for TrackedConnection {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.get_mut().write).poll_write(cx, buf)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use tokio::io::duplex;
+
+	#[tokio::test]
+	async fn test_tracks_open_connections() {
+		let tracker = ActivityTracker::new();
+		assert!(tracker.idle_for().is_some());
+
+		let (tx, rx) = mpsc::unbounded_channel();
+		let mut tracked = tracker.track(rx);
+		let (stream, _other) = duplex(64);
+		tx.send(PortConnection::Local(Box::new(stream))).unwrap();
+
+		let conn = tracked.recv().await.unwrap();
+		assert_eq!(tracker.idle_for(), None);
+		drop(conn);
+		assert!(tracker.idle_for().unwrap() < Duration::from_secs(1));
+	}
+}
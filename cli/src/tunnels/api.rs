@@ -0,0 +1,205 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Library API for hosting tunnels from other Rust programs without going
+//! through the CLI binary. Unlike `DevTunnels::new`, hosts created here
+//! don't read the environment, the keyring, or the CLI's data directory;
+//! authorization and state are supplied by the embedder. They never prompt,
+//! so choices the CLI asks the user about are made through the builder, and
+//! the tunnel's name is given when starting it.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tunnels::management::new_tunnel_management;
+
+use crate::constants::TUNNEL_SERVICE_USER_AGENT;
+use crate::log;
+use crate::options::TelemetryLevel;
+use crate::state::LauncherPaths;
+use crate::util::errors::{wrap, AnyError, DevTunnelError};
+
+use super::legal;
+use super::setup::TunnelSetup;
+
+pub use super::backend::TunnelBackend;
+pub use super::dev_tunnels::{
+	AccessTokenProvider, ActiveTunnel, DevTunnels, ExistingTunnel, PersistedTunnel,
+	StaticAccessTokenProvider,
+};
+pub use super::local_forwarding::ForwardTargets;
+pub use super::port_connection::{LocalStream, PortConnection};
+pub use super::port_forwarder::{PortForwarding, PortForwardingProcessor, PortForwardingRec};
+pub use super::port_streams::{serve_http, PortIncoming, PortStream};
+pub use super::tunnel_service::{
+	ManagementClient, RelayConnection, RelayHost, RequestMetadata, ServiceManagementClient,
+	SharedManagementClient,
+};
+pub use tunnels::connections::ForwardedPortConnection;
+pub use tunnels::management::{Authorization, AuthorizationProvider};
+
+/// Builds a `DevTunnels` host for embedding.
+pub struct TunnelHostBuilder {
+	log: log::Logger,
+	state_dir: PathBuf,
+	client: Option<SharedManagementClient>,
+	reserved_ports: Vec<u16>,
+	forward_buffer_size: Option<usize>,
+	forward_targets: Option<ForwardTargets>,
+	accept_license_terms: bool,
+	telemetry_level: Option<TelemetryLevel>,
+}
+
+impl TunnelHostBuilder {
+	/// Creates a builder. The host persists the tunnel it creates in
+	/// `state_dir`, so that later hosts using the same directory reuse it.
+	pub fn new(log: log::Logger, state_dir: impl Into<PathBuf>) -> Self {
+		Self {
+			log,
+			state_dir: state_dir.into(),
+			client: None,
+			reserved_ports: vec![],
+			forward_buffer_size: None,
+			forward_targets: None,
+			accept_license_terms: false,
+			telemetry_level: None,
+		}
+	}
+
+	/// Authorizes requests to the tunnel service with the given provider.
+	pub fn authorization_provider(
+		mut self,
+		provider: impl AuthorizationProvider + 'static,
+	) -> Self {
+		let mut builder = new_tunnel_management(&TUNNEL_SERVICE_USER_AGENT);
+		builder.authorization_provider(provider);
+		self.client = Some(Arc::new(ServiceManagementClient::from(builder)));
+		self
+	}
+
+	/// Uses the given client for the tunnel service, instead of one created
+	/// from an `authorization_provider`.
+	pub fn management_client(mut self, client: SharedManagementClient) -> Self {
+		self.client = Some(client);
+		self
+	}
+
+	/// Keeps the given ports, such as ones forwarded by the embedder's own
+	/// services, when the host starts a tunnel.
+	pub fn reserved_ports(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+		self.reserved_ports.extend(ports);
+		self
+	}
+
+	/// Sets the number of bytes buffered in each direction of each connection
+	/// to a forwarded port.
+	pub fn forward_buffer_size(mut self, size: usize) -> Self {
+		self.forward_buffer_size = Some(size);
+		self
+	}
+
+	/// Sets the hosts that connections to forwarded ports go to, instead of
+	/// localhost.
+	pub fn forward_targets(mut self, targets: ForwardTargets) -> Self {
+		self.forward_targets = Some(targets);
+		self
+	}
+
+	/// Records that the user accepted the server's license terms. Builds that
+	/// require consent fail unless it's given here, or was given before with
+	/// the same state directory.
+	pub fn accept_license_terms(mut self, accepted: bool) -> Self {
+		self.accept_license_terms = accepted;
+		self
+	}
+
+	/// Sets the telemetry level the user chose for servers hosted with the
+	/// state directory, which is saved there like `code tunnel setup` does.
+	pub fn telemetry_level(mut self, level: TelemetryLevel) -> Self {
+		self.telemetry_level = Some(level);
+		self
+	}
+
+	pub fn build(self) -> Result<DevTunnels, AnyError> {
+		let client = self.client.ok_or_else(|| {
+			DevTunnelError("an authorization provider or management client is required".to_string())
+		})?;
+
+		std::fs::create_dir_all(&self.state_dir)
+			.map_err(|e| wrap(e, "error creating tunnel state directory"))?;
+
+		let paths = LauncherPaths::new_without_replacements(self.state_dir);
+		legal::check_consent(&paths, self.accept_license_terms)?;
+		if let Some(level) = self.telemetry_level {
+			let mut setup = TunnelSetup::load(&paths);
+			setup.telemetry_level = Some(level);
+			setup.save(&paths)?;
+		}
+
+		let mut dt = DevTunnels::new_with_client(&self.log, &paths, client);
+		dt.set_interactive(false);
+		dt.add_reserved_ports(self.reserved_ports);
+		if let Some(size) = self.forward_buffer_size {
+			dt.set_forward_buffer_size(size);
+		}
+		if let Some(targets) = self.forward_targets {
+			dt.set_forward_targets(targets);
+		}
+		Ok(dt)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tunnels::emulator::EmulatedTunnelService;
+
+	#[tokio::test]
+	async fn test_builds_host_with_own_state() {
+		let dir = tempfile::tempdir().unwrap();
+		let state_dir = dir.path().join("state");
+		let built = TunnelHostBuilder::new(log::Logger::test(), &state_dir).build();
+		assert!(matches!(built, Err(AnyError::DevTunnelError(_))));
+
+		let service = EmulatedTunnelService::default();
+		let mut dt = TunnelHostBuilder::new(log::Logger::test(), &state_dir)
+			.management_client(Arc::new(service.clone()))
+			.build()
+			.unwrap();
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("embedded".to_string()), false)
+			.await
+			.unwrap();
+		assert_eq!(active.name, "embedded");
+		assert_eq!(service.tunnels().len(), 1);
+		assert!(state_dir.join("code_tunnel.json").exists());
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_host_never_prompts() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut dt = TunnelHostBuilder::new(log::Logger::test(), dir.path())
+			.management_client(Arc::new(service))
+			.telemetry_level(TelemetryLevel::Off)
+			.build()
+			.unwrap();
+
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		assert_eq!(
+			TunnelSetup::load(&paths).telemetry_level,
+			Some(TelemetryLevel::Off)
+		);
+
+		// without a name, one would be prompted for
+		let started = dt.start_new_launcher_tunnel(None, false).await;
+		assert!(matches!(started, Err(AnyError::TunnelNameRequired(_))));
+
+		let mut active = dt.start_new_launcher_tunnel(None, true).await.unwrap();
+		assert!(!active.name.is_empty());
+		active.close().await.unwrap();
+	}
+}
@@ -0,0 +1,429 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Attestation of the machine hosting a tunnel, published as tags on the
+//! launcher tunnel. The host signs a fingerprint of the machine's ID along
+//! with its OS, architecture, and the tunnel's ID with an SSH key, which can
+//! be held in a TPM or security key through `ssh-agent`, and clients check
+//! the signature against the keys of the machines they trust before
+//! connecting. Since the tunnel's ID is signed, the tags can't be copied onto
+//! another tunnel.
+//!
+//! The tags only show which machine registered the tunnel. Connected clients
+//! can also ask the host to sign a nonce of their choosing with `attest`,
+//! which proves the machine is the one hosting the tunnel right now.
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use serde::Serialize;
+use ssh_key::{HashAlg, PublicKey, SshSig};
+
+use crate::util::command::capture_command;
+use crate::util::errors::{HostAttestationFailed, HostNotAttested, InvalidAuthorizedKeys};
+
+use super::client_auth::parse_authorized_keys;
+
+/// Namespace the attestation is signed in, so that signatures made for other
+/// purposes can't be passed off as an attestation.
+pub const ATTESTATION_NAMESPACE: &str = "vscode-tunnel-host";
+
+/// Prefix of the tags holding the attestation.
+const ATTESTATION_TAG_PREFIX: &str = "vscode-attest-";
+
+/// Length of each piece of the signature, which is split across tags to fit
+/// the service's 50 character limit on them.
+const SIGNATURE_CHUNK_LENGTH: usize = 32;
+
+/// Bytes of the machine ID's hash kept as its fingerprint.
+const FINGERPRINT_LENGTH: usize = 16;
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostAttestation {
+	/// Hash of the machine's ID, identifying it without revealing the ID.
+	pub machine: String,
+	pub os: String,
+	pub arch: String,
+	/// ID of the tunnel that's attested. It isn't in the tags, being the ID
+	/// of the tunnel that has them.
+	pub tunnel: String,
+	/// The `SSHSIG` signature of the other fields, base64url-encoded.
+	#[serde(skip)]
+	pub signature: String,
+}
+
+impl HostAttestation {
+	/// Attests that this machine hosts the tunnel by signing with the key,
+	/// which is either a private key file or the public key of a key held by
+	/// `ssh-agent`.
+	pub async fn create(key: &Path, tunnel_id: &str) -> Result<Self, HostAttestationFailed> {
+		let mut attestation = HostAttestation::unsigned(tunnel_id)?;
+		let pem = sign_with_ssh_keygen(key, &attestation.statement()).await?;
+		let signature = pem_to_signature(&pem).ok_or_else(|| {
+			HostAttestationFailed("ssh-keygen made an invalid signature".to_string())
+		})?;
+		attestation.signature = signature;
+		Ok(attestation)
+	}
+
+	/// Answers a client's challenge by signing the attestation along with the
+	/// nonce the client chose, returning the armored `SSHSIG` signature.
+	pub async fn answer_challenge(
+		key: &Path,
+		tunnel_id: &str,
+		nonce: &str,
+	) -> Result<(Self, String), HostAttestationFailed> {
+		let attestation = HostAttestation::unsigned(tunnel_id)?;
+		let pem = sign_with_ssh_keygen(key, &attestation.challenge_statement(nonce)).await?;
+		Ok((attestation, pem))
+	}
+
+	fn unsigned(tunnel_id: &str) -> Result<Self, HostAttestationFailed> {
+		Ok(HostAttestation {
+			machine: machine_fingerprint().ok_or_else(|| {
+				HostAttestationFailed("could not read the machine's ID".to_string())
+			})?,
+			os: std::env::consts::OS.to_string(),
+			arch: std::env::consts::ARCH.to_string(),
+			tunnel: tunnel_id.to_string(),
+			signature: String::new(),
+		})
+	}
+
+	/// Gets the text that's signed.
+	fn statement(&self) -> String {
+		format!(
+			"machine={}\nos={}\narch={}\ntunnel={}\n",
+			self.machine, self.os, self.arch, self.tunnel
+		)
+	}
+
+	/// Gets the text that's signed to answer a challenge, which is never the
+	/// same as the statement, so neither can be passed off as the other.
+	fn challenge_statement(&self, nonce: &str) -> String {
+		format!("{}nonce={}\n", self.statement(), nonce)
+	}
+
+	/// Gets the tags the attestation is published as.
+	pub fn to_tags(&self) -> Vec<String> {
+		let mut tags = vec![
+			format!("{}m-{}", ATTESTATION_TAG_PREFIX, self.machine),
+			format!("{}os-{}", ATTESTATION_TAG_PREFIX, self.os),
+			format!("{}arch-{}", ATTESTATION_TAG_PREFIX, self.arch),
+		];
+		let chunks = self.signature.as_bytes().chunks(SIGNATURE_CHUNK_LENGTH);
+		for (i, chunk) in chunks.enumerate() {
+			tags.push(format!(
+				"{}s{:02}-{}",
+				ATTESTATION_TAG_PREFIX,
+				i,
+				String::from_utf8_lossy(chunk)
+			));
+		}
+		tags
+	}
+
+	/// Reads the attestation published in the tags of the tunnel with the ID,
+	/// if it has one.
+	pub fn from_tags(tunnel_id: &str, tags: &[String]) -> Option<Self> {
+		let mut attestation = HostAttestation {
+			tunnel: tunnel_id.to_string(),
+			..Default::default()
+		};
+		let mut chunks = vec![];
+		for tag in tags {
+			let value = match tag.strip_prefix(ATTESTATION_TAG_PREFIX) {
+				Some(v) => v,
+				None => continue,
+			};
+
+			if let Some(machine) = value.strip_prefix("m-") {
+				attestation.machine = machine.to_string();
+			} else if let Some(os) = value.strip_prefix("os-") {
+				attestation.os = os.to_string();
+			} else if let Some(arch) = value.strip_prefix("arch-") {
+				attestation.arch = arch.to_string();
+			} else if let Some((index, chunk)) =
+				value.strip_prefix('s').and_then(|s| s.split_once('-'))
+			{
+				if let Ok(index) = index.parse::<usize>() {
+					chunks.push((index, chunk));
+				}
+			}
+		}
+
+		if attestation.machine.is_empty() || chunks.is_empty() {
+			return None;
+		}
+
+		// tags aren't kept in order by the service
+		chunks.sort_by_key(|(i, _)| *i);
+		attestation.signature = chunks.into_iter().map(|(_, c)| c).collect();
+		Some(attestation)
+	}
+
+	/// Checks that the attestation was signed by one of the keys, returning
+	/// the fingerprint of the key.
+	pub fn verify(&self, keys: &[PublicKey]) -> Result<String, HostNotAttested> {
+		let signature = signature_to_pem(&self.signature)
+			.and_then(|pem| SshSig::from_pem(pem).ok())
+			.ok_or_else(|| HostNotAttested("the signature is invalid".to_string()))?;
+		verify_statement(keys, &self.statement(), &signature)
+	}
+
+	/// Checks the host's answer to a challenge with the nonce, returning the
+	/// fingerprint of the key it was signed with.
+	pub fn verify_challenge(
+		&self,
+		keys: &[PublicKey],
+		nonce: &str,
+		pem: &str,
+	) -> Result<String, HostNotAttested> {
+		let signature = SshSig::from_pem(pem)
+			.map_err(|_| HostNotAttested("the signature is invalid".to_string()))?;
+		verify_statement(keys, &self.challenge_statement(nonce), &signature)
+	}
+}
+
+fn verify_statement(
+	keys: &[PublicKey],
+	statement: &str,
+	signature: &SshSig,
+) -> Result<String, HostNotAttested> {
+	keys.iter()
+		.find(|k| {
+			k.verify(ATTESTATION_NAMESPACE, statement.as_bytes(), signature)
+				.is_ok()
+		})
+		.map(|k| k.fingerprint(HashAlg::Sha256).to_string())
+		.ok_or_else(|| HostNotAttested("the host was not attested by a trusted key".to_string()))
+}
+
+/// Reads the keys of trusted hosts from a file in the `authorized_keys` format.
+pub fn read_trusted_keys(path: &Path) -> Result<Vec<PublicKey>, InvalidAuthorizedKeys> {
+	let contents = std::fs::read_to_string(path)
+		.map_err(|e| InvalidAuthorizedKeys(format!("error reading {}: {}", path.display(), e)))?;
+	parse_authorized_keys(&contents)
+		.map_err(|e| InvalidAuthorizedKeys(format!("{} in {}", e, path.display())))
+}
+
+/// Gets a fingerprint of this machine's ID, which stays the same across
+/// reboots and reinstalls of the CLI.
+pub fn machine_fingerprint() -> Option<String> {
+	let id = read_machine_id()?;
+	let hash = HashAlg::Sha256.digest(id.trim().as_bytes());
+	Some(
+		hash[..FINGERPRINT_LENGTH]
+			.iter()
+			.map(|b| format!("{:02x}", b))
+			.collect(),
+	)
+}
+
+#[cfg(target_os = "linux")]
+fn read_machine_id() -> Option<String> {
+	["/etc/machine-id", "/var/lib/dbus/machine-id"]
+		.iter()
+		.filter_map(|p| std::fs::read_to_string(p).ok())
+		.find(|id| !id.trim().is_empty())
+}
+
+#[cfg(target_os = "macos")]
+fn read_machine_id() -> Option<String> {
+	let output = std::process::Command::new("ioreg")
+		.args(["-rd1", "-c", "IOPlatformExpertDevice"])
+		.output()
+		.ok()?;
+	String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.find(|l| l.contains("IOPlatformUUID"))
+		.and_then(|l| l.split('"').nth(3))
+		.map(|id| id.to_string())
+}
+
+#[cfg(windows)]
+fn read_machine_id() -> Option<String> {
+	use winreg::enums::HKEY_LOCAL_MACHINE;
+	use winreg::RegKey;
+
+	RegKey::predef(HKEY_LOCAL_MACHINE)
+		.open_subkey("SOFTWARE\\Microsoft\\Cryptography")
+		.and_then(|k| k.get_value("MachineGuid"))
+		.ok()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn read_machine_id() -> Option<String> {
+	None
+}
+
+/// Signs the statement with `ssh-keygen`, which takes care of keys that are
+/// held by `ssh-agent` rather than in a file.
+async fn sign_with_ssh_keygen(
+	key: &Path,
+	statement: &str,
+) -> Result<String, HostAttestationFailed> {
+	let dir = tempfile::tempdir().map_err(|e| HostAttestationFailed(e.to_string()))?;
+	let file = dir.path().join("host-attestation");
+	std::fs::write(&file, statement).map_err(|e| HostAttestationFailed(e.to_string()))?;
+
+	let output = capture_command(
+		"ssh-keygen",
+		[
+			OsStr::new("-Y"),
+			OsStr::new("sign"),
+			OsStr::new("-n"),
+			OsStr::new(ATTESTATION_NAMESPACE),
+			OsStr::new("-f"),
+			key.as_os_str(),
+			file.as_os_str(),
+		],
+	)
+	.await
+	.map_err(|e| HostAttestationFailed(e.to_string()))?;
+
+	if !output.status.success() {
+		return Err(HostAttestationFailed(format!(
+			"ssh-keygen failed to sign with {}: {}",
+			key.display(),
+			String::from_utf8_lossy(&output.stderr).trim()
+		)));
+	}
+
+	std::fs::read_to_string(dir.path().join("host-attestation.sig"))
+		.map_err(|e| HostAttestationFailed(format!("error reading the signature: {}", e)))
+}
+
+/// Converts a PEM-armored signature to the base64url form used in tags.
+fn pem_to_signature(pem: &str) -> Option<String> {
+	let body: String = pem
+		.lines()
+		.filter(|l| !l.starts_with("-----"))
+		.map(|l| l.trim())
+		.collect();
+	let bytes = base64::decode(body).ok()?;
+	Some(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+/// Converts a signature from tags back to the PEM form `ssh-keygen` makes.
+fn signature_to_pem(signature: &str) -> Option<String> {
+	let bytes = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+	let body = base64::encode(bytes);
+	let mut pem = String::from("-----BEGIN SSH SIGNATURE-----\n");
+	for line in body.as_bytes().chunks(70) {
+		pem.push_str(&String::from_utf8_lossy(line));
+		pem.push('\n');
+	}
+	pem.push_str("-----END SSH SIGNATURE-----\n");
+	Some(pem)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use ssh_key::private::{Ed25519Keypair, PrivateKey};
+	use ssh_key::LineEnding;
+
+	fn key(seed: u8) -> PrivateKey {
+		PrivateKey::from(Ed25519Keypair::from_seed(&[seed; 32]))
+	}
+
+	fn attest(key: &PrivateKey, namespace: &str) -> HostAttestation {
+		let mut attestation = HostAttestation {
+			machine: "0123456789abcdef0123456789abcdef".to_string(),
+			os: "linux".to_string(),
+			arch: "x86_64".to_string(),
+			tunnel: "tunnel-id".to_string(),
+			signature: String::new(),
+		};
+		let pem = key
+			.sign(
+				namespace,
+				HashAlg::Sha512,
+				attestation.statement().as_bytes(),
+			)
+			.unwrap()
+			.to_pem(LineEnding::LF)
+			.unwrap();
+		attestation.signature = pem_to_signature(&pem).unwrap();
+		attestation
+	}
+
+	#[test]
+	fn test_round_trips_through_tags() {
+		let attestation = attest(&key(1), ATTESTATION_NAMESPACE);
+		let mut tags = vec!["my-machine".to_string(), "vscode-host-os-linux".to_string()];
+		tags.extend(attestation.to_tags());
+		assert!(tags.iter().all(|t| t.len() <= 50));
+
+		tags.reverse();
+		assert_eq!(
+			HostAttestation::from_tags("tunnel-id", &tags),
+			Some(attestation)
+		);
+		assert_eq!(
+			HostAttestation::from_tags("tunnel-id", &["my-machine".to_string()]),
+			None
+		);
+	}
+
+	#[test]
+	fn test_verifies_attestation() {
+		let trusted = key(1);
+		let keys = vec![trusted.public_key().clone(), key(2).public_key().clone()];
+
+		let attestation = attest(&trusted, ATTESTATION_NAMESPACE);
+		assert_eq!(
+			attestation.verify(&keys).unwrap(),
+			trusted.fingerprint(HashAlg::Sha256).to_string()
+		);
+
+		// another machine, namespace, or key is refused
+		let moved = HostAttestation {
+			machine: "fedcba9876543210fedcba9876543210".to_string(),
+			..attestation
+		};
+		assert!(moved.verify(&keys).is_err());
+		assert!(attest(&trusted, "file").verify(&keys).is_err());
+
+		// as are its tags copied onto another tunnel
+		let tags = attest(&trusted, ATTESTATION_NAMESPACE).to_tags();
+		let copied = HostAttestation::from_tags("other-tunnel", &tags).unwrap();
+		assert!(copied.verify(&keys).is_err());
+		assert!(attest(&key(3), ATTESTATION_NAMESPACE)
+			.verify(&keys)
+			.is_err());
+	}
+
+	#[test]
+	fn test_verifies_challenge() {
+		let trusted = key(1);
+		let keys = vec![trusted.public_key().clone()];
+		let attestation = attest(&trusted, ATTESTATION_NAMESPACE);
+
+		let answer = trusted
+			.sign(
+				ATTESTATION_NAMESPACE,
+				HashAlg::Sha512,
+				attestation.challenge_statement("nonce-1").as_bytes(),
+			)
+			.unwrap()
+			.to_pem(LineEnding::LF)
+			.unwrap();
+		assert!(attestation
+			.verify_challenge(&keys, "nonce-1", &answer)
+			.is_ok());
+
+		// a replayed answer, or the published attestation, is refused
+		assert!(attestation
+			.verify_challenge(&keys, "nonce-2", &answer)
+			.is_err());
+		let published = signature_to_pem(&attestation.signature).unwrap();
+		assert!(attestation
+			.verify_challenge(&keys, "nonce-1", &published)
+			.is_err());
+	}
+}
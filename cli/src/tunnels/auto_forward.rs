@@ -0,0 +1,244 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Forwards ports as processes started by the VS Code Server begin listening
+//! on them, such as a dev server run in the terminal, like the desktop does
+//! for remote windows. Ports are forwarded through the same `PortForwarding`
+//! handle as clients use, and stop being forwarded once they're closed.
+
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use crate::log;
+use crate::state::LauncherPaths;
+use crate::util::errors::InvalidPortRange;
+use crate::util::machine::descendant_pids;
+
+use super::error_budget::{ErrorBudget, Subsystem};
+use super::paths::get_all_servers;
+use super::port_forwarder::PortForwarding;
+use super::port_owner::list_listeners;
+
+/// How often to look for ports that were opened or closed.
+const SCAN_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Ports from `start` to `end`, inclusive, given as `<port>` or
+/// `<start>-<end>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+	start: u16,
+	end: u16,
+}
+
+impl PortRange {
+	fn contains(&self, port: u16) -> bool {
+		(self.start..=self.end).contains(&port)
+	}
+}
+
+impl FromStr for PortRange {
+	type Err = InvalidPortRange;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let parse = |p: &str| p.trim().parse::<u16>().ok().filter(|p| *p > 0);
+		let range = match s.split_once('-') {
+			Some((start, end)) => parse(start).zip(parse(end)),
+			None => parse(s).map(|p| (p, p)),
+		};
+
+		match range {
+			Some((start, end)) if start <= end => Ok(PortRange { start, end }),
+			_ => Err(InvalidPortRange(s.to_string())),
+		}
+	}
+}
+
+/// Which of the detected ports are forwarded. Excluded ports never are; if
+/// any ports are included, only those are.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AutoForwardFilter {
+	include: Vec<PortRange>,
+	exclude: Vec<PortRange>,
+}
+
+impl AutoForwardFilter {
+	pub fn parse(include: &[String], exclude: &[String]) -> Result<Self, InvalidPortRange> {
+		let parse_all = |ranges: &[String]| {
+			ranges
+				.iter()
+				.map(|r| r.parse())
+				.collect::<Result<Vec<PortRange>, _>>()
+		};
+
+		Ok(AutoForwardFilter {
+			include: parse_all(include)?,
+			exclude: parse_all(exclude)?,
+		})
+	}
+
+	pub fn allows(&self, port: u16) -> bool {
+		!self.exclude.iter().any(|r| r.contains(port))
+			&& (self.include.is_empty() || self.include.iter().any(|r| r.contains(port)))
+	}
+}
+
+/// Forwards ports automatically until it's dropped.
+pub struct AutoForwarder {
+	_stop_tx: oneshot::Sender<()>,
+}
+
+impl AutoForwarder {
+	/// Starts watching for ports that the running servers' child processes
+	/// listen on. Servers are found from their pidfiles, so the warm server and
+	/// those started for each client are all included.
+	pub fn start(
+		log: log::Logger,
+		launcher_paths: LauncherPaths,
+		forwarding: PortForwarding,
+		filter: AutoForwardFilter,
+	) -> AutoForwarder {
+		let (stop_tx, mut stop_rx) = oneshot::channel();
+		let log = log.prefixed("[auto forward]");
+		let budget = ErrorBudget::new(log.clone(), Subsystem::AutoForward);
+
+		tokio::spawn(async move {
+			let mut forwarded = BTreeSet::new();
+			let mut failed = BTreeSet::new();
+			let mut scan = tokio::time::interval(SCAN_INTERVAL);
+
+			loop {
+				tokio::select! {
+					_ = &mut stop_rx => break,
+					_ = scan.tick() => {},
+				}
+
+				let listening = find_server_ports(&launcher_paths, &filter).await;
+
+				for port in forwarded
+					.difference(&listening)
+					.copied()
+					.collect::<Vec<_>>()
+				{
+					forwarded.remove(&port);
+					let result = forwarding.unforward(port).await;
+					budget.record(&result);
+					match result {
+						Ok(()) => info!(log, "Port {} was closed, stopped forwarding it", port),
+						Err(e) => warning!(log, "Could not stop forwarding port {}: {}", port, e),
+					}
+				}
+
+				// ports that couldn't be forwarded are tried again once reopened
+				failed.retain(|p| listening.contains(p));
+				for port in listening.iter().copied() {
+					if forwarded.contains(&port) || failed.contains(&port) {
+						continue;
+					}
+
+					let result = forwarding.forward(port, None).await;
+					budget.record(&result);
+					match result {
+						Ok(uri) => {
+							info!(
+								log,
+								"Forwarded port {}, opened on the server, at {}", port, uri
+							);
+							forwarded.insert(port);
+						}
+						Err(e) => {
+							warning!(log, "Could not forward port {}: {}", port, e);
+							failed.insert(port);
+						}
+					}
+				}
+
+				if budget.is_disabled() {
+					break;
+				}
+			}
+		});
+
+		AutoForwarder { _stop_tx: stop_tx }
+	}
+}
+
+/// Gets the ports the filter allows that are listened on by processes the
+/// running servers started.
+async fn find_server_ports(
+	launcher_paths: &LauncherPaths,
+	filter: &AutoForwardFilter,
+) -> BTreeSet<u16> {
+	let servers: Vec<u32> = get_all_servers(launcher_paths)
+		.into_iter()
+		.filter_map(|s| s.server_paths(launcher_paths).get_running_pid())
+		.collect();
+	if servers.is_empty() {
+		return BTreeSet::new();
+	}
+
+	let children = match tokio::task::spawn_blocking(move || descendant_pids(&servers)).await {
+		Ok(pids) => pids,
+		Err(_) => return BTreeSet::new(),
+	};
+
+	list_listeners()
+		.await
+		.into_iter()
+		.filter(|(port, owner)| {
+			owner.pid.is_some_and(|pid| children.contains(&pid)) && filter.allows(*port)
+		})
+		.map(|(port, _)| port)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_port_ranges() {
+		assert_eq!(
+			"3000".parse::<PortRange>().unwrap(),
+			PortRange {
+				start: 3000,
+				end: 3000
+			}
+		);
+		assert_eq!(
+			"8000-8999".parse::<PortRange>().unwrap(),
+			PortRange {
+				start: 8000,
+				end: 8999
+			}
+		);
+
+		for invalid in ["", "0", "abc", "9000-8000", "1-70000", "3000-"] {
+			assert!(invalid.parse::<PortRange>().is_err(), "{}", invalid);
+		}
+	}
+
+	#[test]
+	fn test_filters_ports() {
+		let all = AutoForwardFilter::default();
+		assert!(all.allows(3000));
+
+		let filter = AutoForwardFilter::parse(
+			&["3000-3999".to_string(), "8080".to_string()],
+			&["3306".to_string()],
+		)
+		.unwrap();
+		assert!(filter.allows(3000));
+		assert!(filter.allows(8080));
+		assert!(!filter.allows(3306));
+		assert!(!filter.allows(5432));
+
+		let filter = AutoForwardFilter::parse(&[], &["5000-5999".to_string()]).unwrap();
+		assert!(filter.allows(3000));
+		assert!(!filter.allows(5432));
+	}
+}
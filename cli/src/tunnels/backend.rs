@@ -0,0 +1,36 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Where tunnels are hosted. Relays other than the dev tunnels service, such
+//! as a self-hosted relay or an SSH reverse tunnel, are added by implementing
+//! `TunnelBackend`, without changing the code specific to dev tunnels.
+//!
+//! A backend creates the tunnel and hosts it on a `RelayHost`, which forwards
+//! ports, and whose `RelayConnection` gives the endpoint clients connect to.
+//! The `ActiveTunnel` it returns is served the same way for every backend.
+
+use async_trait::async_trait;
+
+use crate::util::errors::AnyError;
+
+use super::dev_tunnels::ActiveTunnel;
+
+pub use super::tunnel_service::{RelayConnection, RelayHost};
+
+#[async_trait]
+pub trait TunnelBackend: Send {
+	/// Creates the tunnel to host, or finds the one created before, and
+	/// returns its name. A random name is used if `use_random_name` is set,
+	/// or if no name is given and none was chosen before.
+	async fn create(
+		&mut self,
+		preferred_name: Option<String>,
+		use_random_name: bool,
+	) -> Result<String, AnyError>;
+
+	/// Starts hosting the tunnel that was created, connecting it to its relay.
+	/// The tunnel is created with the defaults if `create` wasn't called.
+	async fn host(&mut self) -> Result<ActiveTunnel, AnyError>;
+}
@@ -0,0 +1,147 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::fmt;
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How the delay between attempts to reconnect the tunnel grows.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackoffStrategy {
+	/// The base delay times the number of failed attempts.
+	Linear,
+	/// The base delay, doubled after each failed attempt.
+	Exponential,
+	/// A random delay up to the exponential delay, so that many hosts losing
+	/// their connection at once don't all retry at the same time.
+	Jittered,
+}
+
+impl fmt::Display for BackoffStrategy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			BackoffStrategy::Linear => write!(f, "linear"),
+			BackoffStrategy::Exponential => write!(f, "exponential"),
+			BackoffStrategy::Jittered => write!(f, "jittered"),
+		}
+	}
+}
+
+/// Policy for reconnecting the tunnel after it loses its connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackoffConfig {
+	pub base: Duration,
+	pub max: Duration,
+	pub strategy: BackoffStrategy,
+	/// Number of failed attempts in a row after which the tunnel stops
+	/// reconnecting, or None to keep trying.
+	pub max_retries: Option<u32>,
+}
+
+impl Default for BackoffConfig {
+	fn default() -> Self {
+		Self {
+			base: Duration::from_secs(5),
+			max: Duration::from_secs(120),
+			strategy: BackoffStrategy::Linear,
+			max_retries: None,
+		}
+	}
+}
+
+pub struct Backoff {
+	pub failures: u32,
+	config: BackoffConfig,
+}
+
+impl Backoff {
+	pub fn new(config: BackoffConfig) -> Self {
+		Self {
+			failures: 0,
+			config,
+		}
+	}
+
+	/// Records a failed attempt, and gets how long to wait before the next
+	/// one, or None if the retries are used up.
+	pub fn next_delay(&mut self) -> Option<Duration> {
+		self.failures += 1;
+		if matches!(self.config.max_retries, Some(max) if self.failures > max) {
+			return None;
+		}
+
+		let BackoffConfig { base, max, .. } = self.config;
+		let exponential = || {
+			2u32.checked_pow(self.failures - 1)
+				.and_then(|f| base.checked_mul(f))
+				.unwrap_or(max)
+				.min(max)
+		};
+
+		Some(match self.config.strategy {
+			BackoffStrategy::Linear => base.checked_mul(self.failures).unwrap_or(max).min(max),
+			BackoffStrategy::Exponential => exponential(),
+			BackoffStrategy::Jittered => {
+				let ceiling = exponential().max(base);
+				rand::thread_rng().gen_range(base..=ceiling)
+			}
+		})
+	}
+
+	pub fn reset(&mut self) {
+		self.failures = 0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn delays(config: BackoffConfig, n: usize) -> Vec<Option<u64>> {
+		let mut backoff = Backoff::new(config);
+		(0..n)
+			.map(|_| backoff.next_delay().map(|d| d.as_secs()))
+			.collect()
+	}
+
+	#[test]
+	fn test_backoff_strategies() {
+		let config = BackoffConfig {
+			base: Duration::from_secs(2),
+			max: Duration::from_secs(10),
+			..Default::default()
+		};
+		assert_eq!(
+			delays(config, 6),
+			vec![Some(2), Some(4), Some(6), Some(8), Some(10), Some(10)]
+		);
+
+		let config = BackoffConfig {
+			strategy: BackoffStrategy::Exponential,
+			..config
+		};
+		assert_eq!(delays(config, 4), vec![Some(2), Some(4), Some(8), Some(10)]);
+
+		let config = BackoffConfig {
+			strategy: BackoffStrategy::Jittered,
+			..config
+		};
+		for d in delays(config, 10) {
+			assert!((2..=10).contains(&d.unwrap()));
+		}
+
+		let config = BackoffConfig {
+			max_retries: Some(2),
+			..config
+		};
+		let mut backoff = Backoff::new(config);
+		assert!(backoff.next_delay().is_some());
+		assert!(backoff.next_delay().is_some());
+		assert!(backoff.next_delay().is_none());
+		backoff.reset();
+		assert!(backoff.next_delay().is_some());
+	}
+}
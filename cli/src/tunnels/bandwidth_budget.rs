@@ -0,0 +1,397 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Limits on the data transferred to and from clients each day or month, for
+//! hosts that pay for egress. Every connection through the tunnel, to the
+//! control port or a forwarded one, is metered as data moves, and the bytes
+//! are counted toward the UTC day they're transferred on. Totals are saved in
+//! the data directory, so they carry across runs of the tunnel. While a
+//! budget is used up, the tunnel refuses new client connections.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::state::{LauncherPaths, PersistedState};
+use crate::util::errors::{InvalidTransferBudget, WrappedError};
+
+use super::port_connection::{PortConnection, PortReadHalf, PortWriteHalf};
+
+/// File in the data directory with the bytes transferred each day.
+const TRANSFERRED_FILE_NAME: &str = "transferred.json";
+
+/// Days kept in the file, enough to cover the current month.
+const KEEP_DAYS: i64 = 31;
+
+lazy_static! {
+	/// Bytes transferred by this process on each UTC day that aren't saved yet.
+	static ref UNSAVED: Mutex<BTreeMap<NaiveDate, u64>> = Mutex::new(BTreeMap::new());
+}
+
+/// Bytes transferred to and from clients on each UTC day.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Transferred {
+	days: BTreeMap<NaiveDate, u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetPeriod {
+	Daily,
+	Monthly,
+}
+
+impl fmt::Display for BudgetPeriod {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			BudgetPeriod::Daily => "daily",
+			BudgetPeriod::Monthly => "monthly",
+		})
+	}
+}
+
+/// Bytes that may be transferred to and from clients, in UTC days and months.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthBudget {
+	pub daily: Option<u64>,
+	pub monthly: Option<u64>,
+}
+
+/// A budget that was used up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BudgetExceeded {
+	pub period: BudgetPeriod,
+	pub used: u64,
+	pub limit: u64,
+}
+
+impl fmt::Display for BudgetExceeded {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"the {} data transfer budget of {} is used up, with {} transferred",
+			self.period,
+			format_size(self.limit),
+			format_size(self.used)
+		)
+	}
+}
+
+impl BandwidthBudget {
+	/// Parses the budgets given as sizes like `500MB` or `10GB`.
+	pub fn parse(
+		daily: Option<&str>,
+		monthly: Option<&str>,
+	) -> Result<Self, InvalidTransferBudget> {
+		Ok(BandwidthBudget {
+			daily: daily.map(parse_size).transpose()?,
+			monthly: monthly.map(parse_size).transpose()?,
+		})
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.daily.is_none() && self.monthly.is_none()
+	}
+
+	/// Checks the budget against the data transferred from this data
+	/// directory, including by connections that are still open. If the totals
+	/// can't be saved, the budget isn't enforced.
+	pub fn check(&self, paths: &LauncherPaths) -> Option<BudgetExceeded> {
+		if self.is_empty() {
+			return None;
+		}
+
+		let now = Utc::now();
+		let unsaved = std::mem::take(&mut *UNSAVED.lock().unwrap());
+		match save_transferred(paths, &unsaved, now.date_naive()) {
+			Ok(days) => self.exceeded(&days, now),
+			Err(_) => {
+				// keep the bytes for the next check
+				let mut pending = UNSAVED.lock().unwrap();
+				for (day, bytes) in unsaved {
+					*pending.entry(day).or_default() += bytes;
+				}
+				None
+			}
+		}
+	}
+
+	/// Gets the budget used up by the bytes transferred each day, if any.
+	fn exceeded(
+		&self,
+		days: &BTreeMap<NaiveDate, u64>,
+		now: DateTime<Utc>,
+	) -> Option<BudgetExceeded> {
+		let today = now.date_naive();
+		let periods = [
+			(
+				BudgetPeriod::Daily,
+				self.daily,
+				days.get(&today).copied().unwrap_or_default(),
+			),
+			(
+				BudgetPeriod::Monthly,
+				self.monthly,
+				days.iter()
+					.filter(|(d, _)| d.year() == today.year() && d.month() == today.month())
+					.map(|(_, bytes)| bytes)
+					.sum(),
+			),
+		];
+
+		periods.into_iter().find_map(|(period, limit, used)| {
+			let limit = limit?;
+			(used >= limit).then_some(BudgetExceeded {
+				period,
+				used,
+				limit,
+			})
+		})
+	}
+}
+
+/// Adds the unsaved bytes to the totals saved in the data directory, dropping
+/// days too old to count toward a budget, and returns the totals.
+fn save_transferred(
+	paths: &LauncherPaths,
+	unsaved: &BTreeMap<NaiveDate, u64>,
+	today: NaiveDate,
+) -> Result<BTreeMap<NaiveDate, u64>, WrappedError> {
+	PersistedState::<Transferred>::new(paths.root().join(TRANSFERRED_FILE_NAME)).update_with(
+		(unsaved, today),
+		|(unsaved, today), state| {
+			for (day, bytes) in unsaved {
+				*state.days.entry(*day).or_default() += bytes;
+			}
+			let oldest = today - chrono::Duration::days(KEEP_DAYS);
+			state.days.retain(|d, _| *d >= oldest);
+			state.days.clone()
+		},
+	)
+}
+
+/// Counts the bytes to and from clients, as they're transferred, toward the
+/// budget of every connection that's received.
+pub fn meter_transfer(
+	mut connections: mpsc::UnboundedReceiver<PortConnection>,
+) -> mpsc::UnboundedReceiver<PortConnection> {
+	let (tx, rx) = mpsc::unbounded_channel();
+	tokio::spawn(async move {
+		while let Some(conn) = connections.recv().await {
+			let (write, read) = conn.into_split();
+			let metered = TransferMeteredConnection { read, write };
+			if tx.send(PortConnection::Local(Box::new(metered))).is_err() {
+				return;
+			}
+		}
+	});
+	rx
+}
+
+fn record_transfer(bytes: usize) {
+	if bytes > 0 {
+		*UNSAVED
+			.lock()
+			.unwrap()
+			.entry(Utc::now().date_naive())
+			.or_default() += bytes as u64;
+	}
+}
+
+/// A connection whose bytes count toward the budget.
+struct TransferMeteredConnection {
+	read: PortReadHalf,
+	write: PortWriteHalf,
+}
+
+impl AsyncRead for TransferMeteredConnection {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let filled = buf.filled().len();
+		let r = Pin::new(&mut self.get_mut().read).poll_read(cx, buf);
+		record_transfer(buf.filled().len() - filled);
+		r
+	}
+}
+
+impl AsyncWrite for TransferMeteredConnection {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let r = Pin::new(&mut self.get_mut().write).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = r {
+			record_transfer(n);
+		}
+		r
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_shutdown(cx)
+	}
+}
+
+const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+/// Parses a size in bytes, with an optional unit. Units are powers of 1024,
+/// and may be given as `G`, `GB`, or `GiB`.
+fn parse_size(s: &str) -> Result<u64, InvalidTransferBudget> {
+	let s = s.trim();
+	let split = s
+		.find(|c: char| !c.is_ascii_digit() && c != '.')
+		.unwrap_or(s.len());
+	let (number, unit) = s.split_at(split);
+	let unit = unit.trim().to_ascii_uppercase();
+	let unit = unit.trim_end_matches("IB").trim_end_matches('B');
+	let exponent = match unit {
+		"" => 0,
+		"K" => 1,
+		"M" => 2,
+		"G" => 3,
+		"T" => 4,
+		_ => return Err(InvalidTransferBudget(s.to_string())),
+	};
+
+	match number.parse::<f64>() {
+		Ok(n) if n > 0.0 => Ok((n * 1024f64.powi(exponent)) as u64),
+		_ => Err(InvalidTransferBudget(s.to_string())),
+	}
+}
+
+/// Formats a number of bytes like `1.5 GB`.
+pub fn format_size(bytes: u64) -> String {
+	let mut size = bytes as f64;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+
+	if unit == 0 {
+		format!("{} B", bytes)
+	} else {
+		format!("{:.1} {}", size, UNITS[unit])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_size() {
+		assert_eq!(parse_size("512").ok(), Some(512));
+		assert_eq!(parse_size("10KB").ok(), Some(10 * 1024));
+		assert_eq!(parse_size("1.5 GiB").ok(), Some(3 * 512 * 1024 * 1024));
+		assert_eq!(parse_size("2t").ok(), Some(2 * 1024u64.pow(4)));
+		for invalid in ["", "GB", "0", "-1GB", "10 apples"] {
+			assert!(parse_size(invalid).is_err(), "{}", invalid);
+		}
+	}
+
+	#[test]
+	fn test_format_size() {
+		assert_eq!(format_size(512), "512 B");
+		assert_eq!(format_size(1536), "1.5 KB");
+		assert_eq!(format_size(10 * 1024u64.pow(3)), "10.0 GB");
+	}
+
+	fn day(s: &str) -> NaiveDate {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn test_exceeded() {
+		let now: DateTime<Utc> = "2024-05-20T12:00:00Z".parse().unwrap();
+		let sessions = BTreeMap::from([
+			// bytes of the previous month don't count
+			(day("2024-04-30"), 5000),
+			(day("2024-05-01"), 600),
+			(day("2024-05-20"), 200),
+		]);
+
+		let budget = BandwidthBudget {
+			daily: Some(300),
+			monthly: Some(1000),
+		};
+		assert_eq!(budget.exceeded(&sessions, now), None);
+
+		let budget = BandwidthBudget {
+			daily: Some(200),
+			monthly: None,
+		};
+		assert_eq!(
+			budget.exceeded(&sessions, now),
+			Some(BudgetExceeded {
+				period: BudgetPeriod::Daily,
+				used: 200,
+				limit: 200,
+			})
+		);
+
+		let budget = BandwidthBudget {
+			daily: None,
+			monthly: Some(800),
+		};
+		assert_eq!(
+			budget.exceeded(&sessions, now).map(|e| e.period),
+			Some(BudgetPeriod::Monthly)
+		);
+		assert_eq!(BandwidthBudget::default().exceeded(&sessions, now), None);
+	}
+
+	#[test]
+	fn test_saves_transferred_bytes() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let today = day("2024-05-20");
+
+		let unsaved = BTreeMap::from([(day("2024-04-01"), 1), (today, 100)]);
+		let days = save_transferred(&paths, &unsaved, today).unwrap();
+		assert_eq!(days, BTreeMap::from([(today, 100)]));
+
+		let unsaved = BTreeMap::from([(today, 50)]);
+		let days = save_transferred(&paths, &unsaved, today).unwrap();
+		assert_eq!(days, BTreeMap::from([(today, 150)]));
+	}
+
+	#[tokio::test]
+	async fn test_meters_open_connections() {
+		use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+		let (tx, rx) = mpsc::unbounded_channel();
+		let mut metered = meter_transfer(rx);
+		let (stream, mut client) = duplex(1024);
+		tx.send(PortConnection::Local(Box::new(stream))).unwrap();
+		let (mut write, mut read) = metered.recv().await.unwrap().into_split();
+
+		let before = UNSAVED.lock().unwrap().values().sum::<u64>();
+		client.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		read.read_exact(&mut buf).await.unwrap();
+		write.write_all(b"world!").await.unwrap();
+
+		// bytes count while the connection is still open, though other tests
+		// may add to them too
+		let after = UNSAVED.lock().unwrap().values().sum::<u64>();
+		assert!(after >= before + 11, "{} -> {}", before, after);
+	}
+}
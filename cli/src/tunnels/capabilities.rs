@@ -0,0 +1,137 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Capabilities of the host, such as its OS and whether it can run
+//! containers, published as tags on the launcher tunnel. Clients get them
+//! along with the tunnel, so they can tell before connecting whether the
+//! host is able to do what the user wants, like opening a dev container.
+
+use std::env;
+use std::path::Path;
+
+use serde::Serialize;
+
+/// Prefix of the tags holding the host's capabilities.
+const CAPABILITY_TAG_PREFIX: &str = "vscode-host-";
+
+/// Language runtimes looked for on the PATH.
+const RUNTIMES: [&str; 6] = ["node", "python3", "java", "go", "dotnet", "cargo"];
+
+/// Container engines looked for on the PATH.
+const CONTAINER_ENGINES: [&str; 2] = ["docker", "podman"];
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostCapabilities {
+	pub os: String,
+	pub arch: String,
+	/// Language runtimes available on the PATH.
+	pub runtimes: Vec<String>,
+	pub gpu: bool,
+	/// Whether a container engine is available.
+	pub containers: bool,
+}
+
+impl HostCapabilities {
+	/// Detects the capabilities of this machine.
+	pub fn detect() -> Self {
+		HostCapabilities {
+			os: env::consts::OS.to_string(),
+			arch: env::consts::ARCH.to_string(),
+			runtimes: RUNTIMES
+				.iter()
+				.filter(|r| is_on_path(r))
+				.map(|r| r.to_string())
+				.collect(),
+			gpu: has_gpu(),
+			containers: CONTAINER_ENGINES.iter().any(|e| is_on_path(e)),
+		}
+	}
+
+	/// Gets the tags the capabilities are published as.
+	pub fn to_tags(&self) -> Vec<String> {
+		let mut tags = vec![
+			format!("{}os-{}", CAPABILITY_TAG_PREFIX, self.os),
+			format!("{}arch-{}", CAPABILITY_TAG_PREFIX, self.arch),
+		];
+		for runtime in &self.runtimes {
+			tags.push(format!("{}runtime-{}", CAPABILITY_TAG_PREFIX, runtime));
+		}
+		if self.gpu {
+			tags.push(format!("{}gpu", CAPABILITY_TAG_PREFIX));
+		}
+		if self.containers {
+			tags.push(format!("{}containers", CAPABILITY_TAG_PREFIX));
+		}
+		tags
+	}
+
+	/// Reads the capabilities published in a tunnel's tags.
+	pub fn from_tags(tags: &[String]) -> Self {
+		let mut caps = HostCapabilities::default();
+		for tag in tags {
+			let cap = match tag.strip_prefix(CAPABILITY_TAG_PREFIX) {
+				Some(c) => c,
+				None => continue,
+			};
+
+			if let Some(os) = cap.strip_prefix("os-") {
+				caps.os = os.to_string();
+			} else if let Some(arch) = cap.strip_prefix("arch-") {
+				caps.arch = arch.to_string();
+			} else if let Some(runtime) = cap.strip_prefix("runtime-") {
+				caps.runtimes.push(runtime.to_string());
+			} else if cap == "gpu" {
+				caps.gpu = true;
+			} else if cap == "containers" {
+				caps.containers = true;
+			}
+		}
+		caps
+	}
+}
+
+fn is_on_path(name: &str) -> bool {
+	let path = match env::var_os("PATH") {
+		Some(p) => p,
+		None => return false,
+	};
+
+	env::split_paths(&path).any(|dir| {
+		dir.join(name).is_file() || (cfg!(windows) && dir.join(format!("{}.exe", name)).is_file())
+	})
+}
+
+fn has_gpu() -> bool {
+	// Macs all have a GPU usable through Metal; elsewhere, look for the
+	// NVIDIA or AMD compute drivers.
+	cfg!(target_os = "macos")
+		|| is_on_path("nvidia-smi")
+		|| Path::new("/dev/nvidia0").exists()
+		|| Path::new("/dev/kfd").exists()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_round_trips_through_tags() {
+		let caps = HostCapabilities {
+			os: "linux".to_string(),
+			arch: "x86_64".to_string(),
+			runtimes: vec!["node".to_string(), "python3".to_string()],
+			gpu: false,
+			containers: true,
+		};
+
+		let mut tags = vec![
+			"my-machine".to_string(),
+			"vscode-server-launcher".to_string(),
+		];
+		tags.extend(caps.to_tags());
+		assert!(tags.contains(&"vscode-host-runtime-python3".to_string()));
+		assert_eq!(HostCapabilities::from_tags(&tags), caps);
+	}
+}
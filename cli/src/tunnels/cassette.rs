@@ -0,0 +1,820 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Record and replay of tunnel management API requests. A `RecordingClient`
+//! wraps a real client and saves each request and response to a cassette,
+//! which a `ReplayClient` then plays back so that `DevTunnels` flows can be
+//! tested deterministically without access to the service.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tunnels::contracts::{Tunnel, TunnelPort, TunnelRelayTunnelEndpoint};
+use tunnels::management::{
+	HttpError, HttpResult, ResponseError, TunnelLocator, TunnelRequestOptions,
+};
+
+use crate::util::errors::{wrap, WrappedError};
+
+use super::port_connection::PortConnection;
+use super::service_limits::ServiceLimits;
+use super::tunnel_service::{ManagementClient, RelayConnection, RelayHost, SharedManagementClient};
+
+/// A management API request, with the details that affect its response.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "method", rename_all = "camelCase")]
+pub enum Request {
+	ListAllTunnels {
+		tags: Vec<String>,
+		require_all_tags: bool,
+	},
+	GetTunnel {
+		locator: String,
+	},
+	CreateTunnel {
+		tags: Vec<String>,
+	},
+	UpdateTunnel {
+		locator: String,
+		tags: Vec<String>,
+	},
+	DeleteTunnel {
+		locator: String,
+	},
+	DeleteTunnelPort {
+		locator: String,
+		port_number: u16,
+	},
+	DeleteTunnelEndpoints {
+		locator: String,
+		host_id: String,
+	},
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Response {
+	Tunnels(Vec<Tunnel>),
+	Tunnel(Box<Tunnel>),
+	Empty,
+	/// The service responded with an error status.
+	Error(u16),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Interaction {
+	pub request: Request,
+	pub response: Response,
+}
+
+/// Client that records requests made through another client.
+#[derive(Clone)]
+pub struct RecordingClient {
+	inner: SharedManagementClient,
+	interactions: Arc<Mutex<Vec<Interaction>>>,
+}
+
+impl RecordingClient {
+	pub fn new(inner: SharedManagementClient) -> Self {
+		Self {
+			inner,
+			interactions: Arc::new(Mutex::new(vec![])),
+		}
+	}
+
+	/// Gets the interactions recorded so far.
+	pub fn interactions(&self) -> Vec<Interaction> {
+		self.interactions.lock().unwrap().clone()
+	}
+
+	/// Saves the recorded interactions to a cassette file.
+	pub fn save(&self, path: &Path) -> Result<(), WrappedError> {
+		let contents = serde_json::to_string_pretty(&self.interactions())
+			.map_err(|e| wrap(e, "error serializing cassette"))?;
+		std::fs::write(path, contents)
+			.map_err(|e| wrap(e, format!("error writing cassette {}", path.display())))
+	}
+
+	fn record<T>(
+		&self,
+		request: Request,
+		result: &HttpResult<T>,
+		to_response: impl FnOnce(&T) -> Response,
+	) {
+		let response = match result {
+			Ok(r) => to_response(r),
+			Err(HttpError::ResponseError(e)) => Response::Error(e.status_code.as_u16()),
+			// connection and authorization failures aren't service behavior
+			Err(_) => return,
+		};
+
+		self.interactions
+			.lock()
+			.unwrap()
+			.push(Interaction { request, response });
+	}
+}
+
+#[async_trait]
+impl ManagementClient for RecordingClient {
+	async fn list_all_tunnels(&self, options: &TunnelRequestOptions) -> HttpResult<Vec<Tunnel>> {
+		let result = self.inner.list_all_tunnels(options).await;
+		self.record(list_request(options), &result, |t| {
+			Response::Tunnels(t.clone())
+		});
+		result
+	}
+
+	async fn get_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		let result = self.inner.get_tunnel(locator, options).await;
+		self.record(
+			Request::GetTunnel {
+				locator: locator_key(locator),
+			},
+			&result,
+			|t| Response::Tunnel(Box::new(t.clone())),
+		);
+		result
+	}
+
+	async fn create_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		let result = self.inner.create_tunnel(tunnel, options).await;
+		self.record(
+			Request::CreateTunnel {
+				tags: tunnel.tags.clone(),
+			},
+			&result,
+			|t| Response::Tunnel(Box::new(t.clone())),
+		);
+		result
+	}
+
+	async fn update_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		let result = self.inner.update_tunnel(tunnel, options).await;
+		self.record(update_request(tunnel), &result, |t| {
+			Response::Tunnel(Box::new(t.clone()))
+		});
+		result
+	}
+
+	async fn delete_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		let result = self.inner.delete_tunnel(locator, options).await;
+		self.record(
+			Request::DeleteTunnel {
+				locator: locator_key(locator),
+			},
+			&result,
+			|_| Response::Empty,
+		);
+		result
+	}
+
+	async fn delete_tunnel_port(
+		&self,
+		locator: &TunnelLocator,
+		port_number: u16,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		let result = self
+			.inner
+			.delete_tunnel_port(locator, port_number, options)
+			.await;
+		self.record(
+			Request::DeleteTunnelPort {
+				locator: locator_key(locator),
+				port_number,
+			},
+			&result,
+			|_| Response::Empty,
+		);
+		result
+	}
+
+	async fn delete_tunnel_endpoints(
+		&self,
+		locator: &TunnelLocator,
+		host_id: &str,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		let result = self
+			.inner
+			.delete_tunnel_endpoints(locator, host_id, options)
+			.await;
+		self.record(
+			Request::DeleteTunnelEndpoints {
+				locator: locator_key(locator),
+				host_id: host_id.to_string(),
+			},
+			&result,
+			|_| Response::Empty,
+		);
+		result
+	}
+
+	async fn get_service_limits(&self) -> HttpResult<Option<ServiceLimits>> {
+		self.inner.get_service_limits().await
+	}
+
+	fn with_host_token(&self, host_token: &str) -> SharedManagementClient {
+		Arc::new(RecordingClient {
+			inner: self.inner.with_host_token(host_token),
+			interactions: self.interactions.clone(),
+		})
+	}
+
+	fn create_relay_host(&self, locator: TunnelLocator) -> Box<dyn RelayHost> {
+		self.inner.create_relay_host(locator)
+	}
+}
+
+/// Client that plays back a cassette. Requests must be made in the order
+/// they were recorded; any other request panics, failing the test.
+#[derive(Clone)]
+pub struct ReplayClient {
+	interactions: Arc<Mutex<VecDeque<Interaction>>>,
+}
+
+impl ReplayClient {
+	pub fn new(interactions: Vec<Interaction>) -> Self {
+		Self {
+			interactions: Arc::new(Mutex::new(interactions.into())),
+		}
+	}
+
+	/// Loads a cassette saved by a `RecordingClient`.
+	pub fn load(path: &Path) -> Result<Self, WrappedError> {
+		let contents = std::fs::read_to_string(path)
+			.map_err(|e| wrap(e, format!("error reading cassette {}", path.display())))?;
+		let interactions = serde_json::from_str(&contents)
+			.map_err(|e| wrap(e, format!("error parsing cassette {}", path.display())))?;
+		Ok(Self::new(interactions))
+	}
+
+	/// Gets the number of recorded interactions that haven't been replayed.
+	pub fn remaining(&self) -> usize {
+		self.interactions.lock().unwrap().len()
+	}
+
+	fn replay(&self, request: Request) -> Response {
+		let next = self.interactions.lock().unwrap().pop_front();
+		match next {
+			Some(i) if i.request == request => i.response,
+			Some(i) => panic!(
+				"unexpected request {:?}, the cassette expected {:?}",
+				request, i.request
+			),
+			None => panic!(
+				"unexpected request {:?} after the end of the cassette",
+				request
+			),
+		}
+	}
+
+	fn replay_tunnel(&self, request: Request) -> Result<Tunnel, Response> {
+		match self.replay(request) {
+			Response::Tunnel(t) => Ok(*t),
+			r => Err(r),
+		}
+	}
+
+	fn replay_empty(&self, request: Request) -> Result<(), Response> {
+		match self.replay(request) {
+			Response::Empty => Ok(()),
+			r => Err(r),
+		}
+	}
+}
+
+#[async_trait]
+impl ManagementClient for ReplayClient {
+	async fn list_all_tunnels(&self, options: &TunnelRequestOptions) -> HttpResult<Vec<Tunnel>> {
+		match self.replay(list_request(options)) {
+			Response::Tunnels(t) => Ok(t),
+			r => Err(to_error(r)),
+		}
+	}
+
+	async fn get_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.replay_tunnel(Request::GetTunnel {
+			locator: locator_key(locator),
+		})
+		.map_err(to_error)
+	}
+
+	async fn create_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.replay_tunnel(Request::CreateTunnel {
+			tags: tunnel.tags.clone(),
+		})
+		.map_err(to_error)
+	}
+
+	async fn update_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.replay_tunnel(update_request(tunnel)).map_err(to_error)
+	}
+
+	async fn delete_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.replay_empty(Request::DeleteTunnel {
+			locator: locator_key(locator),
+		})
+		.map_err(to_error)
+	}
+
+	async fn delete_tunnel_port(
+		&self,
+		locator: &TunnelLocator,
+		port_number: u16,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.replay_empty(Request::DeleteTunnelPort {
+			locator: locator_key(locator),
+			port_number,
+		})
+		.map_err(to_error)
+	}
+
+	async fn delete_tunnel_endpoints(
+		&self,
+		locator: &TunnelLocator,
+		host_id: &str,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.replay_empty(Request::DeleteTunnelEndpoints {
+			locator: locator_key(locator),
+			host_id: host_id.to_string(),
+		})
+		.map_err(to_error)
+	}
+
+	/// Limits aren't part of cassettes, so the defaults are always used.
+	async fn get_service_limits(&self) -> HttpResult<Option<ServiceLimits>> {
+		Ok(None)
+	}
+
+	fn with_host_token(&self, _host_token: &str) -> SharedManagementClient {
+		Arc::new(self.clone())
+	}
+
+	fn create_relay_host(&self, _locator: TunnelLocator) -> Box<dyn RelayHost> {
+		Box::new(ReplayRelayHost)
+	}
+}
+
+/// Relay host used in replay. The relay isn't part of the management API,
+/// so connecting always succeeds and ports are ignored.
+struct ReplayRelayHost;
+
+#[async_trait]
+impl RelayHost for ReplayRelayHost {
+	async fn connect(
+		&mut self,
+		_access_token: &str,
+	) -> Result<Box<dyn RelayConnection>, WrappedError> {
+		Ok(Box::new(ReplayRelayConnection(
+			TunnelRelayTunnelEndpoint::default(),
+		)))
+	}
+
+	async fn add_port(&mut self, _port: &TunnelPort) -> Result<(), WrappedError> {
+		Ok(())
+	}
+
+	async fn add_port_raw(
+		&mut self,
+		_port: &TunnelPort,
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, WrappedError> {
+		Ok(mpsc::unbounded_channel().1)
+	}
+
+	async fn remove_port(&mut self, _port_number: u16) -> Result<(), WrappedError> {
+		Ok(())
+	}
+
+	async fn unregister(&mut self) -> Result<(), WrappedError> {
+		Ok(())
+	}
+}
+
+struct ReplayRelayConnection(TunnelRelayTunnelEndpoint);
+
+#[async_trait]
+impl RelayConnection for ReplayRelayConnection {
+	fn endpoint(&self) -> &TunnelRelayTunnelEndpoint {
+		&self.0
+	}
+
+	async fn wait(&mut self) -> Result<(), WrappedError> {
+		futures::future::pending().await
+	}
+
+	async fn close(&mut self) -> Result<(), WrappedError> {
+		Ok(())
+	}
+}
+
+fn locator_key(locator: &TunnelLocator) -> String {
+	match locator {
+		TunnelLocator::ID { cluster, id } => format!("{}/{}", cluster, id),
+		TunnelLocator::Name(name) => name.clone(),
+	}
+}
+
+fn list_request(options: &TunnelRequestOptions) -> Request {
+	Request::ListAllTunnels {
+		tags: options.tags.clone(),
+		require_all_tags: options.require_all_tags,
+	}
+}
+
+fn update_request(tunnel: &Tunnel) -> Request {
+	Request::UpdateTunnel {
+		locator: TunnelLocator::try_from(tunnel)
+			.map(|l| locator_key(&l))
+			.unwrap_or_default(),
+		tags: tunnel.tags.clone(),
+	}
+}
+
+fn to_error(response: Response) -> HttpError {
+	let status_code = match response {
+		Response::Error(s) => StatusCode::from_u16(s).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+		r => panic!("cassette response {:?} doesn't match the request", r),
+	};
+
+	HttpError::ResponseError(ResponseError {
+		url: "https://tunnels.cassette.invalid/".parse().unwrap(),
+		status_code,
+		data: None,
+		request_id: None,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+
+	use crate::log;
+	use crate::state::LauncherPaths;
+	use crate::tunnels::dev_tunnels::DevTunnels;
+	use crate::util::errors::AnyError;
+
+	const LAUNCHER_TAG: &str = "vscode-server-launcher";
+
+	fn make_tunnel(id: &str, name: &str) -> Tunnel {
+		let mut access_tokens = HashMap::new();
+		access_tokens.insert("host".to_string(), format!("{}-token", id));
+		Tunnel {
+			cluster_id: Some("usw2".to_string()),
+			tunnel_id: Some(id.to_string()),
+			tags: vec![name.to_string(), LAUNCHER_TAG.to_string()],
+			access_tokens: Some(access_tokens),
+			..Default::default()
+		}
+	}
+
+	fn tags(name: &str) -> Vec<String> {
+		vec![name.to_string(), LAUNCHER_TAG.to_string()]
+	}
+
+	fn interaction(request: Request, response: Response) -> Interaction {
+		Interaction { request, response }
+	}
+
+	fn name_is_free(name: &str) -> Interaction {
+		interaction(
+			Request::ListAllTunnels {
+				tags: vec![LAUNCHER_TAG.to_string(), name.to_string()],
+				require_all_tags: true,
+			},
+			Response::Tunnels(vec![]),
+		)
+	}
+
+	/// Lists the tunnel with the ID as the only one with the name, once it's
+	/// been created to reserve the name.
+	fn name_is_reserved(id: &str, name: &str) -> Interaction {
+		interaction(
+			Request::ListAllTunnels {
+				tags: vec![LAUNCHER_TAG.to_string(), name.to_string()],
+				require_all_tags: true,
+			},
+			Response::Tunnels(vec![make_tunnel(id, name)]),
+		)
+	}
+
+	fn make_dev_tunnels(client: &ReplayClient, dir: &tempfile::TempDir) -> DevTunnels {
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		DevTunnels::new_with_client(&log::Logger::test(), &paths, Arc::new(client.clone()))
+	}
+
+	async fn assert_recreates_tunnel_on_lookup_error(status: u16) {
+		let dir = tempfile::tempdir().unwrap();
+		let client = ReplayClient::new(vec![
+			name_is_free("my-machine"),
+			interaction(
+				Request::CreateTunnel {
+					tags: tags("my-machine"),
+				},
+				Response::Tunnel(Box::new(make_tunnel("first", "my-machine"))),
+			),
+			name_is_reserved("first", "my-machine"),
+			interaction(
+				Request::GetTunnel {
+					locator: "usw2/first".to_string(),
+				},
+				Response::Error(status),
+			),
+			interaction(
+				Request::CreateTunnel {
+					tags: tags("my-machine"),
+				},
+				Response::Tunnel(Box::new(make_tunnel("second", "my-machine"))),
+			),
+		]);
+
+		let mut dt = make_dev_tunnels(&client, &dir);
+		dt.rename_tunnel("my-machine").await.unwrap();
+
+		let mut active = dt.start_new_launcher_tunnel(None, true).await.unwrap();
+		assert_eq!(active.name, "my-machine");
+		active.close().await.unwrap();
+
+		assert_eq!(client.remaining(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_recreates_tunnel_when_not_found() {
+		assert_recreates_tunnel_on_lookup_error(404).await;
+	}
+
+	#[tokio::test]
+	async fn test_reports_tunnel_owned_by_other_account() {
+		let dir = tempfile::tempdir().unwrap();
+		let client = ReplayClient::new(vec![
+			name_is_free("my-machine"),
+			interaction(
+				Request::CreateTunnel {
+					tags: tags("my-machine"),
+				},
+				Response::Tunnel(Box::new(make_tunnel("first", "my-machine"))),
+			),
+			name_is_reserved("first", "my-machine"),
+			interaction(
+				Request::GetTunnel {
+					locator: "usw2/first".to_string(),
+				},
+				Response::Error(403),
+			),
+		]);
+
+		let mut dt = make_dev_tunnels(&client, &dir);
+		dt.rename_tunnel("my-machine").await.unwrap();
+
+		let result = dt.start_new_launcher_tunnel(None, true).await;
+		assert!(matches!(
+			result,
+			Err(AnyError::TunnelOwnedByOtherAccount(_))
+		));
+
+		let forgotten = dt.forget_tunnel().unwrap().unwrap();
+		assert_eq!(forgotten.id, "first");
+		assert_eq!(client.remaining(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_recreates_forgotten_tunnel() {
+		let dir = tempfile::tempdir().unwrap();
+		let client = ReplayClient::new(vec![
+			name_is_free("my-machine"),
+			interaction(
+				Request::CreateTunnel {
+					tags: tags("my-machine"),
+				},
+				Response::Tunnel(Box::new(make_tunnel("first", "my-machine"))),
+			),
+			// the other account's tunnel isn't visible under this one
+			name_is_free("my-machine"),
+			interaction(
+				Request::ListAllTunnels {
+					tags: vec![LAUNCHER_TAG.to_string()],
+					require_all_tags: true,
+				},
+				Response::Tunnels(vec![]),
+			),
+			interaction(
+				Request::CreateTunnel {
+					tags: tags("my-machine"),
+				},
+				Response::Tunnel(Box::new(make_tunnel("second", "my-machine"))),
+			),
+			name_is_reserved("second", "my-machine"),
+		]);
+
+		let mut dt = make_dev_tunnels(&client, &dir);
+		dt.rename_tunnel("my-machine").await.unwrap();
+
+		// as when choosing to create a new tunnel under this account
+		dt.forget_tunnel().unwrap();
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		assert_eq!(active.name, "my-machine");
+		active.close().await.unwrap();
+		assert_eq!(client.remaining(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_recycles_tunnel_at_limit() {
+		let dir = tempfile::tempdir().unwrap();
+		let client = ReplayClient::new(vec![
+			name_is_free("my-machine"),
+			interaction(
+				Request::CreateTunnel {
+					tags: tags("my-machine"),
+				},
+				Response::Error(429),
+			),
+			interaction(
+				Request::ListAllTunnels {
+					tags: vec![LAUNCHER_TAG.to_string()],
+					require_all_tags: true,
+				},
+				Response::Tunnels(vec![make_tunnel("old", "old-machine")]),
+			),
+			interaction(
+				Request::DeleteTunnel {
+					locator: "usw2/old".to_string(),
+				},
+				Response::Empty,
+			),
+			interaction(
+				Request::CreateTunnel {
+					tags: tags("my-machine"),
+				},
+				Response::Tunnel(Box::new(make_tunnel("new", "my-machine"))),
+			),
+			name_is_reserved("new", "my-machine"),
+		]);
+
+		make_dev_tunnels(&client, &dir)
+			.rename_tunnel("my-machine")
+			.await
+			.unwrap();
+		assert_eq!(client.remaining(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_fails_at_limit_with_nothing_to_recycle() {
+		let dir = tempfile::tempdir().unwrap();
+		let client = ReplayClient::new(vec![
+			name_is_free("my-machine"),
+			interaction(
+				Request::CreateTunnel {
+					tags: tags("my-machine"),
+				},
+				Response::Error(429),
+			),
+			interaction(
+				Request::ListAllTunnels {
+					tags: vec![LAUNCHER_TAG.to_string()],
+					require_all_tags: true,
+				},
+				Response::Tunnels(vec![]),
+			),
+		]);
+
+		let result = make_dev_tunnels(&client, &dir)
+			.rename_tunnel("my-machine")
+			.await;
+		assert!(result.is_err());
+		assert_eq!(client.remaining(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_gives_up_name_taken_while_creating() {
+		let dir = tempfile::tempdir().unwrap();
+		let client = ReplayClient::new(vec![
+			name_is_free("my-machine"),
+			interaction(
+				Request::CreateTunnel {
+					tags: tags("my-machine"),
+				},
+				Response::Tunnel(Box::new(make_tunnel("second", "my-machine"))),
+			),
+			// another machine created a tunnel with the name first
+			interaction(
+				Request::ListAllTunnels {
+					tags: vec![LAUNCHER_TAG.to_string(), "my-machine".to_string()],
+					require_all_tags: true,
+				},
+				Response::Tunnels(vec![
+					make_tunnel("first", "my-machine"),
+					make_tunnel("second", "my-machine"),
+				]),
+			),
+			interaction(
+				Request::DeleteTunnel {
+					locator: "usw2/second".to_string(),
+				},
+				Response::Empty,
+			),
+		]);
+
+		let mut dt = make_dev_tunnels(&client, &dir);
+		let result = dt.rename_tunnel("my-machine").await;
+		assert!(result.is_err());
+		assert!(dt.forget_tunnel().unwrap().is_none());
+		assert_eq!(client.remaining(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_records_and_replays() {
+		let dir = tempfile::tempdir().unwrap();
+		let interactions = vec![
+			name_is_free("renamed"),
+			interaction(
+				Request::GetTunnel {
+					locator: "usw2/first".to_string(),
+				},
+				Response::Tunnel(Box::new(make_tunnel("first", "my-machine"))),
+			),
+			interaction(
+				Request::UpdateTunnel {
+					locator: "usw2/first".to_string(),
+					tags: tags("renamed"),
+				},
+				Response::Tunnel(Box::new(make_tunnel("first", "renamed"))),
+			),
+		];
+
+		let recorder = RecordingClient::new(Arc::new(ReplayClient::new(interactions.clone())));
+		let options = TunnelRequestOptions {
+			tags: vec![LAUNCHER_TAG.to_string(), "renamed".to_string()],
+			require_all_tags: true,
+			..Default::default()
+		};
+		let locator = TunnelLocator::ID {
+			cluster: "usw2".to_string(),
+			id: "first".to_string(),
+		};
+		recorder.list_all_tunnels(&options).await.unwrap();
+		let mut tunnel = recorder.get_tunnel(&locator, &options).await.unwrap();
+		tunnel.tags = tags("renamed");
+		recorder.update_tunnel(&tunnel, &options).await.unwrap();
+
+		let cassette = dir.path().join("cassette.json");
+		recorder.save(&cassette).unwrap();
+
+		let replay = ReplayClient::load(&cassette).unwrap();
+		let recorded: Vec<Request> = replay
+			.interactions
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|i| i.request.clone())
+			.collect();
+		let expected: Vec<Request> = interactions.into_iter().map(|i| i.request).collect();
+		assert_eq!(recorded, expected);
+	}
+}
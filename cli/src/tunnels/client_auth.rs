@@ -0,0 +1,159 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Authorization of clients by SSH key, enforced by the host on top of the
+//! relay's account-based access control. Before it can use the control
+//! channel, a client asks for a challenge and signs it with a key listed in
+//! an `authorized_keys` file, as `ssh-keygen -Y sign -n vscode-tunnel` does.
+
+use std::path::{Path, PathBuf};
+
+use rand::{distributions::Alphanumeric, Rng};
+use ssh_key::{AuthorizedKeys, HashAlg, PublicKey, SshSig};
+
+use crate::util::errors::{ClientNotAuthorized, InvalidAuthorizedKeys};
+
+/// Namespace that challenges are signed in, so that signatures made for
+/// other purposes can't be replayed to the host.
+pub const SIGNATURE_NAMESPACE: &str = "vscode-tunnel";
+
+/// Length of the challenges issued to clients.
+const CHALLENGE_LENGTH: usize = 32;
+
+/// Keys that clients may authorize with, read from an `authorized_keys` file.
+/// The file is read again for each client, so keys can be added or revoked
+/// while the tunnel is running.
+#[derive(Clone, Debug)]
+pub struct AuthorizedClientKeys {
+	path: PathBuf,
+}
+
+impl AuthorizedClientKeys {
+	/// Uses the keys in the file, checking that it can be read.
+	pub fn load(path: impl Into<PathBuf>) -> Result<Self, InvalidAuthorizedKeys> {
+		let keys = AuthorizedClientKeys { path: path.into() };
+		keys.read()?;
+		Ok(keys)
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	fn read(&self) -> Result<Vec<PublicKey>, InvalidAuthorizedKeys> {
+		let contents = std::fs::read_to_string(&self.path).map_err(|e| {
+			InvalidAuthorizedKeys(format!("error reading {}: {}", self.path.display(), e))
+		})?;
+		parse_authorized_keys(&contents)
+			.map_err(|e| InvalidAuthorizedKeys(format!("{} in {}", e, self.path.display())))
+	}
+
+	/// Checks that the signature of the challenge was made by one of the keys,
+	/// returning the fingerprint of the key.
+	pub fn verify(&self, challenge: &str, signature: &str) -> Result<String, ClientNotAuthorized> {
+		let keys = self
+			.read()
+			.map_err(|e| ClientNotAuthorized(e.to_string()))?;
+		verify_signature(&keys, challenge, signature)
+	}
+}
+
+/// Creates a random challenge for a client to sign.
+pub fn new_challenge() -> String {
+	rand::thread_rng()
+		.sample_iter(&Alphanumeric)
+		.take(CHALLENGE_LENGTH)
+		.map(char::from)
+		.collect()
+}
+
+pub(super) fn parse_authorized_keys(contents: &str) -> Result<Vec<PublicKey>, String> {
+	AuthorizedKeys::new(contents)
+		.map(|entry| {
+			entry
+				.map(|e| e.public_key().clone())
+				.map_err(|e| format!("invalid key: {}", e))
+		})
+		.collect()
+}
+
+fn verify_signature(
+	keys: &[PublicKey],
+	challenge: &str,
+	signature: &str,
+) -> Result<String, ClientNotAuthorized> {
+	let signature = SshSig::from_pem(signature)
+		.map_err(|e| ClientNotAuthorized(format!("invalid signature: {}", e)))?;
+
+	keys.iter()
+		.find(|k| {
+			k.verify(SIGNATURE_NAMESPACE, challenge.as_bytes(), &signature)
+				.is_ok()
+		})
+		.map(|k| k.fingerprint(HashAlg::Sha256).to_string())
+		.ok_or_else(|| {
+			ClientNotAuthorized("the challenge was not signed by an authorized key".to_string())
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use ssh_key::private::{Ed25519Keypair, PrivateKey};
+	use ssh_key::LineEnding;
+
+	fn key(seed: u8) -> PrivateKey {
+		PrivateKey::from(Ed25519Keypair::from_seed(&[seed; 32]))
+	}
+
+	fn sign(key: &PrivateKey, namespace: &str, challenge: &str) -> String {
+		key.sign(namespace, HashAlg::Sha512, challenge.as_bytes())
+			.unwrap()
+			.to_pem(LineEnding::LF)
+			.unwrap()
+	}
+
+	#[test]
+	fn test_verifies_signed_challenge() {
+		let authorized = key(1);
+		let file = format!(
+			"# comment\n\n{}\nno-pty {} me@example\n",
+			authorized.public_key().to_openssh().unwrap(),
+			key(2).public_key().to_openssh().unwrap()
+		);
+		let keys = parse_authorized_keys(&file).unwrap();
+		assert_eq!(keys.len(), 2);
+
+		let challenge = new_challenge();
+		assert_eq!(challenge.len(), CHALLENGE_LENGTH);
+
+		let fingerprint = verify_signature(
+			&keys,
+			&challenge,
+			&sign(&authorized, SIGNATURE_NAMESPACE, &challenge),
+		)
+		.unwrap();
+		assert_eq!(
+			fingerprint,
+			authorized.fingerprint(HashAlg::Sha256).to_string()
+		);
+
+		// a different challenge, namespace, or key is refused
+		let signature = sign(&authorized, SIGNATURE_NAMESPACE, &challenge);
+		assert!(verify_signature(&keys, &new_challenge(), &signature).is_err());
+		let signature = sign(&authorized, "file", &challenge);
+		assert!(verify_signature(&keys, &challenge, &signature).is_err());
+		let signature = sign(&key(3), SIGNATURE_NAMESPACE, &challenge);
+		assert!(verify_signature(&keys, &challenge, &signature).is_err());
+		assert!(verify_signature(&keys, &challenge, "not a signature").is_err());
+	}
+
+	#[test]
+	fn test_rejects_invalid_keys() {
+		assert!(parse_authorized_keys("ssh-ed25519 not-base64").is_err());
+		assert_eq!(parse_authorized_keys("").unwrap().len(), 0);
+	}
+}
@@ -2,8 +2,13 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
+use super::bandwidth_budget::BandwidthBudget;
+use super::client_auth::AuthorizedClientKeys;
+use super::container::DevContainer;
+use super::extension_filter::ExtensionFilter;
 use super::paths::{InstalledServer, LastUsedServers, ServerPaths};
-use crate::options::{Quality, TelemetryLevel};
+use super::warm_server::WarmServerOptions;
+use crate::options::{Quality, ServerArch, TelemetryLevel};
 use crate::state::LauncherPaths;
 use crate::update_service::{
 	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService,
@@ -19,7 +24,7 @@ use crate::{debug, info, log, span, spanf, trace, warning};
 use lazy_static::lazy_static;
 use opentelemetry::KeyValue;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::fs::File;
 use std::io::{ErrorKind, Write};
@@ -55,15 +60,48 @@ pub struct CodeServerArgs {
 	pub install_extensions: Vec<String>,
 	pub uninstall_extensions: Vec<String>,
 	pub list_extensions: bool,
+	/// Filters the extensions clients ask to install as the server starts.
+	pub extension_filter: ExtensionFilter,
+	/// Data transfer budget, past which new client connections are refused.
+	pub bandwidth_budget: BandwidthBudget,
+	/// Keys clients must sign a challenge with before using the control
+	/// channel, if any.
+	pub authorized_keys: Option<AuthorizedClientKeys>,
+	/// Key the host signs clients' attestation challenges with, if any.
+	pub attestation_key: Option<PathBuf>,
+	/// Server kept running while no clients are connected, if any.
+	pub warm_server: Option<WarmServerOptions>,
+	/// Architecture of this machine, if the server is built for another one
+	/// and so runs under emulation.
+	pub host_arch: Option<ServerArch>,
 	pub show_versions: bool,
 	pub category: Option<String>,
 	pub pre_release: bool,
 	pub force: bool,
 	pub start_server: bool,
+	pub server_base_path: Option<String>,
 	// connection tokens
 	pub connection_token: Option<String>,
 	pub connection_token_file: Option<String>,
 	pub without_connection_token: bool,
+	// environment
+	pub extensions_gallery: Option<ExtensionsGallery>,
+	pub proxy: Option<String>,
+	pub no_proxy: Option<String>,
+	// container to run the server in, instead of this machine
+	pub container: Option<DevContainer>,
+}
+
+/// Extension gallery for the server to use instead of the one in its
+/// product.json, in the same shape as product.json's `extensionsGallery`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionsGallery {
+	pub service_url: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub item_url: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub resource_url_template: Option<String>,
 }
 
 impl CodeServerArgs {
@@ -92,6 +130,9 @@ impl CodeServerArgs {
 			}
 		}
 
+		if let Some(i) = &self.server_base_path {
+			args.push(format!("--server-base-path={}", i));
+		}
 		if let Some(i) = &self.connection_token {
 			args.push(format!("--connection-token={}", i));
 		}
@@ -139,6 +180,20 @@ impl CodeServerArgs {
 		}
 		args
 	}
+
+	pub fn command_environment(&self) -> Vec<(&'static str, String)> {
+		let mut env = Vec::new();
+		if let Some(p) = &self.proxy {
+			for name in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+				env.push((name, p.clone()));
+			}
+		}
+		if let Some(p) = &self.no_proxy {
+			env.push(("NO_PROXY", p.clone()));
+			env.push(("no_proxy", p.clone()));
+		}
+		env
+	}
 }
 
 /// Base server params that can be `resolve()`d to a `ResolvedServerParams`.
@@ -269,6 +324,17 @@ impl CodeServerOrigin {
 			}
 		}
 	}
+
+	/// Gets the server's process ID, if it's still running.
+	pub fn running_pid(&mut self) -> Option<u32> {
+		match self {
+			CodeServerOrigin::New(child) => match child.try_wait() {
+				Ok(None) => child.id(),
+				_ => None,
+			},
+			CodeServerOrigin::Existing(pid) => process_exists(*pid).then_some(*pid),
+		}
+	}
 }
 
 async fn check_and_create_dir(path: &Path) -> Result<(), WrappedError> {
@@ -402,8 +468,10 @@ async fn do_extension_install_on_running_server(
 pub struct ServerBuilder<'a> {
 	logger: &'a log::Logger,
 	server_params: &'a ResolvedServerParams,
+	launcher_paths: &'a LauncherPaths,
 	last_used: LastUsedServers<'a>,
 	server_paths: ServerPaths,
+	auto_shutdown: bool,
 }
 
 impl<'a> ServerBuilder<'a> {
@@ -415,15 +483,30 @@ impl<'a> ServerBuilder<'a> {
 		Self {
 			logger,
 			server_params,
+			launcher_paths,
 			last_used: LastUsedServers::new(launcher_paths),
 			server_paths: server_params
 				.as_installed_server()
 				.server_paths(launcher_paths),
+			auto_shutdown: true,
 		}
 	}
 
+	/// Keeps servers started by the builder running once their last client
+	/// disconnects, rather than having them shut themselves down.
+	pub fn keep_alive(mut self) -> Self {
+		self.auto_shutdown = false;
+		self
+	}
+
 	/// Gets any already-running server from this directory.
 	pub async fn get_running(&self) -> Result<Option<AnyCodeServer>, AnyError> {
+		// the server's process is only visible inside the container, so one
+		// is started for each client instead
+		if self.container().is_some() {
+			return Ok(None);
+		}
+
 		info!(
 			self.logger,
 			"Checking {} and {} for a running server...",
@@ -475,6 +558,15 @@ impl<'a> ServerBuilder<'a> {
 		check_and_create_dir(&self.server_paths.server_dir).await?;
 		install_server_if_needed(self.logger, &self.server_paths, &self.server_params.release)
 			.await?;
+		if let Some(container) = self.container() {
+			container
+				.install_server(
+					self.logger,
+					&self.server_paths.server_dir,
+					&self.path_in_container(&self.server_paths.server_dir),
+				)
+				.await?;
+		}
 		debug!(self.logger, "Server setup complete");
 
 		match self.last_used.add(self.server_params.as_installed_server()) {
@@ -491,7 +583,9 @@ impl<'a> ServerBuilder<'a> {
 	}
 
 	pub async fn listen_on_default_socket(&self) -> Result<SocketCodeServer, AnyError> {
-		let requested_file = if cfg!(target_os = "windows") {
+		let requested_file = if self.container().is_some() {
+			DevContainer::new_socket_path()
+		} else if cfg!(target_os = "windows") {
 			PathBuf::from(format!(r"\\.\pipe\vscode-server-{}", Uuid::new_v4()))
 		} else {
 			std::env::temp_dir().join(format!("vscode-server-{}", Uuid::new_v4()))
@@ -517,8 +611,10 @@ impl<'a> ServerBuilder<'a> {
 		let mut cmd = self.get_base_command();
 		cmd.arg("--start-server")
 			.arg("--without-connection-token")
-			.arg("--enable-remote-auto-shutdown")
 			.arg(format!("--socket-path={}", socket.display()));
+		if self.auto_shutdown {
+			cmd.arg("--enable-remote-auto-shutdown");
+		}
 
 		let child = self.spawn_server_process(cmd)?;
 		let log_file = self.get_logfile()?;
@@ -538,6 +634,7 @@ impl<'a> ServerBuilder<'a> {
 			}
 			Ok(Ok(socket)) => Ok(socket),
 		}?;
+		let socket = self.local_socket(socket)?;
 
 		info!(self.logger, "Server started");
 
@@ -570,6 +667,14 @@ impl<'a> ServerBuilder<'a> {
 	fn spawn_server_process(&self, mut cmd: Command) -> Result<Child, AnyError> {
 		info!(self.logger, "Starting server...");
 
+		apply_extensions_gallery(
+			&self.server_paths.server_dir,
+			self.server_params
+				.code_server_args
+				.extensions_gallery
+				.as_ref(),
+		)?;
+
 		debug!(self.logger, "Starting server with command... {:?}", cmd);
 
 		let child = cmd
@@ -597,11 +702,44 @@ impl<'a> ServerBuilder<'a> {
 	}
 
 	fn get_base_command(&self) -> Command {
-		let mut cmd = Command::new(&self.server_paths.executable);
+		let args = &self.server_params.code_server_args;
+		let mut cmd = match self.container() {
+			Some(container) => {
+				let mut cmd = container.exec_command(&args.command_environment());
+				cmd.arg(self.path_in_container(&self.server_paths.executable));
+				cmd
+			}
+			None => {
+				let mut cmd = Command::new(&self.server_paths.executable);
+				cmd.envs(args.command_environment());
+				cmd
+			}
+		};
 		cmd.stdin(std::process::Stdio::null())
-			.args(self.server_params.code_server_args.command_arguments());
+			.args(args.command_arguments());
 		cmd
 	}
+
+	fn container(&self) -> Option<&DevContainer> {
+		self.server_params.code_server_args.container.as_ref()
+	}
+
+	fn path_in_container(&self, local: &Path) -> String {
+		DevContainer::path_in_container(self.launcher_paths, local)
+	}
+
+	/// Gets a socket on this machine for the server's socket, which, for a
+	/// server in a container, is bridged out of the container.
+	fn local_socket(&self, socket: PathBuf) -> Result<PathBuf, AnyError> {
+		match self.container() {
+			Some(container) => container.bridge_socket(
+				self.logger,
+				self.path_in_container(&self.server_paths.server_dir.join("node")),
+				&socket,
+			),
+			None => Ok(socket),
+		}
+	}
 }
 
 fn monitor_server<M, R>(
@@ -755,3 +893,90 @@ fn parse_port_from(text: &str) -> Option<u16> {
 			.and_then(|path| path.as_str().parse::<u16>().ok())
 	})
 }
+
+/// Name of the copy of the server's product.json that's kept while its
+/// extension gallery is overridden, so it can be restored.
+const ORIGINAL_PRODUCT_FILE_NAME: &str = "product.original.json";
+
+/// Sets the extension gallery in the server's product.json, which is the only
+/// place the server reads it from, or restores the server's own gallery if
+/// there's no override.
+fn apply_extensions_gallery(
+	server_dir: &Path,
+	gallery: Option<&ExtensionsGallery>,
+) -> Result<(), AnyError> {
+	let product_file = server_dir.join("product.json");
+	let original_file = server_dir.join(ORIGINAL_PRODUCT_FILE_NAME);
+	let gallery = match gallery {
+		Some(g) => g,
+		None if original_file.exists() => {
+			return fs::rename(&original_file, &product_file)
+				.map_err(|e| wrap(e, "error restoring the server's product.json").into());
+		}
+		None => return Ok(()),
+	};
+
+	if !original_file.exists() {
+		fs::copy(&product_file, &original_file)
+			.map_err(|e| wrap(e, "error backing up the server's product.json"))?;
+	}
+
+	let mut product: serde_json::Value = fs::read_to_string(&original_file)
+		.map_err(|e| wrap(e, "error reading the server's product.json"))
+		.and_then(|s| {
+			serde_json::from_str(&s).map_err(|e| wrap(e, "error parsing the server's product.json"))
+		})?;
+	let gallery = serde_json::to_value(gallery)
+		.map_err(|e| wrap(e, "error serializing the extension gallery"))?;
+	product
+		.as_object_mut()
+		.ok_or_else(|| {
+			wrap(
+				"expected an object",
+				"error parsing the server's product.json",
+			)
+		})?
+		.insert("extensionsGallery".to_string(), gallery);
+
+	fs::write(&product_file, product.to_string())
+		.map_err(|e| wrap(e, "error writing the server's product.json").into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_applies_extensions_gallery() {
+		let dir = tempfile::tempdir().unwrap();
+		let product_file = dir.path().join("product.json");
+		let original =
+			r#"{"nameShort":"Code","extensionsGallery":{"serviceUrl":"https://marketplace"}}"#;
+		fs::write(&product_file, original).unwrap();
+
+		let gallery = ExtensionsGallery {
+			service_url: "https://mirror/gallery".to_string(),
+			item_url: None,
+			resource_url_template: None,
+		};
+		apply_extensions_gallery(dir.path(), Some(&gallery)).unwrap();
+		let product: serde_json::Value =
+			serde_json::from_str(&fs::read_to_string(&product_file).unwrap()).unwrap();
+		assert_eq!(product["nameShort"], "Code");
+		assert_eq!(
+			product["extensionsGallery"],
+			serde_json::json!({ "serviceUrl": "https://mirror/gallery" })
+		);
+
+		// applying it again starts from the server's own product.json
+		apply_extensions_gallery(dir.path(), Some(&gallery)).unwrap();
+		assert_eq!(
+			fs::read_to_string(dir.path().join(ORIGINAL_PRODUCT_FILE_NAME)).unwrap(),
+			original
+		);
+
+		apply_extensions_gallery(dir.path(), None).unwrap();
+		assert_eq!(fs::read_to_string(&product_file).unwrap(), original);
+		assert!(!dir.path().join(ORIGINAL_PRODUCT_FILE_NAME).exists());
+	}
+}
@@ -0,0 +1,353 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Running the VS Code server inside a dev container while the tunnel is
+//! hosted on this machine. The server is downloaded here, copied into the
+//! container, and started with `docker exec`. Its socket is only reachable
+//! from inside the container, so it's bridged to a socket on this machine:
+//! each connection is relayed over the stdio of its own `docker exec`, by
+//! the Node.js that ships with the server.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::log;
+use crate::options::{ServerArch, ServerOs};
+use crate::state::LauncherPaths;
+use crate::util::async_pipe::{get_socket_name, listen_socket_rw_stream, AsyncPipe};
+use crate::util::command::capture_command;
+use crate::util::errors::{wrap, AnyError, DevContainerError};
+
+const DOCKER: &str = "docker";
+const DEVCONTAINER: &str = "devcontainer";
+
+/// Directory in the container that the CLI's data directory is mirrored to.
+const CONTAINER_DATA_DIR: &str = "/tmp/.vscode-cli";
+
+/// Node.js script that relays its stdio to the socket given as its argument.
+const RELAY_SCRIPT: &str = "const s=require('net').connect(process.argv[1]);process.stdin.pipe(s);s.pipe(process.stdout);s.on('error',()=>process.exit(1));";
+
+/// A running container that the server is run in.
+#[derive(Clone, Debug)]
+pub struct DevContainer {
+	pub id: String,
+	/// User to run commands as, if not the container's default.
+	user: Option<String>,
+	/// Directory to run commands in, if not the container's default.
+	workdir: Option<String>,
+}
+
+/// Result the devcontainer CLI prints as the last line of `devcontainer up`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpResult {
+	outcome: String,
+	container_id: Option<String>,
+	remote_user: Option<String>,
+	remote_workspace_folder: Option<String>,
+	message: Option<String>,
+}
+
+impl DevContainer {
+	/// Starts the dev container given by `target`. That's either the path to
+	/// a folder or `devcontainer.json`, which is started with the devcontainer
+	/// CLI, or the name or ID of an existing container.
+	pub async fn start(log: &log::Logger, target: &str) -> Result<Self, AnyError> {
+		let config = match fs::canonicalize(target) {
+			Ok(p) => p,
+			Err(_) => return Self::start_existing(log, target).await,
+		};
+
+		let mut args = vec![OsString::from("up")];
+		if config.is_file() {
+			args.push("--workspace-folder".into());
+			args.push(workspace_folder_for(&config).into_os_string());
+			args.push("--config".into());
+			args.push(config.into_os_string());
+		} else {
+			args.push("--workspace-folder".into());
+			args.push(config.into_os_string());
+		}
+
+		info!(log, "Starting dev container with the devcontainer CLI...");
+		let output = capture_command(DEVCONTAINER, &args).await?;
+		let container = parse_up_result(&String::from_utf8_lossy(&output.stdout))?;
+		info!(log, "Dev container {} is running", container.short_id());
+		Ok(container)
+	}
+
+	async fn start_existing(log: &log::Logger, name: &str) -> Result<Self, AnyError> {
+		info!(log, "Starting container {}...", name);
+		docker(["start", name]).await?;
+		let id = docker(["inspect", "--format", "{{.Id}}", name]).await?;
+		Ok(DevContainer {
+			id,
+			user: None,
+			workdir: None,
+		})
+	}
+
+	fn short_id(&self) -> &str {
+		&self.id[..self.id.len().min(12)]
+	}
+
+	/// Gets the OS and architecture of the server build the container needs.
+	pub async fn server_platform(&self) -> Result<(ServerOs, ServerArch), AnyError> {
+		let output = self
+			.exec([
+				"sh",
+				"-c",
+				"uname -m; test -f /etc/alpine-release && echo alpine",
+			])
+			.await?;
+		let mut lines = output.lines();
+		let arch = lines
+			.next()
+			.and_then(ServerArch::from_machine_name)
+			.ok_or_else(|| DevContainerError(format!("unknown architecture '{}'", output)))?;
+		let os = match lines.next() {
+			Some("alpine") => ServerOs::Alpine,
+			_ => ServerOs::Linux,
+		};
+
+		Ok((os, arch))
+	}
+
+	/// Gets the path in the container that a file in the CLI's data directory
+	/// on this machine is copied to.
+	pub fn path_in_container(paths: &LauncherPaths, local: &Path) -> String {
+		let relative = local.strip_prefix(paths.root()).unwrap_or(local);
+		let mut path = CONTAINER_DATA_DIR.to_string();
+		for component in relative.components() {
+			path.push('/');
+			path.push_str(&component.as_os_str().to_string_lossy());
+		}
+		path
+	}
+
+	/// Gets a new path for a socket in the container.
+	pub fn new_socket_path() -> PathBuf {
+		PathBuf::from(format!("/tmp/vscode-server-{}", Uuid::new_v4()))
+	}
+
+	/// Copies the server installed in `local_dir` on this machine to `dest`
+	/// in the container, unless it's already there.
+	pub async fn install_server(
+		&self,
+		log: &log::Logger,
+		local_dir: &Path,
+		dest: &str,
+	) -> Result<(), AnyError> {
+		if self.exec(["test", "-d", dest]).await.is_ok() {
+			debug!(log, "Server already installed in container at {}", dest);
+			return Ok(());
+		}
+
+		info!(log, "Copying server into container {}...", self.short_id());
+
+		// copy to a temporary directory first, so that an interrupted copy
+		// isn't mistaken for an installed server
+		let partial = format!("{}.partial", dest);
+		self.exec(["rm", "-rf", &partial]).await?;
+		if let Some((parent, _)) = dest.rsplit_once('/') {
+			self.exec(["mkdir", "-p", parent]).await?;
+		}
+		let target = format!("{}:{}", self.id, partial);
+		docker([OsStr::new("cp"), local_dir.as_os_str(), OsStr::new(&target)]).await?;
+		self.exec(["mv", &partial, dest]).await?;
+
+		Ok(())
+	}
+
+	/// Gets a command that runs a command in the container with `docker exec`,
+	/// with the given environment variables.
+	pub fn exec_command(&self, env: &[(&'static str, String)]) -> Command {
+		let mut cmd = Command::new(DOCKER);
+		cmd.arg("exec").arg("-i");
+		if let Some(user) = &self.user {
+			cmd.arg("-u").arg(user);
+		}
+		if let Some(workdir) = &self.workdir {
+			cmd.arg("-w").arg(workdir);
+		}
+		for (name, value) in env {
+			cmd.arg("-e").arg(format!("{}={}", name, value));
+		}
+		cmd.arg(&self.id);
+		cmd
+	}
+
+	async fn exec<I, S>(&self, args: I) -> Result<String, AnyError>
+	where
+		I: IntoIterator<Item = S>,
+		S: AsRef<OsStr>,
+	{
+		let output = self
+			.exec_command(&[])
+			.args(args)
+			.stdin(Stdio::null())
+			.output()
+			.await
+			.map_err(|e| wrap(e, "failed to run docker exec"))?;
+		check_output(output)
+	}
+
+	/// Bridges the `socket` in the container to a new socket on this machine,
+	/// which is returned. Connections are relayed by the server's `node`.
+	pub fn bridge_socket(
+		&self,
+		log: &log::Logger,
+		node: String,
+		socket: &Path,
+	) -> Result<PathBuf, AnyError> {
+		let local = get_socket_name();
+		let mut listener = listen_socket_rw_stream(&local)?;
+		let container = self.clone();
+		let socket = socket.to_string_lossy().to_string();
+		let log = log.clone();
+
+		tokio::spawn(async move {
+			loop {
+				let conn = match listener.accept().await {
+					Ok(c) => c,
+					Err(e) => {
+						debug!(log, "Stopped bridging container socket: {}", e);
+						return;
+					}
+				};
+
+				let mut cmd = container.exec_command(&[]);
+				cmd.arg(&node).arg("-e").arg(RELAY_SCRIPT).arg(&socket);
+				let log = log.clone();
+				tokio::spawn(async move {
+					if let Err(e) = relay(conn, cmd).await {
+						debug!(log, "Error relaying to container socket: {}", e);
+					}
+				});
+			}
+		});
+
+		Ok(local)
+	}
+}
+
+async fn relay(conn: AsyncPipe, mut cmd: Command) -> Result<(), AnyError> {
+	let mut child = cmd
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::null())
+		.kill_on_drop(true)
+		.spawn()
+		.map_err(|e| wrap(e, "failed to run docker exec"))?;
+	let mut stdin = child.stdin.take().expect("expected child stdin");
+	let mut stdout = child.stdout.take().expect("expected child stdout");
+	let (mut read, mut write) = tokio::io::split(conn);
+
+	let to_container = async {
+		tokio::io::copy(&mut read, &mut stdin).await.ok();
+		stdin.shutdown().await.ok();
+	};
+	let from_container = async {
+		tokio::io::copy(&mut stdout, &mut write).await.ok();
+		write.shutdown().await.ok();
+	};
+	tokio::join!(to_container, from_container);
+
+	child.wait().await.ok();
+	Ok(())
+}
+
+async fn docker<I, S>(args: I) -> Result<String, AnyError>
+where
+	I: IntoIterator<Item = S>,
+	S: AsRef<OsStr>,
+{
+	check_output(capture_command(DOCKER, args).await?)
+}
+
+fn check_output(output: std::process::Output) -> Result<String, AnyError> {
+	if !output.status.success() {
+		return Err(
+			DevContainerError(String::from_utf8_lossy(&output.stderr).trim().to_string()).into(),
+		);
+	}
+
+	Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Gets the workspace folder for a `devcontainer.json`, which is the folder
+/// containing its `.devcontainer` folder, if it's in one.
+fn workspace_folder_for(config: &Path) -> PathBuf {
+	let dir = config.parent().unwrap_or(config);
+	match dir.file_name() {
+		Some(name) if name == ".devcontainer" => dir.parent().unwrap_or(dir).to_path_buf(),
+		_ => dir.to_path_buf(),
+	}
+}
+
+fn parse_up_result(stdout: &str) -> Result<DevContainer, DevContainerError> {
+	let result = stdout
+		.lines()
+		.rev()
+		.find_map(|l| serde_json::from_str::<UpResult>(l).ok())
+		.ok_or_else(|| DevContainerError("devcontainer CLI did not report a result".to_string()))?;
+
+	match (result.outcome.as_str(), result.container_id) {
+		("success", Some(id)) => Ok(DevContainer {
+			id,
+			user: result.remote_user,
+			workdir: result.remote_workspace_folder,
+		}),
+		_ => Err(DevContainerError(result.message.unwrap_or_else(|| {
+			"devcontainer CLI failed to start the container".to_string()
+		}))),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_up_result() {
+		let container = parse_up_result(
+			"[2024-05-01T00:00:00.000Z] Start: Run: docker build\n{\"outcome\":\"success\",\"containerId\":\"f00d\",\"remoteUser\":\"node\",\"remoteWorkspaceFolder\":\"/workspaces/app\"}\n",
+		)
+		.unwrap();
+		assert_eq!(container.id, "f00d");
+		assert_eq!(container.user.as_deref(), Some("node"));
+		assert_eq!(container.workdir.as_deref(), Some("/workspaces/app"));
+
+		let err = parse_up_result("{\"outcome\":\"error\",\"message\":\"no docker\"}").unwrap_err();
+		assert_eq!(err.0, "no docker");
+		assert!(parse_up_result("").is_err());
+	}
+
+	#[test]
+	fn test_maps_paths_into_container() {
+		let paths = LauncherPaths::new_without_replacements(PathBuf::from("/home/me/.vscode-cli"));
+		let local = paths.root().join("server-stable").join("bin").join("abc");
+		assert_eq!(
+			DevContainer::path_in_container(&paths, &local),
+			"/tmp/.vscode-cli/server-stable/bin/abc"
+		);
+
+		assert_eq!(
+			workspace_folder_for(Path::new("/src/app/.devcontainer/devcontainer.json")),
+			PathBuf::from("/src/app")
+		);
+		assert_eq!(
+			workspace_folder_for(Path::new("/src/app/devcontainer.json")),
+			PathBuf::from("/src/app")
+		);
+	}
+}
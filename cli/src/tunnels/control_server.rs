@@ -5,40 +5,50 @@
 use crate::commands::tunnels::ShutdownSignal;
 use crate::constants::{CONTROL_PORT, PROTOCOL_VERSION, VSCODE_CLI_VERSION};
 use crate::log;
-use crate::self_update::SelfUpdate;
+use crate::options::UpdateChannel;
+use crate::self_update::{self, SelfUpdate};
 use crate::state::LauncherPaths;
 use crate::update_service::{Platform, UpdateService};
 use crate::util::errors::{
-	wrap, AnyError, MismatchedLaunchModeError, NoAttachedServerError, ServerWriteError,
+	wrap, AnyError, ClientNotAuthorized, ClientServerMismatch, HostAttestationFailed,
+	MismatchedLaunchModeError, NoAttachedServerError, ServerWriteError, UpdateVersionMismatch,
+	UpdatesNotConfigured,
 };
 use crate::util::io::SilentCopyProgress;
 use crate::util::sync::{new_barrier, Barrier};
+use clap::ArgEnum;
 use opentelemetry::trace::SpanKind;
 use opentelemetry::KeyValue;
 use serde::Serialize;
 use std::convert::Infallible;
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
-use tokio::pin;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{broadcast::error::RecvError, mpsc, Mutex};
 
+use super::attestation::HostAttestation;
+use super::client_auth;
 use super::code_server::{
 	AnyCodeServer, CodeServerArgs, ServerBuilder, ServerParamsRaw, SocketCodeServer,
 };
-use super::dev_tunnels::ActiveTunnel;
+use super::dev_tunnels::{self, ActiveTunnel};
+use super::handoff::{self, PendingHandoff};
 use super::paths::prune_stopped_servers;
 use super::port_forwarder::{PortForwarding, PortForwardingProcessor};
 use super::protocol::{
-	CallServerHttpParams, CallServerHttpResult, ClientRequestMethod, EmptyResult, ErrorResponse,
-	ForwardParams, ForwardResult, GetHostnameResponse, RefServerMessageParams, ResponseError,
-	ServeParams, ServerLog, ServerMessageParams, ServerRequestMethod, SuccessResponse,
+	AttestParams, AttestResult, CallServerHttpParams, CallServerHttpResult, ChallengeIssueResult,
+	ChallengeVerifyParams, ClientRequestMethod, EmptyResult, ErrorResponse, ForwardParams,
+	ForwardResult, GetHostnameResponse, NegotiateCompressionParams, NegotiateCompressionResult,
+	RefCompressedMessageParams, RefServerMessageParams, ResponseError, ServeParams, ServerLog,
+	ServerMessageParams, ServerRequestMethod, SubscribeLogsParams, SuccessResponse,
 	ToClientRequest, ToServerRequest, UnforwardParams, UpdateParams, UpdateResult, VersionParams,
 };
+use super::rpc_compression;
 use super::server_bridge::{get_socket_rw_stream, FromServerMessage, ServerBridge};
+use super::warm_server::WarmServer;
 
 type ServerBridgeList = Option<Vec<(u16, ServerBridge)>>;
 type ServerBridgeListLock = Arc<Mutex<ServerBridgeList>>;
@@ -66,6 +76,19 @@ struct HandlerContext {
 	port_forwarding: PortForwarding,
 	/// install platform for the VS Code server
 	platform: Platform,
+	/// source of the launcher's own logs, for clients that subscribe to them
+	log_broadcast: log::BroadcastLogSink,
+	/// whether the client has subscribed to the launcher's logs
+	subscribed_logs: bool,
+	/// channel to take CLI updates from
+	update_channel: Option<UpdateChannel>,
+	/// compression algorithm negotiated with the client, if any
+	compression: Option<&'static str>,
+	/// challenge issued to the client to sign, until it's verified
+	challenge: Option<String>,
+	/// whether the client may make calls, which it can't until it signs a
+	/// challenge when authorized keys are configured
+	authorized: bool,
 }
 
 impl HandlerContext {
@@ -113,6 +136,8 @@ enum SocketSignal {
 	CloseWith(CloseReason),
 	/// Disposes ServerBridge corresponding to an ID
 	CloseServerBridge(u16),
+	/// Sets whether bulky messages sent after this one are compressed
+	SetCompression(bool),
 }
 
 impl SocketSignal {
@@ -137,15 +162,84 @@ impl FromServerMessage for SocketSignal {
 	}
 }
 
+/// How often the server checks whether it's idle, when auto-updating.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How often to check for CLI updates, when auto-updating.
+const AUTO_UPDATE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How the server updates the CLI.
+#[derive(Clone, Default)]
+pub struct UpdateOptions {
+	/// Whether to periodically check for and install updates while idle.
+	pub auto_update: bool,
+	/// Channel to update from. Defaults to the CLI's own quality.
+	pub channel: Option<UpdateChannel>,
+	/// Binary to hand the tunnel over to when restarting, such as after an
+	/// update. If it's None, the tunnel goes offline while it restarts.
+	pub handoff_exe: Option<PathBuf>,
+}
+
 pub struct ServerTermination {
 	/// Whether the server should be respawned in a new binary (see ServerSignal.Respawn).
 	pub respawn: bool,
+	/// The signal the server was shut down with, if any.
+	pub shutdown: Option<ShutdownSignal>,
 	pub tunnel: ActiveTunnel,
+	/// The process the tunnel was handed over to, if it was restarted in place.
+	pub handed_off: Option<tokio::process::Child>,
+	/// The warm server, if hosting was paused, so it's kept running until
+	/// hosting resumes.
+	pub warm_server: Option<WarmServer>,
+}
+
+/// Starts a new process from `exe` to take the tunnel over, logging why if
+/// it can't be.
+fn start_handoff(
+	log: &log::Logger,
+	launcher_paths: &LauncherPaths,
+	tunnel: &ActiveTunnel,
+	exe: Option<&Path>,
+) -> Option<PendingHandoff> {
+	let exe = match exe {
+		Some(exe) => exe,
+		None => {
+			warning!(log, "This tunnel can't be restarted in place");
+			return None;
+		}
+	};
+
+	match PendingHandoff::start(launcher_paths, tunnel, exe) {
+		Ok(h) => {
+			info!(
+				log,
+				"Restarting from {}, the tunnel is served until the new process takes over",
+				exe.display()
+			);
+			Some(h)
+		}
+		Err(e) => {
+			warning!(log, "Could not restart the tunnel: {}", e);
+			None
+		}
+	}
 }
 
-fn print_listening(log: &log::Logger, tunnel_name: &str) {
+fn print_listening(log: &log::Logger, tunnel_name: &str, local_uri: Option<String>) {
 	debug!(log, "VS Code Server is listening for incoming connections");
 
+	if let Some(uri) = local_uri {
+		log.progress(log::ProgressFrame::TunnelState {
+			state: log::TunnelProgressState::Listening,
+			name: Some(tunnel_name),
+			uri: Some(&uri),
+		});
+		log.result(format!(
+			"\nClients on the local network can connect to {}\n",
+			uri
+		));
+		return;
+	}
+
 	let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from(""));
 	let current_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from(""));
 
@@ -168,52 +262,220 @@ fn print_listening(log: &log::Logger, tunnel_name: &str) {
 		}
 	}
 
+	log.progress(log::ProgressFrame::TunnelState {
+		state: log::TunnelProgressState::Listening,
+		name: Some(tunnel_name),
+		uri: Some(addr.as_str()),
+	});
+
 	let message = &format!("\nOpen this link in your browser {}\n", addr);
 	log.result(message);
 }
 
+/// Reports the URIs of the tunnel and its forwarded ports again, after they
+/// changed when the tunnel reconnected.
+async fn announce_endpoint_change(log: &log::Logger, tunnel: &mut ActiveTunnel) {
+	if let Some(uri) = tunnel.local_uri().await {
+		print_listening(log, &tunnel.name, Some(uri));
+	}
+
+	for port in tunnel.forwarded_ports() {
+		match tunnel.get_port_uri(port).await {
+			Ok(uri) => log.progress(log::ProgressFrame::PortUri { port, uri: &uri }),
+			Err(e) => warning!(log, "Could not get the new URI of port {}: {}", port, e),
+		}
+	}
+}
+
 // Runs the launcher server. Exits on a ctrl+c or when requested by a user.
 // Note that client connections may not be closed when this returns; use
 // `close_all_clients()` on the ServerTermination to make this happen.
+#[allow(clippy::too_many_arguments)]
 pub async fn serve(
 	log: &log::Logger,
 	mut tunnel: ActiveTunnel,
 	launcher_paths: &LauncherPaths,
 	code_server_args: &CodeServerArgs,
 	platform: Platform,
-	shutdown_rx: mpsc::Receiver<ShutdownSignal>,
+	log_broadcast: log::BroadcastLogSink,
+	update_options: UpdateOptions,
+	forwarding: &mut PortForwardingProcessor,
+	shutdown_rx: &mut mpsc::Receiver<ShutdownSignal>,
 ) -> Result<ServerTermination, AnyError> {
 	let mut port = tunnel.add_port_direct(CONTROL_PORT).await?;
-	print_listening(log, &tunnel.name);
+	let disconnected = tunnel.wait_for_disconnect();
+	tokio::pin!(disconnected);
+	let mut endpoint_changes = tunnel.endpoint_changes();
+	let local_uri = tunnel.local_uri().await;
+	print_listening(log, &tunnel.name, local_uri);
 
-	let mut forwarding = PortForwardingProcessor::new();
 	let (tx, mut rx) = mpsc::channel::<ServerSignal>(4);
 	let (exit_barrier, signal_exit) = new_barrier();
 
-	pin!(shutdown_rx);
+	let auto_update =
+		update_options.auto_update && update_options.channel != Some(UpdateChannel::Never);
+	let active_clients = Arc::new(AtomicUsize::new(0));
+	let mut warm_server = code_server_args.warm_server.clone().map(|options| {
+		WarmServer::start(
+			log.clone(),
+			launcher_paths.clone(),
+			code_server_args.clone(),
+			platform,
+			options,
+			active_clients.clone(),
+		)
+	});
+	let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+	let (update_done_tx, mut update_done_rx) = mpsc::channel::<bool>(1);
+	let mut last_update_check = Instant::now();
+	let mut checking_for_update = false;
+	let mut update_installed = false;
+	let mut budget_exceeded = false;
+	let mut handoff = None;
 
 	loop {
 		tokio::select! {
+			_ = idle_check.tick(), if auto_update => {
+				if active_clients.load(Ordering::SeqCst) > 0 {
+					continue;
+				}
+
+				if update_installed {
+					info!(log, "Restarting to finish installing the CLI update");
+					drop(signal_exit);
+					return Ok(ServerTermination {
+						respawn: true,
+						shutdown: None,
+						tunnel,
+						handed_off: None,
+						warm_server: None,
+					});
+				}
+
+				if !checking_for_update && last_update_check.elapsed() >= AUTO_UPDATE_INTERVAL {
+					checking_for_update = true;
+					last_update_check = Instant::now();
+
+					let log = log.clone();
+					let channel = update_options.channel;
+					let update_done_tx = update_done_tx.clone();
+					tokio::spawn(async move {
+						let installed = match install_available_update(&log, channel).await {
+							Ok(installed) => installed,
+							Err(e) => {
+								warning!(log, "Error checking for CLI updates: {}", e);
+								false
+							}
+						};
+						update_done_tx.send(installed).await.ok();
+					});
+				}
+			},
+			Some(installed) = update_done_rx.recv() => {
+				checking_for_update = false;
+				// restart on the next idle check, which happens once no clients are connected
+				update_installed = installed;
+			},
 			Some(r) = shutdown_rx.recv() => {
-				info!(log, "Shutting down: {}", r );
+				if let ShutdownSignal::RestartRequested(exe) = r {
+					if handoff.is_none() {
+						let exe = exe.or_else(|| update_options.handoff_exe.clone());
+						handoff = start_handoff(log, launcher_paths, &tunnel, exe.as_deref());
+					}
+					continue;
+				}
+				match r {
+					ShutdownSignal::Renamed(name) => {
+						info!(log, "The tunnel was renamed to {}", name);
+						tunnel.name = name;
+						let local_uri = tunnel.local_uri().await;
+						print_listening(log, &tunnel.name, local_uri);
+						continue;
+					}
+					ShutdownSignal::ResumeRequested => {
+						debug!(log, "Ignoring a request to resume, as hosting isn't paused");
+						continue;
+					}
+					ShutdownSignal::PauseRequested if handoff.is_some() => {
+						warning!(log, "Not pausing hosting, as the tunnel is being handed over");
+						continue;
+					}
+					_ => {}
+				}
+				let paused = matches!(r, ShutdownSignal::PauseRequested);
+
+				// with a restart pending, this is the new process taking over
+				let handed_off = handoff.take().map(PendingHandoff::into_child);
+				match handed_off.as_ref().and_then(|c| c.id()) {
+					Some(pid) => info!(log, "Handing the tunnel over to process {}", pid),
+					None if paused => info!(log, "Taking the tunnel off the relay to pause hosting"),
+					None => info!(log, "Shutting down: {}", r),
+				}
+				log.progress(log::ProgressFrame::TunnelState {
+					state: log::TunnelProgressState::Closed,
+					name: Some(&tunnel.name),
+					uri: None,
+				});
 				drop(signal_exit);
 				return Ok(ServerTermination {
 					respawn: false,
+					shutdown: Some(r),
 					tunnel,
+					handed_off,
+					warm_server: warm_server.take().filter(|_| paused),
 				});
 			},
+			reason = handoff::wait_for_failure(&mut handoff) => {
+				warning!(log, "The new process failed to take the tunnel over, as {}", reason);
+				if let Some(exe) = handoff.take().map(|h| h.exe().to_owned()) {
+					// an update that doesn't start is put back, as on a respawn
+					if self_update::has_rollback(&exe) {
+						if let Err(e) = self_update::rollback(&exe) {
+							error!(log, "Error rolling back the CLI update: {}", e);
+						}
+					}
+				}
+			},
 			c = rx.recv() => {
 				if let Some(ServerSignal::Respawn) = c {
+					// hand over to the updated CLI, so the tunnel stays up for
+					// clients to reconnect to
+					if handoff.is_none() {
+						let exe = update_options.handoff_exe.as_deref();
+						handoff = start_handoff(log, launcher_paths, &tunnel, exe);
+					}
+					if handoff.is_some() {
+						continue;
+					}
+
 					drop(signal_exit);
 					return Ok(ServerTermination {
 						respawn: true,
+						shutdown: None,
 						tunnel,
+						handed_off: None,
+						warm_server: None,
 					});
 				}
 			},
 			Some(w) = forwarding.recv() => {
 				forwarding.process(w, &mut tunnel).await;
 			},
+			Ok(()) = endpoint_changes.changed() => {
+				info!(log, "The tunnel reconnected at different URIs, updating forwarded ports");
+				announce_endpoint_change(log, &mut tunnel).await;
+			},
+			_ = &mut disconnected => {
+				warning!(log, "The tunnel stopped reconnecting, tearing down");
+				drop(signal_exit);
+				return Ok(ServerTermination {
+					respawn: false,
+					shutdown: None,
+					tunnel,
+					handed_off: None,
+					warm_server: None,
+				});
+			},
 			l = port.recv() => {
 				let socket = match l {
 					Some(p) => p,
@@ -221,17 +483,43 @@ pub async fn serve(
 						warning!(log, "ssh tunnel disposed, tearing down");
 						return Ok(ServerTermination {
 							respawn: false,
+							shutdown: None,
 							tunnel,
+							handed_off: None,
+							warm_server: None,
 						});
 					}
 				};
 
+				match code_server_args.bandwidth_budget.check(launcher_paths) {
+					Some(exceeded) => {
+						if !budget_exceeded {
+							let message = format!("Refusing new connections, as {}", exceeded);
+							warning!(log, "{}", message);
+							log.progress(log::ProgressFrame::BudgetExceeded { message: &message });
+							budget_exceeded = true;
+						}
+						drop(socket);
+						continue;
+					}
+					None if budget_exceeded => {
+						info!(log, "Data transfer budget available again, accepting connections");
+						log.progress(log::ProgressFrame::BudgetAvailable);
+						budget_exceeded = false;
+					}
+					None => {}
+				}
+
 				let own_log = log.prefixed(&log::new_rpc_prefix());
 				let own_tx = tx.clone();
 				let own_paths = launcher_paths.clone();
 				let own_exit = exit_barrier.clone();
 				let own_code_server_args = code_server_args.clone();
 				let own_forwarding = forwarding.handle();
+				let own_log_broadcast = log_broadcast.clone();
+				let own_update_channel = update_options.channel;
+				let own_active_clients = active_clients.clone();
+				own_active_clients.fetch_add(1, Ordering::SeqCst);
 
 				tokio::spawn(async move {
 					use opentelemetry::trace::{FutureExt, TraceContextExt};
@@ -241,9 +529,10 @@ pub async fn serve(
 					let serve_at = Instant::now();
 
 					debug!(own_log, "Serving new connection");
+					own_log.progress(log::ProgressFrame::ClientConnected);
 
 					let (writehalf, readhalf) = socket.into_split();
-					let stats = process_socket(own_exit, readhalf, writehalf, own_log, own_tx, own_paths, own_code_server_args, own_forwarding, platform).with_context(cx.clone()).await;
+					let stats = process_socket(own_exit, readhalf, writehalf, own_log.clone(), own_tx, own_paths, own_code_server_args, own_forwarding, platform, own_log_broadcast, own_update_channel).with_context(cx.clone()).await;
 
 					cx.span().add_event(
 						"socket.bandwidth",
@@ -254,6 +543,12 @@ pub async fn serve(
 						],
 					);
 					cx.span().end();
+					own_log.progress(log::ProgressFrame::ClientDisconnected {
+						bytes_received: stats.rx as u64,
+						bytes_sent: stats.tx as u64,
+						duration_ms: serve_at.elapsed().as_millis() as u64,
+					});
+					own_active_clients.fetch_sub(1, Ordering::SeqCst);
 				 });
 			}
 		}
@@ -276,6 +571,8 @@ async fn process_socket(
 	code_server_args: CodeServerArgs,
 	port_forwarding: PortForwarding,
 	platform: Platform,
+	log_broadcast: log::BroadcastLogSink,
+	update_channel: Option<UpdateChannel>,
 ) -> SocketStats {
 	let (socket_tx, mut socket_rx) = mpsc::channel(4);
 
@@ -286,6 +583,7 @@ async fn process_socket(
 	let barrier_ctx = exit_barrier.clone();
 	let log_ctx = log.clone();
 	let rx_counter_ctx = rx_counter.clone();
+	let requires_challenge = code_server_args.authorized_keys.is_some();
 
 	tokio::spawn(async move {
 		let mut ctx = HandlerContext {
@@ -300,9 +598,15 @@ async fn process_socket(
 			server_bridges: server_bridges_lock,
 			port_forwarding,
 			platform,
+			log_broadcast,
+			subscribed_logs: false,
+			update_channel,
+			compression: None,
+			challenge: None,
+			authorized: !requires_challenge,
 		};
 
-		send_version(&ctx.socket_tx).await;
+		send_version(&ctx.socket_tx, requires_challenge).await;
 
 		if let Err(e) = handle_socket_read(readhalf, &mut ctx).await {
 			debug!(ctx.log, "closing socket reader: {}", e);
@@ -316,6 +620,7 @@ async fn process_socket(
 	});
 
 	let mut tx_counter = 0;
+	let mut compress = false;
 
 	loop {
 		tokio::select! {
@@ -327,6 +632,13 @@ async fn process_socket(
 				None => break,
 				Some(message) => match message {
 					SocketSignal::Send(bytes) => {
+						let bytes = match compress.then(|| rpc_compression::compress(&bytes)).flatten() {
+							Some(data) => rmp_serde::to_vec_named(&ToClientRequest {
+								id: None,
+								params: ClientRequestMethod::compressed(RefCompressedMessageParams { data: &data }),
+							}).unwrap(),
+							None => bytes,
+						};
 						tx_counter += bytes.len();
 						if let Err(e) = writehalf.write_all(&bytes).await {
 							debug!(log, "Closing connection: {}", e);
@@ -348,6 +660,7 @@ async fn process_socket(
 							None => {}
 						}
 					}
+					SocketSignal::SetCompression(c) => compress = c,
 				}
 			}
 		}
@@ -359,12 +672,13 @@ async fn process_socket(
 	}
 }
 
-async fn send_version(tx: &mpsc::Sender<SocketSignal>) {
+async fn send_version(tx: &mpsc::Sender<SocketSignal>, requires_challenge: bool) {
 	tx.send(SocketSignal::from_message(&ToClientRequest {
 		id: None,
 		params: ClientRequestMethod::version(VersionParams {
 			version: VSCODE_CLI_VERSION.unwrap_or("dev"),
 			protocol_version: PROTOCOL_VERSION,
+			requires_challenge,
 		}),
 	}))
 	.await
@@ -414,7 +728,7 @@ async fn read_next(
 		_ = ctx.closer.wait() => return Ok(false),
 	};
 
-	let req = match rmp_serde::from_slice::<ToServerRequest>(decode_buf) {
+	let mut req = match rmp_serde::from_slice::<ToServerRequest>(decode_buf) {
 		Ok(req) => req,
 		Err(e) => {
 			warning!(ctx.log, "Error decoding message: {}", e);
@@ -422,6 +736,16 @@ async fn read_next(
 		}
 	};
 
+	if let ServerRequestMethod::compressed(c) = &req.params {
+		req = match decode_compressed(ctx, &c.data) {
+			Ok(req) => req,
+			Err(e) => {
+				warning!(ctx.log, "Error decoding compressed message: {}", e);
+				return Ok(true); // not fatal
+			}
+		};
+	}
+
 	let log = ctx.log.prefixed(
 		req.id
 			.map(|id| format!("[call.{}]", id))
@@ -461,6 +785,35 @@ async fn read_next(
 		};
 	}
 
+	// until a client signs a challenge, it can do nothing but ask for one
+	if !ctx.authorized
+		&& !matches!(
+			req.params,
+			ServerRequestMethod::ping(_)
+				| ServerRequestMethod::challenge_issue(_)
+				| ServerRequestMethod::challenge_verify(_)
+				| ServerRequestMethod::attest(_)
+		) {
+		warning!(log, "Refusing call from a client that isn't authorized yet");
+		if let Some(id) = req.id {
+			let res = rmp_serde::to_vec_named(&ErrorResponse {
+				id,
+				error: ResponseError {
+					code: -1,
+					message: "the client must sign a challenge with challenge_verify first"
+						.to_string(),
+				},
+			})
+			.unwrap();
+			if ctx.socket_tx.send(SocketSignal::Send(res)).await.is_err() {
+				return Ok(false);
+			}
+		}
+		return Ok(true);
+	}
+
+	let mut negotiated = false;
+	let mut refused = false;
 	let response = match req.params {
 		ServerRequestMethod::ping(_) => success!(EmptyResult {}),
 		ServerRequestMethod::serve(p) => tj!("serve", handle_serve(ctx, &log, p)),
@@ -484,6 +837,26 @@ async fn read_next(
 		}
 		ServerRequestMethod::forward(p) => tj!("forward", handle_forward(ctx, p)),
 		ServerRequestMethod::unforward(p) => tj!("unforward", handle_unforward(ctx, p)),
+		ServerRequestMethod::subscribelogs(p) => {
+			tj!("subscribelogs", handle_subscribe_logs(ctx, p))
+		}
+		ServerRequestMethod::negotiatecompression(p) => {
+			negotiated = true;
+			tj!("negotiatecompression", handle_negotiate_compression(ctx, p))
+		}
+		ServerRequestMethod::compressed(_) => {
+			warning!(log, "Ignoring nested compressed message");
+			None
+		}
+		ServerRequestMethod::challenge_issue(_) => {
+			tj!("challenge_issue", handle_challenge_issue(ctx))
+		}
+		ServerRequestMethod::challenge_verify(p) => {
+			let r = tj!("challenge_verify", handle_challenge_verify(ctx, p));
+			refused = !ctx.authorized;
+			r
+		}
+		ServerRequestMethod::attest(p) => tj!("attest", handle_attest(ctx, p)),
 	};
 
 	if let Some(Ok(res)) = response {
@@ -492,6 +865,26 @@ async fn read_next(
 		}
 	}
 
+	// a client only gets one try at a challenge
+	if refused {
+		ctx.socket_tx
+			.send(SocketSignal::CloseWith(CloseReason(
+				"client not authorized".to_string(),
+			)))
+			.await
+			.ok();
+		return Ok(false);
+	}
+
+	// the response to the negotiation itself is sent uncompressed
+	if negotiated {
+		let compress = ctx.compression.is_some();
+		ctx.socket_tx
+			.send(SocketSignal::SetCompression(compress))
+			.await
+			.ok();
+	}
+
 	Ok(true)
 }
 
@@ -521,12 +914,21 @@ async fn handle_serve(
 	log: &log::Logger,
 	params: ServeParams,
 ) -> Result<EmptyResult, AnyError> {
+	check_protocol_version(params.protocol_version)?;
 	let mut code_server_args = ctx.code_server_args.clone();
 
 	// fill params.extensions into code_server_args.install_extensions
-	code_server_args
-		.install_extensions
-		.extend(params.extensions.into_iter());
+	let (allowed, denied) = code_server_args
+		.extension_filter
+		.partition(params.extensions);
+	if !denied.is_empty() {
+		warning!(
+			log,
+			"Not installing extensions the host's extension filter skips: {}",
+			denied.join(", ")
+		);
+	}
+	code_server_args.install_extensions.extend(allowed);
 
 	let resolved = ServerParamsRaw {
 		commit_id: params.commit_id,
@@ -538,7 +940,9 @@ async fn handle_serve(
 	.resolve(log)
 	.await?;
 
-	if ctx.code_server.is_none() {
+	if let Some(running) = &ctx.code_server {
+		check_server_commit(&resolved.release.commit, &running.commit_id)?;
+	} else {
 		let install_log = log.tee(ServerOutputSink {
 			tx: ctx.socket_tx.clone(),
 		});
@@ -548,8 +952,11 @@ async fn handle_serve(
 			Some(AnyCodeServer::Socket(s)) => s,
 			Some(_) => return Err(AnyError::from(MismatchedLaunchModeError())),
 			None => {
-				sb.setup().await?;
-				sb.listen_on_default_socket().await?
+				let started = match sb.setup().await {
+					Ok(()) => sb.listen_on_default_socket().await,
+					Err(e) => Err(e),
+				};
+				started.map_err(|e| explain_start_failure(e, &resolved.code_server_args))?
 			}
 		};
 
@@ -560,6 +967,47 @@ async fn handle_serve(
 	Ok(EmptyResult {})
 }
 
+/// Checks that the host understands the client's control protocol. Clients
+/// that speak an older version are served, as the protocol is only added to.
+fn check_protocol_version(client_version: Option<u32>) -> Result<(), ClientServerMismatch> {
+	match client_version {
+		Some(v) if v > PROTOCOL_VERSION => Err(ClientServerMismatch(format!(
+			"the client uses protocol version {} but this host only supports up to version {}. Update the CLI on the host with `code tunnel update`",
+			v, PROTOCOL_VERSION
+		))),
+		_ => Ok(()),
+	}
+}
+
+/// Checks that the server already attached to the connection is the one the
+/// client asked for, since a client can't talk to a server of another commit.
+fn check_server_commit(requested: &str, running: &str) -> Result<(), ClientServerMismatch> {
+	if requested == running {
+		return Ok(());
+	}
+
+	Err(ClientServerMismatch(format!(
+		"the client needs server {} but this connection is already attached to server {}. Reconnect to start a separate server",
+		requested, running
+	)))
+}
+
+/// Adds a hint to errors starting a server that runs under emulation, as
+/// those usually mean the machine can't run programs for its architecture.
+fn explain_start_failure(e: AnyError, args: &CodeServerArgs) -> AnyError {
+	match args.host_arch {
+		Some(host) => wrap(
+			e,
+			format!(
+				"error starting a server for another architecture on this {} machine. Check that it can run such programs, such as with Rosetta on macOS or qemu-user on Linux, or remove --server-arch",
+				host
+			),
+		)
+		.into(),
+		None => e,
+	}
+}
+
 async fn attach_server_bridge(ctx: &mut HandlerContext, socket_id: u16) -> Result<u16, AnyError> {
 	let attached_fut = ServerBridge::new(
 		&ctx.code_server.as_ref().unwrap().socket,
@@ -619,7 +1067,7 @@ async fn handle_update(
 	params: &UpdateParams,
 ) -> Result<UpdateResult, AnyError> {
 	let update_service = UpdateService::new(ctx.log.clone(), reqwest::Client::new());
-	let updater = SelfUpdate::new(&update_service)?;
+	let updater = create_updater(&update_service, ctx.update_channel)?;
 	let latest_release = updater.get_current_release().await?;
 	let up_to_date = updater.is_up_to_date_with(&latest_release);
 
@@ -627,18 +1075,116 @@ async fn handle_update(
 		return Ok(UpdateResult {
 			up_to_date,
 			did_update: false,
+			commit: latest_release.commit,
 		});
 	}
 
+	if let Some(expected) = &params.expected_commit {
+		if *expected != latest_release.commit {
+			return Err(UpdateVersionMismatch(expected.clone(), latest_release.commit).into());
+		}
+	}
+
 	info!(ctx.log, "Updating CLI to {}", latest_release);
 
+	// keep the current CLI, so it can be restored if the new one fails to restart
 	updater
-		.do_update(&latest_release, SilentCopyProgress())
+		.do_update_with_rollback(&latest_release, SilentCopyProgress())
 		.await?;
 
 	Ok(UpdateResult {
 		up_to_date: true,
 		did_update: true,
+		commit: latest_release.commit,
+	})
+}
+
+fn create_updater(
+	update_service: &UpdateService,
+	channel: Option<UpdateChannel>,
+) -> Result<SelfUpdate<'_>, AnyError> {
+	let updater = SelfUpdate::new(update_service)?;
+	updater.install_source()?.ensure_standalone()?;
+	match channel.map(|c| c.quality()) {
+		None => Ok(updater),
+		Some(Some(quality)) => Ok(updater.with_quality(quality)),
+		Some(None) => Err(UpdatesNotConfigured("updates are disabled".to_string()).into()),
+	}
+}
+
+/// Installs the latest CLI release, if this isn't it already. Returns whether
+/// an update was installed. The new CLI is used once the server restarts.
+async fn install_available_update(
+	log: &log::Logger,
+	channel: Option<UpdateChannel>,
+) -> Result<bool, AnyError> {
+	let update_service = UpdateService::new(log.clone(), reqwest::Client::new());
+	let updater = create_updater(&update_service, channel)?;
+	let latest_release = updater.get_current_release().await?;
+	if updater.is_up_to_date_with(&latest_release) {
+		debug!(log, "CLI is up to date");
+		return Ok(false);
+	}
+
+	info!(log, "Installing CLI update {}", latest_release);
+	updater
+		.do_update_with_rollback(&latest_release, SilentCopyProgress())
+		.await?;
+	Ok(true)
+}
+
+async fn handle_challenge_issue(
+	ctx: &mut HandlerContext,
+) -> Result<ChallengeIssueResult, Infallible> {
+	let challenge = client_auth::new_challenge();
+	ctx.challenge = Some(challenge.clone());
+	Ok(ChallengeIssueResult {
+		challenge,
+		namespace: client_auth::SIGNATURE_NAMESPACE,
+	})
+}
+
+async fn handle_challenge_verify(
+	ctx: &mut HandlerContext,
+	params: ChallengeVerifyParams,
+) -> Result<EmptyResult, AnyError> {
+	let keys = match &ctx.code_server_args.authorized_keys {
+		Some(keys) => keys,
+		None => return Ok(EmptyResult {}),
+	};
+
+	let challenge = ctx
+		.challenge
+		.take()
+		.ok_or_else(|| ClientNotAuthorized("no challenge was issued".to_string()))?;
+	let fingerprint = keys.verify(&challenge, &params.signature)?;
+	info!(ctx.log, "Client authorized with the key {}", fingerprint);
+	ctx.authorized = true;
+	Ok(EmptyResult {})
+}
+
+async fn handle_attest(
+	ctx: &HandlerContext,
+	params: AttestParams,
+) -> Result<AttestResult, AnyError> {
+	let key = ctx
+		.code_server_args
+		.attestation_key
+		.as_ref()
+		.ok_or_else(|| HostAttestationFailed("the host is not attested".to_string()))?;
+	// only the launcher tunnel's attestation is published
+	let tunnel = dev_tunnels::get_persisted_tunnel(&ctx.launcher_paths).ok_or_else(|| {
+		HostAttestationFailed("the host is not serving its own tunnel".to_string())
+	})?;
+
+	let (attestation, signature) =
+		HostAttestation::answer_challenge(key, &tunnel.id, &params.nonce).await?;
+	Ok(AttestResult {
+		machine: attestation.machine,
+		os: attestation.os,
+		arch: attestation.arch,
+		tunnel: attestation.tunnel,
+		signature,
 	})
 }
 
@@ -648,12 +1194,42 @@ async fn handle_get_hostname() -> Result<GetHostnameResponse, Infallible> {
 	})
 }
 
+/// Decodes a message the client sent compressed.
+fn decode_compressed(ctx: &HandlerContext, data: &[u8]) -> Result<ToServerRequest, AnyError> {
+	if ctx.compression.is_none() {
+		return Err(wrap("", "compression was not negotiated").into());
+	}
+
+	let message =
+		rpc_compression::decompress(data).map_err(|e| wrap(e, "error decompressing message"))?;
+	rmp_serde::from_slice::<ToServerRequest>(&message)
+		.map_err(|e| wrap(e, "error decoding message").into())
+}
+
+async fn handle_negotiate_compression(
+	ctx: &mut HandlerContext,
+	params: NegotiateCompressionParams,
+) -> Result<NegotiateCompressionResult, Infallible> {
+	let algorithm = rpc_compression::negotiate(&params);
+	match algorithm {
+		Some(a) => info!(ctx.log, "Compressing messages with {}", a),
+		None => debug!(ctx.log, "Not compressing messages"),
+	}
+
+	ctx.compression = algorithm;
+	Ok(NegotiateCompressionResult { algorithm })
+}
+
 async fn handle_forward(
 	ctx: &HandlerContext,
 	params: ForwardParams,
 ) -> Result<ForwardResult, AnyError> {
 	info!(ctx.log, "Forwarding port {}", params.port);
-	let uri = ctx.port_forwarding.forward(params.port).await?;
+	let uri = ctx.port_forwarding.forward(params.port, None).await?;
+	ctx.log.progress(log::ProgressFrame::PortUri {
+		port: params.port,
+		uri: &uri,
+	});
 	Ok(ForwardResult { uri })
 }
 
@@ -663,6 +1239,61 @@ async fn handle_unforward(
 ) -> Result<EmptyResult, AnyError> {
 	info!(ctx.log, "Unforwarding port {}", params.port);
 	ctx.port_forwarding.unforward(params.port).await?;
+	ctx.log
+		.progress(log::ProgressFrame::PortUnforwarded { port: params.port });
+	Ok(EmptyResult {})
+}
+
+async fn handle_subscribe_logs(
+	ctx: &mut HandlerContext,
+	params: SubscribeLogsParams,
+) -> Result<EmptyResult, Infallible> {
+	if ctx.subscribed_logs {
+		return Ok(EmptyResult {});
+	}
+
+	ctx.subscribed_logs = true;
+	let (_, mut rx) = ctx.log_broadcast.subscribe();
+	let socket_tx = ctx.socket_tx.clone();
+	let mut closer = ctx.closer.clone();
+
+	tokio::spawn(async move {
+		loop {
+			let frame = tokio::select! {
+				f = rx.recv() => f,
+				_ = closer.wait() => return,
+			};
+
+			let frame = match frame {
+				Ok(f) => f,
+				Err(RecvError::Lagged(_)) => continue,
+				Err(RecvError::Closed) => return,
+			};
+
+			let (level, message) = match serde_json::from_str(&frame) {
+				Ok(log::BroadcastFrame::Log { level, message }) => (level, message),
+				_ => continue,
+			};
+
+			let level = match log::Level::from_str(&level, true) {
+				Ok(l) if l.to_u8() >= params.level => l,
+				_ => continue,
+			};
+
+			let s = SocketSignal::from_message(&ToClientRequest {
+				id: None,
+				params: ClientRequestMethod::clilog(ServerLog {
+					line: &message,
+					level: level.to_u8(),
+				}),
+			});
+
+			if socket_tx.send(s).await.is_err() {
+				return;
+			}
+		}
+	});
+
 	Ok(EmptyResult {})
 }
 
@@ -720,3 +1351,20 @@ async fn handle_call_server_http(
 			.to_vec(),
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_checks_client_compatibility() {
+		assert!(check_protocol_version(None).is_ok());
+		assert!(check_protocol_version(Some(PROTOCOL_VERSION)).is_ok());
+		assert!(check_protocol_version(Some(PROTOCOL_VERSION - 1)).is_ok());
+		assert!(check_protocol_version(Some(PROTOCOL_VERSION + 1)).is_err());
+
+		assert!(check_server_commit("abc", "abc").is_ok());
+		let e = check_server_commit("abc", "def").unwrap_err();
+		assert!(e.to_string().contains("needs server abc"));
+	}
+}
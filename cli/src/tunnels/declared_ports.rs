@@ -0,0 +1,125 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::str::FromStr;
+
+use tunnels::contracts::{
+	TunnelPort, TUNNEL_PROTOCOL_AUTO, TUNNEL_PROTOCOL_HTTP, TUNNEL_PROTOCOL_HTTPS,
+};
+
+use crate::util::errors::InvalidDeclaredPort;
+
+use super::port_access::{PortAccessRules, PortPrivacy};
+
+/// A port declared up front, which is included in the request that creates
+/// the tunnel so that it's exposed as soon as the host first connects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeclaredPort {
+	pub port: u16,
+	pub protocol: &'static str,
+	/// Whether anyone can connect to the port without signing in.
+	pub public: bool,
+}
+
+impl DeclaredPort {
+	/// Gets the port as sent to the tunnel service, with any access rules
+	/// for it.
+	pub fn to_tunnel_port(&self, rules: &PortAccessRules) -> TunnelPort {
+		let mut access_control = rules.access_control(self.port);
+		if self.public {
+			access_control = PortPrivacy::Public.apply_to(access_control);
+		}
+
+		TunnelPort {
+			port_number: self.port,
+			protocol: Some(self.protocol.to_string()),
+			access_control,
+			..Default::default()
+		}
+	}
+}
+
+/// Parses ports given as `<port>[:<protocol>][:public|private]`, where the
+/// protocol is `auto`, `http`, or `https`.
+impl FromStr for DeclaredPort {
+	type Err = InvalidDeclaredPort;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut parts = s.split(':');
+		let port = parts
+			.next()
+			.and_then(|p| p.parse::<u16>().ok())
+			.ok_or_else(|| InvalidDeclaredPort(format!("invalid port in '{}'", s)))?;
+
+		let mut declared = DeclaredPort {
+			port,
+			protocol: TUNNEL_PROTOCOL_AUTO,
+			public: false,
+		};
+
+		for part in parts {
+			match part {
+				"auto" => declared.protocol = TUNNEL_PROTOCOL_AUTO,
+				"http" => declared.protocol = TUNNEL_PROTOCOL_HTTP,
+				"https" => declared.protocol = TUNNEL_PROTOCOL_HTTPS,
+				"public" => declared.public = true,
+				"private" => declared.public = false,
+				_ => {
+					return Err(InvalidDeclaredPort(format!(
+						"unknown protocol or visibility '{}' in '{}'",
+						part, s
+					)))
+				}
+			}
+		}
+
+		Ok(declared)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tunnels::contracts::TunnelAccessControlEntryType;
+
+	#[test]
+	fn test_parses_declared_ports() {
+		assert_eq!(
+			"8080".parse::<DeclaredPort>().unwrap(),
+			DeclaredPort {
+				port: 8080,
+				protocol: TUNNEL_PROTOCOL_AUTO,
+				public: false
+			}
+		);
+		assert_eq!(
+			"3000:https:public".parse::<DeclaredPort>().unwrap(),
+			DeclaredPort {
+				port: 3000,
+				protocol: TUNNEL_PROTOCOL_HTTPS,
+				public: true
+			}
+		);
+		assert!("3000:ftp".parse::<DeclaredPort>().is_err());
+		assert!("http".parse::<DeclaredPort>().is_err());
+	}
+
+	#[test]
+	fn test_public_port_allows_anonymous() {
+		let rules = PortAccessRules::parse(&["3000=10.0.0.0/8"]).unwrap();
+		let port = "3000:public"
+			.parse::<DeclaredPort>()
+			.unwrap()
+			.to_tunnel_port(&rules);
+
+		let entries = port.access_control.unwrap().entries;
+		assert_eq!(entries.len(), 2);
+		assert!(matches!(
+			entries[0].kind,
+			TunnelAccessControlEntryType::Anonymous
+		));
+		assert!(entries[1].is_deny);
+	}
+}
@@ -2,34 +2,62 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use crate::auth;
-use crate::constants::{CONTROL_PORT, TUNNEL_SERVICE_USER_AGENT};
-use crate::state::{LauncherPaths, PersistedState};
+use crate::constants::CONTROL_PORT;
+use crate::log::SecurityEventKind;
+use crate::state::{LauncherPaths, Migration, PersistedState};
 use crate::util::errors::{
-	wrap, AnyError, DevTunnelError, InvalidTunnelName, TunnelCreationFailed, WrappedError,
+	get_interception_error, get_request_interception_error, wrap, AnyError, DevTunnelError,
+	PortLimitExceeded, TunnelCreationFailed, TunnelNameRequired, TunnelOwnedByOtherAccount,
+	WrappedError,
 };
 use crate::util::input::prompt_placeholder;
-use crate::{debug, info, log, spanf, trace, warning};
+use crate::util::sync::cancellable;
+use crate::{debug, error, info, log, spanf, trace, warning};
 use async_trait::async_trait;
-use futures::TryFutureExt;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::stream::{self, StreamExt};
 use rand::prelude::IteratorRandom;
-use regex::Regex;
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::sync::{mpsc, watch};
-use tunnels::connections::{ForwardedPortConnection, RelayTunnelHost};
 use tunnels::contracts::{
-	Tunnel, TunnelPort, TunnelRelayTunnelEndpoint, PORT_TOKEN, TUNNEL_PROTOCOL_AUTO,
+	Tunnel, TunnelAccessControl, TunnelPort, TunnelRelayTunnelEndpoint, PORT_TOKEN,
+	TUNNEL_PROTOCOL_AUTO,
 };
 use tunnels::management::{
-	new_tunnel_management, HttpError, TunnelLocator, TunnelManagementClient, TunnelRequestOptions,
-	NO_REQUEST_OPTIONS,
+	new_tunnel_management, AuthorizationProvider, HttpError, HttpResult, TunnelLocator,
+	TunnelRequestOptions, NO_REQUEST_OPTIONS,
 };
 
+use super::activity::ActivityTracker;
+use super::attestation::HostAttestation;
+use super::backend::TunnelBackend;
+use super::backoff::{Backoff, BackoffConfig};
+use super::bandwidth_budget::meter_transfer;
+use super::capabilities::HostCapabilities;
+use super::failover::{self, FailoverOptions};
+use super::guest::{self, GUEST_TUNNEL_TAG};
+use super::local_forwarding::{forward_to_host, ForwardTargets, DEFAULT_FORWARD_BUFFER_SIZE};
+use super::local_relay::LOCAL_CONNECTION_MODE;
 use super::name_generator;
-
+use super::port_access::{PortAccessRules, PortPrivacy};
+use super::port_connection::PortConnection;
+use super::port_metrics::meter_connections;
+use super::rate_limit::RateLimitedClient;
+use super::security_events::SecurityMonitor;
+use super::service_limits::{CachedServiceLimits, ServiceLimits};
+use super::setup::TunnelSetup;
+use super::status::{ConnectionStatus, ConnectionTracker};
+use super::tunnel_service::{RelayHost, ServiceManagementClient, SharedManagementClient};
+
+/// The tunnel this machine hosts, saved between runs. Changing its fields
+/// needs a migration for what earlier versions saved, added to
+/// `PERSISTED_TUNNEL_MIGRATIONS`.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PersistedTunnel {
 	pub name: String,
@@ -37,7 +65,16 @@ pub struct PersistedTunnel {
 	pub cluster: String,
 }
 
+/// Migrations of the saved tunnel from each earlier schema version, see
+/// `PersistedState::with_migrations`. It hasn't changed yet.
+const PERSISTED_TUNNEL_MIGRATIONS: &[Migration] = &[];
+
 impl PersistedTunnel {
+	/// Gets the state of the tunnel saved at the path.
+	pub fn state(path: PathBuf) -> PersistedState<Option<PersistedTunnel>> {
+		PersistedState::with_migrations(path, PERSISTED_TUNNEL_MIGRATIONS)
+	}
+
 	pub fn into_locator(self) -> TunnelLocator {
 		TunnelLocator::ID {
 			cluster: self.cluster,
@@ -52,14 +89,74 @@ impl PersistedTunnel {
 	}
 }
 
+/// Source of the host access token used to connect a tunnel to the relay.
+/// Embedders can implement this to supply tokens from their own store,
+/// and pass it to `DevTunnels::start_existing_tunnel_with_token_provider`.
 #[async_trait]
-trait AccessTokenProvider: Send + Sync {
-	/// Gets the current access token.
+pub trait AccessTokenProvider: Send + Sync {
+	/// Gets the current access token. This is called each time the tunnel
+	/// connects or reconnects to the relay, so implementations should
+	/// return a fresh token if the previous one may have expired. Errors
+	/// are logged and the connection is retried with a backoff.
 	async fn refresh_token(&self) -> Result<String, WrappedError>;
+
+	/// Gets when a token from `refresh_token` expires, if known. The tunnel
+	/// reconnects with a new token shortly before then. By default, this is
+	/// read from the `exp` claim of JWTs.
+	fn expires_at(&self, token: &str) -> Option<SystemTime> {
+		get_token_expiry(token)
+	}
+
+	/// Gets how long a token from `refresh_token` was issued for, if known.
+	/// Both ends of it come from the service's clock, so it bounds when the
+	/// tunnel reconnects even if the local clock is skewed from the service's.
+	/// By default, this is read from the `iat` and `exp` claims of JWTs.
+	fn lifetime(&self, token: &str) -> Option<Duration> {
+		get_token_lifetime(token)
+	}
+
+	/// Checks, while connected with `token`, whether the service would still
+	/// issue the same token, so the tunnel can reconnect if it's been rotated
+	/// or its permissions changed. Called every `TOKEN_REVALIDATE_INTERVAL`.
+	/// By default, tokens are taken to stay current until they expire.
+	async fn revalidate(&self, _token: &str) -> Result<TokenCheck, WrappedError> {
+		Ok(TokenCheck::Current)
+	}
+
+	/// Whether `last_client_connection` can tell when a client connects, so
+	/// the tunnel can sleep while idle and be woken by one. By default, it
+	/// can't, and the tunnel stays connected.
+	///
+	/// Waking relies on the service updating the tunnel's last client
+	/// connection time when a client tries to connect while no host is
+	/// connected to the relay. If it doesn't, a sleeping tunnel isn't woken
+	/// until it's restarted, which is why sleeping is opt-in.
+	fn can_wake(&self) -> bool {
+		false
+	}
+
+	/// Gets when the service last saw a client connect to the tunnel, by the
+	/// service's clock.
+	async fn last_client_connection(&self) -> Result<Option<DateTime<Utc>>, WrappedError> {
+		Ok(None)
+	}
+}
+
+/// Result of checking a connected tunnel's access token against the service.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenCheck {
+	/// The token is still the one the service would issue.
+	Current,
+	/// The service now issues a different token, for the given reason. The
+	/// next `refresh_token` returns it.
+	Changed(String),
+	/// The service no longer issues a token for the tunnel, for the given
+	/// reason, such as after this account's access was revoked.
+	Revoked(String),
 }
 
 /// Access token provider that provides a fixed token without refreshing.
-struct StaticAccessTokenProvider(String);
+pub struct StaticAccessTokenProvider(String);
 
 impl StaticAccessTokenProvider {
 	pub fn new(token: String) -> Self {
@@ -72,11 +169,16 @@ impl AccessTokenProvider for StaticAccessTokenProvider {
 	async fn refresh_token(&self) -> Result<String, WrappedError> {
 		Ok(self.0.clone())
 	}
+
+	// reconnecting with the same token wouldn't extend the connection
+	fn expires_at(&self, _token: &str) -> Option<SystemTime> {
+		None
+	}
 }
 
 /// Access token provider that looks up the token from the tunnels API.
 struct LookupAccessTokenProvider {
-	client: TunnelManagementClient,
+	client: SharedManagementClient,
 	locator: TunnelLocator,
 	log: log::Logger,
 	initial_token: Arc<Mutex<Option<String>>>,
@@ -84,7 +186,7 @@ struct LookupAccessTokenProvider {
 
 impl LookupAccessTokenProvider {
 	pub fn new(
-		client: TunnelManagementClient,
+		client: SharedManagementClient,
 		locator: TunnelLocator,
 		log: log::Logger,
 		initial_token: Option<String>,
@@ -124,13 +226,202 @@ impl AccessTokenProvider for LookupAccessTokenProvider {
 			Err(e) => Err(wrap(e, "failed to lookup tunnel")),
 		}
 	}
+
+	async fn revalidate(&self, token: &str) -> Result<TokenCheck, WrappedError> {
+		let tunnel_lookup = self
+			.client
+			.get_tunnel(
+				&self.locator,
+				&TunnelRequestOptions {
+					token_scopes: vec!["host".to_string()],
+					..Default::default()
+				},
+			)
+			.await;
+
+		let fresh = match tunnel_lookup {
+			Ok(tunnel) => get_host_token_from_tunnel(&tunnel),
+			Err(HttpError::ResponseError(r))
+				if r.status_code == StatusCode::UNAUTHORIZED
+					|| r.status_code == StatusCode::FORBIDDEN
+					|| r.status_code == StatusCode::NOT_FOUND =>
+			{
+				return Ok(TokenCheck::Revoked(format!(
+					"the service refused to issue a host token ({})",
+					r.status_code
+				)));
+			}
+			Err(e) => return Err(wrap(e, "failed to lookup tunnel")),
+		};
+
+		match describe_token_change(token, &fresh) {
+			Some(reason) => {
+				self.initial_token.lock().unwrap().replace(fresh);
+				Ok(TokenCheck::Changed(reason))
+			}
+			None => Ok(TokenCheck::Current),
+		}
+	}
+
+	fn can_wake(&self) -> bool {
+		true
+	}
+
+	async fn last_client_connection(&self) -> Result<Option<DateTime<Utc>>, WrappedError> {
+		let tunnel = self
+			.client
+			.get_tunnel(&self.locator, NO_REQUEST_OPTIONS)
+			.await
+			.map_err(|e| wrap(e, "failed to lookup tunnel"))?;
+		Ok(tunnel.status.and_then(|s| s.last_client_connection_time))
+	}
+}
+
+/// Polls the service until it reports a client connection later than
+/// `baseline`, the last one it reported as the tunnel went to sleep. Both come
+/// from the service, so this machine's clock doesn't need to agree with it.
+/// If the baseline couldn't be read, the first value read becomes it.
+///
+/// Polling starts every `interval`, and backs off to `WAKE_POLL_MAX_INTERVAL`
+/// the longer the tunnel sleeps.
+async fn wait_for_client(
+	log: &log::Logger,
+	provider: &impl AccessTokenProvider,
+	mut baseline: Option<Option<DateTime<Utc>>>,
+	mut interval: Duration,
+) {
+	loop {
+		tokio::time::sleep(interval).await;
+		interval = (interval * 2).min(WAKE_POLL_MAX_INTERVAL);
+		match provider.last_client_connection().await {
+			Ok(last) => match baseline {
+				Some(b) if last > b => return,
+				Some(_) => {}
+				None => baseline = Some(last),
+			},
+			Err(e) => debug!(
+				log,
+				"Error checking for clients, will check again later: {}", e
+			),
+		}
+	}
+}
+
+/// Checks the access token every `TOKEN_REVALIDATE_INTERVAL` until the
+/// service would issue a different one, or none.
+async fn wait_for_token_change(
+	log: &log::Logger,
+	provider: &impl AccessTokenProvider,
+	token: &str,
+) -> TokenCheck {
+	loop {
+		tokio::time::sleep(TOKEN_REVALIDATE_INTERVAL).await;
+		match provider.revalidate(token).await {
+			Ok(TokenCheck::Current) => {}
+			Ok(check) => return check,
+			Err(e) => debug!(
+				log,
+				"Error checking the access token, will check again later: {}", e
+			),
+		}
+	}
+}
+
+/// Describes how a newly issued token differs from the current one, ignoring
+/// claims that differ for every token, or None if it doesn't. Tokens that
+/// aren't JWTs differ if they're not equal.
+fn describe_token_change(current: &str, fresh: &str) -> Option<String> {
+	fn stable_claims(token: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+		let payload = token.split('.').nth(1)?;
+		let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+		let mut claims: serde_json::Map<String, serde_json::Value> =
+			serde_json::from_slice(&payload).ok()?;
+		for claim in VOLATILE_TOKEN_CLAIMS {
+			claims.remove(claim);
+		}
+		Some(claims)
+	}
+
+	match (stable_claims(current), stable_claims(fresh)) {
+		(Some(current), Some(fresh)) => {
+			let mut changed: Vec<&str> = current
+				.iter()
+				.filter(|(k, v)| fresh.get(*k) != Some(*v))
+				.map(|(k, _)| k.as_str())
+				.chain(
+					fresh
+						.keys()
+						.filter(|k| !current.contains_key(*k))
+						.map(String::as_str),
+				)
+				.collect();
+			if changed.is_empty() {
+				return None;
+			}
+			changed.sort_unstable();
+			Some(format!("its {} changed", changed.join(", ")))
+		}
+		_ if current == fresh => None,
+		_ => Some("it was rotated".to_string()),
+	}
 }
 
 #[derive(Clone)]
 pub struct DevTunnels {
 	log: log::Logger,
 	launcher_tunnel: PersistedState<Option<PersistedTunnel>>,
-	client: TunnelManagementClient,
+	/// Host IDs of this machine's recent connections to its tunnels.
+	host_ids: PersistedState<Vec<String>>,
+	limits: PersistedState<Option<CachedServiceLimits>>,
+	client: SharedManagementClient,
+	reserved_ports: HashSet<u16>,
+	/// Ports that will be forwarded again once the next tunnel is started,
+	/// and who can connect to them, so they're kept rather than pruned.
+	desired_ports: HashMap<u16, PortPrivacy>,
+	declared_ports: Vec<TunnelPort>,
+	forward_buffer_size: usize,
+	forward_targets: ForwardTargets,
+	capability_tags: Vec<String>,
+	/// Key the host's attestation is signed with, if it's attested.
+	attestation_key: Option<PathBuf>,
+	/// Attestation of the launcher tunnel, once it's been signed.
+	attestation: Option<HostAttestation>,
+	/// Cluster new tunnels are created in, rather than the one the service
+	/// picks.
+	preferred_cluster: Option<String>,
+	backoff: BackoffConfig,
+	/// How long tunnels idle before disconnecting from the relay until a
+	/// client tries to connect, if they sleep at all.
+	sleep_when_idle: Option<Duration>,
+	/// Whether the user can be prompted, such as for the tunnel's name.
+	interactive: bool,
+	/// Launcher tunnel created through `TunnelBackend`, until it's hosted.
+	created: Option<(Tunnel, PersistedTunnel)>,
+	security: SecurityMonitor,
+}
+
+#[async_trait]
+impl TunnelBackend for DevTunnels {
+	async fn create(
+		&mut self,
+		preferred_name: Option<String>,
+		use_random_name: bool,
+	) -> Result<String, AnyError> {
+		let (tunnel, persisted) = self
+			.create_launcher_tunnel(preferred_name, use_random_name)
+			.await?;
+		let name = persisted.name.clone();
+		self.created = Some((tunnel, persisted));
+		Ok(name)
+	}
+
+	async fn host(&mut self) -> Result<ActiveTunnel, AnyError> {
+		let (tunnel, persisted) = match self.created.take() {
+			Some(created) => created,
+			None => self.create_launcher_tunnel(None, false).await?,
+		};
+		self.host_launcher_tunnel(tunnel, &persisted, false).await
+	}
 }
 
 /// Representation of a tunnel returned from the `start` methods.
@@ -138,9 +429,68 @@ pub struct ActiveTunnel {
 	/// Name of the tunnel
 	pub name: String,
 	manager: ActiveTunnelManager,
+	max_ports: Option<usize>,
+	/// Forwarded ports, and who can connect to them.
+	ports: HashMap<u16, PortPrivacy>,
+	/// Ports that were on the tunnel when it started, such as ones forwarded
+	/// by others, which count toward `max_ports` too.
+	existing_ports: HashSet<u16>,
+	port_access: PortAccessRules,
+	forward_buffer_size: usize,
+	forward_targets: ForwardTargets,
 }
 
 impl ActiveTunnel {
+	/// Hosts a tunnel on the given relay rather than one the tunnel service
+	/// provides, such as the local relay, and waits for it to connect.
+	pub async fn on_relay(
+		log: &log::Logger,
+		name: &str,
+		relay: Box<dyn RelayHost>,
+		backoff: BackoffConfig,
+		forward_buffer_size: usize,
+		forward_targets: ForwardTargets,
+	) -> Result<ActiveTunnel, AnyError> {
+		let mut manager = ActiveTunnelManager::new(
+			log.clone(),
+			relay,
+			StaticAccessTokenProvider::new(String::new()),
+			backoff,
+			None,
+		);
+
+		if let Err(e) = cancellable(manager.get_endpoint()).await {
+			manager.kill().await.ok();
+			return Err(e);
+		}
+
+		Ok(ActiveTunnel {
+			name: name.to_string(),
+			manager,
+			max_ports: None,
+			ports: HashMap::new(),
+			existing_ports: HashSet::new(),
+			port_access: PortAccessRules::default(),
+			forward_buffer_size,
+			forward_targets,
+		})
+	}
+
+	/// Gets the URI clients connect to the tunnel at directly, if it's hosted
+	/// on the local relay rather than through the tunnel service.
+	pub async fn local_uri(&mut self) -> Option<String> {
+		let endpoint = self.manager.get_endpoint().await.ok()?.base;
+		match endpoint.connection_mode.as_deref() {
+			Some(LOCAL_CONNECTION_MODE) => endpoint.tunnel_uri,
+			_ => None,
+		}
+	}
+
+	/// Sets rules restricting who can connect to ports forwarded after this.
+	pub fn set_port_access(&mut self, rules: PortAccessRules) {
+		self.port_access = rules;
+	}
+
 	/// Closes and unregisters the tunnel.
 	pub async fn close(&mut self) -> Result<(), AnyError> {
 		self.manager.kill().await?;
@@ -151,23 +501,139 @@ impl ActiveTunnel {
 	pub async fn add_port_direct(
 		&mut self,
 		port_number: u16,
-	) -> Result<mpsc::UnboundedReceiver<ForwardedPortConnection>, AnyError> {
-		let port = self.manager.add_port_direct(port_number).await?;
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, AnyError> {
+		self.check_port_limit(port_number)?;
+		let port = self
+			.manager
+			.add_port_direct(port_number, self.port_access.access_control(port_number))
+			.await?;
+		self.ports.insert(port_number, PortPrivacy::Private);
+		self.track_ports();
 		Ok(port)
 	}
 
-	/// Forwards a port over TCP.
-	pub async fn add_port_tcp(&mut self, port_number: u16) -> Result<(), AnyError> {
-		self.manager.add_port_tcp(port_number).await?;
+	/// Forwards a port over TCP, which the given clients can connect to.
+	pub async fn add_port_tcp(
+		&mut self,
+		port_number: u16,
+		privacy: PortPrivacy,
+	) -> Result<(), AnyError> {
+		self.add_port(
+			&TunnelPort {
+				port_number,
+				protocol: Some(TUNNEL_PROTOCOL_AUTO.to_owned()),
+				access_control: privacy.apply_to(self.port_access.access_control(port_number)),
+				..Default::default()
+			},
+			privacy,
+		)
+		.await
+	}
+
+	async fn add_port(&mut self, port: &TunnelPort, privacy: PortPrivacy) -> Result<(), AnyError> {
+		self.check_port_limit(port.port_number)?;
+		let target = self.forward_targets.target_for(port.port_number);
+		self.manager
+			.add_port(port, target, self.forward_buffer_size)
+			.await?;
+		self.ports.insert(port.port_number, privacy);
+		self.track_ports();
 		Ok(())
 	}
 
 	/// Removes a forwarded port TCP.
 	pub async fn remove_port(&mut self, port_number: u16) -> Result<(), AnyError> {
 		self.manager.remove_port(port_number).await?;
+		self.ports.remove(&port_number);
+		self.track_ports();
 		Ok(())
 	}
 
+	fn check_port_limit(&self, port_number: u16) -> Result<(), PortLimitExceeded> {
+		let max = match self.max_ports {
+			Some(max) => max,
+			None => return Ok(()),
+		};
+		if self.ports.contains_key(&port_number) || self.existing_ports.contains(&port_number) {
+			return Ok(());
+		}
+
+		let others = self
+			.existing_ports
+			.iter()
+			.filter(|p| !self.ports.contains_key(p))
+			.count();
+		if self.ports.len() + others >= max {
+			Err(PortLimitExceeded(max))
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Gets whether the port is forwarded on the tunnel.
+	pub fn has_port(&self, port_number: u16) -> bool {
+		self.ports.contains_key(&port_number)
+	}
+
+	/// Gets who can connect to a port forwarded on the tunnel.
+	pub fn port_privacy(&self, port_number: u16) -> PortPrivacy {
+		self.ports
+			.get(&port_number)
+			.copied()
+			.unwrap_or(PortPrivacy::Private)
+	}
+
+	/// Gets the ports forwarded on the tunnel, other than the control port.
+	pub fn forwarded_ports(&self) -> Vec<u16> {
+		self.ports
+			.keys()
+			.copied()
+			.filter(|p| *p != CONTROL_PORT)
+			.collect()
+	}
+
+	fn track_ports(&self) {
+		self.manager.status.set_ports(self.forwarded_ports());
+	}
+
+	/// Gets the current state of the tunnel's connection to the relay.
+	pub fn status(&self) -> ConnectionStatus {
+		self.manager.status.snapshot()
+	}
+
+	/// Waits until the tunnel stops reconnecting to the relay, such as once its
+	/// retries are used up.
+	pub fn wait_for_disconnect(&self) -> impl std::future::Future<Output = ()> + 'static {
+		let mut endpoint_rx = self.manager.endpoint_rx.clone();
+		async move { while endpoint_rx.changed().await.is_ok() {} }
+	}
+
+	/// Subscribes to changes in the URIs clients reach the tunnel at, which
+	/// can differ after the tunnel reconnects to the relay. The receiver holds
+	/// the current endpoint, and is only notified of later changes.
+	pub fn endpoint_changes(&self) -> watch::Receiver<Option<TunnelRelayTunnelEndpoint>> {
+		let mut rx = self.manager.endpoint_changes.clone();
+		rx.borrow_and_update();
+		rx
+	}
+
+	/// Gets a handle to the state of the tunnel's connection, which stays up
+	/// to date, such as to report it to other processes.
+	pub fn connection_tracker(&self) -> ConnectionTracker {
+		self.manager.status.clone()
+	}
+
+	/// Gets a handle to the connections clients have open to the tunnel,
+	/// such as to stop hosting once it's idle.
+	pub fn activity(&self) -> ActivityTracker {
+		self.manager.activity.clone()
+	}
+
+	/// Gets the ID this machine is registered with as a host of the tunnel.
+	pub async fn host_id(&mut self) -> Result<String, AnyError> {
+		Ok(self.manager.get_endpoint().await?.base.host_id)
+	}
+
 	/// Gets the public URI on which a forwarded port can be access in browser.
 	pub async fn get_port_uri(&mut self, port: u16) -> Result<String, AnyError> {
 		let endpoint = self.manager.get_endpoint().await?;
@@ -181,7 +647,224 @@ impl ActiveTunnel {
 }
 
 const VSCODE_CLI_TUNNEL_TAG: &str = "vscode-server-launcher";
-const MAX_TUNNEL_NAME_LENGTH: usize = 20;
+/// Tag of ports the CLI forwards, so only those are pruned when the tunnel
+/// starts, and ports forwarded by others, such as from another machine, are
+/// left alone.
+const VSCODE_CLI_PORT_TAG: &str = "vscode-cli-port";
+const PERSISTED_TUNNEL_FILE_NAME: &str = "code_tunnel.json";
+const PERSISTED_LIMITS_FILE_NAME: &str = "tunnel_limits.json";
+const RATE_LIMIT_FILE_NAME: &str = "tunnel_rate_limit.json";
+const PERSISTED_HOST_IDS_FILE_NAME: &str = "tunnel_host_ids.json";
+
+/// Number of host IDs remembered, so the endpoints left behind by hosts of
+/// this machine that crashed can be told apart from those of other hosts.
+const MAX_PERSISTED_HOST_IDS: usize = 16;
+
+/// How long before the access token expires that the tunnel reconnects with
+/// a new one.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// How often a connected tunnel checks its access token against the one the
+/// service would issue now.
+const TOKEN_REVALIDATE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// How often a sleeping tunnel first checks whether a client tried to connect.
+const WAKE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest a sleeping tunnel waits between checks for clients.
+const WAKE_POLL_MAX_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Claims that differ between every token issued, and so are ignored when
+/// checking whether a token changed.
+const VOLATILE_TOKEN_CLAIMS: [&str; 4] = ["iat", "nbf", "exp", "jti"];
+
+/// Gets the tunnel last used by this machine, if any.
+pub fn get_persisted_tunnel(paths: &LauncherPaths) -> Option<PersistedTunnel> {
+	PersistedTunnel::state(paths.root().join(PERSISTED_TUNNEL_FILE_NAME)).load()
+}
+
+/// Gets the cluster of the tunnel last used by this machine, if any.
+pub fn get_persisted_cluster(paths: &LauncherPaths) -> Option<String> {
+	get_persisted_tunnel(paths).map(|t| t.cluster)
+}
+
+/// Number of names tried when the one asked for is taken by another machine.
+const MAX_NAME_CANDIDATES: usize = 5;
+
+/// Number of stale ports or endpoints deleted at once when hosting starts.
+const PRUNE_CONCURRENCY: usize = 8;
+
+/// Gets names to try for a tunnel: the name, and then the name with numeric
+/// suffixes, shortened to fit in `max_length`.
+fn name_candidates(name: &str, max_length: usize) -> impl Iterator<Item = String> + '_ {
+	std::iter::once(name.to_string()).chain((2..=MAX_NAME_CANDIDATES).map(move |n| {
+		let suffix = format!("-{}", n);
+		let base: String = name
+			.chars()
+			.take(max_length.saturating_sub(suffix.len()))
+			.collect();
+		format!("{}{}", base.trim_end_matches('-'), suffix)
+	}))
+}
+
+/// Adds the host ID to those of this machine, forgetting the oldest ones.
+fn remember_host_id(host_id: String, host_ids: &mut Vec<String>) {
+	host_ids.retain(|id| *id != host_id);
+	host_ids.push(host_id);
+	if host_ids.len() > MAX_PERSISTED_HOST_IDS {
+		host_ids.drain(..host_ids.len() - MAX_PERSISTED_HOST_IDS);
+	}
+}
+
+/// Gets whether clients reach the tunnel at different URIs through the new
+/// endpoint than through the old one.
+fn endpoint_uris_changed(old: &TunnelRelayTunnelEndpoint, new: &TunnelRelayTunnelEndpoint) -> bool {
+	old.base.tunnel_uri != new.base.tunnel_uri
+		|| old.base.port_uri_format != new.base.port_uri_format
+		|| old.client_relay_uri != new.client_relay_uri
+}
+
+/// Gets an error explaining a failed management request, if it was
+/// intercepted by a captive portal or proxy instead of reaching the service.
+fn get_management_interception(e: &HttpError) -> Option<AnyError> {
+	match e {
+		HttpError::ResponseError(r) => get_interception_error(
+			r.status_code.as_u16(),
+			r.url.as_str(),
+			Some(r.url.as_str()),
+			r.data.as_deref(),
+		),
+		HttpError::ConnectionError(e) => get_request_interception_error(e),
+		_ => None,
+	}
+}
+
+/// One step of `DevTunnels::remove_tunnel_forced`, and why it failed if it did.
+#[derive(Debug)]
+pub struct CleanupStep {
+	pub description: String,
+	pub error: Option<String>,
+}
+
+impl CleanupStep {
+	fn new(description: String, result: Result<(), HttpError>) -> Self {
+		CleanupStep {
+			description,
+			error: result.err().map(|e| e.to_string()),
+		}
+	}
+}
+
+fn wrap_management_error(e: HttpError, message: &str) -> AnyError {
+	get_management_interception(&e).unwrap_or_else(|| wrap(e, message).into())
+}
+
+/// Wraps an error looking up the tunnel persisted by an earlier run. The
+/// service returns a 403 for tunnels that exist but that the current login
+/// can't access, which means they were created under another account.
+fn wrap_persisted_lookup_error(e: HttpError, persisted: &PersistedTunnel) -> AnyError {
+	match &e {
+		HttpError::ResponseError(r) if r.status_code == StatusCode::FORBIDDEN => {
+			TunnelOwnedByOtherAccount(persisted.name.clone()).into()
+		}
+		_ => wrap_management_error(e, "failed to lookup tunnel"),
+	}
+}
+
+/// Gets whether the request failed because what it's for doesn't exist, such
+/// as on deleting something that's already gone.
+fn is_not_found(e: &HttpError) -> bool {
+	matches!(e, HttpError::ResponseError(e) if e.status_code == StatusCode::NOT_FOUND)
+}
+
+/// Tags the port as forwarded by the CLI.
+fn tag_port(mut port: TunnelPort) -> TunnelPort {
+	if !is_cli_port(&port) {
+		port.tags.push(VSCODE_CLI_PORT_TAG.to_string());
+	}
+	port
+}
+
+fn is_cli_port(port: &TunnelPort) -> bool {
+	port.tags.iter().any(|t| t == VSCODE_CLI_PORT_TAG)
+}
+
+/// Gets whether the tunnel has no host connected, so it can be deleted to
+/// make room for another.
+fn is_recyclable(tunnel: &Tunnel) -> bool {
+	tunnel
+		.status
+		.as_ref()
+		.and_then(|s| s.host_connection_count.as_ref())
+		.map(|c| c.get_count())
+		.unwrap_or(0)
+		== 0
+}
+
+/// Gets whether the service reports that the tunnel has no hosts, and that
+/// none has connected since the cutoff.
+fn is_stale(tunnel: &Tunnel, cutoff: DateTime<Utc>) -> bool {
+	let status = match &tunnel.status {
+		Some(s) => s,
+		None => return false,
+	};
+	let unhosted = matches!(&status.host_connection_count, Some(c) if c.get_count() == 0);
+	unhosted && matches!(status.last_host_connection_time, Some(t) if t < cutoff)
+}
+
+#[derive(Deserialize)]
+struct TimeClaims {
+	iat: Option<u64>,
+	nbf: Option<u64>,
+	exp: Option<u64>,
+}
+
+fn get_time_claims(token: &str) -> Option<TimeClaims> {
+	let payload = token.split('.').nth(1)?;
+	let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+	serde_json::from_slice(&payload).ok()
+}
+
+/// Gets when a JWT expires, from its `exp` claim. Returns None for tokens
+/// that aren't JWTs or don't expire.
+pub fn get_token_expiry(token: &str) -> Option<SystemTime> {
+	get_time_claims(token)?
+		.exp
+		.map(|exp| SystemTime::UNIX_EPOCH + Duration::from_secs(exp))
+}
+
+/// Gets how long a JWT was issued for, from its `exp` claim and its `iat`
+/// or `nbf` claim. Returns None for tokens that don't have them.
+pub fn get_token_lifetime(token: &str) -> Option<Duration> {
+	let claims = get_time_claims(token)?;
+	let issued = claims.iat.or(claims.nbf)?;
+	claims.exp?.checked_sub(issued).map(Duration::from_secs)
+}
+
+/// Gets how long to wait before reconnecting with a new token, which expires
+/// at `expires_at` by the service's clock and was issued for `lifetime`.
+///
+/// The time left by the local clock is capped at the lifetime, so a clock
+/// that's behind the service's doesn't hold on to the token past its expiry.
+/// If the local clock is so far ahead that the token looks expired, it's
+/// taken to have just been issued, as tokens are fetched as the tunnel
+/// connects.
+fn token_refresh_delay(
+	expires_at: Option<SystemTime>,
+	lifetime: Option<Duration>,
+	now: SystemTime,
+) -> Option<Duration> {
+	let by_clock = expires_at?.duration_since(now).ok();
+	let remaining = match (by_clock, lifetime) {
+		(Some(r), Some(l)) => r.min(l),
+		(Some(r), None) => r,
+		(None, Some(l)) => l,
+		(None, None) => return None,
+	};
+	remaining
+		.checked_sub(TOKEN_REFRESH_MARGIN)
+		.filter(|d| !d.is_zero())
+}
 
 fn get_host_token_from_tunnel(tunnel: &Tunnel) -> String {
 	tunnel
@@ -193,25 +876,6 @@ fn get_host_token_from_tunnel(tunnel: &Tunnel) -> String {
 		.to_string()
 }
 
-fn is_valid_name(name: &str) -> Result<(), InvalidTunnelName> {
-	if name.len() > MAX_TUNNEL_NAME_LENGTH {
-		return Err(InvalidTunnelName(format!(
-			"Names cannot be longer than {} characters. Please try a different name.",
-			MAX_TUNNEL_NAME_LENGTH
-		)));
-	}
-
-	let re = Regex::new(r"^([\w-]+)$").unwrap();
-
-	if !re.is_match(name) {
-		return Err(InvalidTunnelName(
-            "Names can only contain letters, numbers, and '-'. Spaces, commas, and all other special characters are not allowed. Please try a different name.".to_string()
-        ));
-	}
-
-	Ok(())
-}
-
 /// Structure optionally passed into `start_existing_tunnel` to forward an existing tunnel.
 #[derive(Clone, Debug)]
 pub struct ExistingTunnel {
@@ -229,15 +893,145 @@ pub struct ExistingTunnel {
 }
 
 impl DevTunnels {
-	pub fn new(log: &log::Logger, auth: auth::Auth, paths: &LauncherPaths) -> DevTunnels {
-		let mut client = new_tunnel_management(&TUNNEL_SERVICE_USER_AGENT);
+	pub fn new(
+		log: &log::Logger,
+		auth: impl AuthorizationProvider + 'static,
+		paths: &LauncherPaths,
+	) -> DevTunnels {
+		#[cfg(feature = "tunnel-emulator")]
+		if super::emulator::is_enabled() {
+			warning!(log, "Using the tunnel service emulator");
+			return DevTunnels::new_with_client(log, paths, super::emulator::shared_client());
+		}
+
+		let metadata = TunnelSetup::load(paths).request_metadata;
+		for name in metadata.invalid_headers() {
+			warning!(log, "Not sending the header {}, as it isn't valid", name);
+		}
+		let mut client = new_tunnel_management(&metadata.user_agent());
 		client.authorization_provider(auth);
 
+		let client = RateLimitedClient::new(
+			log.clone(),
+			Arc::new(ServiceManagementClient::new(client, &metadata)),
+			paths.root().join(RATE_LIMIT_FILE_NAME),
+		);
+		DevTunnels::new_with_client(log, paths, Arc::new(client))
+	}
+
+	/// Creates tunnels using the given management client, such as an emulator.
+	pub fn new_with_client(
+		log: &log::Logger,
+		paths: &LauncherPaths,
+		client: SharedManagementClient,
+	) -> DevTunnels {
 		DevTunnels {
 			log: log.clone(),
-			client: client.into(),
-			launcher_tunnel: PersistedState::new(paths.root().join("code_tunnel.json")),
+			client,
+			launcher_tunnel: PersistedTunnel::state(paths.root().join(PERSISTED_TUNNEL_FILE_NAME)),
+			host_ids: PersistedState::new(paths.root().join(PERSISTED_HOST_IDS_FILE_NAME)),
+			limits: PersistedState::new(paths.root().join(PERSISTED_LIMITS_FILE_NAME)),
+			reserved_ports: HashSet::from([CONTROL_PORT]),
+			desired_ports: HashMap::new(),
+			declared_ports: vec![],
+			forward_buffer_size: DEFAULT_FORWARD_BUFFER_SIZE,
+			forward_targets: ForwardTargets::default(),
+			capability_tags: vec![],
+			attestation_key: None,
+			attestation: None,
+			preferred_cluster: None,
+			backoff: BackoffConfig::default(),
+			sleep_when_idle: None,
+			interactive: true,
+			created: None,
+			security: SecurityMonitor::new(log.clone(), paths),
+		}
+	}
+
+	/// Sets whether the user can be prompted. When they can't, a name or a
+	/// random name must be requested when starting a new launcher tunnel.
+	pub fn set_interactive(&mut self, interactive: bool) {
+		self.interactive = interactive;
+	}
+
+	/// Persists the launcher tunnel at the path instead of in the data
+	/// directory's `code_tunnel.json`, such as for a named tunnel hosted
+	/// alongside it.
+	pub fn set_persisted_tunnel_path(&mut self, path: PathBuf) {
+		self.launcher_tunnel = PersistedTunnel::state(path);
+	}
+
+	/// Sets how long tunnels can go without clients before they disconnect
+	/// from the relay to save power. They keep their registration, and
+	/// reconnect within seconds once a client tries to connect.
+	pub fn set_sleep_when_idle(&mut self, timeout: Option<Duration>) {
+		self.sleep_when_idle = timeout;
+	}
+
+	/// Sets the number of bytes buffered in each direction of each connection
+	/// to a forwarded port.
+	pub fn set_forward_buffer_size(&mut self, size: usize) {
+		self.forward_buffer_size = size;
+	}
+
+	/// Sets the hosts that connections to forwarded ports go to, instead of
+	/// localhost.
+	pub fn set_forward_targets(&mut self, targets: ForwardTargets) {
+		self.forward_targets = targets;
+	}
+
+	/// Sets how tunnels started after this reconnect after losing their
+	/// connection to the relay.
+	pub fn set_backoff(&mut self, config: BackoffConfig) {
+		self.backoff = config;
+	}
+
+	/// Publishes the host's capabilities in the tags of launcher tunnels as
+	/// they're created, renamed, or started.
+	pub fn set_host_capabilities(&mut self, capabilities: &HostCapabilities) {
+		self.capability_tags = capabilities.to_tags();
+	}
+
+	/// Creates new tunnels in the cluster, such as `usw2`, instead of the one
+	/// the service assigns. Existing tunnels stay in the cluster they're in.
+	pub fn set_preferred_cluster(&mut self, cluster: Option<String>) {
+		self.preferred_cluster = cluster;
+	}
+
+	/// Publishes the host's attestation, signed with the key, in the tags of
+	/// launcher tunnels as they're created, renamed, or started.
+	pub fn set_attestation_key(&mut self, key: PathBuf) {
+		self.attestation_key = Some(key);
+	}
+
+	/// Signs the attestation of the tunnel with the ID if it's not yet been,
+	/// returning whether it was.
+	async fn attest(&mut self, tunnel_id: &str) -> Result<bool, AnyError> {
+		let key = match &self.attestation_key {
+			Some(k) => k,
+			None => return Ok(false),
+		};
+		if matches!(&self.attestation, Some(a) if a.tunnel == tunnel_id) {
+			return Ok(false);
 		}
+
+		self.attestation = Some(HostAttestation::create(key, tunnel_id).await?);
+		Ok(true)
+	}
+
+	/// Adds ports that are kept when a tunnel is started, instead of being
+	/// deleted along with ports left from earlier runs. The control port is
+	/// always reserved. Ports the CLI didn't forward are never deleted.
+	pub fn add_reserved_ports(&mut self, ports: impl IntoIterator<Item = u16>) {
+		self.reserved_ports.extend(ports);
+	}
+
+	/// Sets the ports that will be forwarded again on the next tunnel that's
+	/// started, such as on resuming hosting. Those the service still has with
+	/// the same privacy are kept, so clients don't see them disappear, and
+	/// only the others are deleted.
+	pub fn set_desired_ports(&mut self, ports: impl IntoIterator<Item = (u16, PortPrivacy)>) {
+		self.desired_ports = ports.into_iter().collect();
 	}
 
 	pub async fn remove_tunnel(&mut self) -> Result<(), AnyError> {
@@ -254,14 +1048,193 @@ impl DevTunnels {
 			self.client
 				.delete_tunnel(&tunnel.into_locator(), NO_REQUEST_OPTIONS)
 		)
-		.map_err(|e| wrap(e, "failed to execute `tunnel delete`"))?;
+		.map_err(|e| wrap_management_error(e, "failed to execute `tunnel delete`"))?;
 
 		self.launcher_tunnel.save(None)?;
 		Ok(())
 	}
 
+	/// Removes the tunnel like `remove_tunnel`, but first deletes its
+	/// endpoints, ports, and access control entries one at a time, so they
+	/// don't linger if deleting the tunnel itself fails. Every step is tried
+	/// even if earlier ones fail, and the persisted tunnel is forgotten
+	/// either way. Returns the steps taken, in order.
+	pub async fn remove_tunnel_forced(&mut self) -> Result<Vec<CleanupStep>, AnyError> {
+		let persisted = match self.launcher_tunnel.load() {
+			Some(t) => t,
+			None => return Ok(vec![]),
+		};
+
+		let locator = persisted.locator();
+		let mut steps = vec![];
+		let lookup = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.tag.get"),
+			self.client.get_tunnel(
+				&locator,
+				&TunnelRequestOptions {
+					include_ports: true,
+					include_access_control: true,
+					..Default::default()
+				}
+			)
+		);
+
+		match lookup {
+			Ok(tunnel) => {
+				let mut host_ids: Vec<&str> = tunnel
+					.endpoints
+					.iter()
+					.map(|e| e.host_id.as_str())
+					.collect();
+				host_ids.sort_unstable();
+				host_ids.dedup();
+				for host_id in host_ids {
+					let result = spanf!(
+						self.log,
+						self.log.span("dev-tunnel.endpoint.prune"),
+						self.client
+							.delete_tunnel_endpoints(&locator, host_id, NO_REQUEST_OPTIONS)
+					);
+					steps.push(CleanupStep::new(
+						format!("delete endpoint of host {}", host_id),
+						result,
+					));
+				}
+
+				for port in &tunnel.ports {
+					let result = spanf!(
+						self.log,
+						self.log.span("dev-tunnel.port.delete"),
+						self.client.delete_tunnel_port(
+							&locator,
+							port.port_number,
+							NO_REQUEST_OPTIONS
+						)
+					);
+					steps.push(CleanupStep::new(
+						format!("delete port {}", port.port_number),
+						result,
+					));
+				}
+
+				let has_entries = tunnel
+					.access_control
+					.as_ref()
+					.map(|ac| !ac.entries.is_empty())
+					.unwrap_or(false);
+				if has_entries {
+					let cleared = Tunnel {
+						access_control: Some(TunnelAccessControl::default()),
+						..tunnel.clone()
+					};
+					let result = spanf!(
+						self.log,
+						self.log.span("dev-tunnel.access.update"),
+						self.client.update_tunnel(&cleared, NO_REQUEST_OPTIONS)
+					);
+					steps.push(CleanupStep::new(
+						"clear access control entries".to_string(),
+						result.map(|_| ()),
+					));
+				}
+			}
+			// nothing is left to clean up on the service
+			Err(HttpError::ResponseError(e)) if e.status_code == StatusCode::NOT_FOUND => {
+				self.launcher_tunnel.save(None)?;
+				steps.push(CleanupStep::new(
+					format!("delete tunnel {} (it was already deleted)", persisted.name),
+					Ok(()),
+				));
+				return Ok(steps);
+			}
+			Err(e) => steps.push(CleanupStep::new(
+				format!("look up tunnel {}", persisted.name),
+				Err(e),
+			)),
+		}
+
+		let result = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.delete"),
+			self.client.delete_tunnel(&locator, NO_REQUEST_OPTIONS)
+		);
+		steps.push(CleanupStep::new(
+			format!("delete tunnel {}", persisted.name),
+			result,
+		));
+
+		self.launcher_tunnel.save(None)?;
+		Ok(steps)
+	}
+
+	/// Declares ports to include in the request that creates a tunnel, so
+	/// they're exposed when the host first connects. They're kept when the
+	/// tunnel is started, and added if a reused tunnel doesn't have them.
+	pub fn declare_ports(&mut self, ports: Vec<TunnelPort>) {
+		self.reserved_ports
+			.extend(ports.iter().map(|p| p.port_number));
+		self.declared_ports = ports.into_iter().map(tag_port).collect();
+	}
+
+	/// Forgets the tunnel persisted by an earlier run without deleting it,
+	/// such as one owned by another account, so that the next start creates a
+	/// new tunnel. Returns the forgotten tunnel.
+	pub fn forget_tunnel(&mut self) -> Result<Option<PersistedTunnel>, AnyError> {
+		let tunnel = self.launcher_tunnel.load();
+		self.launcher_tunnel.save(None)?;
+		Ok(tunnel)
+	}
+
+	/// Gets the service's limits, refetching them if the cached limits are
+	/// stale. Falls back to the last known or default limits on failure.
+	async fn get_limits(&mut self) -> ServiceLimits {
+		let cached = self.limits.load();
+		if let Some(c) = &cached {
+			if c.is_fresh() {
+				return c.limits.clone();
+			}
+		}
+
+		let fetched = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.limits"),
+			self.client.get_service_limits()
+		);
+
+		match fetched {
+			Ok(Some(mut limits)) => {
+				if let Err(e) = limits.fix_name_pattern() {
+					warning!(
+						self.log,
+						"The tunnel service's name pattern is invalid, using the default: {}",
+						e
+					);
+				}
+				if let Err(e) = self
+					.limits
+					.save(Some(CachedServiceLimits::new(limits.clone())))
+				{
+					debug!(self.log, "Error caching tunnel service limits: {}", e);
+				}
+				limits
+			}
+			Ok(None) => {
+				debug!(
+					self.log,
+					"No URL for the tunnel service's limits is set, using the last known or default limits"
+				);
+				cached.map(|c| c.limits).unwrap_or_default()
+			}
+			Err(e) => {
+				debug!(self.log, "Error fetching tunnel service limits: {}", e);
+				cached.map(|c| c.limits).unwrap_or_default()
+			}
+		}
+	}
+
 	pub async fn rename_tunnel(&mut self, name: &str) -> Result<(), AnyError> {
-		is_valid_name(name)?;
+		self.get_limits().await.validate_name(name)?;
 
 		self.check_is_name_free(name).await?;
 
@@ -269,7 +1242,9 @@ impl DevTunnels {
 			Some(t) => t,
 			None => {
 				debug!(self.log, "No code server tunnel found, creating new one");
-				let (persisted, _) = self.create_tunnel(name).await?;
+				let (persisted, _) = self.reserve_name(name).await?.ok_or_else(|| {
+					TunnelCreationFailed(name.to_string(), "tunnel name already in use".to_string())
+				})?;
 				self.launcher_tunnel.save(Some(persisted))?;
 				return Ok(());
 			}
@@ -282,15 +1257,14 @@ impl DevTunnels {
 			self.log.span("dev-tunnel.tag.get"),
 			self.client.get_tunnel(&locator, NO_REQUEST_OPTIONS)
 		)
-		.map_err(|e| wrap(e, "failed to lookup tunnel"))?;
+		.map_err(|e| wrap_management_error(e, "failed to lookup tunnel"))?;
 
-		full_tunnel.tags = vec![name.to_string(), VSCODE_CLI_TUNNEL_TAG.to_string()];
-		spanf!(
-			self.log,
-			self.log.span("dev-tunnel.tag.update"),
-			self.client.update_tunnel(&full_tunnel, NO_REQUEST_OPTIONS)
-		)
-		.map_err(|e| wrap(e, "failed to update tunnel tags"))?;
+		if !self.claim_name(&mut full_tunnel, name).await? {
+			return Err(AnyError::from(TunnelCreationFailed(
+				name.to_string(),
+				"tunnel name already in use".to_string(),
+			)));
+		}
 
 		tunnel.name = name.to_string();
 		self.launcher_tunnel.save(Some(tunnel.clone()))?;
@@ -299,36 +1273,54 @@ impl DevTunnels {
 
 	/// Starts a new tunnel for the code server on the port. Unlike `start_new_tunnel`,
 	/// this attempts to reuse or create a tunnel of a preferred name or of a generated friendly tunnel name.
+	/// A new tunnel's name is prompted for unless `set_interactive(false)` was
+	/// called; see `get_name_for_tunnel`.
 	pub async fn start_new_launcher_tunnel(
 		&mut self,
 		preferred_name: Option<String>,
 		use_random_name: bool,
 	) -> Result<ActiveTunnel, AnyError> {
-		let (tunnel, persisted) = match self.launcher_tunnel.load() {
+		let (tunnel, persisted) = self
+			.create_launcher_tunnel(preferred_name, use_random_name)
+			.await?;
+		self.host_launcher_tunnel(tunnel, &persisted, false).await
+	}
+
+	/// Finds the launcher tunnel created before, renaming it if a different
+	/// name is preferred, or creates one.
+	async fn create_launcher_tunnel(
+		&mut self,
+		preferred_name: Option<String>,
+		use_random_name: bool,
+	) -> Result<(Tunnel, PersistedTunnel), AnyError> {
+		let mut created = match self.launcher_tunnel.load() {
 			Some(mut persisted) => {
+				self.attest(&persisted.id).await?;
+				match &self.preferred_cluster {
+					Some(c) if c != &persisted.cluster => warning!(
+						self.log,
+						"The tunnel {} is hosted in the {} cluster rather than {}. Run `code tunnel unregister` to create it again in {}.",
+						persisted.name,
+						persisted.cluster,
+						c,
+						c
+					),
+					_ => {}
+				}
+
 				if let Some(name) = preferred_name {
 					if persisted.name.ne(&name) {
-						self.check_is_name_free(&name).await?;
 						let mut full_tunnel = spanf!(
 							self.log,
 							self.log.span("dev-tunnel.tag.get"),
 							self.client
 								.get_tunnel(&persisted.locator(), NO_REQUEST_OPTIONS)
 						)
-						.map_err(|e| wrap(e, "failed to lookup tunnel"))?;
+						.map_err(|e| wrap_persisted_lookup_error(e, &persisted))?;
 
 						info!(self.log, "Updating name of existing tunnel");
-
-						full_tunnel.tags =
-							vec![name.to_string(), VSCODE_CLI_TUNNEL_TAG.to_string()];
-						if spanf!(
-							self.log,
-							self.log.span("dev-tunnel.tag.update"),
-							self.client.update_tunnel(&full_tunnel, NO_REQUEST_OPTIONS)
-						)
-						.is_ok()
-						{
-							persisted.name = name.to_string();
+						if let Some(name) = self.claim_free_name(&mut full_tunnel, &name).await? {
+							persisted.name = name;
 							self.launcher_tunnel.save(Some(persisted.clone()))?;
 						}
 					}
@@ -348,16 +1340,18 @@ impl DevTunnels {
 				);
 
 				match tunnel_lookup {
-					Ok(ft) => (ft, persisted),
-					Err(HttpError::ResponseError(e))
-						if e.status_code == StatusCode::NOT_FOUND
-							|| e.status_code == StatusCode::FORBIDDEN =>
-					{
+					Ok(ft) => {
+						if ft.tags != self.launcher_tags(&persisted.name, Some(&persisted.id)) {
+							self.update_launcher_tags(&persisted).await;
+						}
+						(ft, persisted)
+					}
+					Err(HttpError::ResponseError(e)) if e.status_code == StatusCode::NOT_FOUND => {
 						let (persisted, tunnel) = self.create_tunnel(&persisted.name).await?;
 						self.launcher_tunnel.save(Some(persisted.clone()))?;
 						(tunnel, persisted)
 					}
-					Err(e) => return Err(AnyError::from(wrap(e, "failed to lookup tunnel"))),
+					Err(e) => return Err(wrap_persisted_lookup_error(e, &persisted)),
 				}
 			}
 			None => {
@@ -365,70 +1359,336 @@ impl DevTunnels {
 				let name = self
 					.get_name_for_tunnel(preferred_name, use_random_name)
 					.await?;
-				let (persisted, full_tunnel) = self.create_tunnel(&name).await?;
+				let max_length = self.get_limits().await.max_name_length;
+				let mut created = None;
+				for candidate in name_candidates(&name, max_length) {
+					match self.reserve_name(&candidate).await? {
+						Some(c) => {
+							created = Some(c);
+							break;
+						}
+						None => info!(
+							self.log,
+							"{} was just taken by another machine, trying another name", candidate
+						),
+					}
+				}
+				let (persisted, full_tunnel) = created.ok_or_else(|| {
+					TunnelCreationFailed(name, "tunnel name already in use".to_string())
+				})?;
 				self.launcher_tunnel.save(Some(persisted.clone()))?;
 				(full_tunnel, persisted)
 			}
 		};
 
+		// a new tunnel's attestation can only be signed once it has an ID
+		let (tunnel, persisted) = &mut created;
+		if self.attest(&persisted.id).await? {
+			self.update_launcher_tags(persisted).await;
+			tunnel.tags = self.launcher_tags(&persisted.name, Some(&persisted.id));
+		}
+
+		Ok(created)
+	}
+
+	/// Finds a tunnel hosted by another machine under the given name, to join
+	/// it as a standby host.
+	pub async fn find_launcher_tunnel(&mut self, name: &str) -> Result<PersistedTunnel, AnyError> {
+		let tunnel = self
+			.list_all_server_tunnels()
+			.await?
+			.into_iter()
+			.find(|t| t.tags.iter().any(|tag| tag == name))
+			.ok_or_else(|| {
+				DevTunnelError(format!(
+					"no tunnel named {} was found to join as a standby",
+					name
+				))
+			})?;
+
+		Ok(PersistedTunnel {
+			cluster: tunnel.cluster_id.unwrap_or_default(),
+			id: tunnel.tunnel_id.unwrap_or_default(),
+			name: name.to_string(),
+		})
+	}
+
+	/// Waits until no host has been connected to the tunnel for the number of
+	/// consecutive checks given in the `options`.
+	pub async fn wait_for_host_offline(
+		&mut self,
+		persisted: &PersistedTunnel,
+		options: &FailoverOptions,
+	) -> Result<(), AnyError> {
+		let mut offline_checks = 0;
+		loop {
+			match self.get_tunnel_for_failover(persisted).await? {
+				Some(t) if failover::is_hosted(&t) => offline_checks = 0,
+				Some(_) => offline_checks += 1,
+				None => {}
+			}
+
+			if offline_checks >= options.offline_checks {
+				return Ok(());
+			}
+
+			tokio::time::sleep(options.poll_interval).await;
+		}
+	}
+
+	/// Waits until the tunnel no longer exists on the tunnel service, such as
+	/// when it's deleted by an admin or expired, checking every `poll_interval`.
+	/// Other errors are logged and the tunnel is checked again later.
+	pub async fn wait_for_tunnel_deleted(
+		&mut self,
+		persisted: &PersistedTunnel,
+		poll_interval: Duration,
+	) {
+		loop {
+			tokio::time::sleep(poll_interval).await;
+
+			match self
+				.client
+				.get_tunnel(&persisted.locator(), NO_REQUEST_OPTIONS)
+				.await
+			{
+				Err(HttpError::ResponseError(e)) if e.status_code == StatusCode::NOT_FOUND => {
+					return;
+				}
+				Err(e) => debug!(self.log, "Error checking tunnel registration: {}", e),
+				Ok(_) => {}
+			}
+		}
+	}
+
+	/// Waits until a host other than the one with `host_id` registers an
+	/// endpoint on the tunnel, such as when the primary comes back online.
+	/// Endpoints of the `offline_host_ids` that took over from are ignored,
+	/// in case they couldn't be pruned.
+	pub async fn wait_for_other_host(
+		&mut self,
+		persisted: &PersistedTunnel,
+		host_id: &str,
+		offline_host_ids: &[String],
+		options: &FailoverOptions,
+	) -> Result<(), AnyError> {
+		loop {
+			tokio::time::sleep(options.poll_interval).await;
+
+			if let Some(t) = self.get_tunnel_for_failover(persisted).await? {
+				if failover::has_other_host(&t, host_id, offline_host_ids) {
+					return Ok(());
+				}
+			}
+		}
+	}
+
+	/// Takes over hosting a tunnel found with `find_launcher_tunnel`, once
+	/// `wait_for_host_offline` finds no host connected. Endpoints left behind
+	/// by the hosts that went offline are pruned, and their host IDs returned.
+	pub async fn start_standby_tunnel(
+		&mut self,
+		persisted: &PersistedTunnel,
+	) -> Result<(ActiveTunnel, Vec<String>), AnyError> {
+		let tunnel = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.tag.get"),
+			self.client.get_tunnel(
+				&persisted.locator(),
+				&TunnelRequestOptions {
+					include_ports: true,
+					token_scopes: vec!["host".to_string()],
+					..Default::default()
+				}
+			)
+		)
+		.map_err(|e| wrap_management_error(e, "failed to lookup tunnel"))?;
+
+		let mut offline_host_ids: Vec<String> =
+			tunnel.endpoints.iter().map(|e| e.host_id.clone()).collect();
+		offline_host_ids.sort_unstable();
+		offline_host_ids.dedup();
+		let active = self.host_launcher_tunnel(tunnel, persisted, true).await?;
+		Ok((active, offline_host_ids))
+	}
+
+	/// Gets the tunnel while waiting on failover. Errors that may be transient
+	/// are logged and give None, so the caller checks again later.
+	async fn get_tunnel_for_failover(
+		&mut self,
+		persisted: &PersistedTunnel,
+	) -> Result<Option<Tunnel>, AnyError> {
+		match self
+			.client
+			.get_tunnel(&persisted.locator(), NO_REQUEST_OPTIONS)
+			.await
+		{
+			Ok(t) => Ok(Some(t)),
+			Err(HttpError::ResponseError(e))
+				if e.status_code == StatusCode::NOT_FOUND
+					|| e.status_code == StatusCode::FORBIDDEN =>
+			{
+				Err(DevTunnelError(format!(
+					"tunnel {} no longer exists or is not accessible",
+					persisted.name
+				))
+				.into())
+			}
+			Err(e) => {
+				warning!(self.log, "Error checking tunnel hosts, will retry: {}", e);
+				Ok(None)
+			}
+		}
+	}
+
+	/// Prunes ports and endpoints left from earlier hosts, and starts hosting
+	/// the tunnel. Endpoints of other machines' hosts are only pruned if
+	/// `offline_hosts` is set, as when a standby takes over from them.
+	async fn host_launcher_tunnel(
+		&mut self,
+		tunnel: Tunnel,
+		persisted: &PersistedTunnel,
+		offline_hosts: bool,
+	) -> Result<ActiveTunnel, AnyError> {
 		let locator = TunnelLocator::try_from(&tunnel).unwrap();
 		let host_token = get_host_token_from_tunnel(&tunnel);
-
-		for port_to_delete in tunnel
+		self.security.check_access_control(&persisted.name, &tunnel);
+		self.security.report(
+			SecurityEventKind::HostTokenIssued,
+			&format!("A host token was issued for the tunnel {}", persisted.name),
+		);
+		let (existing_declared, missing_declared): (Vec<TunnelPort>, Vec<TunnelPort>) = self
+			.declared_ports
+			.iter()
+			.cloned()
+			.partition(|d| tunnel.ports.iter().any(|p| p.port_number == d.port_number));
+
+		// stale ports and endpoints are deleted a few at a time, and one failing
+		// doesn't keep the tunnel from starting, since they're only clutter
+		let mut deletes: Vec<BoxFuture<'static, (String, HttpResult<()>)>> = Vec::new();
+		let desired_ports = std::mem::take(&mut self.desired_ports);
+		let mut stale_ports = vec![];
+		for port in &tunnel.ports {
+			let privacy = PortPrivacy::of(port.access_control.as_ref());
+			if !is_cli_port(port) {
+				debug!(
+					self.log,
+					"Leaving port {}, which wasn't forwarded by the CLI", port.port_number
+				);
+			} else if desired_ports.get(&port.port_number) == Some(&privacy) {
+				debug!(
+					self.log,
+					"Keeping port {}, which is still forwarded", port.port_number
+				);
+			} else if !self.reserved_ports.contains(&port.port_number) {
+				stale_ports.push(port.port_number);
+			}
+		}
+		let existing_ports = tunnel
 			.ports
 			.iter()
-			.filter(|p| p.port_number != CONTROL_PORT)
-		{
-			let output_fut = self.client.delete_tunnel_port(
-				&locator,
-				port_to_delete.port_number,
-				NO_REQUEST_OPTIONS,
-			);
-			spanf!(
-				self.log,
-				self.log.span("dev-tunnel.port.delete"),
-				output_fut
-			)
-			.map_err(|e| wrap(e, "failed to delete port"))?;
+			.map(|p| p.port_number)
+			.filter(|p| !stale_ports.contains(p))
+			.collect::<HashSet<_>>();
+		for port in stale_ports {
+			let (client, locator, log) = (self.client.clone(), locator.clone(), self.log.clone());
+			deletes.push(Box::pin(async move {
+				let fut = client.delete_tunnel_port(&locator, port, NO_REQUEST_OPTIONS);
+				let result = spanf!(log, log.span("dev-tunnel.port.delete"), fut);
+				(format!("port {}", port), result)
+			}));
 		}
 
-		// cleanup any old trailing tunnel endpoints
+		// cleanup trailing endpoints left by earlier hosts on this machine. Those
+		// of other hosts are left alone, since they may still be serving it,
+		// unless they're known to be offline.
+		let own_host_ids = self.host_ids.load();
+		let mut prune_host_ids = HashSet::new();
 		for endpoint in tunnel.endpoints {
-			let fut = self.client.delete_tunnel_endpoints(
-				&locator,
-				&endpoint.host_id,
-				None,
-				NO_REQUEST_OPTIONS,
-			);
+			if offline_hosts || own_host_ids.contains(&endpoint.host_id) {
+				prune_host_ids.insert(endpoint.host_id);
+			} else {
+				debug!(
+					self.log,
+					"Leaving endpoint of host {}, which isn't from this machine", endpoint.host_id
+				);
+			}
+		}
+		for host_id in prune_host_ids {
+			let (client, locator, log) = (self.client.clone(), locator.clone(), self.log.clone());
+			deletes.push(Box::pin(async move {
+				let fut = client.delete_tunnel_endpoints(&locator, &host_id, NO_REQUEST_OPTIONS);
+				let result = spanf!(log, log.span("dev-tunnel.endpoint.prune"), fut);
+				(format!("endpoint of host {}", host_id), result)
+			}));
+		}
 
-			spanf!(self.log, self.log.span("dev-tunnel.endpoint.prune"), fut)
-				.map_err(|e| wrap(e, "failed to prune tunnel endpoint"))?;
+		let results = stream::iter(deletes)
+			.buffer_unordered(PRUNE_CONCURRENCY)
+			.collect::<Vec<_>>()
+			.await;
+		for (what, result) in results {
+			match result {
+				Err(e) if !is_not_found(&e) => {
+					warning!(self.log, "Could not delete stale {}: {}", what, e)
+				}
+				_ => {}
+			}
 		}
 
-		self.start_tunnel(
-			locator.clone(),
-			&persisted,
-			self.client.clone(),
-			LookupAccessTokenProvider::new(
+		let mut active = self
+			.start_tunnel(
+				locator.clone(),
+				persisted,
 				self.client.clone(),
-				locator,
-				self.log.clone(),
-				Some(host_token),
-			),
-		)
-		.await
-	}
+				LookupAccessTokenProvider::new(
+					self.client.clone(),
+					locator,
+					self.log.clone(),
+					Some(host_token),
+				),
+			)
+			.await?;
+		active.existing_ports = existing_ports;
 
-	async fn create_tunnel(&mut self, name: &str) -> Result<(PersistedTunnel, Tunnel), AnyError> {
-		info!(self.log, "Creating tunnel with the name: {}", name);
+		active.ports.extend(
+			existing_declared
+				.iter()
+				.map(|p| (p.port_number, PortPrivacy::of(p.access_control.as_ref()))),
+		);
+		for port in missing_declared {
+			let privacy = PortPrivacy::of(port.access_control.as_ref());
+			active.add_port(&port, privacy).await?;
+		}
 
-		let mut tried_recycle = false;
+		Ok(active)
+	}
 
+	async fn create_tunnel(&mut self, name: &str) -> Result<(PersistedTunnel, Tunnel), AnyError> {
 		let new_tunnel = Tunnel {
-			tags: vec![name.to_string(), VSCODE_CLI_TUNNEL_TAG.to_string()],
+			tags: self.launcher_tags(name, None),
+			ports: self.declared_ports.clone(),
 			..Default::default()
 		};
 
+		self.create_tunnel_from(name, new_tunnel, true).await
+	}
+
+	/// Creates the `new_tunnel`. If the tunnel limit is hit and `recycle` is
+	/// set, an unused launcher tunnel is deleted to make room for it.
+	async fn create_tunnel_from(
+		&mut self,
+		name: &str,
+		mut new_tunnel: Tunnel,
+		recycle: bool,
+	) -> Result<(PersistedTunnel, Tunnel), AnyError> {
+		info!(self.log, "Creating tunnel with the name: {}", name);
+		if new_tunnel.cluster_id.is_none() {
+			new_tunnel.cluster_id = self.preferred_cluster.clone();
+		}
+
+		let mut tried_recycle = !recycle;
+
 		loop {
 			let result = spanf!(
 				self.log,
@@ -446,15 +1706,14 @@ impl DevTunnels {
 					}
 
 					return Err(AnyError::from(TunnelCreationFailed(
-                        name.to_string(),
-                        "You've exceeded the 10 machine limit for the port fowarding service. Please remove other machines before trying to add this machine.".to_string(),
-                    )));
+						name.to_string(),
+						self.get_limits().await.quota_exceeded_message(),
+					)));
 				}
 				Err(e) => {
-					return Err(AnyError::from(TunnelCreationFailed(
-						name.to_string(),
-						format!("{:?}", e),
-					)))
+					return Err(get_management_interception(&e).unwrap_or_else(|| {
+						AnyError::from(TunnelCreationFailed(name.to_string(), format!("{:?}", e)))
+					}))
 				}
 				Ok(t) => {
 					return Ok((
@@ -482,13 +1741,7 @@ impl DevTunnels {
 
 		let recyclable = existing_tunnels
 			.iter()
-			.filter(|t| {
-				t.status
-					.as_ref()
-					.and_then(|s| s.host_connection_count.as_ref())
-					.map(|c| c.get_count())
-					.unwrap_or(0) == 0
-			})
+			.filter(|t| is_recyclable(t))
 			.choose(&mut rand::thread_rng());
 
 		match recyclable {
@@ -500,7 +1753,7 @@ impl DevTunnels {
 					self.client
 						.delete_tunnel(&tunnel.try_into().unwrap(), NO_REQUEST_OPTIONS)
 				)
-				.map_err(|e| wrap(e, "failed to execute `tunnel delete`"))?;
+				.map_err(|e| wrap_management_error(e, "failed to execute `tunnel delete`"))?;
 				Ok(true)
 			}
 			None => {
@@ -510,7 +1763,52 @@ impl DevTunnels {
 		}
 	}
 
-	async fn list_all_server_tunnels(&mut self) -> Result<Vec<Tunnel>, AnyError> {
+	/// Lists the launcher tunnels of other machines that have no hosts, and
+	/// that no host has connected to in at least `max_age`. Tunnels the
+	/// service doesn't report a host count or last connection for are never
+	/// stale, since they may belong to machines that are still in use.
+	pub async fn find_stale_tunnels(&mut self, max_age: Duration) -> Result<Vec<Tunnel>, AnyError> {
+		let cutoff = match chrono::Duration::from_std(max_age)
+			.ok()
+			.and_then(|d| Utc::now().checked_sub_signed(d))
+		{
+			Some(c) => c,
+			None => return Ok(vec![]),
+		};
+
+		let own_id = self.launcher_tunnel.load().map(|t| t.id);
+		let tunnels = self.list_all_server_tunnels().await?;
+		Ok(tunnels
+			.into_iter()
+			.filter(|t| t.tunnel_id != own_id && is_stale(t, cutoff))
+			.collect())
+	}
+
+	/// Deletes the tunnels, such as ones from `find_stale_tunnels`. Each is
+	/// tried even if others fail, and reported as a step.
+	pub async fn delete_tunnels(&mut self, tunnels: &[Tunnel]) -> Vec<CleanupStep> {
+		let mut steps = Vec::with_capacity(tunnels.len());
+		for tunnel in tunnels {
+			let result = spanf!(
+				self.log,
+				self.log.span("dev-tunnel.delete"),
+				self.client
+					.delete_tunnel(&tunnel.try_into().unwrap(), NO_REQUEST_OPTIONS)
+			);
+			steps.push(CleanupStep::new(
+				format!(
+					"delete tunnel {}",
+					tunnel.tags.first().map(|t| t.as_str()).unwrap_or_default()
+				),
+				result,
+			));
+		}
+
+		steps
+	}
+
+	/// Lists the launcher tunnels of all machines on the account.
+	pub async fn list_all_server_tunnels(&mut self) -> Result<Vec<Tunnel>, AnyError> {
 		let tunnels = spanf!(
 			self.log,
 			self.log.span("dev-tunnel.listall"),
@@ -520,23 +1818,179 @@ impl DevTunnels {
 				..Default::default()
 			})
 		)
-		.map_err(|e| wrap(e, "error listing current tunnels"))?;
+		.map_err(|e| wrap_management_error(e, "error listing current tunnels"))?;
 
 		Ok(tunnels)
 	}
 
-	async fn check_is_name_free(&mut self, name: &str) -> Result<(), AnyError> {
-		let existing = spanf!(
+	/// Gets the tags of the launcher tunnel with the name, and ID once it has
+	/// one. Its attestation is only included once it's signed for the ID.
+	fn launcher_tags(&self, name: &str, tunnel_id: Option<&str>) -> Vec<String> {
+		let mut tags = vec![name.to_string(), VSCODE_CLI_TUNNEL_TAG.to_string()];
+		tags.extend(self.capability_tags.iter().cloned());
+		match &self.attestation {
+			Some(a) if tunnel_id == Some(a.tunnel.as_str()) => tags.extend(a.to_tags()),
+			_ => {}
+		}
+		tags
+	}
+
+	/// Brings the launcher tunnel's tags up to date, such as after the host's
+	/// capabilities change. This is best-effort, since the tunnel works fine
+	/// with outdated capabilities.
+	async fn update_launcher_tags(&mut self, persisted: &PersistedTunnel) {
+		let full_tunnel = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.tag.get"),
+			self.client
+				.get_tunnel(&persisted.locator(), NO_REQUEST_OPTIONS)
+		);
+
+		let result = match full_tunnel {
+			Ok(mut t) => {
+				t.tags = self.launcher_tags(&persisted.name, Some(&persisted.id));
+				spanf!(
+					self.log,
+					self.log.span("dev-tunnel.tag.update"),
+					self.client.update_tunnel(&t, NO_REQUEST_OPTIONS)
+				)
+				.map(|_| ())
+			}
+			Err(e) => Err(e),
+		};
+
+		if let Err(e) = result {
+			debug!(self.log, "Error updating tunnel tags: {}", e);
+		}
+	}
+
+	/// Creates a launcher tunnel with the name, as a reservation of it. Names
+	/// are only checked to be free before the tunnel is created, so another
+	/// machine may create a tunnel with the same name in between: the tunnel
+	/// created first keeps the name, and if that's not the new tunnel, it's
+	/// deleted and None is returned.
+	async fn reserve_name(
+		&mut self,
+		name: &str,
+	) -> Result<Option<(PersistedTunnel, Tunnel)>, AnyError> {
+		let (persisted, tunnel) = self.create_tunnel(name).await?;
+		if !self.is_name_claimed_before(name, &tunnel).await? {
+			return Ok(Some((persisted, tunnel)));
+		}
+
+		spanf!(
+			self.log,
+			self.log.span("dev-tunnel.delete"),
+			self.client
+				.delete_tunnel(&persisted.locator(), NO_REQUEST_OPTIONS)
+		)
+		.map_err(|e| wrap_management_error(e, "failed to delete duplicate tunnel"))?;
+
+		Ok(None)
+	}
+
+	/// Tags the existing launcher tunnel with the name. As with `reserve_name`,
+	/// another machine may claim the name in between checking that it's free
+	/// and tagging: if its tunnel keeps the name, the tunnel's previous tags
+	/// are restored and false is returned.
+	async fn claim_name(&mut self, tunnel: &mut Tunnel, name: &str) -> Result<bool, AnyError> {
+		let tags = self.launcher_tags(name, tunnel.tunnel_id.as_deref());
+		let previous_tags = std::mem::replace(&mut tunnel.tags, tags);
+		spanf!(
+			self.log,
+			self.log.span("dev-tunnel.tag.update"),
+			self.client.update_tunnel(tunnel, NO_REQUEST_OPTIONS)
+		)
+		.map_err(|e| wrap_management_error(e, "failed to update tunnel tags"))?;
+
+		if !self.is_name_claimed_before(name, tunnel).await? {
+			return Ok(true);
+		}
+
+		tunnel.tags = previous_tags;
+		spanf!(
+			self.log,
+			self.log.span("dev-tunnel.tag.update"),
+			self.client.update_tunnel(tunnel, NO_REQUEST_OPTIONS)
+		)
+		.map_err(|e| wrap_management_error(e, "failed to restore tunnel tags"))?;
+
+		Ok(false)
+	}
+
+	/// Renames the existing launcher tunnel to the name, or if it's taken, to
+	/// the name with a numeric suffix, so a conflict doesn't stop the tunnel
+	/// from starting. Returns the name that was claimed, if any.
+	async fn claim_free_name(
+		&mut self,
+		tunnel: &mut Tunnel,
+		name: &str,
+	) -> Result<Option<String>, AnyError> {
+		let max_length = self.get_limits().await.max_name_length;
+		for candidate in name_candidates(name, max_length) {
+			if !self.is_name_free(&candidate).await? {
+				info!(
+					self.log,
+					"{} is already in use, trying another name", candidate
+				);
+				continue;
+			}
+
+			match self.claim_name(tunnel, &candidate).await {
+				Ok(true) => return Ok(Some(candidate)),
+				Ok(false) => info!(
+					self.log,
+					"{} was just taken by another machine, trying another name", candidate
+				),
+				Err(e) => {
+					warning!(self.log, "Error renaming tunnel, keeping its name: {}", e);
+					return Ok(None);
+				}
+			}
+		}
+
+		warning!(
+			self.log,
+			"No free name like {} was found, keeping the tunnel's name",
+			name
+		);
+		Ok(None)
+	}
+
+	/// Gets whether a tunnel other than the given one claimed the name first.
+	/// All machines racing for a name agree on which tunnel claimed it first,
+	/// the one created first, so exactly one of them keeps it.
+	async fn is_name_claimed_before(
+		&mut self,
+		name: &str,
+		tunnel: &Tunnel,
+	) -> Result<bool, AnyError> {
+		let existing = self.list_tunnels_named(name).await?;
+		let claim = |t: &Tunnel| (t.created, t.tunnel_id.clone());
+		Ok(existing
+			.iter()
+			.any(|t| t.tunnel_id != tunnel.tunnel_id && claim(t) < claim(tunnel)))
+	}
+
+	async fn is_name_free(&mut self, name: &str) -> Result<bool, AnyError> {
+		Ok(self.list_tunnels_named(name).await?.is_empty())
+	}
+
+	async fn list_tunnels_named(&mut self, name: &str) -> Result<Vec<Tunnel>, AnyError> {
+		spanf!(
 			self.log,
-			self.log.span("dev-tunnel.rename.search"),
+			self.log.span("dev-tunnel.name.search"),
 			self.client.list_all_tunnels(&TunnelRequestOptions {
 				tags: vec![VSCODE_CLI_TUNNEL_TAG.to_string(), name.to_string()],
 				require_all_tags: true,
 				..Default::default()
 			})
 		)
-		.map_err(|e| wrap(e, "failed to list existing tunnels"))?;
-		if !existing.is_empty() {
+		.map_err(|e| wrap_management_error(e, "failed to list existing tunnels"))
+	}
+
+	async fn check_is_name_free(&mut self, name: &str) -> Result<(), AnyError> {
+		if !self.is_name_free(name).await? {
 			return Err(AnyError::from(TunnelCreationFailed(
 				name.to_string(),
 				"tunnel name already in use".to_string(),
@@ -545,11 +1999,17 @@ impl DevTunnels {
 		Ok(())
 	}
 
-	async fn get_name_for_tunnel(
+	/// Gets a free name for the launcher tunnel. The preferred name is used if
+	/// it's free, and otherwise the user is prompted for one, unless a random
+	/// name is requested. If the user can't be prompted, a taken preferred
+	/// name falls back to a random name, and no name at all is a
+	/// `TunnelNameRequired` error.
+	pub async fn get_name_for_tunnel(
 		&mut self,
 		preferred_name: Option<String>,
 		mut use_random_name: bool,
 	) -> Result<String, AnyError> {
+		let limits = self.get_limits().await;
 		let existing_tunnels = self.list_all_server_tunnels().await?;
 		let is_name_free = |n: &str| {
 			!existing_tunnels
@@ -559,7 +2019,7 @@ impl DevTunnels {
 
 		if let Some(machine_name) = preferred_name {
 			let name = machine_name;
-			if let Err(e) = is_valid_name(&name) {
+			if let Err(e) = limits.validate_name(&name) {
 				info!(self.log, "{} is an invalid name", e);
 				return Err(AnyError::from(wrap(e, "invalid name")));
 			}
@@ -573,21 +2033,25 @@ impl DevTunnels {
 			use_random_name = true;
 		}
 
-		let mut placeholder_name = name_generator::generate_name(MAX_TUNNEL_NAME_LENGTH);
+		let mut placeholder_name = name_generator::generate_name(limits.max_name_length);
 		if use_random_name {
 			while !is_name_free(&placeholder_name) {
-				placeholder_name = name_generator::generate_name(MAX_TUNNEL_NAME_LENGTH);
+				placeholder_name = name_generator::generate_name(limits.max_name_length);
 			}
 			return Ok(placeholder_name);
 		}
 
+		if !self.interactive {
+			return Err(TunnelNameRequired().into());
+		}
+
 		loop {
 			let name = prompt_placeholder(
 				"What would you like to call this machine?",
 				&placeholder_name,
 			)?;
 
-			if let Err(e) = is_valid_name(&name) {
+			if let Err(e) = limits.validate_name(&name) {
 				info!(self.log, "{}", e);
 				continue;
 			}
@@ -600,6 +2064,93 @@ impl DevTunnels {
 		}
 	}
 
+	/// Creates and hosts a guest tunnel, which expires at `expires_at`. Unlike
+	/// the launcher tunnel, it's not persisted, and the caller is responsible
+	/// for deleting it with `delete_guest_tunnel` once it's done.
+	pub async fn start_guest_tunnel(
+		&mut self,
+		preferred_name: Option<String>,
+		expires_at: SystemTime,
+		access_control: Option<TunnelAccessControl>,
+	) -> Result<(ActiveTunnel, PersistedTunnel), AnyError> {
+		let name = self.get_name_for_tunnel(preferred_name, true).await?;
+		let new_tunnel = Tunnel {
+			tags: vec![
+				name.clone(),
+				GUEST_TUNNEL_TAG.to_string(),
+				guest::expiry_tag(expires_at),
+			],
+			access_control,
+			..Default::default()
+		};
+
+		// never recycle a launcher tunnel to make room for a guest
+		let (persisted, tunnel) = self.create_tunnel_from(&name, new_tunnel, false).await?;
+		let locator = persisted.locator();
+		let host_token = get_host_token_from_tunnel(&tunnel);
+		let started = self
+			.start_tunnel(
+				locator.clone(),
+				&persisted,
+				self.client.clone(),
+				LookupAccessTokenProvider::new(
+					self.client.clone(),
+					locator,
+					self.log.clone(),
+					Some(host_token),
+				),
+			)
+			.await;
+
+		match started {
+			Ok(active) => Ok((active, persisted)),
+			Err(e) => {
+				self.delete_guest_tunnel(&persisted).await.ok();
+				Err(e)
+			}
+		}
+	}
+
+	pub async fn delete_guest_tunnel(&mut self, tunnel: &PersistedTunnel) -> Result<(), AnyError> {
+		spanf!(
+			self.log,
+			self.log.span("dev-tunnel.delete"),
+			self.client
+				.delete_tunnel(&tunnel.locator(), NO_REQUEST_OPTIONS)
+		)
+		.map_err(|e| wrap_management_error(e, "failed to delete guest tunnel"))
+	}
+
+	/// Deletes guest tunnels whose TTL is up, such as ones left by a host that
+	/// exited without deleting them. Returns the names of deleted tunnels.
+	pub async fn delete_expired_guest_tunnels(&mut self) -> Result<Vec<String>, AnyError> {
+		let tunnels = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.listall"),
+			self.client.list_all_tunnels(&TunnelRequestOptions {
+				tags: vec![GUEST_TUNNEL_TAG.to_string()],
+				require_all_tags: true,
+				..Default::default()
+			})
+		)
+		.map_err(|e| wrap_management_error(e, "error listing guest tunnels"))?;
+
+		let now = SystemTime::now();
+		let mut deleted = vec![];
+		for tunnel in tunnels.iter().filter(|t| guest::is_expired(t, now)) {
+			spanf!(
+				self.log,
+				self.log.span("dev-tunnel.delete"),
+				self.client
+					.delete_tunnel(&tunnel.try_into().unwrap(), NO_REQUEST_OPTIONS)
+			)
+			.map_err(|e| wrap_management_error(e, "failed to delete guest tunnel"))?;
+			deleted.push(tunnel.tags.first().cloned().unwrap_or_default());
+		}
+
+		Ok(deleted)
+	}
+
 	/// Hosts an existing tunnel, where the tunnel ID and host token are given.
 	pub async fn start_existing_tunnel(
 		&mut self,
@@ -611,16 +2162,28 @@ impl DevTunnels {
 			cluster: tunnel.cluster,
 		};
 
-		let mut mgmt = self.client.build();
-		mgmt.authorization(tunnels::management::Authorization::Tunnel(
-			tunnel.host_token.clone(),
-		));
+		self.start_existing_tunnel_with_token_provider(
+			tunnel_details,
+			StaticAccessTokenProvider::new(tunnel.host_token),
+		)
+		.await
+	}
+
+	/// Hosts an existing tunnel, where the tunnel ID is given and host tokens
+	/// are obtained from the `access_token` provider.
+	pub async fn start_existing_tunnel_with_token_provider(
+		&mut self,
+		tunnel_details: PersistedTunnel,
+		access_token: impl AccessTokenProvider + 'static,
+	) -> Result<ActiveTunnel, AnyError> {
+		let host_token = access_token.refresh_token().await?;
+		let mgmt = self.client.with_host_token(&host_token);
 
 		self.start_tunnel(
 			tunnel_details.locator(),
 			&tunnel_details,
-			mgmt.into(),
-			StaticAccessTokenProvider::new(tunnel.host_token),
+			mgmt,
+			access_token,
 		)
 		.await
 	}
@@ -629,15 +2192,24 @@ impl DevTunnels {
 		&mut self,
 		locator: TunnelLocator,
 		tunnel_details: &PersistedTunnel,
-		client: TunnelManagementClient,
+		client: SharedManagementClient,
 		access_token: impl AccessTokenProvider + 'static,
 	) -> Result<ActiveTunnel, AnyError> {
-		let mut manager = ActiveTunnelManager::new(self.log.clone(), client, locator, access_token);
+		let relay = client.create_relay_host(locator);
+		let mut manager = ActiveTunnelManager::new(
+			self.log.clone(),
+			relay,
+			access_token,
+			self.backoff,
+			self.sleep_when_idle,
+		);
 
+		// Connecting retries with a backoff, so let Ctrl+C break out of it here
+		// so that the relay registration is torn down rather than left behind.
 		let endpoint_result = spanf!(
 			self.log,
 			self.log.span("dev-tunnel.serve.callback"),
-			manager.get_endpoint()
+			cancellable(manager.get_endpoint())
 		);
 
 		let endpoint = match endpoint_result {
@@ -650,92 +2222,137 @@ impl DevTunnels {
 		};
 
 		debug!(self.log, "Connected to tunnel endpoint: {:?}", endpoint);
+		if let Err(e) = self
+			.host_ids
+			.update_with(endpoint.base.host_id.clone(), remember_host_id)
+		{
+			warning!(self.log, "Error saving the tunnel's host ID: {}", e);
+		}
 
 		Ok(ActiveTunnel {
 			name: tunnel_details.name.clone(),
 			manager,
+			max_ports: self.get_limits().await.max_ports,
+			ports: HashMap::new(),
+			existing_ports: HashSet::new(),
+			port_access: PortAccessRules::default(),
+			forward_buffer_size: self.forward_buffer_size,
+			forward_targets: self.forward_targets.clone(),
 		})
 	}
 }
 
 struct ActiveTunnelManager {
+	log: log::Logger,
 	close_tx: Option<mpsc::Sender<()>>,
 	endpoint_rx: watch::Receiver<Option<Result<TunnelRelayTunnelEndpoint, WrappedError>>>,
-	relay: Arc<tokio::sync::Mutex<RelayTunnelHost>>,
+	/// Last endpoint the tunnel connected with, updated only when the URIs
+	/// clients use change.
+	endpoint_changes: watch::Receiver<Option<TunnelRelayTunnelEndpoint>>,
+	relay: Arc<tokio::sync::Mutex<Box<dyn RelayHost>>>,
+	status: ConnectionTracker,
+	/// Connections clients have open to the tunnel's ports.
+	activity: ActivityTracker,
 }
 
 impl ActiveTunnelManager {
 	pub fn new(
 		log: log::Logger,
-		mgmt: TunnelManagementClient,
-		locator: TunnelLocator,
+		relay: Box<dyn RelayHost>,
 		access_token: impl AccessTokenProvider + 'static,
+		backoff: BackoffConfig,
+		sleep_when_idle: Option<Duration>,
 	) -> ActiveTunnelManager {
 		let (endpoint_tx, endpoint_rx) = watch::channel(None);
+		let (changes_tx, endpoint_changes) = watch::channel(None);
 		let (close_tx, close_rx) = mpsc::channel(1);
 
-		let relay = Arc::new(tokio::sync::Mutex::new(RelayTunnelHost::new(locator, mgmt)));
+		let relay = Arc::new(tokio::sync::Mutex::new(relay));
 		let relay_spawned = relay.clone();
+		let log_spawned = log.clone();
+		let status = ConnectionTracker::new();
+		let status_spawned = status.clone();
+		let activity = ActivityTracker::new();
+		let activity_spawned = activity.clone();
 
 		tokio::spawn(async move {
 			ActiveTunnelManager::spawn_tunnel(
-				log,
+				log_spawned,
 				relay_spawned,
 				close_rx,
 				endpoint_tx,
+				changes_tx,
+				status_spawned,
 				access_token,
+				backoff,
+				activity_spawned,
+				sleep_when_idle,
 			)
 			.await;
 		});
 
 		ActiveTunnelManager {
+			log,
 			endpoint_rx,
+			endpoint_changes,
 			relay,
 			close_tx: Some(close_tx),
+			status,
+			activity,
 		}
 	}
 
-	/// Adds a port for TCP/IP forwarding.
-	#[allow(dead_code)] // todo: port forwarding
-	pub async fn add_port_tcp(&self, port_number: u16) -> Result<(), WrappedError> {
-		self.relay
-			.lock()
-			.await
-			.add_port(&TunnelPort {
-				port_number,
-				protocol: Some(TUNNEL_PROTOCOL_AUTO.to_owned()),
-				..Default::default()
-			})
-			.await
-			.map_err(|e| wrap(e, "error adding port to relay"))?;
+	/// Adds a port for TCP/IP forwarding. Connections to it are forwarded to
+	/// the `target` host and port with at most `buffer_size` bytes buffered
+	/// in each direction.
+	pub async fn add_port(
+		&self,
+		port: &TunnelPort,
+		(host, target_port): (&str, u16),
+		buffer_size: usize,
+	) -> Result<(), WrappedError> {
+		let port = tag_port(port.clone());
+		let connections = self.relay.lock().await.add_port_raw(&port).await?;
+		let connections = meter_transfer(self.activity.track(connections));
+		forward_to_host(
+			self.log.clone(),
+			host.to_string(),
+			target_port,
+			port.port_number,
+			connections,
+			buffer_size,
+		);
 		Ok(())
 	}
 
-	/// Adds a port for TCP/IP forwarding.
+	/// Adds a port for TCP/IP forwarding. Connections to it are reported as
+	/// they open and close, for the port's metrics.
 	pub async fn add_port_direct(
 		&self,
 		port_number: u16,
-	) -> Result<mpsc::UnboundedReceiver<ForwardedPortConnection>, WrappedError> {
-		self.relay
+		access_control: Option<TunnelAccessControl>,
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, WrappedError> {
+		let connections = self
+			.relay
 			.lock()
 			.await
-			.add_port_raw(&TunnelPort {
+			.add_port_raw(&tag_port(TunnelPort {
 				port_number,
 				protocol: Some(TUNNEL_PROTOCOL_AUTO.to_owned()),
+				access_control,
 				..Default::default()
-			})
-			.await
-			.map_err(|e| wrap(e, "error adding port to relay"))
+			}))
+			.await?;
+		Ok(meter_connections(
+			self.log.clone(),
+			port_number,
+			meter_transfer(self.activity.track(connections)),
+		))
 	}
 
 	/// Removes a port from TCP/IP forwarding.
 	pub async fn remove_port(&self, port_number: u16) -> Result<(), WrappedError> {
-		self.relay
-			.lock()
-			.await
-			.remove_port(port_number)
-			.await
-			.map_err(|e| wrap(e, "error remove port from relay"))
+		self.relay.lock().await.remove_port(port_number).await
 	}
 
 	/// Gets the most recent details from the tunnel process. Returns None if
@@ -759,37 +2376,72 @@ impl ActiveTunnelManager {
 			drop(tx);
 		}
 
-		self.relay
-			.lock()
-			.await
-			.unregister()
-			.await
-			.map_err(|e| wrap(e, "error unregistering relay"))?;
+		self.relay.lock().await.unregister().await?;
 
 		while self.endpoint_rx.changed().await.is_ok() {}
+		self.status.closed();
 
 		Ok(())
 	}
 
+	#[allow(clippy::too_many_arguments)]
 	async fn spawn_tunnel(
 		log: log::Logger,
-		relay: Arc<tokio::sync::Mutex<RelayTunnelHost>>,
+		relay: Arc<tokio::sync::Mutex<Box<dyn RelayHost>>>,
 		mut close_rx: mpsc::Receiver<()>,
 		endpoint_tx: watch::Sender<Option<Result<TunnelRelayTunnelEndpoint, WrappedError>>>,
+		changes_tx: watch::Sender<Option<TunnelRelayTunnelEndpoint>>,
+		status: ConnectionTracker,
 		access_token_provider: impl AccessTokenProvider + 'static,
+		backoff_config: BackoffConfig,
+		activity: ActivityTracker,
+		sleep_when_idle: Option<Duration>,
 	) {
-		let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(120));
+		let mut backoff = Backoff::new(backoff_config);
+		let sleep_when_idle = sleep_when_idle.filter(|_| access_token_provider.can_wake());
+
+		// waits to retry, or stops reconnecting once the retries are used up
+		macro_rules! retry {
+			($error: expr) => {
+				match backoff.next_delay() {
+					Some(delay) => {
+						status.backoff($error, delay);
+						tokio::time::sleep(delay).await;
+					}
+					None => {
+						error!(
+							log,
+							"Giving up on connecting the tunnel after {} failed attempts",
+							backoff.failures
+						);
+						status.gave_up($error);
+						break;
+					}
+				}
+			};
+		}
 
 		macro_rules! fail {
 			($e: expr, $msg: expr) => {
 				warning!(log, "{}: {}", $msg, $e);
+				let error = $e.to_string();
 				endpoint_tx.send(Some(Err($e))).ok();
-				backoff.delay().await;
+				retry!(error);
 			};
 		}
 
 		loop {
 			debug!(log, "Starting tunnel to server...");
+			status.connecting();
+			log.progress(log::ProgressFrame::TunnelState {
+				state: if backoff.failures == 0 {
+					log::TunnelProgressState::Connecting
+				} else {
+					log::TunnelProgressState::Reconnecting
+				},
+				name: None,
+				uri: None,
+			});
 
 			let access_token = match access_token_provider.refresh_token().await {
 				Ok(t) => t,
@@ -799,15 +2451,14 @@ impl ActiveTunnelManager {
 				}
 			};
 
-			// we don't bother making a client that can refresh the token, since
-			// the tunnel won't be able to host as soon as the access token expires.
-			let handle_res = {
-				let mut relay = relay.lock().await;
-				relay
-					.connect(&access_token)
-					.await
-					.map_err(|e| wrap(e, "error connecting to tunnel"))
-			};
+			// the relay can't be given a new token while connected, so reconnect
+			// with a new one shortly before this one expires
+			let refresh_in = token_refresh_delay(
+				access_token_provider.expires_at(&access_token),
+				access_token_provider.lifetime(&access_token),
+				SystemTime::now(),
+			);
+			let handle_res = relay.lock().await.connect(&access_token).await;
 
 			let mut handle = match handle_res {
 				Ok(handle) => handle,
@@ -818,58 +2469,278 @@ impl ActiveTunnelManager {
 			};
 
 			backoff.reset();
-			endpoint_tx.send(Some(Ok(handle.endpoint().clone()))).ok();
+			let endpoint = handle.endpoint();
+			status.connected(
+				endpoint.base.host_id.clone(),
+				endpoint.host_relay_uri.clone(),
+			);
+			changes_tx.send_if_modified(|last| {
+				if matches!(last, Some(l) if !endpoint_uris_changed(l, endpoint)) {
+					return false;
+				}
+				*last = Some(endpoint.clone());
+				true
+			});
+			endpoint_tx.send(Some(Ok(endpoint.clone()))).ok();
+			log.progress(log::ProgressFrame::TunnelState {
+				state: log::TunnelProgressState::Connected,
+				name: None,
+				uri: None,
+			});
 
 			tokio::select! {
-				// error is mapped like this prevent it being used across an await,
-				// which Rust dislikes since there's a non-sendable dyn Error in there
-				res = (&mut handle).map_err(|e| wrap(e, "error from tunnel connection")) => {
+				res = handle.wait() => {
 					if let Err(e) = res {
 						fail!(e, "Tunnel exited unexpectedly, reconnecting");
 					} else {
 						warning!(log, "Tunnel exited unexpectedly but gracefully, reconnecting");
-						backoff.delay().await;
+						retry!("tunnel exited unexpectedly".to_string());
 					}
 				},
 				_ = close_rx.recv() => {
 					trace!(log, "Tunnel closing gracefully");
 					trace!(log, "Tunnel closed with result: {:?}", handle.close().await);
+					status.closed();
 					break;
+				},
+				_ = tokio::time::sleep(refresh_in.unwrap_or_default()), if refresh_in.is_some() => {
+					info!(log, "Access token expires soon, reconnecting with a new one");
+					trace!(log, "Tunnel closed with result: {:?}", handle.close().await);
+				}
+				check = wait_for_token_change(&log, &access_token_provider, &access_token) => {
+					match check {
+						TokenCheck::Revoked(reason) => warning!(log, "The access token is no longer valid, as {}. Reconnecting", reason),
+						TokenCheck::Changed(reason) => info!(log, "The access token changed, as {}. Reconnecting with the new one", reason),
+						TokenCheck::Current => {}
+					}
+					trace!(log, "Tunnel closed with result: {:?}", handle.close().await);
+				}
+				_ = activity.wait_until_idle(sleep_when_idle.unwrap_or_default()), if sleep_when_idle.is_some() => {
+					info!(log, "No clients connected for a while, disconnecting from the relay until one tries to connect");
+					let baseline = access_token_provider.last_client_connection().await.ok();
+					trace!(log, "Tunnel closed with result: {:?}", handle.close().await);
+					status.sleeping();
+					tokio::select! {
+						_ = wait_for_client(&log, &access_token_provider, baseline, WAKE_POLL_INTERVAL) => {
+							info!(log, "A client tried to connect, waking the tunnel");
+							activity.touch();
+						},
+						_ = close_rx.recv() => {
+							status.closed();
+							break;
+						},
+					}
 				}
 			}
 		}
 	}
 }
 
-struct Backoff {
-	failures: u32,
-	base_duration: Duration,
-	max_duration: Duration,
-}
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-impl Backoff {
-	pub fn new(base_duration: Duration, max_duration: Duration) -> Self {
-		Self {
-			failures: 0,
-			base_duration,
-			max_duration,
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use tunnels::contracts::TunnelEndpoint;
+
+	/// Reports the last client connections in order, then the last one again.
+	struct ClientConnections {
+		times: Vec<Option<&'static str>>,
+		polls: AtomicUsize,
+	}
+
+	#[async_trait]
+	impl AccessTokenProvider for ClientConnections {
+		async fn refresh_token(&self) -> Result<String, WrappedError> {
+			Ok(String::new())
 		}
+
+		async fn last_client_connection(&self) -> Result<Option<DateTime<Utc>>, WrappedError> {
+			let i = self.polls.fetch_add(1, Ordering::SeqCst);
+			let t = self.times[i.min(self.times.len() - 1)];
+			Ok(t.map(|t| t.parse().unwrap()))
+		}
+	}
+
+	async fn polls_until_woken(
+		baseline: Option<Option<&'static str>>,
+		times: Vec<Option<&'static str>>,
+	) -> Option<usize> {
+		let provider = ClientConnections {
+			times,
+			polls: AtomicUsize::new(0),
+		};
+		let baseline = baseline.map(|b| b.map(|t| t.parse().unwrap()));
+		let log = log::Logger::test();
+		let wait = wait_for_client(&log, &provider, baseline, Duration::from_millis(1));
+		tokio::time::timeout(Duration::from_secs(1), wait)
+			.await
+			.ok()
+			.map(|_| provider.polls.load(Ordering::SeqCst))
+	}
+
+	#[tokio::test]
+	async fn test_wakes_on_new_client_connection() {
+		let earlier = "2024-05-20T10:00:00Z";
+		let later = "2024-05-20T10:05:00Z";
+
+		// only a connection after the service's own baseline wakes the
+		// tunnel, whatever the local clock says
+		assert_eq!(
+			polls_until_woken(Some(Some(earlier)), vec![Some(earlier), Some(later)]).await,
+			Some(2)
+		);
+		assert_eq!(
+			polls_until_woken(Some(None), vec![None, Some(earlier)]).await,
+			Some(2)
+		);
+		assert_eq!(
+			polls_until_woken(Some(Some(later)), vec![Some(later)]).await,
+			None
+		);
+
+		// without a baseline, the first value read becomes it
+		assert_eq!(
+			polls_until_woken(None, vec![Some(earlier), Some(earlier), Some(later)]).await,
+			Some(3)
+		);
+	}
+
+	#[test]
+	fn test_get_token_expiry() {
+		let payload = base64::encode_config(r#"{"exp":1700000000}"#, base64::URL_SAFE_NO_PAD);
+		assert_eq!(
+			get_token_expiry(&format!("header.{}.signature", payload)),
+			Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+		);
+
+		let payload = base64::encode_config(r#"{"sub":"me"}"#, base64::URL_SAFE_NO_PAD);
+		assert_eq!(
+			get_token_expiry(&format!("header.{}.signature", payload)),
+			None
+		);
+		assert_eq!(get_token_expiry("opaque-token"), None);
+	}
+
+	#[test]
+	fn test_token_refresh_delay_with_skewed_clock() {
+		let hour = Duration::from_secs(60 * 60);
+		let issued = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		let expires = issued + hour;
+		let expected = Some(hour - TOKEN_REFRESH_MARGIN);
+
+		// in sync, or with the local clock hours behind or ahead
+		assert_eq!(
+			token_refresh_delay(Some(expires), Some(hour), issued),
+			expected
+		);
+		assert_eq!(
+			token_refresh_delay(Some(expires), Some(hour), issued - 3 * hour),
+			expected
+		);
+		assert_eq!(
+			token_refresh_delay(Some(expires), Some(hour), issued + 3 * hour),
+			expected
+		);
+
+		// without a lifetime, only the local clock can be used
+		assert_eq!(
+			token_refresh_delay(Some(expires), None, issued + 3 * hour),
+			None
+		);
+		assert_eq!(token_refresh_delay(None, Some(hour), issued), None);
+
+		let payload = base64::encode_config(
+			r#"{"iat":1700000000,"exp":1700003600}"#,
+			base64::URL_SAFE_NO_PAD,
+		);
+		assert_eq!(
+			get_token_lifetime(&format!("header.{}.signature", payload)),
+			Some(hour)
+		);
+		assert_eq!(get_token_lifetime("opaque-token"), None);
+	}
+
+	#[test]
+	fn test_describe_token_change() {
+		let jwt = |claims: &str| {
+			format!(
+				"header.{}.signature",
+				base64::encode_config(claims, base64::URL_SAFE_NO_PAD)
+			)
+		};
+
+		let current = jwt(r#"{"scp":"host","tunnelId":"t1","exp":1,"iat":0}"#);
+		assert_eq!(
+			describe_token_change(
+				&current,
+				&jwt(r#"{"scp":"host","tunnelId":"t1","exp":2,"iat":1}"#)
+			),
+			None
+		);
+		assert_eq!(
+			describe_token_change(
+				&current,
+				&jwt(r#"{"scp":"host connect","tunnelId":"t1","exp":2,"aud":"x"}"#)
+			),
+			Some("its aud, scp changed".to_string())
+		);
+
+		assert_eq!(describe_token_change("opaque", "opaque"), None);
+		assert_eq!(
+			describe_token_change("opaque", "other"),
+			Some("it was rotated".to_string())
+		);
 	}
 
-	pub async fn delay(&mut self) {
-		tokio::time::sleep(self.next()).await
+	#[test]
+	fn test_name_candidates() {
+		let names: Vec<String> = name_candidates("my-machine", 20).collect();
+		assert_eq!(names.len(), MAX_NAME_CANDIDATES);
+		assert_eq!(names[0], "my-machine");
+		assert_eq!(names[1], "my-machine-2");
+
+		// shortened to fit the suffix, without doubling up dashes
+		let names: Vec<String> = name_candidates("my-machine", 10).collect();
+		assert_eq!(names[0], "my-machine");
+		assert_eq!(names[1], "my-machi-2");
+		assert_eq!(names[4], "my-machi-5");
+		let names: Vec<String> = name_candidates("my-machine", 5).collect();
+		assert_eq!(names[1], "my-2");
 	}
 
-	pub fn next(&mut self) -> Duration {
-		self.failures += 1;
-		let duration = self
-			.base_duration
-			.checked_mul(self.failures)
-			.unwrap_or(self.max_duration);
-		std::cmp::min(duration, self.max_duration)
+	#[test]
+	fn test_remember_host_id() {
+		let mut ids = vec!["a".to_string(), "b".to_string()];
+		remember_host_id("a".to_string(), &mut ids);
+		assert_eq!(ids, vec!["b", "a"]);
+
+		for i in 0..MAX_PERSISTED_HOST_IDS {
+			remember_host_id(i.to_string(), &mut ids);
+		}
+		assert_eq!(ids.len(), MAX_PERSISTED_HOST_IDS);
+		assert_eq!(ids.first().map(String::as_str), Some("0"));
 	}
 
-	pub fn reset(&mut self) {
-		self.failures = 0;
+	#[test]
+	fn test_endpoint_uris_changed() {
+		let endpoint = |host_id: &str, format: &str| TunnelRelayTunnelEndpoint {
+			base: TunnelEndpoint {
+				host_id: host_id.to_string(),
+				port_uri_format: Some(format.to_string()),
+				..Default::default()
+			},
+			..Default::default()
+		};
+
+		let old = endpoint("host1", "https://abc-{port}.usw2.devtunnels.ms/");
+		assert!(!endpoint_uris_changed(
+			&old,
+			&endpoint("host2", "https://abc-{port}.usw2.devtunnels.ms/")
+		));
+		assert!(endpoint_uris_changed(
+			&old,
+			&endpoint("host1", "https://abc-{port}.euw.devtunnels.ms/")
+		));
 	}
 }
@@ -0,0 +1,1261 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! An in-process emulator of the tunnel management API and relay, used for
+//! testing and for developing against the CLI without service credentials.
+//! It's enabled for `DevTunnels` by setting `VSCODE_CLI_TUNNEL_EMULATOR`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use reqwest::StatusCode;
+use tokio::sync::mpsc;
+use tunnels::contracts::{
+	ResourceStatus, Tunnel, TunnelEndpoint, TunnelPort, TunnelRelayTunnelEndpoint, TunnelStatus,
+	PORT_TOKEN,
+};
+use tunnels::management::{
+	HttpError, HttpResult, ResponseError, TunnelLocator, TunnelRequestOptions,
+};
+
+use crate::util::errors::{wrap, WrappedError};
+
+use super::port_connection::PortConnection;
+use super::service_limits::ServiceLimits;
+use super::tunnel_service::{ManagementClient, RelayConnection, RelayHost, SharedManagementClient};
+
+pub const EMULATOR_ENV_VAR: &str = "VSCODE_CLI_TUNNEL_EMULATOR";
+
+const EMULATED_CLUSTER: &str = "emu";
+const EMULATED_HOST: &str = "tunnels.emulator.invalid";
+const DEFAULT_TUNNEL_LIMIT: usize = 10;
+
+lazy_static! {
+	static ref SHARED_SERVICE: EmulatedTunnelService = EmulatedTunnelService::default();
+}
+
+/// Gets whether the emulator was requested in the environment.
+pub fn is_enabled() -> bool {
+	std::env::var(EMULATOR_ENV_VAR)
+		.map(|v| !v.is_empty())
+		.unwrap_or(false)
+}
+
+/// Gets a client for the emulator shared by the process.
+pub fn shared_client() -> SharedManagementClient {
+	Arc::new(SHARED_SERVICE.clone())
+}
+
+struct EmulatorState {
+	tunnels: Vec<Tunnel>,
+	next_id: u32,
+	tunnel_limit: usize,
+	port_limit: Option<usize>,
+}
+
+/// Emulated tunnel service. Clones share the same tunnels.
+#[derive(Clone)]
+pub struct EmulatedTunnelService {
+	state: Arc<Mutex<EmulatorState>>,
+}
+
+impl Default for EmulatedTunnelService {
+	fn default() -> Self {
+		EmulatedTunnelService {
+			state: Arc::new(Mutex::new(EmulatorState {
+				tunnels: vec![],
+				next_id: 1,
+				tunnel_limit: DEFAULT_TUNNEL_LIMIT,
+				port_limit: None,
+			})),
+		}
+	}
+}
+
+impl EmulatedTunnelService {
+	/// Sets the number of tunnels that can be created before the service
+	/// responds with "too many requests", as it does for the machine limit.
+	pub fn with_tunnel_limit(self, limit: usize) -> Self {
+		self.state.lock().unwrap().tunnel_limit = limit;
+		self
+	}
+
+	/// Sets the number of ports that can be forwarded on a tunnel, which is
+	/// reported in the service's limits.
+	pub fn with_port_limit(self, limit: usize) -> Self {
+		self.state.lock().unwrap().port_limit = Some(limit);
+		self
+	}
+
+	/// Gets a snapshot of the tunnels in the service.
+	pub fn tunnels(&self) -> Vec<Tunnel> {
+		self.state.lock().unwrap().tunnels.clone()
+	}
+
+	/// Creates a tunnel, as the service does when a machine creates one, such
+	/// as to act as another machine in tests. None is returned once the
+	/// tunnel limit is reached.
+	pub fn add_tunnel(&self, tunnel: &Tunnel) -> Option<Tunnel> {
+		let mut state = self.state.lock().unwrap();
+		if state.tunnels.len() >= state.tunnel_limit {
+			return None;
+		}
+
+		let id = state.next_id;
+		state.next_id += 1;
+
+		let mut access_tokens = HashMap::new();
+		access_tokens.insert("host".to_string(), format!("emulated-host-token-{}", id));
+
+		let cluster_id = Some(
+			tunnel
+				.cluster_id
+				.clone()
+				.unwrap_or_else(|| EMULATED_CLUSTER.to_string()),
+		);
+		let tunnel_id = Some(format!("emulated{}", id));
+		let created = Tunnel {
+			ports: tunnel
+				.ports
+				.iter()
+				.map(|p| TunnelPort {
+					cluster_id: cluster_id.clone(),
+					tunnel_id: tunnel_id.clone(),
+					..p.clone()
+				})
+				.collect(),
+			cluster_id,
+			tunnel_id,
+			access_tokens: Some(access_tokens),
+			status: Some(TunnelStatus {
+				host_connection_count: Some(ResourceStatus::default()),
+				..Default::default()
+			}),
+			endpoints: vec![],
+			created: tunnel.created.or_else(|| Some(Utc::now())),
+			..tunnel.clone()
+		};
+
+		state.tunnels.push(created.clone());
+		Some(created)
+	}
+
+	fn with_tunnel<T>(
+		&self,
+		locator: &TunnelLocator,
+		f: impl FnOnce(&mut Tunnel) -> T,
+	) -> Option<T> {
+		let mut state = self.state.lock().unwrap();
+		state
+			.tunnels
+			.iter_mut()
+			.find(|t| is_match(t, locator))
+			.map(f)
+	}
+}
+
+#[async_trait]
+impl ManagementClient for EmulatedTunnelService {
+	async fn list_all_tunnels(&self, options: &TunnelRequestOptions) -> HttpResult<Vec<Tunnel>> {
+		let state = self.state.lock().unwrap();
+		Ok(state
+			.tunnels
+			.iter()
+			.filter(|t| {
+				let has_tag = |tag: &String| t.tags.contains(tag);
+				options.tags.is_empty()
+					|| (options.require_all_tags && options.tags.iter().all(has_tag))
+					|| (!options.require_all_tags && options.tags.iter().any(has_tag))
+			})
+			.cloned()
+			.collect())
+	}
+
+	async fn get_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.with_tunnel(locator, |t| t.clone())
+			.ok_or_else(not_found)
+	}
+
+	async fn create_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.add_tunnel(tunnel)
+			.ok_or_else(|| response_error(StatusCode::TOO_MANY_REQUESTS))
+	}
+
+	async fn update_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		let locator = TunnelLocator::try_from(tunnel).map_err(|_| not_found())?;
+		self.with_tunnel(&locator, |t| {
+			t.name = tunnel.name.clone();
+			t.description = tunnel.description.clone();
+			t.tags = tunnel.tags.clone();
+			if tunnel.access_control.is_some() {
+				t.access_control = tunnel.access_control.clone();
+			}
+			t.clone()
+		})
+		.ok_or_else(not_found)
+	}
+
+	async fn delete_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		let mut state = self.state.lock().unwrap();
+		let len = state.tunnels.len();
+		state.tunnels.retain(|t| !is_match(t, locator));
+		if state.tunnels.len() == len {
+			return Err(not_found());
+		}
+
+		Ok(())
+	}
+
+	async fn delete_tunnel_port(
+		&self,
+		locator: &TunnelLocator,
+		port_number: u16,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.with_tunnel(locator, |t| {
+			t.ports.retain(|p| p.port_number != port_number);
+		})
+		.ok_or_else(not_found)
+	}
+
+	async fn delete_tunnel_endpoints(
+		&self,
+		locator: &TunnelLocator,
+		host_id: &str,
+		_options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.with_tunnel(locator, |t| {
+			t.endpoints.retain(|e| e.host_id != host_id);
+		})
+		.ok_or_else(not_found)
+	}
+
+	async fn get_service_limits(&self) -> HttpResult<Option<ServiceLimits>> {
+		let state = self.state.lock().unwrap();
+		Ok(Some(ServiceLimits {
+			machine_quota: state.tunnel_limit,
+			max_ports: state.port_limit,
+			..Default::default()
+		}))
+	}
+
+	fn with_host_token(&self, _host_token: &str) -> SharedManagementClient {
+		Arc::new(self.clone())
+	}
+
+	fn create_relay_host(&self, locator: TunnelLocator) -> Box<dyn RelayHost> {
+		let id = {
+			let mut state = self.state.lock().unwrap();
+			state.next_id += 1;
+			state.next_id
+		};
+
+		Box::new(EmulatedRelayHost {
+			service: self.clone(),
+			locator,
+			host_id: format!("emulated-host-{}", id),
+			port_senders: HashMap::new(),
+		})
+	}
+}
+
+/// Emulated relay host. Ports are recorded on the tunnel, but no
+/// connections are ever forwarded to them.
+struct EmulatedRelayHost {
+	service: EmulatedTunnelService,
+	locator: TunnelLocator,
+	host_id: String,
+	port_senders: HashMap<u16, mpsc::UnboundedSender<PortConnection>>,
+}
+
+impl EmulatedRelayHost {
+	fn record_port(&self, port: &TunnelPort) -> Result<(), WrappedError> {
+		self.service
+			.with_tunnel(&self.locator, |t| {
+				if !t.ports.iter().any(|p| p.port_number == port.port_number) {
+					t.ports.push(TunnelPort {
+						cluster_id: t.cluster_id.clone(),
+						tunnel_id: t.tunnel_id.clone(),
+						..port.clone()
+					});
+				}
+			})
+			.ok_or_else(not_found)
+			.map_err(|e| wrap(e, "error adding port to relay"))
+	}
+}
+
+#[async_trait]
+impl RelayHost for EmulatedRelayHost {
+	async fn connect(
+		&mut self,
+		_access_token: &str,
+	) -> Result<Box<dyn RelayConnection>, WrappedError> {
+		let host_id = self.host_id.clone();
+		let endpoint = self
+			.service
+			.with_tunnel(&self.locator, |t| {
+				let tunnel_id = t.tunnel_id.clone().unwrap_or_default();
+				let endpoint = TunnelEndpoint {
+					id: Some(format!("{}-relay", host_id)),
+					connection_mode: Some("TunnelRelay".to_string()),
+					host_id: host_id.clone(),
+					port_uri_format: Some(format!(
+						"https://{}-{}.{}.{}/",
+						tunnel_id, PORT_TOKEN, EMULATED_CLUSTER, EMULATED_HOST
+					)),
+					tunnel_uri: Some(format!(
+						"https://{}.{}.{}/",
+						tunnel_id, EMULATED_CLUSTER, EMULATED_HOST
+					)),
+					..Default::default()
+				};
+
+				t.endpoints.retain(|e| e.host_id != host_id);
+				t.endpoints.push(endpoint.clone());
+				set_host_connections(t, 1);
+				endpoint
+			})
+			.ok_or_else(not_found)
+			.map_err(|e| wrap(e, "error connecting to tunnel"))?;
+
+		Ok(Box::new(EmulatedRelayConnection {
+			service: self.service.clone(),
+			locator: self.locator.clone(),
+			endpoint: TunnelRelayTunnelEndpoint {
+				base: endpoint,
+				..Default::default()
+			},
+		}))
+	}
+
+	async fn add_port(&mut self, port: &TunnelPort) -> Result<(), WrappedError> {
+		self.record_port(port)
+	}
+
+	async fn add_port_raw(
+		&mut self,
+		port: &TunnelPort,
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, WrappedError> {
+		self.record_port(port)?;
+		let (tx, rx) = mpsc::unbounded_channel();
+		self.port_senders.insert(port.port_number, tx);
+		Ok(rx)
+	}
+
+	async fn remove_port(&mut self, port_number: u16) -> Result<(), WrappedError> {
+		self.port_senders.remove(&port_number);
+		self.service
+			.with_tunnel(&self.locator, |t| {
+				t.ports.retain(|p| p.port_number != port_number);
+			})
+			.ok_or_else(not_found)
+			.map_err(|e| wrap(e, "error remove port from relay"))
+	}
+
+	async fn unregister(&mut self) -> Result<(), WrappedError> {
+		self.port_senders.clear();
+		// the tunnel may have been deleted before the host unregisters
+		self.service.with_tunnel(&self.locator, |t| {
+			t.endpoints.retain(|e| e.host_id != self.host_id);
+		});
+		Ok(())
+	}
+}
+
+struct EmulatedRelayConnection {
+	service: EmulatedTunnelService,
+	locator: TunnelLocator,
+	endpoint: TunnelRelayTunnelEndpoint,
+}
+
+#[async_trait]
+impl RelayConnection for EmulatedRelayConnection {
+	fn endpoint(&self) -> &TunnelRelayTunnelEndpoint {
+		&self.endpoint
+	}
+
+	async fn wait(&mut self) -> Result<(), WrappedError> {
+		futures::future::pending().await
+	}
+
+	async fn close(&mut self) -> Result<(), WrappedError> {
+		self.service
+			.with_tunnel(&self.locator, |t| set_host_connections(t, 0));
+		Ok(())
+	}
+}
+
+fn is_match(tunnel: &Tunnel, locator: &TunnelLocator) -> bool {
+	match locator {
+		TunnelLocator::ID { cluster, id } => {
+			tunnel.cluster_id.as_ref() == Some(cluster) && tunnel.tunnel_id.as_ref() == Some(id)
+		}
+		TunnelLocator::Name(name) => tunnel.name.as_ref() == Some(name),
+	}
+}
+
+fn set_host_connections(tunnel: &mut Tunnel, count: u64) {
+	let status = tunnel.status.get_or_insert_with(Default::default);
+	if count > 0 {
+		status.last_host_connection_time = Some(Utc::now());
+	}
+	status
+		.host_connection_count
+		.get_or_insert_with(Default::default)
+		.current = count;
+}
+
+fn not_found() -> HttpError {
+	response_error(StatusCode::NOT_FOUND)
+}
+
+pub(super) fn response_error(status_code: StatusCode) -> HttpError {
+	HttpError::ResponseError(ResponseError {
+		url: format!("https://{}/api/v1/tunnels", EMULATED_HOST)
+			.parse()
+			.unwrap(),
+		status_code,
+		data: None,
+		request_id: None,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::time::{Duration, SystemTime};
+
+	use crate::log;
+	use crate::state::LauncherPaths;
+	use crate::tunnels::capabilities::HostCapabilities;
+	use crate::tunnels::dev_tunnels::{self, AccessTokenProvider, DevTunnels, PersistedTunnel};
+	use crate::tunnels::failover::FailoverOptions;
+	use crate::tunnels::guest;
+	use crate::tunnels::port_access::{PortAccessRules, PortPrivacy};
+	use crate::tunnels::registry::TunnelRegistry;
+	use crate::util::errors::AnyError;
+
+	const LAUNCHER_TAG: &str = "vscode-server-launcher";
+
+	fn make_dev_tunnels(service: &EmulatedTunnelService, dir: &tempfile::TempDir) -> DevTunnels {
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		DevTunnels::new_with_client(&log::Logger::test(), &paths, Arc::new(service.clone()))
+	}
+
+	struct CountingTokenProvider(Arc<AtomicUsize>);
+
+	#[async_trait]
+	impl AccessTokenProvider for CountingTokenProvider {
+		async fn refresh_token(&self) -> Result<String, WrappedError> {
+			let n = self.0.fetch_add(1, Ordering::SeqCst);
+			Ok(format!("token-{}", n))
+		}
+	}
+
+	fn tunnel_tags(service: &EmulatedTunnelService) -> Vec<Vec<String>> {
+		service.tunnels().into_iter().map(|t| t.tags).collect()
+	}
+
+	#[tokio::test]
+	async fn test_lists_launcher_tunnels() {
+		let service = EmulatedTunnelService::default();
+		service
+			.add_tunnel(&Tunnel {
+				tags: vec!["not-a-launcher".to_string()],
+				..Default::default()
+			})
+			.unwrap();
+
+		let (dir_a, dir_b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+		for (dir, name) in [(&dir_a, "machine-a"), (&dir_b, "machine-b")] {
+			make_dev_tunnels(&service, dir)
+				.start_new_launcher_tunnel(Some(name.to_string()), false)
+				.await
+				.unwrap();
+		}
+
+		let mut names = make_dev_tunnels(&service, &dir_a)
+			.list_all_server_tunnels()
+			.await
+			.unwrap()
+			.into_iter()
+			.map(|t| t.tags[0].clone())
+			.collect::<Vec<_>>();
+		names.sort();
+		assert_eq!(names, vec!["machine-a", "machine-b"]);
+	}
+
+	#[tokio::test]
+	async fn test_creates_and_removes_tunnel() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut dt = make_dev_tunnels(&service, &dir);
+
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		assert_eq!(active.name, "my-machine");
+		assert_eq!(
+			tunnel_tags(&service),
+			vec![vec!["my-machine".to_string(), LAUNCHER_TAG.to_string()]]
+		);
+		assert_eq!(service.tunnels()[0].endpoints.len(), 1);
+
+		active.close().await.unwrap();
+		assert!(service.tunnels()[0].endpoints.is_empty());
+
+		dt.remove_tunnel().await.unwrap();
+		assert!(service.tunnels().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_publishes_host_capabilities() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let caps = HostCapabilities {
+			os: "linux".to_string(),
+			arch: "x86_64".to_string(),
+			runtimes: vec!["node".to_string()],
+			gpu: true,
+			containers: false,
+		};
+
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		active.close().await.unwrap();
+		assert_eq!(
+			HostCapabilities::from_tags(&service.tunnels()[0].tags),
+			HostCapabilities::default()
+		);
+
+		// capabilities are added to a tunnel created before they were published
+		let mut dt = make_dev_tunnels(&service, &dir);
+		dt.set_host_capabilities(&caps);
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		active.close().await.unwrap();
+
+		let tags = &service.tunnels()[0].tags;
+		assert_eq!(&tags[..2], ["my-machine", LAUNCHER_TAG]);
+		assert_eq!(HostCapabilities::from_tags(tags), caps);
+	}
+
+	#[tokio::test]
+	async fn test_creates_tunnel_in_preferred_cluster() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut dt = make_dev_tunnels(&service, &dir);
+		dt.set_preferred_cluster(Some("euw".to_string()));
+
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		active.close().await.unwrap();
+
+		assert_eq!(service.tunnels()[0].cluster_id.as_deref(), Some("euw"));
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		assert_eq!(
+			dev_tunnels::get_persisted_cluster(&paths).as_deref(),
+			Some("euw")
+		);
+	}
+
+	#[tokio::test]
+	async fn test_reuses_persisted_tunnel() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		active.close().await.unwrap();
+
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		active.close().await.unwrap();
+
+		assert_eq!(service.tunnels().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_renames_tunnel() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut dt = make_dev_tunnels(&service, &dir);
+
+		dt.rename_tunnel("first-name").await.unwrap();
+		dt.rename_tunnel("second-name").await.unwrap();
+		assert_eq!(
+			tunnel_tags(&service),
+			vec![vec!["second-name".to_string(), LAUNCHER_TAG.to_string()]]
+		);
+
+		let other_dir = tempfile::tempdir().unwrap();
+		let mut other = make_dev_tunnels(&service, &other_dir);
+		assert!(other.rename_tunnel("second-name").await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_starts_with_suffixed_name_when_taken() {
+		let service = EmulatedTunnelService::default();
+		let dir = tempfile::tempdir().unwrap();
+		make_dev_tunnels(&service, &dir)
+			.rename_tunnel("my-machine")
+			.await
+			.unwrap();
+
+		let other_dir = tempfile::tempdir().unwrap();
+		let mut other = make_dev_tunnels(&service, &other_dir);
+		other.rename_tunnel("other-machine").await.unwrap();
+		let mut active = other
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		assert_eq!(active.name, "my-machine-2");
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_needs_name_when_not_interactive() {
+		let service = EmulatedTunnelService::default();
+		let dir = tempfile::tempdir().unwrap();
+		let mut dt = make_dev_tunnels(&service, &dir);
+		dt.set_interactive(false);
+
+		let result = dt.start_new_launcher_tunnel(None, false).await;
+		assert!(matches!(result, Err(AnyError::TunnelNameRequired(_))));
+		assert!(service.tunnels().is_empty());
+
+		let mut active = dt.start_new_launcher_tunnel(None, true).await.unwrap();
+		assert!(!active.name.is_empty());
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_recycles_unused_tunnel_at_limit() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default().with_tunnel_limit(1);
+		service
+			.create_tunnel(
+				&Tunnel {
+					tags: vec!["old-machine".to_string(), LAUNCHER_TAG.to_string()],
+					..Default::default()
+				},
+				&TunnelRequestOptions::default(),
+			)
+			.await
+			.unwrap();
+
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("new-machine".to_string()), false)
+			.await
+			.unwrap();
+		active.close().await.unwrap();
+
+		assert_eq!(
+			tunnel_tags(&service),
+			vec![vec!["new-machine".to_string(), LAUNCHER_TAG.to_string()]]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_finds_stale_tunnels() {
+		let service = EmulatedTunnelService::default();
+		let days_ago = |d| Utc::now() - chrono::Duration::days(d);
+		for (name, last_host) in [
+			("old-machine", Some(60)),
+			("new-machine", Some(1)),
+			("unreported-machine", None),
+		] {
+			let created = service
+				.create_tunnel(
+					&Tunnel {
+						tags: vec![name.to_string(), LAUNCHER_TAG.to_string()],
+						created: Some(days_ago(90)),
+						..Default::default()
+					},
+					&TunnelRequestOptions::default(),
+				)
+				.await
+				.unwrap();
+			// a tunnel the service reports no status for isn't stale, as its
+			// host may still be active
+			let locator = TunnelLocator::try_from(&created).unwrap();
+			service.with_tunnel(&locator, |t| match last_host {
+				Some(d) => t.status.as_mut().unwrap().last_host_connection_time = Some(days_ago(d)),
+				None => t.status = None,
+			});
+		}
+
+		// a tunnel that's hosted isn't stale, however old it is
+		let dir = tempfile::tempdir().unwrap();
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("busy-machine".to_string()), false)
+			.await
+			.unwrap();
+
+		let other_dir = tempfile::tempdir().unwrap();
+		let mut dt = make_dev_tunnels(&service, &other_dir);
+		let stale = dt
+			.find_stale_tunnels(Duration::from_secs(30 * 24 * 60 * 60))
+			.await
+			.unwrap();
+		assert_eq!(
+			stale.iter().map(|t| t.tags[0].as_str()).collect::<Vec<_>>(),
+			vec!["old-machine"]
+		);
+
+		let steps = dt.delete_tunnels(&stale).await;
+		assert_eq!(steps.len(), 1);
+		assert!(steps[0].error.is_none());
+		assert_eq!(service.tunnels().len(), 3);
+
+		// deleting reports each tunnel, including ones that no longer exist
+		let steps = dt.delete_tunnels(&stale).await;
+		assert!(steps[0].error.is_some());
+
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_does_not_recycle_tunnel_in_use() {
+		let service = EmulatedTunnelService::default().with_tunnel_limit(1);
+
+		let dir = tempfile::tempdir().unwrap();
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("machine-a".to_string()), false)
+			.await
+			.unwrap();
+
+		let other_dir = tempfile::tempdir().unwrap();
+		let result = make_dev_tunnels(&service, &other_dir)
+			.start_new_launcher_tunnel(Some("machine-b".to_string()), false)
+			.await;
+		let message = result.err().unwrap().to_string();
+		assert!(message.contains("1 machine limit"), "{}", message);
+
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_forwards_ports() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+
+		let _rx = active.add_port_direct(8080).await.unwrap();
+		let ports: Vec<u16> = service.tunnels()[0]
+			.ports
+			.iter()
+			.map(|p| p.port_number)
+			.collect();
+		assert_eq!(ports, vec![8080]);
+
+		let uri = active.get_port_uri(8080).await.unwrap();
+		assert!(uri.contains("-8080."), "unexpected uri {}", uri);
+
+		active.remove_port(8080).await.unwrap();
+		assert!(service.tunnels()[0].ports.is_empty());
+
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_keeps_reserved_ports_on_start() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		let _admin = active.add_port_direct(9000).await.unwrap();
+		let _web = active.add_port_direct(8080).await.unwrap();
+		active.close().await.unwrap();
+
+		let mut dt = make_dev_tunnels(&service, &dir);
+		dt.add_reserved_ports([9000]);
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+
+		let ports: Vec<u16> = service.tunnels()[0]
+			.ports
+			.iter()
+			.map(|p| p.port_number)
+			.collect();
+		assert_eq!(ports, vec![9000]);
+
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_counts_existing_ports_toward_limit() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default().with_port_limit(2);
+		make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap()
+			.close()
+			.await
+			.unwrap();
+
+		let locator = TunnelLocator::try_from(&service.tunnels()[0]).unwrap();
+		service.with_tunnel(&locator, |t| {
+			t.ports.push(TunnelPort {
+				port_number: 5000,
+				..Default::default()
+			})
+		});
+
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		let _web = active.add_port_direct(8080).await.unwrap();
+		let _again = active.add_port_direct(8080).await.unwrap();
+		assert!(active.add_port_direct(8081).await.is_err());
+
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_keeps_ports_forwarded_by_others_on_start() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		let _web = active.add_port_direct(8080).await.unwrap();
+		active.close().await.unwrap();
+
+		// a port forwarded from another machine, without the CLI's tag
+		let locator = TunnelLocator::try_from(&service.tunnels()[0]).unwrap();
+		service.with_tunnel(&locator, |t| {
+			t.ports.push(TunnelPort {
+				port_number: 5000,
+				..Default::default()
+			})
+		});
+
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		let ports: Vec<u16> = service.tunnels()[0]
+			.ports
+			.iter()
+			.map(|p| p.port_number)
+			.collect();
+		assert_eq!(ports, vec![5000]);
+
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_keeps_desired_ports_on_start() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		active
+			.add_port_tcp(8080, PortPrivacy::Private)
+			.await
+			.unwrap();
+		active
+			.add_port_tcp(8081, PortPrivacy::Public)
+			.await
+			.unwrap();
+		active
+			.add_port_tcp(9000, PortPrivacy::Private)
+			.await
+			.unwrap();
+		active.close().await.unwrap();
+
+		// 8081 changed privacy, and 9000 is no longer forwarded
+		let mut dt = make_dev_tunnels(&service, &dir);
+		dt.set_desired_ports([(8080, PortPrivacy::Private), (8081, PortPrivacy::Private)]);
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		let ports: Vec<u16> = service.tunnels()[0]
+			.ports
+			.iter()
+			.map(|p| p.port_number)
+			.collect();
+		assert_eq!(ports, vec![8080]);
+
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_creates_tunnel_with_declared_ports() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let declared = |port: u16| TunnelPort {
+			port_number: port,
+			protocol: Some("https".to_string()),
+			..Default::default()
+		};
+
+		let mut dt = make_dev_tunnels(&service, &dir);
+		dt.declare_ports(vec![declared(3000)]);
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		let ports = service.tunnels()[0].ports.clone();
+		assert_eq!(ports.len(), 1);
+		assert_eq!(ports[0].port_number, 3000);
+		assert_eq!(ports[0].protocol.as_deref(), Some("https"));
+		active.close().await.unwrap();
+
+		let mut dt = make_dev_tunnels(&service, &dir);
+		dt.declare_ports(vec![declared(3000), declared(4000)]);
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		let ports: Vec<u16> = service.tunnels()[0]
+			.ports
+			.iter()
+			.map(|p| p.port_number)
+			.collect();
+		assert_eq!(ports, vec![3000, 4000]);
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_sets_port_access_rules() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+
+		active.set_port_access(PortAccessRules::parse(&["5432=10.0.0.0/8"]).unwrap());
+		let _db = active.add_port_direct(5432).await.unwrap();
+		let _web = active.add_port_direct(8080).await.unwrap();
+
+		let ports = service.tunnels()[0].ports.clone();
+		let access_control = |port: u16| {
+			ports
+				.iter()
+				.find(|p| p.port_number == port)
+				.unwrap()
+				.access_control
+				.clone()
+		};
+		assert_eq!(access_control(5432).unwrap().entries.len(), 1);
+		assert!(access_control(8080).is_none());
+
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_starts_tunnel_with_token_provider() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let tunnel = service
+			.create_tunnel(
+				&Tunnel {
+					tags: vec!["my-machine".to_string()],
+					..Default::default()
+				},
+				&TunnelRequestOptions::default(),
+			)
+			.await
+			.unwrap();
+
+		let calls = Arc::new(AtomicUsize::new(0));
+		let mut active = make_dev_tunnels(&service, &dir)
+			.start_existing_tunnel_with_token_provider(
+				PersistedTunnel {
+					name: "my-machine".to_string(),
+					id: tunnel.tunnel_id.unwrap(),
+					cluster: tunnel.cluster_id.unwrap(),
+				},
+				CountingTokenProvider(calls.clone()),
+			)
+			.await
+			.unwrap();
+
+		assert!(calls.load(Ordering::SeqCst) >= 2);
+		assert_eq!(service.tunnels()[0].endpoints.len(), 1);
+
+		active.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_standby_takes_over_and_hands_back() {
+		let service = EmulatedTunnelService::default();
+		let options = FailoverOptions {
+			poll_interval: Duration::from_millis(1),
+			offline_checks: 2,
+		};
+
+		let primary_dir = tempfile::tempdir().unwrap();
+		let mut primary = make_dev_tunnels(&service, &primary_dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+
+		let standby_dir = tempfile::tempdir().unwrap();
+		let mut dt = make_dev_tunnels(&service, &standby_dir);
+		let persisted = dt.find_launcher_tunnel("my-machine").await.unwrap();
+
+		let waiting = tokio::time::timeout(
+			Duration::from_millis(50),
+			dt.wait_for_host_offline(&persisted, &options),
+		);
+		assert!(
+			waiting.await.is_err(),
+			"expected to wait while the primary hosts"
+		);
+
+		primary.close().await.unwrap();
+		dt.wait_for_host_offline(&persisted, &options)
+			.await
+			.unwrap();
+
+		let (mut standby, offline) = dt.start_standby_tunnel(&persisted).await.unwrap();
+		let host_id = standby.host_id().await.unwrap();
+		assert_eq!(service.tunnels().len(), 1);
+		assert_eq!(service.tunnels()[0].endpoints[0].host_id, host_id);
+
+		let mut primary = make_dev_tunnels(&service, &primary_dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		dt.wait_for_other_host(&persisted, &host_id, &offline, &options)
+			.await
+			.unwrap();
+
+		standby.close().await.unwrap();
+		primary.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_standby_takes_over_from_crashed_primary() {
+		let service = EmulatedTunnelService::default();
+		let options = FailoverOptions {
+			poll_interval: Duration::from_millis(1),
+			offline_checks: 2,
+		};
+
+		let primary_dir = tempfile::tempdir().unwrap();
+		let mut primary = make_dev_tunnels(&service, &primary_dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		let crashed_id = primary.host_id().await.unwrap();
+
+		// the primary crashes, leaving its endpoint behind
+		let paths = LauncherPaths::new_without_replacements(primary_dir.path().to_owned());
+		let persisted = dev_tunnels::get_persisted_tunnel(&paths).unwrap();
+		service.with_tunnel(&persisted.locator(), |t| set_host_connections(t, 0));
+
+		let standby_dir = tempfile::tempdir().unwrap();
+		let mut dt = make_dev_tunnels(&service, &standby_dir);
+		let persisted = dt.find_launcher_tunnel("my-machine").await.unwrap();
+		dt.wait_for_host_offline(&persisted, &options)
+			.await
+			.unwrap();
+
+		let (mut standby, offline) = dt.start_standby_tunnel(&persisted).await.unwrap();
+		let host_id = standby.host_id().await.unwrap();
+		assert_eq!(offline, vec![crashed_id]);
+		let endpoints = &service.tunnels()[0].endpoints;
+		assert_eq!(endpoints.len(), 1);
+		assert_eq!(endpoints[0].host_id, host_id);
+
+		// the standby keeps hosting, rather than handing back to the crashed host
+		let handing_back = tokio::time::timeout(
+			Duration::from_millis(50),
+			dt.wait_for_other_host(&persisted, &host_id, &offline, &options),
+		);
+		assert!(handing_back.await.is_err(), "expected to keep hosting");
+
+		standby.close().await.unwrap();
+		drop(primary);
+	}
+
+	#[tokio::test]
+	async fn test_standby_requires_existing_tunnel() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let result = make_dev_tunnels(&service, &dir)
+			.find_launcher_tunnel("my-machine")
+			.await;
+		assert!(result.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_recreates_tunnel_deleted_while_running() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut dt = make_dev_tunnels(&service, &dir);
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		active
+			.add_port_tcp(8080, PortPrivacy::Public)
+			.await
+			.unwrap();
+		let persisted = dt.find_launcher_tunnel("my-machine").await.unwrap();
+
+		let poll_interval = Duration::from_millis(1);
+		let waiting = tokio::time::timeout(
+			Duration::from_millis(50),
+			dt.wait_for_tunnel_deleted(&persisted, poll_interval),
+		);
+		assert!(waiting.await.is_err(), "expected the tunnel to exist");
+
+		service
+			.delete_tunnel(&persisted.locator(), &TunnelRequestOptions::default())
+			.await
+			.unwrap();
+		dt.wait_for_tunnel_deleted(&persisted, poll_interval).await;
+		active.close().await.unwrap();
+
+		let mut recreated = dt
+			.start_new_launcher_tunnel(Some(active.name.clone()), false)
+			.await
+			.unwrap();
+		for port in active.forwarded_ports() {
+			recreated
+				.add_port_tcp(port, active.port_privacy(port))
+				.await
+				.unwrap();
+		}
+		assert_eq!(recreated.port_privacy(8080), PortPrivacy::Public);
+		assert_eq!(
+			tunnel_tags(&service),
+			vec![vec!["my-machine".to_string(), LAUNCHER_TAG.to_string()]]
+		);
+		assert!(recreated.has_port(8080));
+
+		recreated.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_hosts_several_named_tunnels() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let mut registry = TunnelRegistry::new(make_dev_tunnels(&service, &dir), &paths);
+
+		registry.host("work").await.unwrap();
+		registry.host("personal").await.unwrap();
+		assert!(registry.host("work").await.is_err());
+		assert!(registry.host("../escape").await.is_err());
+
+		let names: Vec<String> = registry.list().into_iter().map(|t| t.name).collect();
+		assert_eq!(names, vec!["personal".to_string(), "work".to_string()]);
+		assert_eq!(service.tunnels().len(), 2);
+		assert!(registry.get_mut("work").unwrap().host_id().await.is_ok());
+
+		// neither is the machine's launcher tunnel
+		assert!(dev_tunnels::get_persisted_tunnel(&paths).is_none());
+
+		registry.remove("work").await.unwrap();
+		registry.close("personal").await.unwrap();
+		assert_eq!(
+			tunnel_tags(&service),
+			vec![vec!["personal".to_string(), LAUNCHER_TAG.to_string()]]
+		);
+
+		// the remaining tunnel is reused when it's hosted again
+		registry.host("personal").await.unwrap();
+		assert_eq!(service.tunnels().len(), 1);
+		registry.close("personal").await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_guest_tunnels_are_separate_and_expire() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = EmulatedTunnelService::default();
+		let mut dt = make_dev_tunnels(&service, &dir);
+
+		let expired_at = SystemTime::now() - Duration::from_secs(60);
+		let (mut expired, _) = dt
+			.start_guest_tunnel(Some("old-guest".to_string()), expired_at, None)
+			.await
+			.unwrap();
+		expired.close().await.unwrap();
+
+		let expires_at = SystemTime::now() + Duration::from_secs(60 * 60);
+		let (mut active, persisted) = dt
+			.start_guest_tunnel(
+				Some("new-guest".to_string()),
+				expires_at,
+				guest::access_control(&["someone@example.com".to_string()]),
+			)
+			.await
+			.unwrap();
+
+		// guest tunnels aren't the machine's launcher tunnel
+		assert!(dt.find_launcher_tunnel("new-guest").await.is_err());
+
+		assert_eq!(
+			dt.delete_expired_guest_tunnels().await.unwrap(),
+			vec!["old-guest".to_string()]
+		);
+		assert_eq!(
+			tunnel_tags(&service),
+			vec![vec![
+				"new-guest".to_string(),
+				guest::GUEST_TUNNEL_TAG.to_string(),
+				guest::expiry_tag(expires_at),
+			]]
+		);
+		assert!(service.tunnels()[0].access_control.is_some());
+
+		active.close().await.unwrap();
+		dt.delete_guest_tunnel(&persisted).await.unwrap();
+		assert!(service.tunnels().is_empty());
+	}
+}
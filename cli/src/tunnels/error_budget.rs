@@ -0,0 +1,170 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Error budgets for optional subsystems, like auto port forwarding, webhooks,
+//! and the metrics endpoint. Each tracks how often its recent attempts failed,
+//! and once they fail steadily it's turned off for the rest of the run, so an
+//! auxiliary feature that keeps erroring can't destabilize hosting itself.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::log;
+
+/// How far back attempts are counted towards the failure rate.
+const WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Fewest attempts in the window before a subsystem can be turned off, so a
+/// couple of early errors don't.
+const MIN_ATTEMPTS: usize = 10;
+
+/// Share of attempts in the window, in percent, that have to have failed for
+/// a subsystem to be turned off.
+const MAX_FAILURE_PERCENT: usize = 80;
+
+/// An optional subsystem whose failures are budgeted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+	AutoForward,
+	Webhooks,
+	Metrics,
+}
+
+impl Subsystem {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Subsystem::AutoForward => "auto port forwarding",
+			Subsystem::Webhooks => "webhooks",
+			Subsystem::Metrics => "the metrics endpoint",
+		}
+	}
+}
+
+impl fmt::Display for Subsystem {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+#[derive(Default)]
+struct BudgetState {
+	/// When each attempt in the window happened, and whether it failed.
+	attempts: VecDeque<(Instant, bool)>,
+	disabled: bool,
+}
+
+impl BudgetState {
+	/// Records an attempt, returning whether it used up the budget.
+	fn record(&mut self, now: Instant, failed: bool) -> bool {
+		if self.disabled {
+			return false;
+		}
+
+		self.attempts.push_back((now, failed));
+		while let Some((at, _)) = self.attempts.front() {
+			if now.duration_since(*at) <= WINDOW {
+				break;
+			}
+			self.attempts.pop_front();
+		}
+
+		let failures = self.attempts.iter().filter(|(_, f)| *f).count();
+		self.disabled = self.attempts.len() >= MIN_ATTEMPTS
+			&& failures * 100 >= self.attempts.len() * MAX_FAILURE_PERCENT;
+		self.disabled
+	}
+
+	fn failure_summary(&self) -> String {
+		let failures = self.attempts.iter().filter(|(_, f)| *f).count();
+		format!(
+			"{} of its last {} attempts failed",
+			failures,
+			self.attempts.len()
+		)
+	}
+}
+
+/// Failure budget of a subsystem. Clones share the same budget.
+#[derive(Clone)]
+pub struct ErrorBudget {
+	subsystem: Subsystem,
+	log: log::Logger,
+	state: Arc<Mutex<BudgetState>>,
+}
+
+impl ErrorBudget {
+	pub fn new(log: log::Logger, subsystem: Subsystem) -> Self {
+		ErrorBudget {
+			subsystem,
+			log,
+			state: Arc::new(Mutex::new(BudgetState::default())),
+		}
+	}
+
+	/// Gets whether the subsystem was turned off after failing too often.
+	pub fn is_disabled(&self) -> bool {
+		self.state.lock().unwrap().disabled
+	}
+
+	/// Records whether an attempt succeeded, turning the subsystem off and
+	/// reporting it if it's failed too often.
+	pub fn record<T, E>(&self, result: &Result<T, E>) {
+		let mut state = self.state.lock().unwrap();
+		if !state.record(Instant::now(), result.is_err()) {
+			return;
+		}
+
+		let reason = state.failure_summary();
+		drop(state);
+		warning!(
+			self.log,
+			"Turning {} off for the rest of this run, as {}",
+			self.subsystem,
+			reason
+		);
+		self.log.progress(log::ProgressFrame::SubsystemDisabled {
+			subsystem: self.subsystem.name(),
+			reason: &reason,
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_disables_on_sustained_failures() {
+		let mut state = BudgetState::default();
+		let start = Instant::now();
+		for i in 0..(MIN_ATTEMPTS - 1) {
+			assert!(!state.record(start + Duration::from_secs(i as u64), true));
+		}
+		assert!(state.record(start + Duration::from_secs(60), true));
+		assert!(state.disabled);
+		assert_eq!(state.failure_summary(), "10 of its last 10 attempts failed");
+	}
+
+	#[test]
+	fn test_tolerates_occasional_and_old_failures() {
+		let mut state = BudgetState::default();
+		let start = Instant::now();
+		for i in 0..50u64 {
+			state.record(start + Duration::from_secs(i), i % 3 == 0);
+		}
+		assert!(!state.disabled);
+
+		// failures from before the window are forgotten
+		let mut state = BudgetState::default();
+		for i in 0..(MIN_ATTEMPTS as u64 - 1) {
+			state.record(start + Duration::from_secs(i), true);
+		}
+		let later = start + WINDOW + Duration::from_secs(60);
+		assert!(!state.record(later, true));
+		assert_eq!(state.attempts.len(), 1);
+	}
+}
@@ -0,0 +1,87 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Filters the extensions a client asks the server to install as it starts.
+//! This isn't a security control: it only applies to that initial install,
+//! and clients can still install any extension through the running server.
+
+use serde::{Deserialize, Serialize};
+
+/// Extensions installed when a client starts the server. Entries are
+/// extension IDs like `publisher.name`, all of a publisher's extensions as
+/// `publisher.*`, or every extension as `*`, compared case-insensitively.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionFilter {
+	/// If not empty, only these extensions are installed.
+	#[serde(default)]
+	pub allowed: Vec<String>,
+	/// Extensions that aren't installed, even if allowed.
+	#[serde(default)]
+	pub denied: Vec<String>,
+}
+
+impl ExtensionFilter {
+	pub fn is_empty(&self) -> bool {
+		self.allowed.is_empty() && self.denied.is_empty()
+	}
+
+	/// Gets whether the extension, optionally with an `@version`, passes the
+	/// filter.
+	pub fn is_allowed(&self, extension: &str) -> bool {
+		let id = extension.split('@').next().unwrap_or(extension);
+		let matches = |pattern: &String| is_match(pattern, id);
+		!self.denied.iter().any(matches)
+			&& (self.allowed.is_empty() || self.allowed.iter().any(matches))
+	}
+
+	/// Splits the extensions into those that pass the filter, and those that
+	/// don't.
+	pub fn partition(&self, extensions: Vec<String>) -> (Vec<String>, Vec<String>) {
+		extensions.into_iter().partition(|e| self.is_allowed(e))
+	}
+}
+
+fn is_match(pattern: &str, id: &str) -> bool {
+	match pattern.strip_suffix('*') {
+		Some(prefix) => id
+			.get(..prefix.len())
+			.is_some_and(|p| p.eq_ignore_ascii_case(prefix)),
+		None => pattern.eq_ignore_ascii_case(id),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_is_allowed() {
+		let filter = ExtensionFilter {
+			allowed: vec![
+				"ms-python.*".to_string(),
+				"rust-lang.rust-analyzer".to_string(),
+			],
+			denied: vec!["ms-python.debugpy".to_string()],
+		};
+		assert!(filter.is_allowed("ms-python.python"));
+		assert!(filter.is_allowed("MS-Python.Python@2024.1.0"));
+		assert!(filter.is_allowed("rust-lang.rust-analyzer"));
+		assert!(!filter.is_allowed("ms-python.debugpy"));
+		assert!(!filter.is_allowed("evil.extension"));
+		assert!(!filter.is_allowed("ms-pythonx.python"));
+
+		let filter = ExtensionFilter {
+			denied: vec!["evil.*".to_string()],
+			..Default::default()
+		};
+		assert!(filter.is_allowed("ms-python.python"));
+		assert!(!filter.is_allowed("evil.extension"));
+		assert!(ExtensionFilter::default().is_allowed("evil.extension"));
+
+		let (allowed, denied) = filter.partition(vec!["a.b".to_string(), "evil.c".to_string()]);
+		assert_eq!(allowed, vec!["a.b"]);
+		assert_eq!(denied, vec!["evil.c"]);
+	}
+}
@@ -0,0 +1,109 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Failover between two machines hosting the same tunnel. The primary hosts
+//! the tunnel as usual. A standby watches the endpoints the tunnel service
+//! lists for the tunnel: when no host has been connected for a few checks it
+//! takes over hosting, pruning the endpoints the offline hosts left behind,
+//! and when an endpoint from another host shows up again it hands the tunnel
+//! back.
+
+use std::time::Duration;
+
+use tunnels::contracts::Tunnel;
+
+/// Controls how quickly a standby host reacts to the primary.
+#[derive(Clone, Debug)]
+pub struct FailoverOptions {
+	/// How often the tunnel is checked.
+	pub poll_interval: Duration,
+	/// Number of consecutive checks on which no host is connected before the
+	/// standby takes over.
+	pub offline_checks: u32,
+}
+
+impl Default for FailoverOptions {
+	fn default() -> Self {
+		Self {
+			poll_interval: Duration::from_secs(10),
+			offline_checks: 3,
+		}
+	}
+}
+
+/// Gets whether any host is currently connected to the tunnel.
+pub fn is_hosted(tunnel: &Tunnel) -> bool {
+	match tunnel
+		.status
+		.as_ref()
+		.and_then(|s| s.host_connection_count.as_ref())
+	{
+		Some(count) => count.get_count() > 0,
+		None => !tunnel.endpoints.is_empty(),
+	}
+}
+
+/// Gets whether the tunnel has an endpoint registered by a host other than
+/// the one with `host_id`, ignoring those of the `offline_host_ids`.
+pub fn has_other_host(tunnel: &Tunnel, host_id: &str, offline_host_ids: &[String]) -> bool {
+	tunnel
+		.endpoints
+		.iter()
+		.any(|e| e.host_id != host_id && !offline_host_ids.contains(&e.host_id))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use tunnels::contracts::TunnelEndpoint;
+
+	fn tunnel_with_hosts(host_ids: &[&str]) -> Tunnel {
+		Tunnel {
+			endpoints: host_ids
+				.iter()
+				.map(|id| TunnelEndpoint {
+					host_id: id.to_string(),
+					..Default::default()
+				})
+				.collect(),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_is_hosted_falls_back_to_endpoints() {
+		assert!(!is_hosted(&tunnel_with_hosts(&[])));
+		assert!(is_hosted(&tunnel_with_hosts(&["primary"])));
+	}
+
+	#[test]
+	fn test_has_other_host() {
+		assert!(!has_other_host(&tunnel_with_hosts(&[]), "standby", &[]));
+		assert!(!has_other_host(
+			&tunnel_with_hosts(&["standby"]),
+			"standby",
+			&[]
+		));
+		assert!(has_other_host(
+			&tunnel_with_hosts(&["standby", "primary"]),
+			"standby",
+			&[]
+		));
+
+		// a crashed primary's endpoint, left from before the takeover
+		let offline = vec!["crashed".to_string()];
+		assert!(!has_other_host(
+			&tunnel_with_hosts(&["standby", "crashed"]),
+			"standby",
+			&offline
+		));
+		assert!(has_other_host(
+			&tunnel_with_hosts(&["standby", "crashed", "primary"]),
+			"standby",
+			&offline
+		));
+	}
+}
@@ -0,0 +1,144 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Guest tunnels are throwaway tunnels, separate from the machine's launcher
+//! tunnel, for one-off pairing or support sessions. The host deletes them
+//! once their TTL is up, and since the tunnel service has no notion of
+//! expiry, the time they expire is also kept in a tag so that ones left
+//! behind by a host that didn't get to delete them can be cleaned up later.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tunnels::contracts::{
+	Tunnel, TunnelAccessControl, TunnelAccessControlEntry, TunnelAccessControlEntryType,
+};
+
+use crate::util::errors::InvalidGuestTtl;
+
+use super::port_access::CONNECT_SCOPE;
+
+/// Tag set on all guest tunnels.
+pub const GUEST_TUNNEL_TAG: &str = "vscode-guest-tunnel";
+
+/// Prefix of the tag holding the Unix time, in seconds, a guest tunnel expires.
+const EXPIRES_TAG_PREFIX: &str = "vscode-guest-expires-";
+
+/// Parses a TTL given as a sequence of numbers with `d`, `h`, `m`, or `s`
+/// units, such as `2h` or `1h30m`.
+pub fn parse_ttl(s: &str) -> Result<Duration, InvalidGuestTtl> {
+	let invalid = || InvalidGuestTtl(s.to_string());
+	let mut total = 0u64;
+	let mut digits = String::new();
+
+	for c in s.trim().chars() {
+		if c.is_ascii_digit() {
+			digits.push(c);
+			continue;
+		}
+
+		let unit = match c {
+			'd' => 24 * 60 * 60,
+			'h' => 60 * 60,
+			'm' => 60,
+			's' => 1,
+			_ => return Err(invalid()),
+		};
+		let n = digits.parse::<u64>().map_err(|_| invalid())?;
+		total = n
+			.checked_mul(unit)
+			.and_then(|n| total.checked_add(n))
+			.ok_or_else(invalid)?;
+		digits.clear();
+	}
+
+	if !digits.is_empty() || total == 0 {
+		return Err(invalid());
+	}
+
+	Ok(Duration::from_secs(total))
+}
+
+/// Gets the tag recording that a guest tunnel expires at `expires_at`.
+pub fn expiry_tag(expires_at: SystemTime) -> String {
+	let secs = expires_at
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0);
+	format!("{}{}", EXPIRES_TAG_PREFIX, secs)
+}
+
+/// Gets the time the guest tunnel expires, from its tags.
+pub fn get_expiry(tunnel: &Tunnel) -> Option<SystemTime> {
+	tunnel
+		.tags
+		.iter()
+		.find_map(|t| t.strip_prefix(EXPIRES_TAG_PREFIX))
+		.and_then(|secs| secs.parse::<u64>().ok())
+		.map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Gets whether the tunnel is a guest tunnel that expired before `now`.
+pub fn is_expired(tunnel: &Tunnel, now: SystemTime) -> bool {
+	tunnel.tags.iter().any(|t| t == GUEST_TUNNEL_TAG)
+		&& matches!(get_expiry(tunnel), Some(e) if e <= now)
+}
+
+/// Gets the access control for a guest tunnel, which lets the given users
+/// connect in addition to the tunnel's owner. With no users, only the owner
+/// can connect.
+pub fn access_control(identities: &[String]) -> Option<TunnelAccessControl> {
+	if identities.is_empty() {
+		return None;
+	}
+
+	Some(TunnelAccessControl {
+		entries: vec![TunnelAccessControlEntry {
+			kind: TunnelAccessControlEntryType::Users,
+			subjects: identities.to_vec(),
+			scopes: vec![CONNECT_SCOPE.to_string()],
+			..Default::default()
+		}],
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_ttl() {
+		assert_eq!(parse_ttl("2h").unwrap(), Duration::from_secs(2 * 60 * 60));
+		assert_eq!(parse_ttl("1h30m").unwrap(), Duration::from_secs(90 * 60));
+		assert_eq!(parse_ttl("90s").unwrap(), Duration::from_secs(90));
+		assert_eq!(parse_ttl("1d").unwrap(), Duration::from_secs(24 * 60 * 60));
+
+		for invalid in ["", "2", "h", "0m", "2x", "-1h", "99999999999999999d"] {
+			assert!(parse_ttl(invalid).is_err(), "{}", invalid);
+		}
+	}
+
+	#[test]
+	fn test_reads_expiry_from_tags() {
+		let expires_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		let tunnel = Tunnel {
+			tags: vec![
+				"my-guest".to_string(),
+				GUEST_TUNNEL_TAG.to_string(),
+				expiry_tag(expires_at),
+			],
+			..Default::default()
+		};
+
+		assert_eq!(get_expiry(&tunnel), Some(expires_at));
+		assert!(is_expired(&tunnel, expires_at));
+		assert!(!is_expired(&tunnel, expires_at - Duration::from_secs(1)));
+
+		let launcher = Tunnel {
+			tags: vec!["my-machine".to_string()],
+			..Default::default()
+		};
+		assert!(!is_expired(&launcher, SystemTime::now()));
+	}
+}
@@ -0,0 +1,202 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Restarts the tunnel without it going offline. The running process starts
+//! a new one with the same arguments and keeps serving until it has connected
+//! to the relay as a second host of the tunnel. The new process then takes
+//! the singleton lock like `--force` would, which shuts the old one down, and
+//! forwards the ports that were handed off to it.
+//!
+//! Connections aren't handed off: clients connected to the old process are
+//! disconnected as it exits, and reconnect to the new one, which is already
+//! hosting the tunnel when they do.
+
+use std::{
+	fs::{read_to_string, remove_file, write},
+	path::{Path, PathBuf},
+	time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{process::Child, time::timeout};
+
+use crate::{
+	log,
+	state::LauncherPaths,
+	util::errors::{wrap, AnyError},
+};
+
+use super::{dev_tunnels::ActiveTunnel, port_access::PortPrivacy};
+
+/// Set to the ID of the process handing off to a new one, so the new one
+/// knows to take the tunnel over rather than start on its own.
+const HANDOFF_ENV_VAR: &str = "VSCODE_CLI_HANDOFF_FROM";
+/// `Handoff` from the old process to the new one, as JSON.
+const HANDOFF_FILE: &str = "tunnel-handoff.json";
+
+/// How long the new process has to take the tunnel over before it's killed,
+/// and the old one carries on.
+pub const HANDOFF_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct HandedOffPort {
+	port: u16,
+	privacy: PortPrivacy,
+}
+
+/// State of the tunnel passed from the old process to the new one.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Handoff {
+	pid: u32,
+	ports: Vec<HandedOffPort>,
+}
+
+impl Handoff {
+	fn from_tunnel(tunnel: &ActiveTunnel) -> Self {
+		Handoff {
+			pid: std::process::id(),
+			ports: tunnel
+				.forwarded_ports()
+				.into_iter()
+				.map(|port| HandedOffPort {
+					port,
+					privacy: tunnel.port_privacy(port),
+				})
+				.collect(),
+		}
+	}
+
+	fn save(&self, paths: &LauncherPaths) -> Result<(), AnyError> {
+		let path = paths.root().join(HANDOFF_FILE);
+		write(&path, serde_json::to_string(self).unwrap())
+			.map_err(|e| wrap(e, format!("error writing {}", path.display())))?;
+		Ok(())
+	}
+
+	/// Takes the handoff meant for this process, if it was started to take
+	/// the tunnel over from another.
+	pub fn take(paths: &LauncherPaths) -> Option<Handoff> {
+		let from = std::env::var(HANDOFF_ENV_VAR).ok()?.parse().ok()?;
+		Self::take_from(paths, from)
+	}
+
+	fn take_from(paths: &LauncherPaths, from: u32) -> Option<Handoff> {
+		let path = paths.root().join(HANDOFF_FILE);
+		let handoff: Handoff = serde_json::from_str(&read_to_string(&path).ok()?).ok()?;
+		// a handoff from another process is for its own new process
+		if handoff.pid != from {
+			return None;
+		}
+
+		remove_file(&path).ok();
+		Some(handoff)
+	}
+
+	/// Gets the ID of the process that handed off.
+	pub fn from_pid(&self) -> u32 {
+		self.pid
+	}
+
+	/// Forwards the ports that were forwarded by the old process.
+	pub async fn forward_ports(&self, log: &log::Logger, tunnel: &mut ActiveTunnel) {
+		for p in &self.ports {
+			if tunnel.has_port(p.port) {
+				continue;
+			}
+			if let Err(e) = tunnel.add_port_tcp(p.port, p.privacy).await {
+				warning!(log, "Could not forward port {} again: {}", p.port, e);
+			}
+		}
+	}
+}
+
+/// A new process that was started to take the tunnel over.
+pub struct PendingHandoff {
+	child: Child,
+	exe: PathBuf,
+	started: Instant,
+}
+
+impl PendingHandoff {
+	/// Starts `exe` with the arguments of this process, to take over the
+	/// tunnel and the ports forwarded on it.
+	pub fn start(
+		paths: &LauncherPaths,
+		tunnel: &ActiveTunnel,
+		exe: &Path,
+	) -> Result<PendingHandoff, AnyError> {
+		let handoff = Handoff::from_tunnel(tunnel);
+		handoff.save(paths)?;
+
+		let child = tokio::process::Command::new(exe)
+			.args(std::env::args_os().skip(1))
+			.env(HANDOFF_ENV_VAR, handoff.pid.to_string())
+			.spawn()
+			.map_err(|e| wrap(e, format!("error starting {}", exe.display())))?;
+
+		Ok(PendingHandoff {
+			child,
+			exe: exe.to_owned(),
+			started: Instant::now(),
+		})
+	}
+
+	/// Gets the binary the new process was started from.
+	pub fn exe(&self) -> &Path {
+		&self.exe
+	}
+
+	/// Waits until the new process fails to take the tunnel over, either by
+	/// exiting or by running out of time, in which case it's killed. Returns
+	/// the reason.
+	pub async fn failed(&mut self) -> String {
+		let remaining = HANDOFF_TIMEOUT.saturating_sub(self.started.elapsed());
+		match timeout(remaining, self.child.wait()).await {
+			Ok(Ok(status)) => format!("it exited ({})", status),
+			Ok(Err(e)) => format!("error waiting for it: {}", e),
+			Err(_) => {
+				self.child.kill().await.ok();
+				format!("it did not take over within {}s", HANDOFF_TIMEOUT.as_secs())
+			}
+		}
+	}
+
+	/// Gets the new process once it's taken the tunnel over.
+	pub fn into_child(self) -> Child {
+		self.child
+	}
+}
+
+/// Waits for the pending handoff, if any, to fail. See `PendingHandoff::failed`.
+pub async fn wait_for_failure(handoff: &mut Option<PendingHandoff>) -> String {
+	match handoff {
+		Some(h) => h.failed().await,
+		None => std::future::pending().await,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_takes_handoff_from_expected_process() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let handoff = Handoff {
+			pid: 1234,
+			ports: vec![HandedOffPort {
+				port: 8080,
+				privacy: PortPrivacy::Public,
+			}],
+		};
+		handoff.save(&paths).unwrap();
+
+		assert_eq!(Handoff::take_from(&paths, 5678), None);
+		assert_eq!(Handoff::take_from(&paths, 1234), Some(handoff));
+		// it can only be taken once
+		assert_eq!(Handoff::take_from(&paths, 1234), None);
+	}
+}
@@ -0,0 +1,366 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+use serde::Serialize;
+
+use crate::log::{self, Level, LogSink, ProgressFrame, SecurityEventKind, TunnelProgressState};
+
+use super::error_budget::{ErrorBudget, Subsystem};
+
+/// Commands run on tunnel lifecycle events. Each is run with a shell, with
+/// details of the event in `VSCODE_TUNNEL_*` environment variables.
+#[derive(Clone, Debug, Default)]
+pub struct TunnelHooks {
+	pub on_connect: Option<String>,
+	pub on_disconnect: Option<String>,
+	pub on_reconnect: Option<String>,
+	pub on_port_forwarded: Option<String>,
+	pub on_security_event: Option<String>,
+	/// URL that security events are posted to as JSON.
+	pub security_webhook: Option<String>,
+}
+
+impl TunnelHooks {
+	pub fn is_empty(&self) -> bool {
+		self.on_connect.is_none()
+			&& self.on_disconnect.is_none()
+			&& self.on_reconnect.is_none()
+			&& self.on_port_forwarded.is_none()
+			&& self.on_security_event.is_none()
+			&& self.security_webhook.is_none()
+	}
+
+	fn command_for(&self, event: &HookEvent) -> Option<&str> {
+		match event {
+			HookEvent::Connect => self.on_connect.as_deref(),
+			HookEvent::Disconnect => self.on_disconnect.as_deref(),
+			HookEvent::Reconnect => self.on_reconnect.as_deref(),
+			HookEvent::PortForwarded { .. } => self.on_port_forwarded.as_deref(),
+			HookEvent::Security { .. } => self.on_security_event.as_deref(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum HookEvent {
+	Connect,
+	Disconnect,
+	Reconnect,
+	PortForwarded {
+		port: u16,
+		uri: String,
+	},
+	Security {
+		kind: SecurityEventKind,
+		detail: String,
+	},
+}
+
+impl HookEvent {
+	fn name(&self) -> &'static str {
+		match self {
+			HookEvent::Connect => "connect",
+			HookEvent::Disconnect => "disconnect",
+			HookEvent::Reconnect => "reconnect",
+			HookEvent::PortForwarded { .. } => "port-forwarded",
+			HookEvent::Security { .. } => "security",
+		}
+	}
+}
+
+/// What's known about the tunnel when an event fires.
+#[derive(Clone, Debug, Default)]
+struct TunnelDetails {
+	name: Option<String>,
+	uri: Option<String>,
+}
+
+#[derive(Default)]
+struct HookState {
+	details: TunnelDetails,
+	connected: bool,
+	reconnecting: bool,
+}
+
+impl HookState {
+	/// Updates the state from a progress frame, returning the event it
+	/// fires, if any.
+	fn on_frame(&mut self, frame: &ProgressFrame) -> Option<HookEvent> {
+		match frame {
+			ProgressFrame::TunnelState { state, name, uri } => match state {
+				TunnelProgressState::Listening => {
+					self.details = TunnelDetails {
+						name: name.map(str::to_string),
+						uri: uri.map(str::to_string),
+					};
+					self.connected = true;
+					self.reconnecting = false;
+					Some(HookEvent::Connect)
+				}
+				TunnelProgressState::Reconnecting | TunnelProgressState::Closed
+					if self.connected =>
+				{
+					self.connected = false;
+					self.reconnecting = matches!(state, TunnelProgressState::Reconnecting);
+					Some(HookEvent::Disconnect)
+				}
+				TunnelProgressState::Connected if self.reconnecting => {
+					self.connected = true;
+					self.reconnecting = false;
+					Some(HookEvent::Reconnect)
+				}
+				_ => None,
+			},
+			ProgressFrame::PortUri { port, uri } => Some(HookEvent::PortForwarded {
+				port: *port,
+				uri: uri.to_string(),
+			}),
+			ProgressFrame::SecurityEvent { kind, detail } => Some(HookEvent::Security {
+				kind: *kind,
+				detail: detail.to_string(),
+			}),
+			_ => None,
+		}
+	}
+}
+
+/// Log sink that runs hook commands as the tunnel reports its progress.
+/// Commands run one at a time, in the order their events happened.
+#[derive(Clone)]
+pub struct HookSink {
+	state: Arc<Mutex<HookState>>,
+	tx: mpsc::UnboundedSender<(HookEvent, TunnelDetails)>,
+}
+
+impl HookSink {
+	/// Creates the sink. Its output is logged to `log`, which should not
+	/// itself include the sink.
+	pub fn new(log: log::Logger, hooks: TunnelHooks) -> Self {
+		let (tx, mut rx) = mpsc::unbounded_channel::<(HookEvent, TunnelDetails)>();
+		tokio::spawn(async move {
+			let client = reqwest::Client::new();
+			let webhook_budget = ErrorBudget::new(log.clone(), Subsystem::Webhooks);
+			while let Some((event, details)) = rx.recv().await {
+				if let Some(command) = hooks.command_for(&event) {
+					run_hook(&log, command, &event, &details).await;
+				}
+				if let (HookEvent::Security { kind, detail }, Some(url)) =
+					(&event, &hooks.security_webhook)
+				{
+					if !webhook_budget.is_disabled() {
+						let result =
+							post_security_webhook(&log, &client, url, *kind, detail, &details)
+								.await;
+						webhook_budget.record(&result);
+					}
+				}
+			}
+		});
+
+		Self {
+			state: Arc::new(Mutex::new(HookState::default())),
+			tx,
+		}
+	}
+}
+
+impl LogSink for HookSink {
+	fn write_log(&self, _level: Level, _prefix: &str, _message: &str) {}
+	fn write_result(&self, _message: &str) {}
+
+	fn write_progress(&self, frame: &ProgressFrame) {
+		let mut state = self.state.lock().unwrap();
+		if let Some(event) = state.on_frame(frame) {
+			self.tx.send((event, state.details.clone())).ok();
+		}
+	}
+}
+
+async fn run_hook(log: &log::Logger, command: &str, event: &HookEvent, details: &TunnelDetails) {
+	info!(log, "Running {} hook: {}", event.name(), command);
+
+	#[cfg(windows)]
+	let mut cmd = {
+		let mut cmd = Command::new("cmd");
+		cmd.arg("/C").arg(command);
+		cmd
+	};
+	#[cfg(not(windows))]
+	let mut cmd = {
+		let mut cmd = Command::new("sh");
+		cmd.arg("-c").arg(command);
+		cmd
+	};
+
+	cmd.stdin(Stdio::null())
+		.env("VSCODE_TUNNEL_EVENT", event.name())
+		.env(
+			"VSCODE_TUNNEL_NAME",
+			details.name.as_deref().unwrap_or_default(),
+		)
+		.env(
+			"VSCODE_TUNNEL_URI",
+			details.uri.as_deref().unwrap_or_default(),
+		);
+
+	match event {
+		HookEvent::PortForwarded { port, uri } => {
+			cmd.env("VSCODE_TUNNEL_PORT", port.to_string())
+				.env("VSCODE_TUNNEL_PORT_URI", uri);
+		}
+		HookEvent::Security { kind, detail } => {
+			cmd.env("VSCODE_TUNNEL_SECURITY_EVENT", kind.name())
+				.env("VSCODE_TUNNEL_SECURITY_DETAIL", detail);
+		}
+		_ => {}
+	}
+
+	match cmd.status().await {
+		Ok(status) if status.success() => {}
+		Ok(status) => warning!(log, "The {} hook exited with {}", event.name(), status),
+		Err(e) => warning!(log, "Error running the {} hook: {}", event.name(), e),
+	}
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SecurityWebhookBody<'a> {
+	event: &'static str,
+	kind: &'static str,
+	detail: &'a str,
+	tunnel_name: Option<&'a str>,
+	tunnel_uri: Option<&'a str>,
+}
+
+async fn post_security_webhook(
+	log: &log::Logger,
+	client: &reqwest::Client,
+	url: &str,
+	kind: SecurityEventKind,
+	detail: &str,
+	details: &TunnelDetails,
+) -> Result<(), ()> {
+	let body = SecurityWebhookBody {
+		event: "security",
+		kind: kind.name(),
+		detail,
+		tunnel_name: details.name.as_deref(),
+		tunnel_uri: details.uri.as_deref(),
+	};
+
+	match client.post(url).json(&body).send().await {
+		Ok(r) if r.status().is_success() => return Ok(()),
+		Ok(r) => warning!(log, "The security webhook returned {}", r.status()),
+		Err(e) => warning!(log, "Error posting to the security webhook: {}", e),
+	}
+	Err(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn state_frame(state: TunnelProgressState) -> ProgressFrame<'static> {
+		ProgressFrame::TunnelState {
+			state,
+			name: Some("my-machine"),
+			uri: Some("https://vscode.dev/tunnel/my-machine"),
+		}
+	}
+
+	#[test]
+	fn test_fires_lifecycle_events() {
+		let mut state = HookState::default();
+		let events: Vec<Option<HookEvent>> = [
+			TunnelProgressState::Connecting,
+			TunnelProgressState::Connected,
+			TunnelProgressState::Listening,
+			TunnelProgressState::Reconnecting,
+			TunnelProgressState::Reconnecting,
+			TunnelProgressState::Connected,
+			TunnelProgressState::Closed,
+		]
+		.into_iter()
+		.map(|s| state.on_frame(&state_frame(s)))
+		.collect();
+
+		assert_eq!(
+			events,
+			vec![
+				None,
+				None,
+				Some(HookEvent::Connect),
+				Some(HookEvent::Disconnect),
+				None,
+				Some(HookEvent::Reconnect),
+				Some(HookEvent::Disconnect),
+			]
+		);
+		assert_eq!(state.details.name.as_deref(), Some("my-machine"));
+	}
+
+	#[test]
+	fn test_fires_port_forwarded() {
+		let mut state = HookState::default();
+		let event = state.on_frame(&ProgressFrame::PortUri {
+			port: 8080,
+			uri: "https://example.com",
+		});
+		assert_eq!(
+			event,
+			Some(HookEvent::PortForwarded {
+				port: 8080,
+				uri: "https://example.com".to_string()
+			})
+		);
+	}
+
+	#[cfg(not(windows))]
+	#[tokio::test]
+	async fn test_runs_hook_with_event_details() {
+		let dir = tempfile::tempdir().unwrap();
+		let out = dir.path().join("out.txt");
+		let command = format!(
+			"echo \"$VSCODE_TUNNEL_EVENT $VSCODE_TUNNEL_PORT\" > '{}'",
+			out.display()
+		);
+
+		run_hook(
+			&log::Logger::test(),
+			&command,
+			&HookEvent::PortForwarded {
+				port: 8080,
+				uri: "https://example.com".to_string(),
+			},
+			&TunnelDetails::default(),
+		)
+		.await;
+
+		let written = std::fs::read_to_string(out).unwrap();
+		assert_eq!(written.trim(), "port-forwarded 8080");
+	}
+
+	#[test]
+	fn test_fires_security_event() {
+		let mut state = HookState::default();
+		let event = state.on_frame(&ProgressFrame::SecurityEvent {
+			kind: SecurityEventKind::NewAccount,
+			detail: "Logged in as mallory",
+		});
+		assert_eq!(
+			event,
+			Some(HookEvent::Security {
+				kind: SecurityEventKind::NewAccount,
+				detail: "Logged in as mallory".to_string(),
+			})
+		);
+	}
+}
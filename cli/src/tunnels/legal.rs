@@ -51,6 +51,7 @@ pub fn require_consent(
 						.to_string(),
 				)))
 			}
+			Err(AnyError::OperationCancelled(e)) => return Err(e.into()),
 			Err(e) => return Err(AnyError::from(MissingLegalConsent(e.to_string()))),
 		}
 	}
@@ -61,3 +62,26 @@ pub fn require_consent(
 
 	Ok(())
 }
+
+/// Checks for consent to the license terms without prompting, for hosts that
+/// collect it themselves. Consent given here is saved like a prompted one.
+pub fn check_consent(paths: &LauncherPaths, accepted: bool) -> Result<(), AnyError> {
+	if LICENSE_TEXT.is_none() || LICENSE_PROMPT.is_none() {
+		return Ok(());
+	}
+
+	let license: PersistedState<PersistedConsent> =
+		PersistedState::new(paths.root().join("license_consent.json"));
+
+	if accepted {
+		license.save(PersistedConsent {
+			consented: Some(true),
+		})?;
+	} else if !license.load().consented.unwrap_or(false) {
+		return Err(AnyError::from(MissingLegalConsent(
+			"The license terms must be accepted to host a tunnel.".to_string(),
+		)));
+	}
+
+	Ok(())
+}
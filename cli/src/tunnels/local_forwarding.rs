@@ -0,0 +1,401 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::log;
+use crate::util::errors::{AnyError, ForwardTargetNotAllowed, InvalidForwardTarget};
+use crate::util::os_permissions::explain_connect_error;
+use tokio::io::{copy_buf, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use super::port_connection::PortConnection;
+
+/// Default number of bytes buffered in each direction of a forwarded connection.
+pub const DEFAULT_FORWARD_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Host connections to forwarded ports go to when no other is set.
+const DEFAULT_FORWARD_HOST: &str = "127.0.0.1";
+
+/// Hosts that connections to forwarded ports go to, so services that don't
+/// listen on IPv4 loopback, such as ones on `::1`, a specific interface, or
+/// another machine on the network, can be forwarded too.
+///
+/// A port can also go to a different port on another machine, given like
+/// `8080=scope.lab:80`, which turns the host into a gateway for equipment on
+/// its network. Any target other than loopback has to be allowed explicitly,
+/// see `ensure_allowed`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForwardTargets {
+	default_host: Option<String>,
+	hosts: HashMap<u16, HostPort>,
+}
+
+/// A host, and the port on it if it's not the same as the forwarded port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct HostPort {
+	host: String,
+	port: Option<u16>,
+}
+
+impl HostPort {
+	/// Parses `host`, `host:port`, or `[ipv6]:port`.
+	fn parse(s: &str) -> Result<Self, InvalidForwardTarget> {
+		let s = s.trim();
+		let (host, port) = match s.rsplit_once(':') {
+			// a bare IPv6 address has more than one colon
+			Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+				let port = port
+					.parse::<u16>()
+					.map_err(|_| InvalidForwardTarget(s.to_string()))?;
+				(host, Some(port))
+			}
+			_ => (s, None),
+		};
+
+		Ok(HostPort {
+			host: parse_host(host)?,
+			port,
+		})
+	}
+
+	/// Gets whether the allowlist entry covers `port` on `host`, or every
+	/// port on it if `port` is None. Entries without a port cover every port
+	/// on their host.
+	fn covers(&self, host: &str, port: Option<u16>) -> bool {
+		self.host.eq_ignore_ascii_case(host) && (self.port.is_none() || self.port == port)
+	}
+}
+
+impl ForwardTargets {
+	/// Parses the targets from a default host, if any, and hosts for specific
+	/// ports given like `5432=db.lan` or `8080=scope.lab:80`.
+	pub fn parse(
+		default_host: Option<&str>,
+		port_hosts: &[String],
+	) -> Result<Self, InvalidForwardTarget> {
+		let mut targets = ForwardTargets {
+			default_host: default_host.map(parse_host).transpose()?,
+			hosts: HashMap::new(),
+		};
+
+		for target in port_hosts {
+			let (port, host) = target
+				.split_once('=')
+				.and_then(|(port, host)| Some((port.trim().parse::<u16>().ok()?, host)))
+				.ok_or_else(|| InvalidForwardTarget(target.to_string()))?;
+			targets.hosts.insert(port, HostPort::parse(host)?);
+		}
+
+		Ok(targets)
+	}
+
+	/// Checks that every target other than loopback is covered by the
+	/// allowlist, whose entries are hosts like `scope.lab`, or hosts and ports
+	/// like `scope.lab:80`. The default host goes to every forwarded port, so
+	/// only entries without a port cover it.
+	pub fn ensure_allowed(&self, allowlist: &[String]) -> Result<(), AnyError> {
+		let allowlist = allowlist
+			.iter()
+			.map(|e| HostPort::parse(e))
+			.collect::<Result<Vec<_>, _>>()?;
+		let is_allowed = |host: &str, port: Option<u16>| {
+			is_loopback(host) || allowlist.iter().any(|e| e.covers(host, port))
+		};
+
+		if let Some(host) = &self.default_host {
+			if !is_allowed(host, None) {
+				return Err(ForwardTargetNotAllowed(host.clone()).into());
+			}
+		}
+
+		let mut ports = self.hosts.keys().copied().collect::<Vec<_>>();
+		ports.sort_unstable();
+		for port in ports {
+			let target = &self.hosts[&port];
+			let target_port = target.port.unwrap_or(port);
+			if !is_allowed(&target.host, Some(target_port)) {
+				return Err(
+					ForwardTargetNotAllowed(format!("{}:{}", target.host, target_port)).into(),
+				);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Gets the host and port that connections to the forwarded port go to.
+	pub fn target_for(&self, port: u16) -> (&str, u16) {
+		match self.hosts.get(&port) {
+			Some(target) => (&target.host, target.port.unwrap_or(port)),
+			None => (
+				self.default_host.as_deref().unwrap_or(DEFAULT_FORWARD_HOST),
+				port,
+			),
+		}
+	}
+}
+
+/// Gets whether the host is this machine's loopback interface.
+pub fn is_loopback(host: &str) -> bool {
+	match host.parse::<IpAddr>() {
+		Ok(ip) => ip.is_loopback(),
+		Err(_) => host.eq_ignore_ascii_case("localhost"),
+	}
+}
+
+/// Checks a host is an IP address or a plausible host name. IPv6 addresses
+/// can be given in brackets, as in URIs.
+fn parse_host(host: &str) -> Result<String, InvalidForwardTarget> {
+	let host = host.trim();
+	let unbracketed = host
+		.strip_prefix('[')
+		.and_then(|h| h.strip_suffix(']'))
+		.unwrap_or(host);
+	if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+		return Ok(ip.to_string());
+	}
+
+	let is_name = !host.is_empty()
+		&& host.len() <= 253
+		&& host
+			.split('.')
+			.all(|l| !l.is_empty() && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+	if is_name {
+		Ok(host.to_string())
+	} else {
+		Err(InvalidForwardTarget(host.to_string()))
+	}
+}
+
+/// Forwards connections that clients make to a port through the relay to
+/// `target_port` on `host`, until the port is removed from the tunnel.
+///
+/// Each direction of each connection buffers at most `buffer_size` bytes, and
+/// only reads more once those have been written to the other side. A client
+/// that reads slowly therefore holds back the local server it's downloading
+/// from, rather than having the host buffer everything it hasn't read yet.
+///
+/// Each connection is reported as it opens and closes, for the port's metrics.
+pub fn forward_to_host(
+	log: log::Logger,
+	host: String,
+	target_port: u16,
+	port: u16,
+	mut connections: mpsc::UnboundedReceiver<PortConnection>,
+	buffer_size: usize,
+) {
+	tokio::spawn(async move {
+		while let Some(conn) = connections.recv().await {
+			let log = log.clone();
+			let host = host.clone();
+			tokio::spawn(async move {
+				log.progress(log::ProgressFrame::PortConnectionOpened { port });
+				let (rx, tx) =
+					forward_connection(&log, (&host, target_port), conn, buffer_size).await;
+				log.progress(log::ProgressFrame::PortConnectionClosed {
+					port,
+					bytes_received: rx,
+					bytes_sent: tx,
+				});
+			});
+		}
+	});
+}
+
+/// Forwards a connection to the host and port until either side closes it.
+/// Returns the bytes received from and sent to the client, which are zero if
+/// the connection failed.
+async fn forward_connection(
+	log: &log::Logger,
+	(host, port): (&str, u16),
+	conn: PortConnection,
+	buffer_size: usize,
+) -> (u64, u64) {
+	let local = match TcpStream::connect((host, port)).await {
+		Ok(s) => s,
+		Err(e) => {
+			// errors from missing permissions are worth surfacing, others are
+			// usually just nothing listening on the port yet
+			match explain_connect_error(&e) {
+				Some(hint) => warning!(
+					log,
+					"Could not connect to forwarded port {} on {}: {}, {}",
+					port,
+					host,
+					e,
+					hint
+				),
+				None => debug!(
+					log,
+					"Could not connect to forwarded port {} on {}: {}", port, host, e
+				),
+			}
+			return (0, 0);
+		}
+	};
+
+	let (relay_write, relay_read) = conn.into_split();
+	let (local_read, local_write) = local.into_split();
+	let (received, sent) = tokio::join!(
+		pipe(relay_read, local_write, buffer_size),
+		pipe(local_read, relay_write, buffer_size),
+	);
+
+	match (received, sent) {
+		(Ok(rx), Ok(tx)) => {
+			trace!(
+				log,
+				"Closed connection to port {} ({} bytes in, {} bytes out)",
+				port,
+				rx,
+				tx
+			);
+			(rx, tx)
+		}
+		(Err(e), _) | (_, Err(e)) => {
+			debug!(log, "Connection to port {} closed: {}", port, e);
+			(0, 0)
+		}
+	}
+}
+
+/// Copies from `read` to `write` through a buffer of `buffer_size` bytes,
+/// shutting down `write` once `read` is done. Returns the bytes copied.
+async fn pipe<R, W>(read: R, mut write: W, buffer_size: usize) -> std::io::Result<u64>
+where
+	R: AsyncRead + Unpin,
+	W: AsyncWrite + Unpin,
+{
+	let mut read = BufReader::with_capacity(buffer_size, read);
+	let copied = copy_buf(&mut read, &mut write).await?;
+	write.shutdown().await?;
+	Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::time::Duration;
+	use tokio::io::{duplex, AsyncReadExt};
+	use tokio::time::timeout;
+
+	#[test]
+	fn test_parses_forward_targets() {
+		let targets = ForwardTargets::parse(
+			Some("[::1]"),
+			&["5432=db.lan".to_string(), "8080=192.168.1.20".to_string()],
+		)
+		.unwrap();
+		assert_eq!(targets.target_for(5432), ("db.lan", 5432));
+		assert_eq!(targets.target_for(8080), ("192.168.1.20", 8080));
+		assert_eq!(targets.target_for(3000), ("::1", 3000));
+		assert_eq!(
+			ForwardTargets::default().target_for(3000),
+			("127.0.0.1", 3000)
+		);
+
+		for invalid in [
+			"5432",
+			"abc=db.lan",
+			"5432=",
+			"5432=db lan",
+			"5432=db..lan",
+			"5432=db.lan:x",
+		] {
+			assert!(
+				ForwardTargets::parse(None, &[invalid.to_string()]).is_err(),
+				"{}",
+				invalid
+			);
+		}
+	}
+
+	#[test]
+	fn test_allows_remote_ports_on_allowlist() {
+		let targets = ForwardTargets::parse(
+			None,
+			&[
+				"8080=scope.lab:80".to_string(),
+				"2222=[fd00::5]:22".to_string(),
+				"5432=db.lan".to_string(),
+			],
+		)
+		.unwrap();
+		assert_eq!(targets.target_for(8080), ("scope.lab", 80));
+		assert_eq!(targets.target_for(2222), ("fd00::5", 22));
+
+		let allow = |entries: &[&str]| {
+			let entries = entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+			targets.ensure_allowed(&entries).is_ok()
+		};
+		assert!(allow(&["Scope.Lab", "[fd00::5]:22", "db.lan:5432"]));
+		assert!(allow(&["scope.lab", "fd00::5", "db.lan"]));
+		assert!(!allow(&["scope.lab:8080", "fd00::5", "db.lan"]));
+		assert!(!allow(&["scope.lab", "fd00::5"]));
+		assert!(!allow(&["scope.lab", "fd00::5", "db.lan:5433"]));
+		assert!(!allow(&[]));
+	}
+
+	#[test]
+	fn test_requires_allowlist_for_any_remote_target() {
+		let allowed = |default_host: Option<&str>, port_hosts: &[&str], allowlist: &[&str]| {
+			let port_hosts = port_hosts.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+			let allowlist = allowlist.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+			ForwardTargets::parse(default_host, &port_hosts)
+				.unwrap()
+				.ensure_allowed(&allowlist)
+				.is_ok()
+		};
+
+		// loopback needs no entry
+		assert!(allowed(None, &[], &[]));
+		assert!(allowed(Some("[::1]"), &["3000=localhost:3001"], &[]));
+		assert!(allowed(Some("127.0.0.2"), &["5432=127.0.0.1"], &[]));
+
+		// a default host on the network needs an entry for all its ports
+		assert!(!allowed(Some("192.168.1.20"), &[], &[]));
+		assert!(!allowed(Some("192.168.1.20"), &[], &["192.168.1.20:80"]));
+		assert!(allowed(Some("192.168.1.20"), &[], &["192.168.1.20"]));
+
+		// as does a target on the same port of another machine
+		assert!(!allowed(None, &["5432=db.lan"], &[]));
+		assert!(allowed(None, &["5432=db.lan"], &["db.lan:5432"]));
+	}
+
+	#[tokio::test]
+	async fn test_pipe_applies_backpressure() {
+		let (mut source, source_read) = duplex(1024);
+		let (dest_write, mut dest) = duplex(1024);
+		let piping = tokio::spawn(pipe(source_read, dest_write, 4096));
+
+		// with nothing reading the destination, at most a few buffers' worth
+		// of data can be accepted from the source
+		let data = vec![7u8; 1024 * 1024];
+		let mut written = 0;
+		while written < data.len() {
+			match timeout(Duration::from_millis(100), source.write(&data[written..])).await {
+				Ok(n) => written += n.unwrap(),
+				Err(_) => break,
+			}
+		}
+		assert!(written < 16 * 1024, "wrote {} bytes", written);
+
+		// once the destination is read, everything makes it through
+		let writing = tokio::spawn(async move {
+			source.write_all(&data[written..]).await.unwrap();
+			source.shutdown().await.unwrap();
+		});
+		let mut received = vec![];
+		dest.read_to_end(&mut received).await.unwrap();
+		writing.await.unwrap();
+
+		assert_eq!(received.len(), 1024 * 1024);
+		assert_eq!(piping.await.unwrap().unwrap(), 1024 * 1024);
+	}
+}
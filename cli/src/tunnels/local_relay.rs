@@ -0,0 +1,503 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Hosting the tunnel on the local network without the dev tunnels service,
+//! for air-gapped environments. Clients connect to a TCP listener on this
+//! machine, optionally over TLS, and send the access token as a line before
+//! speaking the same control protocol they would through the relay.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_native_tls::TlsAcceptor;
+use tunnels::contracts::{TunnelEndpoint, TunnelPort, TunnelRelayTunnelEndpoint, PORT_TOKEN};
+
+use crate::constants::CONTROL_PORT;
+use crate::log;
+use crate::util::errors::{wrap, AnyError, InsecureLocalListener, WrappedError};
+
+use super::backend::{RelayConnection, RelayHost, TunnelBackend};
+use super::backoff::BackoffConfig;
+use super::dev_tunnels::ActiveTunnel;
+use super::local_forwarding::{is_loopback, ForwardTargets};
+use super::name_generator;
+use super::port_connection::{LocalStream, PortConnection};
+use super::service_limits::ServiceLimits;
+
+/// How long a client has to send the access token after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest access token line that's read from a client.
+const MAX_TOKEN_LINE: usize = 256;
+
+/// Connection mode reported in the local relay's endpoint.
+pub const LOCAL_CONNECTION_MODE: &str = "Local";
+
+/// Address the listener is bound to when no other is given.
+pub const DEFAULT_LOCAL_HOST: &str = "127.0.0.1";
+
+#[derive(Clone)]
+pub struct LocalRelayOptions {
+	/// Address the listener is bound to.
+	pub host: String,
+	pub port: u16,
+	/// Token clients send to be let in.
+	pub token: String,
+	pub tls: Option<TlsAcceptor>,
+}
+
+impl LocalRelayOptions {
+	/// Gets a random access token for clients.
+	pub fn generate_token() -> String {
+		rand::thread_rng()
+			.sample_iter(&Alphanumeric)
+			.take(32)
+			.map(char::from)
+			.collect()
+	}
+
+	/// Loads the TLS certificate and private key, both PEM encoded, that the
+	/// listener is served with.
+	pub fn load_tls(cert: &Path, key: &Path) -> Result<TlsAcceptor, AnyError> {
+		let read = |p: &Path| {
+			std::fs::read(p).map_err(|e| wrap(e, format!("error reading {}", p.display())))
+		};
+		let identity = native_tls::Identity::from_pkcs8(&read(cert)?, &read(key)?)
+			.map_err(|e| wrap(e, "invalid TLS certificate or key"))?;
+		let acceptor =
+			native_tls::TlsAcceptor::new(identity).map_err(|e| wrap(e, "error setting up TLS"))?;
+		Ok(TlsAcceptor::from(acceptor))
+	}
+
+	/// Checks that the token and traffic can't be read by others on the
+	/// network: the listener has to be served over TLS unless it's only bound
+	/// to loopback, or `allow_insecure` is set.
+	pub fn ensure_secure(&self, allow_insecure: bool) -> Result<(), InsecureLocalListener> {
+		let host = self
+			.host
+			.strip_prefix('[')
+			.and_then(|h| h.strip_suffix(']'))
+			.unwrap_or(&self.host);
+		if self.tls.is_some() || allow_insecure || is_loopback(host) {
+			Ok(())
+		} else {
+			Err(InsecureLocalListener(format!(
+				"{}:{}",
+				self.host, self.port
+			)))
+		}
+	}
+
+	/// Gets the URI clients on the network connect to.
+	pub fn uri(&self) -> String {
+		self.uri_for_port(self.port)
+	}
+
+	fn uri_for_port(&self, port: impl std::fmt::Display) -> String {
+		let scheme = if self.tls.is_some() { "https" } else { "http" };
+		let host = match self.host.as_str() {
+			"0.0.0.0" | "::" => gethostname::gethostname().to_string_lossy().to_string(),
+			h => h.to_string(),
+		};
+		format!("{}://{}:{}/", scheme, host, port)
+	}
+}
+
+/// Backend that hosts tunnels on a listener on this machine, for clients on
+/// the local network.
+pub struct LocalRelayBackend {
+	log: log::Logger,
+	options: LocalRelayOptions,
+	backoff: BackoffConfig,
+	forward_buffer_size: usize,
+	forward_targets: ForwardTargets,
+	name: Option<String>,
+}
+
+impl LocalRelayBackend {
+	pub fn new(
+		log: log::Logger,
+		options: LocalRelayOptions,
+		backoff: BackoffConfig,
+		forward_buffer_size: usize,
+		forward_targets: ForwardTargets,
+	) -> Self {
+		Self {
+			log,
+			options,
+			backoff,
+			forward_buffer_size,
+			forward_targets,
+			name: None,
+		}
+	}
+}
+
+#[async_trait]
+impl TunnelBackend for LocalRelayBackend {
+	async fn create(
+		&mut self,
+		preferred_name: Option<String>,
+		use_random_name: bool,
+	) -> Result<String, AnyError> {
+		let name = match preferred_name {
+			Some(name) => name,
+			None if use_random_name => {
+				name_generator::generate_name(ServiceLimits::default().max_name_length)
+			}
+			// there's no service to remember a name, so name it after the machine
+			None => gethostname::gethostname().to_string_lossy().to_string(),
+		};
+		self.name = Some(name.clone());
+		Ok(name)
+	}
+
+	async fn host(&mut self) -> Result<ActiveTunnel, AnyError> {
+		let name = match &self.name {
+			Some(name) => name.clone(),
+			None => self.create(None, false).await?,
+		};
+		let relay = LocalRelayHost::new(self.log.clone(), self.options.clone());
+		ActiveTunnel::on_relay(
+			&self.log,
+			&name,
+			Box::new(relay),
+			self.backoff,
+			self.forward_buffer_size,
+			self.forward_targets.clone(),
+		)
+		.await
+	}
+}
+
+/// Relay host that serves the control port on a listener on this machine.
+/// Other ports aren't relayed; clients on the network reach them directly.
+pub struct LocalRelayHost {
+	log: log::Logger,
+	options: LocalRelayOptions,
+	/// Sender for the control port, shared with the listener since the port
+	/// is only added after connecting.
+	control_tx: ControlSender,
+}
+
+type ControlSender = Arc<Mutex<Option<mpsc::UnboundedSender<PortConnection>>>>;
+
+impl LocalRelayHost {
+	pub fn new(log: log::Logger, options: LocalRelayOptions) -> Self {
+		Self {
+			log,
+			options,
+			control_tx: Arc::new(Mutex::new(None)),
+		}
+	}
+}
+
+#[async_trait]
+impl RelayHost for LocalRelayHost {
+	async fn connect(
+		&mut self,
+		_access_token: &str,
+	) -> Result<Box<dyn RelayConnection>, WrappedError> {
+		let listener = TcpListener::bind((self.options.host.as_str(), self.options.port))
+			.await
+			.map_err(|e| {
+				wrap(
+					e,
+					format!(
+						"error listening on {}:{}",
+						self.options.host, self.options.port
+					),
+				)
+			})?;
+		let port = listener
+			.local_addr()
+			.map_err(|e| wrap(e, "error getting the local listener address"))?
+			.port();
+
+		let (stop_tx, stop_rx) = oneshot::channel();
+		let (done_tx, done_rx) = oneshot::channel();
+		tokio::spawn(accept_loop(
+			self.log.clone(),
+			listener,
+			self.options.token.clone(),
+			self.options.tls.clone(),
+			self.control_tx.clone(),
+			stop_rx,
+			done_tx,
+		));
+
+		Ok(Box::new(LocalRelayConnection {
+			endpoint: TunnelRelayTunnelEndpoint {
+				base: TunnelEndpoint {
+					id: Some("local".to_string()),
+					connection_mode: Some(LOCAL_CONNECTION_MODE.to_string()),
+					host_id: gethostname::gethostname().to_string_lossy().to_string(),
+					port_uri_format: Some(self.options.uri_for_port(PORT_TOKEN)),
+					tunnel_uri: Some(self.options.uri_for_port(port)),
+					..Default::default()
+				},
+				..Default::default()
+			},
+			stop_tx: Some(stop_tx),
+			done_rx,
+		}))
+	}
+
+	async fn add_port(&mut self, _port: &TunnelPort) -> Result<(), WrappedError> {
+		Ok(())
+	}
+
+	async fn add_port_raw(
+		&mut self,
+		port: &TunnelPort,
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, WrappedError> {
+		let (tx, rx) = mpsc::unbounded_channel();
+		if port.port_number == CONTROL_PORT {
+			*self.control_tx.lock().unwrap() = Some(tx);
+		}
+		Ok(rx)
+	}
+
+	async fn remove_port(&mut self, port_number: u16) -> Result<(), WrappedError> {
+		if port_number == CONTROL_PORT {
+			self.control_tx.lock().unwrap().take();
+		}
+		Ok(())
+	}
+
+	async fn unregister(&mut self) -> Result<(), WrappedError> {
+		self.control_tx.lock().unwrap().take();
+		Ok(())
+	}
+}
+
+struct LocalRelayConnection {
+	endpoint: TunnelRelayTunnelEndpoint,
+	/// Stops the listener when sent or dropped.
+	stop_tx: Option<oneshot::Sender<()>>,
+	/// Receives the error the listener failed with.
+	done_rx: oneshot::Receiver<std::io::Error>,
+}
+
+#[async_trait]
+impl RelayConnection for LocalRelayConnection {
+	fn endpoint(&self) -> &TunnelRelayTunnelEndpoint {
+		&self.endpoint
+	}
+
+	async fn wait(&mut self) -> Result<(), WrappedError> {
+		match (&mut self.done_rx).await {
+			Ok(e) => Err(wrap(e, "error accepting local connections")),
+			Err(_) => Ok(()),
+		}
+	}
+
+	async fn close(&mut self) -> Result<(), WrappedError> {
+		self.stop_tx.take();
+		Ok(())
+	}
+}
+
+async fn accept_loop(
+	log: log::Logger,
+	listener: TcpListener,
+	token: String,
+	tls: Option<TlsAcceptor>,
+	control_tx: ControlSender,
+	mut stop_rx: oneshot::Receiver<()>,
+	done_tx: oneshot::Sender<std::io::Error>,
+) {
+	loop {
+		let (stream, addr) = tokio::select! {
+			_ = &mut stop_rx => return,
+			r = listener.accept() => match r {
+				Ok(a) => a,
+				Err(e) => {
+					done_tx.send(e).ok();
+					return;
+				}
+			},
+		};
+
+		let control_tx = match control_tx.lock().unwrap().clone() {
+			Some(tx) => tx,
+			None => continue,
+		};
+
+		let log = log.clone();
+		let token = token.clone();
+		let tls = tls.clone();
+		tokio::spawn(async move {
+			match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(stream, &token, tls)).await {
+				Ok(Ok(conn)) => {
+					debug!(log, "Accepted local connection from {}", addr);
+					control_tx.send(conn).ok();
+				}
+				Ok(Err(e)) => debug!(log, "Rejected local connection from {}: {}", addr, e),
+				Err(_) => debug!(log, "Local connection from {} timed out", addr),
+			}
+		});
+	}
+}
+
+/// Sets up TLS, if it's used, and checks the client's access token.
+async fn handshake(
+	stream: TcpStream,
+	token: &str,
+	tls: Option<TlsAcceptor>,
+) -> Result<PortConnection, AnyError> {
+	let mut stream: Box<dyn LocalStream> = match tls {
+		Some(tls) => Box::new(
+			tls.accept(stream)
+				.await
+				.map_err(|e| wrap(e, "TLS handshake failed"))?,
+		),
+		None => Box::new(stream),
+	};
+
+	// read a byte at a time, so nothing after the token is consumed
+	let mut line = Vec::with_capacity(token.len() + 2);
+	loop {
+		let b = stream
+			.read_u8()
+			.await
+			.map_err(|e| wrap(e, "error reading access token"))?;
+		if b == b'\n' {
+			break;
+		}
+		if line.len() >= MAX_TOKEN_LINE {
+			return Err(wrap("line too long", "invalid access token").into());
+		}
+		line.push(b);
+	}
+
+	if !is_token_match(&line, token) {
+		return Err(wrap("mismatched token", "invalid access token").into());
+	}
+
+	Ok(PortConnection::Local(stream))
+}
+
+/// Compares the token a client sent, ignoring a trailing carriage return,
+/// without returning early on the first difference.
+fn is_token_match(line: &[u8], token: &str) -> bool {
+	let line = line.strip_suffix(b"\r").unwrap_or(line);
+	line.len() == token.len()
+		&& line
+			.iter()
+			.zip(token.as_bytes())
+			.fold(0u8, |acc, (a, b)| acc | (a ^ b))
+			== 0
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::AsyncWriteExt;
+
+	#[test]
+	fn test_matches_token() {
+		assert!(is_token_match(b"secret", "secret"));
+		assert!(is_token_match(b"secret\r", "secret"));
+		assert!(!is_token_match(b"secreT", "secret"));
+		assert!(!is_token_match(b"secret2", "secret"));
+		assert!(!is_token_match(b"", "secret"));
+	}
+
+	#[test]
+	fn test_requires_tls_off_loopback() {
+		let options = |host: &str| LocalRelayOptions {
+			host: host.to_string(),
+			port: 8000,
+			token: "secret".to_string(),
+			tls: None,
+		};
+		assert!(options(DEFAULT_LOCAL_HOST).ensure_secure(false).is_ok());
+		assert!(options("[::1]").ensure_secure(false).is_ok());
+		assert!(options("localhost").ensure_secure(false).is_ok());
+		assert!(options("0.0.0.0").ensure_secure(false).is_err());
+		assert!(options("192.168.1.20").ensure_secure(false).is_err());
+		assert!(options("0.0.0.0").ensure_secure(true).is_ok());
+	}
+
+	#[tokio::test]
+	async fn test_backend_hosts_tunnel() {
+		let mut backend = LocalRelayBackend::new(
+			log::Logger::test(),
+			LocalRelayOptions {
+				host: "127.0.0.1".to_string(),
+				port: 0,
+				token: "secret".to_string(),
+				tls: None,
+			},
+			BackoffConfig::default(),
+			1024,
+			ForwardTargets::default(),
+		);
+		let name = backend
+			.create(Some("box".to_string()), false)
+			.await
+			.unwrap();
+		assert_eq!(name, "box");
+
+		let mut tunnel = backend.host().await.unwrap();
+		assert_eq!(tunnel.name, "box");
+		let uri = tunnel.local_uri().await.unwrap();
+		let port: u16 = uri
+			.strip_prefix("http://127.0.0.1:")
+			.and_then(|p| p.strip_suffix('/'))
+			.and_then(|p| p.parse().ok())
+			.unwrap();
+		assert_ne!(port, 0);
+		tunnel.close().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_accepts_clients_with_token() {
+		let mut relay = LocalRelayHost::new(
+			log::Logger::test(),
+			LocalRelayOptions {
+				host: "127.0.0.1".to_string(),
+				port: 0,
+				token: "secret".to_string(),
+				tls: None,
+			},
+		);
+		// the control port is added once connected, as the tunnel does
+		let connection = relay.connect("").await.unwrap();
+		let addr = connection
+			.endpoint()
+			.base
+			.tunnel_uri
+			.as_deref()
+			.and_then(|u| u.strip_prefix("http://"))
+			.and_then(|u| u.strip_suffix('/'))
+			.unwrap()
+			.to_string();
+		let mut control = relay
+			.add_port_raw(&TunnelPort {
+				port_number: CONTROL_PORT,
+				..Default::default()
+			})
+			.await
+			.unwrap();
+
+		let mut rejected = TcpStream::connect(&addr).await.unwrap();
+		rejected.write_all(b"wrong\n").await.unwrap();
+		let mut accepted = TcpStream::connect(&addr).await.unwrap();
+		accepted.write_all(b"secret\nhello").await.unwrap();
+
+		let (_, mut read) = control.recv().await.unwrap().into_split();
+		let mut buf = [0u8; 5];
+		read.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+	}
+}
@@ -0,0 +1,275 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use tunnels::contracts::{
+	TunnelAccessControl, TunnelAccessControlEntry, TunnelAccessControlEntryType,
+};
+
+use crate::util::errors::InvalidPortAccessRule;
+
+pub(crate) const CONNECT_SCOPE: &str = "connect";
+
+/// Who can connect to a forwarded port, beyond the tunnel's owner. Per-port
+/// rules still apply on top of it.
+#[derive(clap::ArgEnum, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortPrivacy {
+	/// Only the tunnel's owner, like the tunnel itself.
+	Private,
+	/// Anyone in the organization the tunnel's owner signed in with.
+	#[clap(name = "org")]
+	#[serde(rename = "org")]
+	Organization,
+	/// Anyone, without signing in.
+	Public,
+}
+
+impl fmt::Display for PortPrivacy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PortPrivacy::Private => write!(f, "private"),
+			PortPrivacy::Organization => write!(f, "org"),
+			PortPrivacy::Public => write!(f, "public"),
+		}
+	}
+}
+
+impl PortPrivacy {
+	/// Adds the entry granting access to the port to its access control.
+	/// It goes first, so the port's deny rules are evaluated after it.
+	pub fn apply_to(
+		&self,
+		access_control: Option<TunnelAccessControl>,
+	) -> Option<TunnelAccessControl> {
+		let kind = match self {
+			PortPrivacy::Private => return access_control,
+			PortPrivacy::Organization => TunnelAccessControlEntryType::Organizations,
+			PortPrivacy::Public => TunnelAccessControlEntryType::Anonymous,
+		};
+
+		let mut access_control = access_control.unwrap_or(TunnelAccessControl { entries: vec![] });
+		access_control.entries.insert(
+			0,
+			TunnelAccessControlEntry {
+				kind,
+				scopes: vec![CONNECT_SCOPE.to_string()],
+				..Default::default()
+			},
+		);
+		Some(access_control)
+	}
+
+	/// Gets the privacy of a port from the access control it was created
+	/// with, such as a declared port.
+	pub fn of(access_control: Option<&TunnelAccessControl>) -> Self {
+		let grants = |kind: fn(&TunnelAccessControlEntryType) -> bool| {
+			access_control
+				.map(|a| a.entries.iter().any(|e| !e.is_deny && kind(&e.kind)))
+				.unwrap_or(false)
+		};
+
+		if grants(|k| matches!(k, TunnelAccessControlEntryType::Anonymous)) {
+			PortPrivacy::Public
+		} else if grants(|k| matches!(k, TunnelAccessControlEntryType::Organizations)) {
+			PortPrivacy::Organization
+		} else {
+			PortPrivacy::Private
+		}
+	}
+}
+
+/// Clients allowed to connect to a single forwarded port.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortAllowlist {
+	/// Identities of signed-in users.
+	pub identities: Vec<String>,
+	/// CIDR ranges of client IP addresses.
+	pub ip_ranges: Vec<String>,
+}
+
+/// Per-port rules that restrict who can connect to forwarded ports, beyond
+/// the access control of the tunnel itself. They're sent to the tunnel
+/// service as access control entries on the port, and enforced by the relay
+/// when clients connect.
+#[derive(Clone, Debug, Default)]
+pub struct PortAccessRules(HashMap<u16, PortAllowlist>);
+
+impl PortAccessRules {
+	/// Parses rules given as `<port>=<rule>[,<rule>...]`, where each rule is
+	/// a CIDR range or a user identity. Rules for the same port are merged.
+	pub fn parse<T: AsRef<str>>(specs: &[T]) -> Result<Self, InvalidPortAccessRule> {
+		let mut rules: HashMap<u16, PortAllowlist> = HashMap::new();
+		for spec in specs {
+			let spec = spec.as_ref();
+			let (port, subjects) = spec.split_once('=').ok_or_else(|| {
+				InvalidPortAccessRule(format!("expected <port>=<rules>, got '{}'", spec))
+			})?;
+
+			let port = port
+				.trim()
+				.parse::<u16>()
+				.map_err(|_| InvalidPortAccessRule(format!("invalid port '{}'", port)))?;
+
+			let allowlist = rules.entry(port).or_default();
+			for subject in subjects.split(',').map(str::trim) {
+				if subject.is_empty() {
+					return Err(InvalidPortAccessRule(format!(
+						"empty rule for port {}",
+						port
+					)));
+				}
+
+				if looks_like_ip(subject) {
+					validate_cidr(subject)?;
+					allowlist.ip_ranges.push(subject.to_string());
+				} else {
+					allowlist.identities.push(subject.to_string());
+				}
+			}
+		}
+
+		Ok(PortAccessRules(rules))
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	pub fn get(&self, port: u16) -> Option<&PortAllowlist> {
+		self.0.get(&port)
+	}
+
+	/// Gets the access control to set on the port, if it has rules. Each kind
+	/// of rule becomes an inverse deny entry, which denies clients that don't
+	/// match it, so the port is never more open than the tunnel.
+	pub fn access_control(&self, port: u16) -> Option<TunnelAccessControl> {
+		let allowlist = self.0.get(&port)?;
+		let mut entries = vec![];
+
+		if !allowlist.identities.is_empty() {
+			entries.push(deny_others(
+				TunnelAccessControlEntryType::Users,
+				&allowlist.identities,
+			));
+		}
+
+		if !allowlist.ip_ranges.is_empty() {
+			entries.push(deny_others(
+				TunnelAccessControlEntryType::IPAddressRanges,
+				&allowlist.ip_ranges,
+			));
+		}
+
+		Some(TunnelAccessControl { entries })
+	}
+}
+
+fn deny_others(
+	kind: TunnelAccessControlEntryType,
+	subjects: &[String],
+) -> TunnelAccessControlEntry {
+	TunnelAccessControlEntry {
+		kind,
+		is_deny: true,
+		is_inverse: true,
+		subjects: subjects.to_vec(),
+		scopes: vec![CONNECT_SCOPE.to_string()],
+		..Default::default()
+	}
+}
+
+/// Subjects with a prefix length, or that are IP addresses, are ranges.
+/// Anything else is taken to be an identity.
+fn looks_like_ip(subject: &str) -> bool {
+	subject.contains('/') || subject.parse::<IpAddr>().is_ok()
+}
+
+fn validate_cidr(subject: &str) -> Result<(), InvalidPortAccessRule> {
+	let invalid = || InvalidPortAccessRule(format!("invalid IP address range '{}'", subject));
+	let (addr, prefix) = match subject.split_once('/') {
+		Some((addr, prefix)) => (addr, Some(prefix)),
+		None => (subject, None),
+	};
+
+	let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+	let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+	if let Some(prefix) = prefix {
+		match prefix.parse::<u8>() {
+			Ok(p) if p <= max_prefix => {}
+			_ => return Err(invalid()),
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_rules() {
+		let rules =
+			PortAccessRules::parse(&["5432=10.0.0.0/8, alice@contoso.com", "5432=::1", "8080=bob"])
+				.unwrap();
+
+		assert_eq!(
+			rules.get(5432),
+			Some(&PortAllowlist {
+				identities: vec!["alice@contoso.com".to_string()],
+				ip_ranges: vec!["10.0.0.0/8".to_string(), "::1".to_string()],
+			})
+		);
+		assert_eq!(rules.get(8080).unwrap().identities, vec!["bob"]);
+		assert!(rules.get(3000).is_none());
+	}
+
+	#[test]
+	fn test_rejects_invalid_rules() {
+		assert!(PortAccessRules::parse(&["5432"]).is_err());
+		assert!(PortAccessRules::parse(&["port=bob"]).is_err());
+		assert!(PortAccessRules::parse(&["5432=10.0.0.0/33"]).is_err());
+		assert!(PortAccessRules::parse(&["5432=10.0.0/8"]).is_err());
+		assert!(PortAccessRules::parse(&["5432=bob,"]).is_err());
+	}
+
+	#[test]
+	fn test_access_control_denies_others() {
+		let rules = PortAccessRules::parse(&["5432=10.0.0.0/8"]).unwrap();
+		assert!(rules.access_control(8080).is_none());
+
+		let acl = rules.access_control(5432).unwrap();
+		assert_eq!(acl.entries.len(), 1);
+		let entry = &acl.entries[0];
+		assert!(matches!(
+			entry.kind,
+			TunnelAccessControlEntryType::IPAddressRanges
+		));
+		assert!(entry.is_deny && entry.is_inverse);
+		assert_eq!(entry.subjects, vec!["10.0.0.0/8"]);
+	}
+
+	#[test]
+	fn test_privacy_round_trips_through_access_control() {
+		let rules = PortAccessRules::parse(&["5432=10.0.0.0/8"]).unwrap();
+		assert!(PortPrivacy::Private.apply_to(None).is_none());
+
+		for privacy in [
+			PortPrivacy::Private,
+			PortPrivacy::Organization,
+			PortPrivacy::Public,
+		] {
+			let acl = privacy.apply_to(rules.access_control(5432));
+			assert_eq!(PortPrivacy::of(acl.as_ref()), privacy);
+			// the port's own rules still apply
+			assert!(acl.unwrap().entries.iter().any(|e| e.is_deny));
+		}
+	}
+}
@@ -0,0 +1,95 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tunnels::connections::{ForwardedPortConnection, ReadHalf, WriteHalf};
+
+/// A stream accepted on this machine, such as by the local relay.
+pub trait LocalStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> LocalStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// A connection a client made to a forwarded port, either through the dev
+/// tunnels relay or directly to a listener on this machine.
+pub enum PortConnection {
+	Relay(ForwardedPortConnection),
+	Local(Box<dyn LocalStream>),
+}
+
+impl From<ForwardedPortConnection> for PortConnection {
+	fn from(conn: ForwardedPortConnection) -> Self {
+		PortConnection::Relay(conn)
+	}
+}
+
+impl PortConnection {
+	/// Splits the connection into its write and read halves.
+	pub fn into_split(self) -> (PortWriteHalf, PortReadHalf) {
+		match self {
+			PortConnection::Relay(conn) => {
+				let (write, read) = conn.into_split();
+				(PortWriteHalf::Relay(write), PortReadHalf::Relay(read))
+			}
+			PortConnection::Local(stream) => {
+				let (read, write) = tokio::io::split(stream);
+				(PortWriteHalf::Local(write), PortReadHalf::Local(read))
+			}
+		}
+	}
+}
+
+pub enum PortReadHalf {
+	Relay(ReadHalf),
+	Local(tokio::io::ReadHalf<Box<dyn LocalStream>>),
+}
+
+impl AsyncRead for PortReadHalf {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			PortReadHalf::Relay(r) => Pin::new(r).poll_read(cx, buf),
+			PortReadHalf::Local(r) => Pin::new(r).poll_read(cx, buf),
+		}
+	}
+}
+
+pub enum PortWriteHalf {
+	Relay(WriteHalf),
+	Local(tokio::io::WriteHalf<Box<dyn LocalStream>>),
+}
+
+impl AsyncWrite for PortWriteHalf {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			PortWriteHalf::Relay(w) => Pin::new(w).poll_write(cx, buf),
+			PortWriteHalf::Local(w) => Pin::new(w).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			PortWriteHalf::Relay(w) => Pin::new(w).poll_flush(cx),
+			PortWriteHalf::Local(w) => Pin::new(w).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			PortWriteHalf::Relay(w) => Pin::new(w).poll_shutdown(cx),
+			PortWriteHalf::Local(w) => Pin::new(w).poll_shutdown(cx),
+		}
+	}
+}
@@ -3,19 +3,34 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
 
 use crate::{
 	constants::CONTROL_PORT,
-	util::errors::{AnyError, CannotForwardControlPort, ServerHasClosed},
+	log,
+	util::errors::{AnyError, CannotForwardControlPort, PortNotListening, ServerHasClosed},
 };
 
-use super::dev_tunnels::ActiveTunnel;
+use super::{
+	dev_tunnels::ActiveTunnel,
+	port_access::PortPrivacy,
+	port_owner::{find_owner, is_listening},
+};
+
+/// How often to check whether forwarded ports are served by another process.
+const PORT_OWNER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
 pub enum PortForwardingRec {
-	Forward(u16, oneshot::Sender<Result<String, AnyError>>),
+	/// Forwards a port with the given privacy, or the processor's default.
+	Forward(
+		u16,
+		Option<PortPrivacy>,
+		oneshot::Sender<Result<String, AnyError>>,
+	),
 	Unforward(u16, oneshot::Sender<Result<(), AnyError>>),
 }
 
@@ -26,6 +41,28 @@ pub struct PortForwardingProcessor {
 	tx: mpsc::Sender<PortForwardingRec>,
 	rx: mpsc::Receiver<PortForwardingRec>,
 	forwarded: HashSet<u16>,
+	privacy: PortPrivacy,
+	checks: Option<PortChecks>,
+	owner_watchers: HashMap<u16, JoinHandle<()>>,
+}
+
+struct PortChecks {
+	log: log::Logger,
+	require_listener: bool,
+}
+
+impl Drop for PortForwardingProcessor {
+	fn drop(&mut self) {
+		for watcher in self.owner_watchers.values() {
+			watcher.abort();
+		}
+	}
+}
+
+impl Default for PortForwardingProcessor {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl PortForwardingProcessor {
@@ -35,9 +72,27 @@ impl PortForwardingProcessor {
 			tx,
 			rx,
 			forwarded: HashSet::new(),
+			privacy: PortPrivacy::Private,
+			checks: None,
+			owner_watchers: HashMap::new(),
 		}
 	}
 
+	/// Checks the local ports that are forwarded. If `require_listener` is
+	/// set, ports nothing is listening on are refused. Once forwarded, it's
+	/// logged as a warning if another process starts serving a port.
+	pub fn check_ports(&mut self, log: log::Logger, require_listener: bool) {
+		self.checks = Some(PortChecks {
+			log,
+			require_listener,
+		});
+	}
+
+	/// Sets the privacy of ports forwarded without one being requested.
+	pub fn set_default_privacy(&mut self, privacy: PortPrivacy) {
+		self.privacy = privacy;
+	}
+
 	/// Gets a handle that can be passed off to consumers of port forwarding.
 	pub fn handle(&self) -> PortForwarding {
 		PortForwarding {
@@ -54,8 +109,10 @@ impl PortForwardingProcessor {
 	/// Processes the incoming forwarding request.
 	pub async fn process(&mut self, req: PortForwardingRec, tunnel: &mut ActiveTunnel) {
 		match req {
-			PortForwardingRec::Forward(port, tx) => {
-				tx.send(self.process_forward(port, tunnel).await).ok();
+			PortForwardingRec::Forward(port, privacy, tx) => {
+				let privacy = privacy.unwrap_or(self.privacy);
+				tx.send(self.process_forward(port, privacy, tunnel).await)
+					.ok();
 			}
 			PortForwardingRec::Unforward(port, tx) => {
 				tx.send(self.process_unforward(port, tunnel).await).ok();
@@ -74,35 +131,93 @@ impl PortForwardingProcessor {
 
 		tunnel.remove_port(port).await?;
 		self.forwarded.remove(&port);
+		if let Some(watcher) = self.owner_watchers.remove(&port) {
+			watcher.abort();
+		}
 		Ok(())
 	}
 
 	async fn process_forward(
 		&mut self,
 		port: u16,
+		privacy: PortPrivacy,
 		tunnel: &mut ActiveTunnel,
 	) -> Result<String, AnyError> {
 		if port == CONTROL_PORT {
 			return Err(CannotForwardControlPort().into());
 		}
 
-		if !self.forwarded.contains(&port) {
-			tunnel.add_port_tcp(port).await?;
-			self.forwarded.insert(port);
+		if let Some(checks) = &self.checks {
+			if checks.require_listener && !tunnel.has_port(port) && !is_listening(port).await {
+				return Err(PortNotListening(port).into());
+			}
+		}
+
+		// ports may already be forwarded on a tunnel that was recreated, and
+		// the processor outlives tunnels that were replaced. Changing a port's
+		// privacy means forwarding it anew.
+		if tunnel.has_port(port) && tunnel.port_privacy(port) != privacy {
+			tunnel.remove_port(port).await?;
+		}
+		if !tunnel.has_port(port) {
+			tunnel.add_port_tcp(port, privacy).await?;
+		}
+		self.forwarded.insert(port);
+
+		if let Some(checks) = &self.checks {
+			self.owner_watchers
+				.entry(port)
+				.or_insert_with(|| watch_port_owner(checks.log.clone(), port));
 		}
 
 		tunnel.get_port_uri(port).await
 	}
 }
 
+/// Warns when the process serving the port changes after it's forwarded, so
+/// clients aren't unknowingly connected to someone else's service.
+fn watch_port_owner(log: log::Logger, port: u16) -> JoinHandle<()> {
+	tokio::spawn(async move {
+		let mut owner = find_owner(port).await;
+		loop {
+			tokio::time::sleep(PORT_OWNER_CHECK_INTERVAL).await;
+			let current = match find_owner(port).await {
+				Some(o) => o,
+				None => continue,
+			};
+
+			if let Some(previous) = owner.filter(|o| o.differs_from(&current)) {
+				warning!(
+					log,
+					"Forwarded port {} is now served by {}, not {} as when it was forwarded",
+					port,
+					current,
+					previous
+				);
+				log.progress(log::ProgressFrame::PortOwnerChanged {
+					port,
+					pid: current.pid,
+					previous_pid: previous.pid,
+				});
+			}
+			owner = Some(current);
+		}
+	})
+}
+
+#[derive(Clone)]
 pub struct PortForwarding {
 	tx: mpsc::Sender<PortForwardingRec>,
 }
 
 impl PortForwarding {
-	pub async fn forward(&self, port: u16) -> Result<String, AnyError> {
+	pub async fn forward(
+		&self,
+		port: u16,
+		privacy: Option<PortPrivacy>,
+	) -> Result<String, AnyError> {
 		let (tx, rx) = oneshot::channel();
-		let req = PortForwardingRec::Forward(port, tx);
+		let req = PortForwardingRec::Forward(port, privacy, tx);
 
 		if self.tx.send(req).await.is_err() {
 			return Err(ServerHasClosed().into());
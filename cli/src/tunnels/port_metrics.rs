@@ -0,0 +1,323 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Traffic on each forwarded port. Connections report when they open and
+//! close as progress frames, which the `StatusSink` counts up per port, so
+//! they show in `code tunnel status` and can be scraped by Prometheus.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::log;
+use crate::util::errors::{wrap, AnyError};
+
+use super::error_budget::{ErrorBudget, Subsystem};
+use super::port_connection::{PortConnection, PortReadHalf, PortWriteHalf};
+use super::status::StatusSink;
+
+/// Traffic on a forwarded port since the tunnel started.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortMetrics {
+	/// Number of connections currently open.
+	pub active_connections: u64,
+	pub connects: u64,
+	pub disconnects: u64,
+	/// Bytes received from clients, counted once their connection closes.
+	pub bytes_received: u64,
+	/// Bytes sent to clients, counted once their connection closes.
+	pub bytes_sent: u64,
+}
+
+/// Reports connections to a port added with `add_port_direct` as they open
+/// and close, returning the connections to hand to whoever serves the port.
+pub fn meter_connections(
+	log: log::Logger,
+	port: u16,
+	mut connections: mpsc::UnboundedReceiver<PortConnection>,
+) -> mpsc::UnboundedReceiver<PortConnection> {
+	let (tx, rx) = mpsc::unbounded_channel();
+	tokio::spawn(async move {
+		while let Some(conn) = connections.recv().await {
+			let metered = MeteredConnection::new(log.clone(), port, conn);
+			if tx.send(PortConnection::Local(Box::new(metered))).is_err() {
+				return;
+			}
+		}
+	});
+	rx
+}
+
+/// A connection that counts the bytes through it, and reports that it closed
+/// once it's dropped.
+struct MeteredConnection {
+	log: log::Logger,
+	port: u16,
+	read: PortReadHalf,
+	write: PortWriteHalf,
+	bytes_received: u64,
+	bytes_sent: u64,
+}
+
+impl MeteredConnection {
+	fn new(log: log::Logger, port: u16, conn: PortConnection) -> Self {
+		log.progress(log::ProgressFrame::PortConnectionOpened { port });
+		let (write, read) = conn.into_split();
+		MeteredConnection {
+			log,
+			port,
+			read,
+			write,
+			bytes_received: 0,
+			bytes_sent: 0,
+		}
+	}
+}
+
+impl Drop for MeteredConnection {
+	fn drop(&mut self) {
+		self.log.progress(log::ProgressFrame::PortConnectionClosed {
+			port: self.port,
+			bytes_received: self.bytes_received,
+			bytes_sent: self.bytes_sent,
+		});
+	}
+}
+
+impl AsyncRead for MeteredConnection {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		let filled = buf.filled().len();
+		let r = Pin::new(&mut this.read).poll_read(cx, buf);
+		this.bytes_received += (buf.filled().len() - filled) as u64;
+		r
+	}
+}
+
+impl AsyncWrite for MeteredConnection {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		let r = Pin::new(&mut this.write).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = r {
+			this.bytes_sent += n as u64;
+		}
+		r
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_shutdown(cx)
+	}
+}
+
+/// Name, type, and help text of a Prometheus metric, and how to get its value.
+type MetricFamily = (
+	&'static str,
+	&'static str,
+	&'static str,
+	fn(&PortMetrics) -> u64,
+);
+
+/// Formats the metrics in the Prometheus text exposition format.
+pub fn render_prometheus(ports: &BTreeMap<u16, PortMetrics>) -> String {
+	let families: [MetricFamily; 5] = [
+		(
+			"code_tunnel_port_active_connections",
+			"gauge",
+			"Connections currently open to the forwarded port.",
+			|m| m.active_connections,
+		),
+		(
+			"code_tunnel_port_connects_total",
+			"counter",
+			"Connections made to the forwarded port.",
+			|m| m.connects,
+		),
+		(
+			"code_tunnel_port_disconnects_total",
+			"counter",
+			"Connections to the forwarded port that closed.",
+			|m| m.disconnects,
+		),
+		(
+			"code_tunnel_port_received_bytes_total",
+			"counter",
+			"Bytes received from clients of the forwarded port.",
+			|m| m.bytes_received,
+		),
+		(
+			"code_tunnel_port_sent_bytes_total",
+			"counter",
+			"Bytes sent to clients of the forwarded port.",
+			|m| m.bytes_sent,
+		),
+	];
+
+	let mut out = String::new();
+	for (name, kind, help, value) in families {
+		writeln!(out, "# HELP {} {}", name, help).unwrap();
+		writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+		for (port, metrics) in ports {
+			writeln!(out, "{}{{port=\"{}\"}} {}", name, port, value(metrics)).unwrap();
+		}
+	}
+	out
+}
+
+/// Serves the port metrics of the tunnel to Prometheus at
+/// `http://127.0.0.1:<port>/metrics`, until the process exits or serving
+/// fails too often.
+pub async fn serve_prometheus(
+	log: log::Logger,
+	port: u16,
+	status: StatusSink,
+) -> Result<(), AnyError> {
+	let listener = TcpListener::bind(("127.0.0.1", port))
+		.await
+		.map_err(|e| wrap(e, format!("error listening for metrics on port {}", port)))?;
+	info!(
+		log,
+		"Serving port metrics at http://127.0.0.1:{}/metrics", port
+	);
+
+	let budget = ErrorBudget::new(log.clone(), Subsystem::Metrics);
+	tokio::spawn(async move {
+		while !budget.is_disabled() {
+			let accepted = listener.accept().await;
+			budget.record(&accepted);
+			let stream = match accepted {
+				Ok((stream, _)) => stream,
+				Err(e) => {
+					debug!(log, "Error accepting metrics connection: {}", e);
+					continue;
+				}
+			};
+
+			let status = status.clone();
+			let log = log.clone();
+			let budget = budget.clone();
+			tokio::spawn(async move {
+				let result = respond_to_scrape(stream, &status).await;
+				budget.record(&result);
+				if let Err(e) = result {
+					debug!(log, "Error serving metrics: {}", e);
+				}
+			});
+		}
+	});
+
+	Ok(())
+}
+
+/// Largest request head read from a scraper.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Answers a single HTTP request for the metrics, then closes the connection.
+async fn respond_to_scrape(mut stream: TcpStream, status: &StatusSink) -> io::Result<()> {
+	let mut head = Vec::new();
+	let mut buf = [0u8; 1024];
+	while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+		let n = stream.read(&mut buf).await?;
+		if n == 0 || head.len() + n > MAX_REQUEST_HEAD {
+			return Ok(());
+		}
+		head.extend_from_slice(&buf[..n]);
+	}
+
+	let request_line = String::from_utf8_lossy(&head);
+	let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+	let (status_line, body) = match path {
+		"/metrics" => ("200 OK", render_prometheus(&status.snapshot().port_metrics)),
+		_ => ("404 Not Found", String::new()),
+	};
+
+	let response = format!(
+		"HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status_line,
+		body.len(),
+		body
+	);
+	stream.write_all(response.as_bytes()).await?;
+	stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render_prometheus() {
+		let mut ports = BTreeMap::new();
+		ports.insert(
+			8000,
+			PortMetrics {
+				active_connections: 1,
+				connects: 3,
+				disconnects: 2,
+				bytes_received: 100,
+				bytes_sent: 2048,
+			},
+		);
+		ports.insert(3000, PortMetrics::default());
+
+		let text = render_prometheus(&ports);
+		assert!(text.contains(
+			"# TYPE code_tunnel_port_active_connections gauge\ncode_tunnel_port_active_connections{port=\"3000\"} 0\ncode_tunnel_port_active_connections{port=\"8000\"} 1\n"
+		));
+		assert!(text.contains("code_tunnel_port_connects_total{port=\"8000\"} 3\n"));
+		assert!(text.contains("code_tunnel_port_sent_bytes_total{port=\"8000\"} 2048\n"));
+	}
+
+	#[tokio::test]
+	async fn test_metered_connection_reports_traffic() {
+		let status = StatusSink::new();
+		let log = log::Logger::test().tee(status.clone());
+
+		let (mut client, server) = tokio::io::duplex(1024);
+		let (tx, rx) = mpsc::unbounded_channel();
+		let mut rx = meter_connections(log, 8000, rx);
+		tx.send(PortConnection::Local(Box::new(server))).unwrap();
+
+		let conn = rx.recv().await.unwrap();
+		client.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		let (mut write, mut read) = conn.into_split();
+		read.read_exact(&mut buf).await.unwrap();
+		write.write_all(b"hi").await.unwrap();
+		assert_eq!(status.snapshot().port_metrics[&8000].active_connections, 1);
+
+		drop((write, read));
+		let metrics = status.snapshot().port_metrics[&8000].clone();
+		assert_eq!(
+			metrics,
+			PortMetrics {
+				active_connections: 0,
+				connects: 1,
+				disconnects: 1,
+				bytes_received: 5,
+				bytes_sent: 2,
+			}
+		);
+	}
+}
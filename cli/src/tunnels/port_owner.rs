@@ -0,0 +1,395 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Finding what's serving a local port, so ports aren't forwarded to nothing,
+//! and so it's noticed when another process takes a forwarded port over, as
+//! can happen on machines shared by several users. Listing every port that's
+//! listened on is what lets ports be forwarded automatically.
+
+#[cfg(target_os = "linux")]
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// How long to wait when checking whether something is listening on a port.
+const LISTEN_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Process listening on a local port, as far as it can be seen. Processes
+/// of other users may only be identified by their user.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PortOwner {
+	pub pid: Option<u32>,
+	pub uid: Option<u32>,
+}
+
+impl PortOwner {
+	/// Gets whether the owners are different processes, comparing what's known
+	/// about both of them.
+	pub fn differs_from(&self, other: &PortOwner) -> bool {
+		match (self.pid, other.pid, self.uid, other.uid) {
+			(Some(a), Some(b), _, _) => a != b,
+			(_, _, Some(a), Some(b)) => a != b,
+			_ => false,
+		}
+	}
+}
+
+impl fmt::Display for PortOwner {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match (self.pid, self.uid) {
+			(Some(pid), _) => write!(f, "process {}", pid),
+			(None, Some(uid)) => write!(f, "a process of user {}", uid),
+			(None, None) => write!(f, "an unknown process"),
+		}
+	}
+}
+
+/// Gets whether something accepts connections on the port, at the address
+/// forwarded connections are made to.
+pub async fn is_listening(port: u16) -> bool {
+	matches!(
+		timeout(
+			LISTEN_CHECK_TIMEOUT,
+			TcpStream::connect(("127.0.0.1", port))
+		)
+		.await,
+		Ok(Ok(_))
+	)
+}
+
+/// Finds the process listening on the port, if it can be found.
+#[cfg(target_os = "linux")]
+pub async fn find_owner(port: u16) -> Option<PortOwner> {
+	tokio::task::spawn_blocking(move || {
+		let (inode, uid) = ["/proc/net/tcp", "/proc/net/tcp6"]
+			.iter()
+			.filter_map(|p| std::fs::read_to_string(p).ok())
+			.find_map(|c| find_listening_socket(&c, port))?;
+		Some(PortOwner {
+			pid: find_socket_pid(inode),
+			uid: Some(uid),
+		})
+	})
+	.await
+	.ok()
+	.flatten()
+}
+
+/// Finds the process listening on the port, if it can be found.
+#[cfg(target_os = "macos")]
+pub async fn find_owner(port: u16) -> Option<PortOwner> {
+	let output = crate::util::command::capture_command(
+		"lsof",
+		["-nP", &format!("-iTCP:{}", port), "-sTCP:LISTEN", "-t"],
+	)
+	.await
+	.ok()?;
+	let pid = String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.find_map(|l| l.trim().parse().ok())?;
+	Some(PortOwner {
+		pid: Some(pid),
+		uid: None,
+	})
+}
+
+/// Finds the process listening on the port, if it can be found.
+#[cfg(target_os = "windows")]
+pub async fn find_owner(port: u16) -> Option<PortOwner> {
+	let output = crate::util::command::capture_command("netstat", ["-ano", "-p", "TCP"])
+		.await
+		.ok()?;
+	let pid = find_netstat_listener(&String::from_utf8_lossy(&output.stdout), port)?;
+	Some(PortOwner {
+		pid: Some(pid),
+		uid: None,
+	})
+}
+
+/// Finds the process listening on the port, if it can be found.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+pub async fn find_owner(_port: u16) -> Option<PortOwner> {
+	None
+}
+
+/// Lists the ports listened on, with the process listening on each as far as
+/// it can be found. A port may be listed more than once, such as for IPv4
+/// and IPv6.
+#[cfg(target_os = "linux")]
+pub async fn list_listeners() -> Vec<(u16, PortOwner)> {
+	tokio::task::spawn_blocking(|| {
+		let sockets: Vec<(u16, u64, u32)> = ["/proc/net/tcp", "/proc/net/tcp6"]
+			.iter()
+			.filter_map(|p| std::fs::read_to_string(p).ok())
+			.flat_map(|c| listening_sockets(&c).collect::<Vec<_>>())
+			.collect();
+		let pids = find_socket_pids(sockets.iter().map(|(_, inode, _)| *inode).collect());
+		sockets
+			.into_iter()
+			.map(|(port, inode, uid)| {
+				let owner = PortOwner {
+					pid: pids.get(&inode).copied(),
+					uid: Some(uid),
+				};
+				(port, owner)
+			})
+			.collect()
+	})
+	.await
+	.unwrap_or_default()
+}
+
+/// Lists the ports listened on, with the process listening on each as far as
+/// it can be found. A port may be listed more than once, such as for IPv4
+/// and IPv6.
+#[cfg(target_os = "macos")]
+pub async fn list_listeners() -> Vec<(u16, PortOwner)> {
+	let output = match crate::util::command::capture_command(
+		"lsof",
+		["-nP", "-iTCP", "-sTCP:LISTEN", "-Fpn"],
+	)
+	.await
+	{
+		Ok(output) => output,
+		Err(_) => return vec![],
+	};
+	let listeners = parse_lsof_listeners(&String::from_utf8_lossy(&output.stdout));
+	listeners
+}
+
+/// Lists the ports listened on, with the process listening on each as far as
+/// it can be found. A port may be listed more than once, such as for IPv4
+/// and IPv6.
+#[cfg(target_os = "windows")]
+pub async fn list_listeners() -> Vec<(u16, PortOwner)> {
+	let output = match crate::util::command::capture_command("netstat", ["-ano", "-p", "TCP"]).await
+	{
+		Ok(output) => output,
+		Err(_) => return vec![],
+	};
+	let listeners = netstat_listeners(&String::from_utf8_lossy(&output.stdout))
+		.map(|(port, pid)| {
+			let owner = PortOwner {
+				pid: Some(pid),
+				uid: None,
+			};
+			(port, owner)
+		})
+		.collect();
+	listeners
+}
+
+/// Lists the ports listened on, with the process listening on each as far as
+/// it can be found.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+pub async fn list_listeners() -> Vec<(u16, PortOwner)> {
+	vec![]
+}
+
+/// Gets the port, inode, and owning user of each socket listening in the
+/// contents of `/proc/net/tcp` or `/proc/net/tcp6`.
+#[cfg(target_os = "linux")]
+fn listening_sockets(contents: &str) -> impl Iterator<Item = (u16, u64, u32)> + '_ {
+	const TCP_LISTEN: &str = "0A";
+
+	contents.lines().skip(1).filter_map(|line| {
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		if *fields.get(3)? != TCP_LISTEN {
+			return None;
+		}
+		let local_port = fields.get(1)?.rsplit(':').next()?;
+		Some((
+			u16::from_str_radix(local_port, 16).ok()?,
+			fields.get(9)?.parse().ok()?,
+			fields.get(7)?.parse().ok()?,
+		))
+	})
+}
+
+/// Finds the inode and owning user of the socket listening on the port in
+/// the contents of `/proc/net/tcp` or `/proc/net/tcp6`.
+#[cfg(target_os = "linux")]
+fn find_listening_socket(contents: &str, port: u16) -> Option<(u64, u32)> {
+	listening_sockets(contents)
+		.find(|(p, _, _)| *p == port)
+		.map(|(_, inode, uid)| (inode, uid))
+}
+
+/// Finds the process with the socket open. Only processes whose file
+/// descriptors we can read, generally those of the same user, are found.
+#[cfg(target_os = "linux")]
+fn find_socket_pid(inode: u64) -> Option<u32> {
+	find_socket_pids(HashSet::from([inode])).remove(&inode)
+}
+
+/// Finds the processes with the sockets open, keyed by the socket's inode,
+/// reading the file descriptors of each process at most once.
+#[cfg(target_os = "linux")]
+fn find_socket_pids(mut inodes: HashSet<u64>) -> HashMap<u64, u32> {
+	let mut pids = HashMap::new();
+	let entries = match std::fs::read_dir("/proc") {
+		Ok(e) => e,
+		Err(_) => return pids,
+	};
+
+	for entry in entries.flatten() {
+		if inodes.is_empty() {
+			break;
+		}
+		let pid: u32 = match entry.file_name().to_str().and_then(|p| p.parse().ok()) {
+			Some(pid) => pid,
+			None => continue,
+		};
+		let fds = match std::fs::read_dir(entry.path().join("fd")) {
+			Ok(fds) => fds,
+			Err(_) => continue,
+		};
+
+		for fd in fds.flatten() {
+			let inode = std::fs::read_link(fd.path()).ok().and_then(|link| {
+				link.to_str()?
+					.strip_prefix("socket:[")?
+					.strip_suffix(']')?
+					.parse()
+					.ok()
+			});
+			if let Some(inode) = inode.filter(|i| inodes.remove(i)) {
+				pids.insert(inode, pid);
+			}
+		}
+	}
+
+	pids
+}
+
+/// Parses the output of `lsof -Fpn`, in which each process's `p<pid>` line
+/// is followed by an `n<address>:<port>` line for each of its sockets.
+#[cfg(target_os = "macos")]
+fn parse_lsof_listeners(output: &str) -> Vec<(u16, PortOwner)> {
+	let mut pid = None;
+	let mut listeners = vec![];
+	for line in output.lines() {
+		if let Some(p) = line.strip_prefix('p') {
+			pid = p.parse().ok();
+		} else if let Some(name) = line.strip_prefix('n') {
+			if let Some(port) = name.rsplit(':').next().and_then(|p| p.parse().ok()) {
+				listeners.push((port, PortOwner { pid, uid: None }));
+			}
+		}
+	}
+	listeners
+}
+
+/// Gets the port and process of each listener in the output of `netstat -ano`.
+#[cfg(target_os = "windows")]
+fn netstat_listeners(output: &str) -> impl Iterator<Item = (u16, u32)> + '_ {
+	output.lines().filter_map(|line| {
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		match fields.as_slice() {
+			["TCP", local, _, "LISTENING", pid] => {
+				Some((local.rsplit(':').next()?.parse().ok()?, pid.parse().ok()?))
+			}
+			_ => None,
+		}
+	})
+}
+
+/// Finds the process listening on the port in the output of `netstat -ano`.
+#[cfg(target_os = "windows")]
+fn find_netstat_listener(output: &str, port: u16) -> Option<u32> {
+	netstat_listeners(output)
+		.find(|(p, _)| *p == port)
+		.map(|(_, pid)| pid)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_differs_from() {
+		let owner = |pid, uid| PortOwner { pid, uid };
+		assert!(owner(Some(1), Some(1000)).differs_from(&owner(Some(2), Some(1000))));
+		assert!(!owner(Some(1), Some(1000)).differs_from(&owner(Some(1), Some(1000))));
+		assert!(owner(None, Some(1000)).differs_from(&owner(None, Some(1001))));
+		assert!(!owner(None, Some(1000)).differs_from(&owner(Some(2), Some(1000))));
+		assert!(!owner(None, None).differs_from(&owner(Some(2), None)));
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_find_listening_socket() {
+		let contents = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 12345 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:1F91 0100007F:D431 01 00000000:00000000 00:00000000 00000000  1000        0 12346 1 0000000000000000 20 4 30 10 -1";
+
+		assert_eq!(find_listening_socket(contents, 8080), Some((12345, 1000)));
+		// established, not listening
+		assert_eq!(find_listening_socket(contents, 8081), None);
+		assert_eq!(find_listening_socket(contents, 3000), None);
+	}
+
+	#[cfg(target_os = "windows")]
+	#[test]
+	fn test_find_netstat_listener() {
+		let output = "
+Active Connections
+
+  Proto  Local Address          Foreign Address        State           PID
+  TCP    0.0.0.0:135            0.0.0.0:0              LISTENING       1024
+  TCP    127.0.0.1:8080         0.0.0.0:0              LISTENING       4321
+  TCP    127.0.0.1:8081         127.0.0.1:50000        ESTABLISHED     5555";
+
+		assert_eq!(find_netstat_listener(output, 8080), Some(4321));
+		assert_eq!(find_netstat_listener(output, 8081), None);
+		assert_eq!(find_netstat_listener(output, 80), None);
+	}
+
+	#[cfg(target_os = "macos")]
+	#[test]
+	fn test_parse_lsof_listeners() {
+		let output = "p123\nf4\nn127.0.0.1:8080\nf5\nn[::1]:8080\np456\nf7\nn*:3000\n";
+		let owner = |pid| PortOwner {
+			pid: Some(pid),
+			uid: None,
+		};
+		assert_eq!(
+			parse_lsof_listeners(output),
+			vec![(8080, owner(123)), (8080, owner(123)), (3000, owner(456))]
+		);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn test_list_listeners() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let listeners = list_listeners().await;
+		assert!(listeners
+			.iter()
+			.any(|(p, o)| *p == port && o.pid == Some(std::process::id())));
+	}
+
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn test_find_owner() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let owner = find_owner(port).await.unwrap();
+		assert_eq!(owner.pid, Some(std::process::id()));
+	}
+
+	#[tokio::test]
+	async fn test_is_listening() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		assert!(is_listening(port).await);
+		drop(listener);
+		assert!(!is_listening(port).await);
+	}
+}
@@ -0,0 +1,178 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Traffic on each forwarded port, accumulated across runs of the tunnel so
+//! that `code tunnel status` shows more than `PortMetrics` has counted since
+//! the last restart. Totals are kept for the port's lifetime and for each UTC
+//! day, and days older than a week are rolled off.
+
+use std::collections::BTreeMap;
+
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::log::{Level, LogSink, ProgressFrame};
+use crate::state::{LauncherPaths, PersistedState};
+
+const PORT_STATS_FILE: &str = "port-stats.json";
+
+/// Number of days, including today, that daily totals are kept for.
+pub const RECENT_DAYS: i64 = 7;
+
+/// Traffic counted once connections to a port close.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortTraffic {
+	pub connections: u64,
+	pub bytes_received: u64,
+	pub bytes_sent: u64,
+}
+
+impl PortTraffic {
+	fn add(&mut self, other: &PortTraffic) {
+		self.connections = self.connections.saturating_add(other.connections);
+		self.bytes_received = self.bytes_received.saturating_add(other.bytes_received);
+		self.bytes_sent = self.bytes_sent.saturating_add(other.bytes_sent);
+	}
+}
+
+/// Traffic on a port across runs of the tunnel.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortStats {
+	pub lifetime: PortTraffic,
+	/// Totals of the last `RECENT_DAYS` UTC days that had traffic.
+	days: BTreeMap<NaiveDate, PortTraffic>,
+}
+
+impl PortStats {
+	fn record(&mut self, today: NaiveDate, traffic: &PortTraffic) {
+		self.lifetime.add(traffic);
+		self.days.entry(today).or_default().add(traffic);
+		let oldest = today - chrono::Duration::days(RECENT_DAYS - 1);
+		self.days.retain(|day, _| *day >= oldest);
+	}
+
+	/// Gets the traffic over the last `RECENT_DAYS` days, including today.
+	pub fn recent(&self, today: NaiveDate) -> PortTraffic {
+		let oldest = today - chrono::Duration::days(RECENT_DAYS - 1);
+		let mut total = PortTraffic::default();
+		for (_, traffic) in self.days.range(oldest..) {
+			total.add(traffic);
+		}
+		total
+	}
+}
+
+fn port_stats_state(paths: &LauncherPaths) -> PersistedState<BTreeMap<u16, PortStats>> {
+	PersistedState::new(paths.root().join(PORT_STATS_FILE))
+}
+
+/// Reads the accumulated traffic of each port, keyed by port number.
+pub fn load(paths: &LauncherPaths) -> BTreeMap<u16, PortStats> {
+	port_stats_state(paths).load()
+}
+
+/// Log sink that adds the traffic of each port connection to the persisted
+/// totals as it closes.
+#[derive(Clone)]
+pub struct PortStatsSink {
+	state: PersistedState<BTreeMap<u16, PortStats>>,
+}
+
+impl PortStatsSink {
+	pub fn new(paths: &LauncherPaths) -> Self {
+		Self {
+			state: port_stats_state(paths),
+		}
+	}
+}
+
+impl LogSink for PortStatsSink {
+	fn write_log(&self, _level: Level, _prefix: &str, _message: &str) {}
+	fn write_result(&self, _message: &str) {}
+
+	fn write_progress(&self, frame: &ProgressFrame) {
+		if let ProgressFrame::PortConnectionClosed {
+			port,
+			bytes_received,
+			bytes_sent,
+		} = frame
+		{
+			let traffic = PortTraffic {
+				connections: 1,
+				bytes_received: *bytes_received,
+				bytes_sent: *bytes_sent,
+			};
+
+			// ignore any errors, not much we can do if recording fails...
+			self.state
+				.update_with(
+					(*port, traffic, Utc::now().date_naive()),
+					|(port, traffic, today), stats| {
+						stats.entry(port).or_default().record(today, &traffic);
+					},
+				)
+				.ok();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_rolls_off_old_days() {
+		let mut stats = PortStats::default();
+		let day = |d| NaiveDate::from_ymd_opt(2024, 5, d).unwrap();
+		let traffic = PortTraffic {
+			connections: 1,
+			bytes_received: 10,
+			bytes_sent: 20,
+		};
+
+		stats.record(day(1), &traffic);
+		stats.record(day(5), &traffic);
+		stats.record(day(7), &traffic);
+		assert_eq!(stats.recent(day(7)).connections, 3);
+
+		stats.record(day(10), &traffic);
+		assert_eq!(stats.days.len(), 3);
+		assert_eq!(
+			stats.recent(day(10)),
+			PortTraffic {
+				connections: 3,
+				bytes_received: 30,
+				bytes_sent: 60,
+			}
+		);
+		assert_eq!(stats.lifetime.connections, 4);
+		assert_eq!(stats.recent(day(20)), PortTraffic::default());
+	}
+
+	#[test]
+	fn test_accumulates_across_sinks() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let closed = ProgressFrame::PortConnectionClosed {
+			port: 8080,
+			bytes_received: 10,
+			bytes_sent: 20,
+		};
+
+		// as if the tunnel restarted in between
+		PortStatsSink::new(&paths).write_progress(&closed);
+		PortStatsSink::new(&paths).write_progress(&closed);
+
+		let stats = load(&paths);
+		assert_eq!(stats[&8080].lifetime.connections, 2);
+		assert_eq!(stats[&8080].lifetime.bytes_sent, 40);
+		assert_eq!(
+			stats[&8080].recent(Utc::now().date_naive()).bytes_received,
+			20
+		);
+	}
+}
@@ -0,0 +1,192 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Streaming adapters over connections to ports added with
+//! `ActiveTunnel::add_port_direct`, so that embedders can serve their own
+//! protocols behind a tunnel with ordinary async IO and hyper services.
+
+use std::error::Error as StdError;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::log;
+use futures::Stream;
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+use super::port_connection::{PortConnection, PortReadHalf, PortWriteHalf};
+
+/// A connection to a directly-forwarded port as a single bidirectional
+/// stream, which can be handed to anything that takes an `AsyncRead` and
+/// `AsyncWrite`, such as hyper's `serve_connection`.
+pub struct PortStream {
+	read: PortReadHalf,
+	write: PortWriteHalf,
+}
+
+impl From<PortConnection> for PortStream {
+	fn from(conn: PortConnection) -> Self {
+		let (write, read) = conn.into_split();
+		PortStream { read, write }
+	}
+}
+
+impl AsyncRead for PortStream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().read).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for PortStream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.get_mut().write).poll_write(cx, buf)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_shutdown(cx)
+	}
+}
+
+/// Connections made to a directly-forwarded port, as a stream that ends when
+/// the port is removed. This is the shape taken by hyper's
+/// `accept::from_stream`. `PortStream` doesn't implement tonic's `Connected`,
+/// so gRPC services should be served with `serve_http` instead of tonic's
+/// `serve_with_incoming`.
+pub struct PortIncoming(mpsc::UnboundedReceiver<PortConnection>);
+
+impl From<mpsc::UnboundedReceiver<PortConnection>> for PortIncoming {
+	fn from(rx: mpsc::UnboundedReceiver<PortConnection>) -> Self {
+		PortIncoming(rx)
+	}
+}
+
+impl Stream for PortIncoming {
+	type Item = Result<PortStream, io::Error>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		self.get_mut()
+			.0
+			.poll_recv(cx)
+			.map(|conn| conn.map(|c| Ok(PortStream::from(c))))
+	}
+}
+
+/// Serves HTTP on each connection to the port with a service made by
+/// `make_service`, until the port is removed. `http` configures the
+/// protocol; use `Http::new().http2_only(true)` for gRPC services.
+pub async fn serve_http<F, S, B>(
+	log: log::Logger,
+	mut incoming: PortIncoming,
+	http: Http,
+	make_service: F,
+) where
+	F: Fn() -> S,
+	S: Service<Request<Body>, Response = Response<B>> + Send + 'static,
+	S::Future: Send + 'static,
+	S::Error: Into<Box<dyn StdError + Send + Sync>>,
+	B: HttpBody + Send + 'static,
+	B::Data: Send,
+	B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+	while let Some(conn) = incoming.0.recv().await {
+		let served = http
+			.serve_connection(PortStream::from(conn), make_service())
+			.with_upgrades();
+		let log = log.clone();
+		tokio::spawn(async move {
+			if let Err(e) = served.await {
+				debug!(log, "Error serving HTTP connection: {}", e);
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use futures::StreamExt;
+	use hyper::client::conn::Builder;
+	use hyper::service::service_fn;
+	use std::convert::Infallible;
+	use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+	#[tokio::test]
+	async fn test_incoming_ends_with_port() {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let mut incoming = PortIncoming::from(rx);
+
+		let (stream, mut client) = duplex(64);
+		tx.send(PortConnection::Local(Box::new(stream))).unwrap();
+		let mut conn = incoming.next().await.unwrap().unwrap();
+
+		client.write_all(b"ping").await.unwrap();
+		let mut buf = [0u8; 4];
+		conn.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"ping");
+		conn.write_all(b"pong").await.unwrap();
+		client.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"pong");
+
+		drop(tx);
+		assert!(incoming.next().await.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_serves_http() {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let serving = tokio::spawn(serve_http(
+			log::Logger::test(),
+			PortIncoming::from(rx),
+			Http::new(),
+			|| {
+				service_fn(|req: Request<Body>| async move {
+					Ok::<_, Infallible>(Response::new(Body::from(format!(
+						"hello {}",
+						req.uri().path()
+					))))
+				})
+			},
+		));
+
+		let (stream, client) = duplex(1024);
+		tx.send(PortConnection::Local(Box::new(stream))).unwrap();
+		let (mut sender, connection) = Builder::new().handshake(client).await.unwrap();
+		tokio::spawn(connection);
+
+		let response = sender
+			.send_request(
+				Request::builder()
+					.uri("http://127.0.0.1/world")
+					.body(Body::empty())
+					.unwrap(),
+			)
+			.await
+			.unwrap();
+		let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		assert_eq!(&body[..], b"hello /world");
+
+		// serving stops once the port is removed
+		drop(tx);
+		serving.await.unwrap();
+	}
+}
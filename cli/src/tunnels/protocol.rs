@@ -20,6 +20,12 @@ pub enum ServerRequestMethod {
 	update(UpdateParams),
 	servermsg(ServerMessageParams),
 	callserverhttp(CallServerHttpParams),
+	subscribelogs(SubscribeLogsParams),
+	negotiatecompression(NegotiateCompressionParams),
+	compressed(CompressedMessageParams),
+	challenge_issue(EmptyResult),
+	challenge_verify(ChallengeVerifyParams),
+	attest(AttestParams),
 }
 
 #[derive(Serialize, Debug)]
@@ -28,7 +34,9 @@ pub enum ServerRequestMethod {
 pub enum ClientRequestMethod<'a> {
 	servermsg(RefServerMessageParams<'a>),
 	serverlog(ServerLog<'a>),
+	clilog(ServerLog<'a>),
 	version(VersionParams),
+	compressed(RefCompressedMessageParams<'a>),
 }
 
 #[derive(Deserialize, Debug)]
@@ -52,6 +60,9 @@ pub struct ServeParams {
 	pub commit_id: Option<String>,
 	pub quality: Quality,
 	pub extensions: Vec<String>,
+	/// Version of the control protocol the client speaks, if it says.
+	#[serde(default)]
+	pub protocol_version: Option<u32>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -60,6 +71,9 @@ pub struct EmptyResult {}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateParams {
 	pub do_update: bool,
+	/// If set, the update is refused unless the latest release has this commit.
+	#[serde(default)]
+	pub expected_commit: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -80,6 +94,8 @@ pub struct RefServerMessageParams<'a> {
 pub struct UpdateResult {
 	pub up_to_date: bool,
 	pub did_update: bool,
+	/// Commit of the latest release.
+	pub commit: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -123,6 +139,12 @@ pub struct ServerLog<'a> {
 	pub level: u8,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct SubscribeLogsParams {
+	/// Minimum level of log records to send, as in `ServerLog`.
+	pub level: u8,
+}
+
 #[derive(Serialize)]
 pub struct GetHostnameResponse {
 	pub value: String,
@@ -148,4 +170,74 @@ pub struct CallServerHttpResult {
 pub struct VersionParams {
 	pub version: &'static str,
 	pub protocol_version: u32,
+	/// Whether the client must sign a challenge with an authorized SSH key,
+	/// using `challenge_issue` and `challenge_verify`, before anything else.
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	pub requires_challenge: bool,
+}
+
+#[derive(Serialize)]
+pub struct ChallengeIssueResult {
+	/// Text for the client to sign.
+	pub challenge: String,
+	/// Namespace the challenge is signed in, as in `ssh-keygen -Y sign -n`.
+	pub namespace: &'static str,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChallengeVerifyParams {
+	/// Signature of the challenge in the armored SSHSIG format.
+	pub signature: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AttestParams {
+	/// Nonce the client chose, which the host signs along with its
+	/// attestation, so the answer can't be replayed.
+	pub nonce: String,
+}
+
+#[derive(Serialize)]
+pub struct AttestResult {
+	pub machine: String,
+	pub os: String,
+	pub arch: String,
+	/// ID of the tunnel the host is attesting it hosts.
+	pub tunnel: String,
+	/// Signature of the attestation and nonce in the armored SSHSIG format,
+	/// signed in the `vscode-tunnel-host` namespace.
+	pub signature: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NegotiateCompressionParams {
+	/// Compression algorithms the client supports, in order of preference.
+	pub algorithms: Vec<String>,
+	/// Whether the client is connected over a local or direct transport,
+	/// rather than through the relay.
+	#[serde(default)]
+	pub local: bool,
+	/// The client's estimate of the link's bandwidth, in kilobits per second.
+	#[serde(default)]
+	pub bandwidth_kbps: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct NegotiateCompressionResult {
+	/// The algorithm used for messages from now on, if any.
+	pub algorithm: Option<&'static str>,
+}
+
+/// A message compressed with the negotiated algorithm. Once decompressed,
+/// it's handled as if it had been sent on its own.
+#[derive(Deserialize, Debug)]
+pub struct CompressedMessageParams {
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RefCompressedMessageParams<'a> {
+	#[serde(with = "serde_bytes")]
+	pub data: &'a [u8],
 }
@@ -0,0 +1,329 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Client-side rate limiting of tunnel management API requests, so that loops
+//! over tunnels or names, and scripts running the CLI over and over, don't
+//! exceed the service's quota and get throttled. Requests draw from a token
+//! bucket that's saved in the data directory, so that it carries over between
+//! runs of the CLI. Requests the service throttles anyway are retried a few
+//! times after a delay.
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tunnels::contracts::Tunnel;
+use tunnels::management::{HttpError, HttpResult, TunnelLocator, TunnelRequestOptions};
+
+use crate::log;
+use crate::state::PersistedState;
+
+use super::backoff::{Backoff, BackoffConfig, BackoffStrategy};
+use super::service_limits::ServiceLimits;
+use super::tunnel_service::{ManagementClient, RelayHost, SharedManagementClient};
+
+/// Number of requests that can be made in a burst.
+const BUCKET_CAPACITY: f64 = 30.0;
+
+/// Number of requests per second that can be made once the burst is used up.
+const REFILL_PER_SEC: f64 = 2.0;
+
+/// How requests the service throttles are retried. The tunnels SDK doesn't
+/// expose the `Retry-After` header of its responses, so the delay backs off,
+/// with jitter so that throttled machines don't all retry at once.
+const THROTTLED_RETRY: BackoffConfig = BackoffConfig {
+	base: Duration::from_secs(1),
+	max: Duration::from_secs(30),
+	strategy: BackoffStrategy::Jittered,
+	max_retries: Some(3),
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+struct BucketState {
+	tokens: f64,
+	/// When `tokens` was last updated, in milliseconds since the Unix epoch.
+	updated_at_ms: u64,
+}
+
+/// Takes a token from the bucket at `now_ms`, and gets how long to wait
+/// before making the request it's for. Tokens are taken even if the bucket
+/// is empty, so that waiting requests are let through in order.
+fn take_token(state: Option<BucketState>, now_ms: u64) -> (BucketState, Duration) {
+	let tokens = match state {
+		Some(s) => {
+			let elapsed_secs = now_ms.saturating_sub(s.updated_at_ms) as f64 / 1000.0;
+			(s.tokens + elapsed_secs * REFILL_PER_SEC).min(BUCKET_CAPACITY)
+		}
+		None => BUCKET_CAPACITY,
+	} - 1.0;
+
+	let wait = if tokens < 0.0 {
+		Duration::from_secs_f64(-tokens / REFILL_PER_SEC)
+	} else {
+		Duration::ZERO
+	};
+
+	(
+		BucketState {
+			tokens,
+			updated_at_ms: now_ms,
+		},
+		wait,
+	)
+}
+
+fn is_throttled(e: &HttpError) -> bool {
+	matches!(e, HttpError::ResponseError(r) if r.status_code == StatusCode::TOO_MANY_REQUESTS)
+}
+
+/// Makes a request with `make_request`, and makes it again after a delay
+/// while the service throttles it, until the retries run out.
+async fn retry_throttled<T, F, Fut>(
+	log: &log::Logger,
+	config: BackoffConfig,
+	mut make_request: F,
+) -> HttpResult<T>
+where
+	F: FnMut() -> Fut,
+	Fut: Future<Output = HttpResult<T>>,
+{
+	let mut backoff = Backoff::new(config);
+	loop {
+		match make_request().await {
+			Err(e) if is_throttled(&e) => match backoff.next_delay() {
+				Some(delay) => {
+					debug!(
+						log,
+						"Throttled by the tunnel service, retrying in {}ms",
+						delay.as_millis()
+					);
+					tokio::time::sleep(delay).await;
+				}
+				None => return Err(e),
+			},
+			r => return r,
+		}
+	}
+}
+
+/// Client that waits for a token from a shared bucket before each request
+/// made through another client.
+pub struct RateLimitedClient {
+	inner: SharedManagementClient,
+	bucket: PersistedState<Option<BucketState>>,
+	log: log::Logger,
+}
+
+impl RateLimitedClient {
+	/// Creates a client whose bucket is saved at `bucket_path`.
+	pub fn new(log: log::Logger, inner: SharedManagementClient, bucket_path: PathBuf) -> Self {
+		Self {
+			inner,
+			bucket: PersistedState::new(bucket_path),
+			log,
+		}
+	}
+
+	async fn acquire(&self) {
+		let now_ms = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_millis() as u64)
+			.unwrap_or_default();
+		let (state, wait) = take_token(self.bucket.load(), now_ms);
+		if let Err(e) = self.bucket.save(Some(state)) {
+			debug!(self.log, "Error saving rate limit state: {}", e);
+		}
+
+		if !wait.is_zero() {
+			debug!(
+				self.log,
+				"Waiting {}ms to stay within the tunnel service's rate limit",
+				wait.as_millis()
+			);
+			tokio::time::sleep(wait).await;
+		}
+	}
+}
+
+#[async_trait]
+impl ManagementClient for RateLimitedClient {
+	async fn list_all_tunnels(&self, options: &TunnelRequestOptions) -> HttpResult<Vec<Tunnel>> {
+		retry_throttled(&self.log, THROTTLED_RETRY, || async move {
+			self.acquire().await;
+			self.inner.list_all_tunnels(options).await
+		})
+		.await
+	}
+
+	async fn get_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		retry_throttled(&self.log, THROTTLED_RETRY, || async move {
+			self.acquire().await;
+			self.inner.get_tunnel(locator, options).await
+		})
+		.await
+	}
+
+	async fn create_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		retry_throttled(&self.log, THROTTLED_RETRY, || async move {
+			self.acquire().await;
+			self.inner.create_tunnel(tunnel, options).await
+		})
+		.await
+	}
+
+	async fn update_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		retry_throttled(&self.log, THROTTLED_RETRY, || async move {
+			self.acquire().await;
+			self.inner.update_tunnel(tunnel, options).await
+		})
+		.await
+	}
+
+	async fn delete_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.acquire().await;
+		self.inner.delete_tunnel(locator, options).await
+	}
+
+	async fn delete_tunnel_port(
+		&self,
+		locator: &TunnelLocator,
+		port_number: u16,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.acquire().await;
+		self.inner
+			.delete_tunnel_port(locator, port_number, options)
+			.await
+	}
+
+	async fn delete_tunnel_endpoints(
+		&self,
+		locator: &TunnelLocator,
+		host_id: &str,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.acquire().await;
+		self.inner
+			.delete_tunnel_endpoints(locator, host_id, options)
+			.await
+	}
+
+	// limits aren't served by the management API, so aren't rate limited
+	async fn get_service_limits(&self) -> HttpResult<Option<ServiceLimits>> {
+		self.inner.get_service_limits().await
+	}
+
+	fn with_host_token(&self, host_token: &str) -> SharedManagementClient {
+		Arc::new(RateLimitedClient {
+			inner: self.inner.with_host_token(host_token),
+			bucket: self.bucket.clone(),
+			log: self.log.clone(),
+		})
+	}
+
+	fn create_relay_host(&self, locator: TunnelLocator) -> Box<dyn RelayHost> {
+		self.inner.create_relay_host(locator)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use tunnels::management::ResponseError;
+
+	fn error(status_code: StatusCode) -> HttpError {
+		HttpError::ResponseError(ResponseError {
+			url: "https://tunnels.example.com".parse().unwrap(),
+			status_code,
+			data: None,
+			request_id: None,
+		})
+	}
+
+	/// Runs `retry_throttled` over responses with the statuses, or success
+	/// once they run out, and gets how many requests were made.
+	async fn requests_made(statuses: &[StatusCode]) -> (usize, HttpResult<()>) {
+		let made = AtomicUsize::new(0);
+		let config = BackoffConfig {
+			base: Duration::ZERO,
+			..THROTTLED_RETRY
+		};
+		let result = retry_throttled(&log::Logger::test(), config, || async {
+			match statuses.get(made.fetch_add(1, Ordering::SeqCst)) {
+				Some(s) => Err(error(*s)),
+				None => Ok(()),
+			}
+		})
+		.await;
+
+		(made.load(Ordering::SeqCst), result)
+	}
+
+	#[tokio::test]
+	async fn test_retries_throttled_requests() {
+		let throttled = StatusCode::TOO_MANY_REQUESTS;
+
+		let (made, result) = requests_made(&[throttled, throttled]).await;
+		assert_eq!(made, 3);
+		assert!(result.is_ok());
+
+		let (made, result) = requests_made(&[throttled; 5]).await;
+		assert_eq!(made, 4);
+		assert!(result.is_err());
+
+		let (made, result) = requests_made(&[StatusCode::NOT_FOUND]).await;
+		assert_eq!(made, 1);
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn test_takes_tokens() {
+		let (mut state, wait) = take_token(None, 0);
+		assert_eq!(wait, Duration::ZERO);
+		assert_eq!(state.tokens, BUCKET_CAPACITY - 1.0);
+
+		for _ in 1..BUCKET_CAPACITY as usize {
+			let (s, wait) = take_token(Some(state), 0);
+			assert_eq!(wait, Duration::ZERO);
+			state = s;
+		}
+
+		// the bucket is empty, so requests wait for it to refill
+		let (s, wait) = take_token(Some(state), 0);
+		assert_eq!(wait, Duration::from_millis(500));
+		let (s, wait) = take_token(Some(s), 0);
+		assert_eq!(wait, Duration::from_secs(1));
+
+		// and it refills over time, up to its capacity
+		let (s, wait) = take_token(Some(s), 2000);
+		assert_eq!(wait, Duration::ZERO);
+		assert_eq!(s.tokens, 1.0);
+		let (s, _) = take_token(Some(s), 3_600_000);
+		assert_eq!(s.tokens, BUCKET_CAPACITY - 1.0);
+	}
+}
@@ -0,0 +1,133 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Several named tunnels hosted from one CLI process, such as separate `work`
+//! and `personal` tunnels. Each is persisted in its own file in the data
+//! directory, apart from the launcher tunnel in `code_tunnel.json`, and is
+//! hosted with its own `ActiveTunnel`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::state::LauncherPaths;
+use crate::util::errors::{wrap, AnyError, DevTunnelError};
+
+use super::dev_tunnels::{ActiveTunnel, DevTunnels, PersistedTunnel};
+
+const NAMED_TUNNELS_DIR_NAME: &str = "tunnels";
+
+pub struct TunnelRegistry {
+	/// Tunnel management that each named tunnel's is copied from.
+	template: DevTunnels,
+	dir: PathBuf,
+	hosted: HashMap<String, ActiveTunnel>,
+}
+
+impl TunnelRegistry {
+	/// Creates a registry whose tunnels are managed with copies of `template`,
+	/// so they share its client and settings.
+	pub fn new(template: DevTunnels, paths: &LauncherPaths) -> Self {
+		Self {
+			template,
+			dir: paths.root().join(NAMED_TUNNELS_DIR_NAME),
+			hosted: HashMap::new(),
+		}
+	}
+
+	/// Gets tunnel management for the named tunnel, which persists the tunnel
+	/// in the tunnel's own file.
+	pub fn dev_tunnels(&self, name: &str) -> Result<DevTunnels, AnyError> {
+		let mut dt = self.template.clone();
+		dt.set_persisted_tunnel_path(self.path_for(name)?);
+		Ok(dt)
+	}
+
+	/// Lists the named tunnels created on this machine.
+	pub fn list(&self) -> Vec<PersistedTunnel> {
+		let entries = match fs::read_dir(&self.dir) {
+			Ok(e) => e,
+			Err(_) => return vec![],
+		};
+
+		let mut tunnels: Vec<PersistedTunnel> = entries
+			.filter_map(|e| e.ok())
+			.map(|e| e.path())
+			.filter(|p| p.extension().map(|e| e == "json").unwrap_or(false))
+			.filter_map(|p| PersistedTunnel::state(p).load())
+			.collect();
+		tunnels.sort_by(|a, b| a.name.cmp(&b.name));
+		tunnels
+	}
+
+	pub fn is_hosted(&self, name: &str) -> bool {
+		self.hosted.contains_key(name)
+	}
+
+	/// Starts hosting the named tunnel, creating it if it doesn't exist yet.
+	pub async fn host(&mut self, name: &str) -> Result<&mut ActiveTunnel, AnyError> {
+		if self.is_hosted(name) {
+			return Err(DevTunnelError(format!("tunnel {} is already hosted", name)).into());
+		}
+
+		fs::create_dir_all(&self.dir)
+			.map_err(|e| wrap(e, "error creating named tunnels directory"))?;
+		let mut dt = self.dev_tunnels(name)?;
+
+		// create the tunnel under exactly this name, rather than letting the
+		// launcher pick another name if it's taken
+		let path = self.path_for(name)?;
+		if PersistedTunnel::state(path).load().is_none() {
+			dt.rename_tunnel(name).await?;
+		}
+
+		let active = dt
+			.start_new_launcher_tunnel(Some(name.to_string()), false)
+			.await?;
+		Ok(self.hosted.entry(name.to_string()).or_insert(active))
+	}
+
+	/// Takes the hosted tunnel out of the registry, such as to serve it.
+	pub fn take(&mut self, name: &str) -> Option<ActiveTunnel> {
+		self.hosted.remove(name)
+	}
+
+	pub fn get_mut(&mut self, name: &str) -> Option<&mut ActiveTunnel> {
+		self.hosted.get_mut(name)
+	}
+
+	/// Stops hosting the named tunnel, if it's hosted by the registry.
+	pub async fn close(&mut self, name: &str) -> Result<(), AnyError> {
+		match self.hosted.remove(name) {
+			Some(mut active) => active.close().await,
+			None => Ok(()),
+		}
+	}
+
+	/// Stops hosting the named tunnel, deletes it from the tunnel service, and
+	/// forgets it.
+	pub async fn remove(&mut self, name: &str) -> Result<(), AnyError> {
+		self.close(name).await?;
+		self.dev_tunnels(name)?.remove_tunnel().await?;
+		match fs::remove_file(self.path_for(name)?) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+				Err(wrap(e, "error removing named tunnel").into())
+			}
+			_ => Ok(()),
+		}
+	}
+
+	fn path_for(&self, name: &str) -> Result<PathBuf, AnyError> {
+		let is_valid = !name.is_empty()
+			&& name
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+		if !is_valid {
+			return Err(DevTunnelError(format!("invalid tunnel name '{}'", name)).into());
+		}
+
+		Ok(self.dir.join(format!("{}.json", name)))
+	}
+}
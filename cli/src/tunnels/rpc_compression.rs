@@ -0,0 +1,86 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Optional compression of control RPC messages. Clients ask for it with a
+//! `negotiatecompression` call; once it's agreed, either side may send a
+//! message as a `compressed` message wrapping the zstd-compressed original.
+//! Only bulky messages, such as extension lists, are worth compressing, and
+//! only on slower links, so small messages and fast transports are left alone.
+
+use super::protocol::NegotiateCompressionParams;
+
+pub const ZSTD: &str = "zstd";
+
+/// Messages smaller than this are sent as-is.
+const MIN_COMPRESSED_SIZE: usize = 4 * 1024;
+
+/// Links at least this fast gain little from compression, and it's declined.
+const FAST_LINK_KBPS: u32 = 10_000;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Limit on the size of a decompressed message, so that a small compressed
+/// message can't be used to exhaust the host's memory.
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Picks the algorithm to use for the connection, if any.
+pub fn negotiate(params: &NegotiateCompressionParams) -> Option<&'static str> {
+	if params.local || matches!(params.bandwidth_kbps, Some(b) if b >= FAST_LINK_KBPS) {
+		return None;
+	}
+
+	params.algorithms.iter().any(|a| a == ZSTD).then_some(ZSTD)
+}
+
+/// Compresses the message, returning None if it's too small to be worth
+/// compressing or doesn't get any smaller.
+pub fn compress(message: &[u8]) -> Option<Vec<u8>> {
+	if message.len() < MIN_COMPRESSED_SIZE {
+		return None;
+	}
+
+	zstd::bulk::compress(message, ZSTD_LEVEL)
+		.ok()
+		.filter(|c| c.len() < message.len())
+}
+
+pub fn decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+	zstd::bulk::decompress(data, MAX_DECOMPRESSED_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn params(local: bool, bandwidth_kbps: Option<u32>) -> NegotiateCompressionParams {
+		NegotiateCompressionParams {
+			algorithms: vec!["gzip".to_string(), ZSTD.to_string()],
+			local,
+			bandwidth_kbps,
+		}
+	}
+
+	#[test]
+	fn test_negotiates_on_slow_links_only() {
+		assert_eq!(negotiate(&params(false, None)), Some(ZSTD));
+		assert_eq!(negotiate(&params(false, Some(2_000))), Some(ZSTD));
+		assert_eq!(negotiate(&params(false, Some(100_000))), None);
+		assert_eq!(negotiate(&params(true, None)), None);
+
+		let mut unsupported = params(false, None);
+		unsupported.algorithms = vec!["gzip".to_string()];
+		assert_eq!(negotiate(&unsupported), None);
+	}
+
+	#[test]
+	fn test_compresses_bulky_messages() {
+		assert!(compress(b"small").is_none());
+
+		let message = "extension-id,".repeat(1000).into_bytes();
+		let compressed = compress(&message).unwrap();
+		assert!(compressed.len() < message.len());
+		assert_eq!(decompress(&compressed).unwrap(), message);
+	}
+}
@@ -0,0 +1,161 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Notices of changes that could mean the account or tunnel is being misused,
+//! such as the tunnel's access control being changed from elsewhere. They're
+//! reported as `SecurityEvent` progress frames, so they're logged, kept in the
+//! tunnel's status, and passed to the security hook and webhook.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tunnels::contracts::Tunnel;
+
+use crate::log::{self, ProgressFrame, SecurityEventKind};
+use crate::state::{LauncherPaths, PersistedState};
+
+const KNOWN_STATE_FILE_NAME: &str = "security.json";
+
+/// A security event, as kept in the tunnel's status.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SecurityEvent {
+	pub kind: SecurityEventKind,
+	pub detail: String,
+	pub at: DateTime<Utc>,
+}
+
+/// What this machine has seen before, to tell which changes are new.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct KnownState {
+	/// Access control of each tunnel hosted here, as JSON, keyed by tunnel ID.
+	access_control: HashMap<String, String>,
+	/// Accounts the CLI has logged in with, as `<provider> <name>`.
+	accounts: Vec<String>,
+}
+
+impl KnownState {
+	/// Records the tunnel's access control, returning whether it differs from
+	/// what was recorded before.
+	fn record_access_control(&mut self, tunnel_id: &str, access_control: String) -> bool {
+		match self
+			.access_control
+			.insert(tunnel_id.to_string(), access_control.clone())
+		{
+			Some(previous) => previous != access_control,
+			None => false,
+		}
+	}
+
+	/// Records the account, returning whether it's new to a machine that was
+	/// already used with other accounts.
+	fn record_account(&mut self, account: &str) -> bool {
+		if self.accounts.iter().any(|a| a == account) {
+			return false;
+		}
+
+		self.accounts.push(account.to_string());
+		self.accounts.len() > 1
+	}
+}
+
+/// Checks for, and reports, security-relevant changes.
+#[derive(Clone)]
+pub struct SecurityMonitor {
+	log: log::Logger,
+	known: PersistedState<KnownState>,
+}
+
+impl SecurityMonitor {
+	pub fn new(log: log::Logger, paths: &LauncherPaths) -> Self {
+		Self {
+			log,
+			known: PersistedState::new(paths.root().join(KNOWN_STATE_FILE_NAME)),
+		}
+	}
+
+	/// Reports a security event. Issued host tokens are expected whenever the
+	/// tunnel is hosted, so they're only logged at info level.
+	pub fn report(&self, kind: SecurityEventKind, detail: &str) {
+		match kind {
+			SecurityEventKind::HostTokenIssued => info!(self.log, "{}", detail),
+			_ => warning!(self.log, "Security notice: {}", detail),
+		}
+		self.log
+			.progress(ProgressFrame::SecurityEvent { kind, detail });
+	}
+
+	/// Reports if the tunnel's access control changed since it was last
+	/// hosted from this machine.
+	pub fn check_access_control(&self, name: &str, tunnel: &Tunnel) {
+		let id = match &tunnel.tunnel_id {
+			Some(id) => id,
+			None => return,
+		};
+
+		let access_control = serde_json::to_string(&tunnel.access_control).unwrap_or_default();
+		let mut known = self.known.load();
+		let changed = known.record_access_control(id, access_control);
+		self.save(known);
+
+		if changed {
+			self.report(
+				SecurityEventKind::AccessControlChanged,
+				&format!(
+					"Who can access the tunnel {} changed since it was last hosted from this machine",
+					name
+				),
+			);
+		}
+	}
+
+	/// Reports if the account wasn't logged in with on this machine before.
+	pub fn check_account(&self, provider: &str, name: &str) {
+		let mut known = self.known.load();
+		let new = known.record_account(&format!("{} {}", provider, name));
+		self.save(known);
+
+		if new {
+			self.report(
+				SecurityEventKind::NewAccount,
+				&format!(
+					"Logged in as {} ({}), an account not used on this machine before",
+					name, provider
+				),
+			);
+		}
+	}
+
+	fn save(&self, known: KnownState) {
+		if let Err(e) = self.known.save(known) {
+			debug!(self.log, "Error saving security state: {}", e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_records_access_control_changes() {
+		let mut known = KnownState::default();
+		assert!(!known.record_access_control("t1", "a".to_string()));
+		assert!(!known.record_access_control("t1", "a".to_string()));
+		assert!(known.record_access_control("t1", "b".to_string()));
+		assert!(!known.record_access_control("t2", "c".to_string()));
+	}
+
+	#[test]
+	fn test_records_new_accounts() {
+		let mut known = KnownState::default();
+		assert!(!known.record_account("github alice"));
+		assert!(!known.record_account("github alice"));
+		assert!(known.record_account("github mallory"));
+		assert!(!known.record_account("github mallory"));
+	}
+}
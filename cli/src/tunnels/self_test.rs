@@ -0,0 +1,199 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! End-to-end check of hosting on this machine: a disposable tunnel is
+//! created, a local echo server is forwarded on it, and the echo server is
+//! reached through the relay as a client would, before the tunnel's deleted.
+//! Each stage is timed, so a new host can be validated before it's relied on.
+
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::log;
+
+use super::dev_tunnels::DevTunnels;
+use super::port_access::PortPrivacy;
+
+/// How long the tunnel lives if the self-test can't delete it, after which a
+/// later run of `code tunnel guest` or `self-test` deletes it.
+const TUNNEL_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long the forwarded port is retried for while the relay picks it up.
+const CONNECT_DEADLINE: Duration = Duration::from_secs(30);
+
+/// Largest request head the echo server reads.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Outcome of one stage of the self-test.
+pub struct Stage {
+	pub name: &'static str,
+	pub duration: Duration,
+	pub error: Option<String>,
+}
+
+/// Runs the self-test, returning the stages that ran. Later stages are
+/// skipped once one fails, except for deleting the tunnel.
+pub async fn run(log: &log::Logger, dt: &mut DevTunnels, http: &reqwest::Client) -> Vec<Stage> {
+	let mut stages = vec![];
+
+	for name in dt.delete_expired_guest_tunnels().await.unwrap_or_default() {
+		info!(log, "Deleted expired tunnel {}", name);
+	}
+
+	let expires_at = SystemTime::now() + TUNNEL_TTL;
+	let created = timed(
+		&mut stages,
+		"create a disposable tunnel",
+		dt.start_guest_tunnel(None, expires_at, None),
+	)
+	.await;
+	let (mut tunnel, persisted) = match created {
+		Some(c) => c,
+		None => return stages,
+	};
+	info!(log, "Created tunnel {} for the self-test", persisted.name);
+
+	let echo = timed(&mut stages, "start an echo server", EchoServer::start()).await;
+	if let Some(echo) = &echo {
+		let forwarded = timed(&mut stages, "forward the echo port", async {
+			tunnel.add_port_tcp(echo.port, PortPrivacy::Public).await?;
+			tunnel.get_port_uri(echo.port).await
+		})
+		.await;
+
+		if let Some(uri) = forwarded {
+			timed(
+				&mut stages,
+				"connect through the relay",
+				request_echo(http, &uri),
+			)
+			.await;
+		}
+	}
+
+	timed(&mut stages, "delete the tunnel", async {
+		tunnel.close().await.ok();
+		dt.delete_guest_tunnel(&persisted).await
+	})
+	.await;
+
+	stages
+}
+
+/// Runs a stage, recording how long it took and whether it failed.
+async fn timed<T, E: fmt::Display>(
+	stages: &mut Vec<Stage>,
+	name: &'static str,
+	stage: impl Future<Output = Result<T, E>>,
+) -> Option<T> {
+	let started = Instant::now();
+	let result = stage.await;
+	stages.push(Stage {
+		name,
+		duration: started.elapsed(),
+		error: result.as_ref().err().map(|e| e.to_string()),
+	});
+	result.ok()
+}
+
+/// Requests a random path from the echo server through the forwarded port's
+/// URI, until it's echoed back or the deadline passes.
+async fn request_echo(http: &reqwest::Client, uri: &str) -> Result<(), String> {
+	let path = format!("/{}", Uuid::new_v4());
+	let url = format!("{}{}", uri.trim_end_matches('/'), path);
+	let started = Instant::now();
+
+	loop {
+		let error = match http
+			.get(&url)
+			// the interstitial shown to browsers on public ports
+			.header("X-Tunnel-Skip-AntiPhishing-Page", "true")
+			.send()
+			.await
+		{
+			Ok(r) if r.status().is_success() => match r.text().await {
+				Ok(body) if body == path => return Ok(()),
+				Ok(body) => format!("expected {} to be echoed back, got {:?}", path, body),
+				Err(e) => format!("error reading the response: {}", e),
+			},
+			Ok(r) => format!("the relay responded with {}", r.status()),
+			Err(e) => format!("error requesting {}: {}", url, e),
+		};
+
+		if started.elapsed() > CONNECT_DEADLINE {
+			return Err(error);
+		}
+		tokio::time::sleep(Duration::from_secs(1)).await;
+	}
+}
+
+/// HTTP server on a local port that responds to each request with its path.
+/// It's stopped once dropped.
+struct EchoServer {
+	port: u16,
+	task: JoinHandle<()>,
+}
+
+impl EchoServer {
+	async fn start() -> Result<EchoServer, std::io::Error> {
+		let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+		let port = listener.local_addr()?.port();
+		let task = tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				tokio::spawn(async move {
+					echo_path(stream).await.ok();
+				});
+			}
+		});
+
+		Ok(EchoServer { port, task })
+	}
+}
+
+impl Drop for EchoServer {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+async fn echo_path(mut stream: TcpStream) -> std::io::Result<()> {
+	let mut head = Vec::new();
+	let mut buf = [0u8; 1024];
+	while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+		let n = stream.read(&mut buf).await?;
+		if n == 0 || head.len() + n > MAX_REQUEST_HEAD {
+			return Ok(());
+		}
+		head.extend_from_slice(&buf[..n]);
+	}
+
+	let request_line = String::from_utf8_lossy(&head);
+	let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+	let response = format!(
+		"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		path.len(),
+		path
+	);
+	stream.write_all(response.as_bytes()).await?;
+	stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_echoes_path() {
+		let echo = EchoServer::start().await.unwrap();
+		let uri = format!("http://127.0.0.1:{}/", echo.port);
+		request_echo(&reqwest::Client::new(), &uri).await.unwrap();
+	}
+}
@@ -41,12 +41,22 @@ pub trait ServiceManager {
 
 	/// Unregisters the current executable as a service.
 	fn unregister(&self) -> Result<(), AnyError>;
+
+	/// Prints the logs of the service, and then follows them as they're
+	/// written, until the process is stopped.
+	fn show_logs(&self, launcher_paths: &LauncherPaths) -> Result<(), AnyError>;
 }
 
 #[cfg(target_os = "windows")]
 pub type ServiceManagerImpl = super::service_windows::WindowsService;
 
-#[cfg(not(target_os = "windows"))]
+#[cfg(target_os = "linux")]
+pub type ServiceManagerImpl = super::service_linux::SystemdService;
+
+#[cfg(target_os = "macos")]
+pub type ServiceManagerImpl = super::service_macos::LaunchdService;
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
 pub type ServiceManagerImpl = UnimplementedServiceManager;
 
 #[allow(unreachable_code)]
@@ -79,4 +89,72 @@ impl ServiceManager for UnimplementedServiceManager {
 	fn unregister(&self) -> Result<(), AnyError> {
 		unimplemented!("Service management is not supported on this platform");
 	}
+
+	fn show_logs(&self, _launcher_paths: &LauncherPaths) -> Result<(), AnyError> {
+		unimplemented!("Service management is not supported on this platform");
+	}
+}
+
+/// Adds the service's log file in the data directory to the logger, for
+/// platforms where the service's output isn't kept by the system.
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub(super) fn tee_service_log_file(
+	log: &log::Logger,
+	launcher_paths: &LauncherPaths,
+) -> log::Logger {
+	match log::FileLogSink::new(
+		log::Level::Debug,
+		&launcher_paths.root().join(SERVICE_LOG_FILE_NAME),
+	) {
+		Ok(sink) => log.tee(sink),
+		Err(e) => {
+			warning!(log, "Failed to create service log file: {}", e);
+			log.clone()
+		}
+	}
+}
+
+/// Prints the service's log file in the data directory, and then what's
+/// added to it, until the process is stopped.
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub(super) fn follow_service_log_file(launcher_paths: &LauncherPaths) -> Result<(), AnyError> {
+	use crate::util::errors::wrap;
+	use std::io::Write;
+
+	let path = launcher_paths.root().join(SERVICE_LOG_FILE_NAME);
+	let mut file = std::fs::File::open(&path)
+		.map_err(|e| wrap(e, format!("error opening service log {}", path.display())))?;
+	let mut stdout = std::io::stdout();
+	loop {
+		std::io::copy(&mut file, &mut stdout).map_err(|e| wrap(e, "error reading service log"))?;
+		stdout.flush().ok();
+		std::thread::sleep(std::time::Duration::from_secs(1));
+	}
+}
+
+/// Runs the service until it exits, or until the supervisor asks it to stop
+/// by sending SIGTERM, as systemd and launchd do.
+#[cfg(unix)]
+pub(super) fn run_until_terminated(
+	log: log::Logger,
+	launcher_paths: LauncherPaths,
+	mut handle: impl 'static + ServiceContainer,
+) -> Result<(), AnyError> {
+	use crate::util::errors::wrap;
+	use tokio::signal::unix::{signal, SignalKind};
+
+	// `run` is called from within the runtime, but isn't async
+	tokio::task::block_in_place(|| {
+		tokio::runtime::Handle::current().block_on(async move {
+			let mut terminate = signal(SignalKind::terminate())
+				.map_err(|e| wrap(e, "error listening for SIGTERM"))?;
+			let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+			tokio::spawn(async move {
+				terminate.recv().await;
+				shutdown_tx.send(ShutdownSignal::ServiceStopped).await.ok();
+			});
+
+			handle.run_service(log, launcher_paths, shutdown_rx).await
+		})
+	})
 }
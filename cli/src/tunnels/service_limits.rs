@@ -0,0 +1,168 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::util::errors::InvalidTunnelName;
+
+/// How long limits fetched from the service are used before being refetched.
+const LIMITS_CACHE_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+
+const DEFAULT_NAME_PATTERN: &str = r"^([\w-]+)$";
+
+/// Limits the tunnel service places on tunnels. These are fetched from the
+/// service where possible, so that the CLI validates input against its
+/// current rules, with the defaults used when they can't be fetched.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ServiceLimits {
+	/// Maximum length of a tunnel name.
+	pub max_name_length: usize,
+	/// Regex that tunnel names must match.
+	pub name_pattern: String,
+	/// Maximum number of ports that can be forwarded on a tunnel, if limited.
+	pub max_ports: Option<usize>,
+	/// Number of tunnels, or machines, a user can have.
+	pub machine_quota: usize,
+}
+
+impl Default for ServiceLimits {
+	fn default() -> Self {
+		Self {
+			max_name_length: 20,
+			name_pattern: DEFAULT_NAME_PATTERN.to_string(),
+			max_ports: None,
+			machine_quota: 10,
+		}
+	}
+}
+
+impl ServiceLimits {
+	pub fn validate_name(&self, name: &str) -> Result<(), InvalidTunnelName> {
+		if name.len() > self.max_name_length {
+			return Err(InvalidTunnelName(format!(
+				"Names cannot be longer than {} characters. Please try a different name.",
+				self.max_name_length
+			)));
+		}
+
+		let re = Regex::new(&self.name_pattern)
+			.unwrap_or_else(|_| Regex::new(DEFAULT_NAME_PATTERN).unwrap());
+
+		if !re.is_match(name) {
+			if self.name_pattern == DEFAULT_NAME_PATTERN {
+				return Err(InvalidTunnelName(
+					"Names can only contain letters, numbers, and '-'. Spaces, commas, and all other special characters are not allowed. Please try a different name.".to_string()
+				));
+			}
+
+			return Err(InvalidTunnelName(format!(
+				"Names must match the pattern {}. Please try a different name.",
+				self.name_pattern
+			)));
+		}
+
+		Ok(())
+	}
+
+	/// Replaces the name pattern with the default if it isn't a valid regex,
+	/// returning the error it had.
+	pub fn fix_name_pattern(&mut self) -> Result<(), regex::Error> {
+		match Regex::new(&self.name_pattern) {
+			Ok(_) => Ok(()),
+			Err(e) => {
+				self.name_pattern = DEFAULT_NAME_PATTERN.to_string();
+				Err(e)
+			}
+		}
+	}
+
+	/// Gets the message shown when the machine quota is exceeded.
+	pub fn quota_exceeded_message(&self) -> String {
+		format!("You've exceeded the {} machine limit for the port fowarding service. Please remove other machines before trying to add this machine.", self.machine_quota)
+	}
+}
+
+/// Service limits as persisted, with the time they were fetched.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedServiceLimits {
+	pub limits: ServiceLimits,
+	pub fetched_at: u64,
+}
+
+impl CachedServiceLimits {
+	pub fn new(limits: ServiceLimits) -> Self {
+		Self {
+			limits,
+			fetched_at: now_secs(),
+		}
+	}
+
+	pub fn is_fresh(&self) -> bool {
+		now_secs().saturating_sub(self.fetched_at) < LIMITS_CACHE_DURATION.as_secs()
+	}
+}
+
+fn now_secs() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_validate_name_with_default_limits() {
+		let limits = ServiceLimits::default();
+		assert!(limits.validate_name("my-machine_1").is_ok());
+		assert!(limits.validate_name("my machine").is_err());
+		assert!(limits.validate_name(&"a".repeat(21)).is_err());
+	}
+
+	#[test]
+	fn test_validate_name_with_service_limits() {
+		let limits = ServiceLimits {
+			max_name_length: 30,
+			name_pattern: r"^[a-z0-9-]+$".to_string(),
+			..Default::default()
+		};
+		assert!(limits.validate_name(&"a".repeat(30)).is_ok());
+		assert!(limits.validate_name("My-Machine").is_err());
+	}
+
+	#[test]
+	fn test_fixes_invalid_name_pattern() {
+		let mut limits = ServiceLimits {
+			name_pattern: r"^[a-z+$".to_string(),
+			..Default::default()
+		};
+		assert!(limits.fix_name_pattern().is_err());
+		assert_eq!(limits.name_pattern, DEFAULT_NAME_PATTERN);
+		assert!(limits.fix_name_pattern().is_ok());
+		assert!(limits.validate_name("my-machine").is_ok());
+	}
+
+	#[test]
+	fn test_parses_partial_limits() {
+		let limits: ServiceLimits = serde_json::from_str(r#"{"machineQuota":5}"#).unwrap();
+		assert_eq!(limits.machine_quota, 5);
+		assert_eq!(limits.max_name_length, 20);
+		assert!(limits.quota_exceeded_message().contains("5 machine limit"));
+	}
+
+	#[test]
+	fn test_cache_expires() {
+		let mut cached = CachedServiceLimits::new(ServiceLimits::default());
+		assert!(cached.is_fresh());
+		cached.fetched_at -= LIMITS_CACHE_DURATION.as_secs();
+		assert!(!cached.is_fresh());
+	}
+}
@@ -0,0 +1,192 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	ffi::OsStr,
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use crate::{
+	log,
+	state::LauncherPaths,
+	util::errors::{wrap, AnyError, ServiceCommandFailed},
+};
+
+use super::service::{run_until_terminated, ServiceContainer, ServiceManager};
+
+/// Runs the tunnel as a systemd user service. Its output is kept in the
+/// journal, and lingering is enabled so it starts at boot rather than when
+/// the user logs in.
+pub struct SystemdService {
+	log: log::Logger,
+}
+
+const SERVICE_NAME: &str = "code-tunnel.service";
+
+impl SystemdService {
+	pub fn new(log: log::Logger) -> Self {
+		Self { log }
+	}
+
+	fn unit_path() -> Result<PathBuf, AnyError> {
+		let config = dirs::config_dir().ok_or_else(|| {
+			ServiceCommandFailed("could not find the user's configuration directory".to_string())
+		})?;
+		Ok(config.join("systemd").join("user").join(SERVICE_NAME))
+	}
+}
+
+impl ServiceManager for SystemdService {
+	fn register(&self, exe: PathBuf, args: &[&str]) -> Result<(), AnyError> {
+		let unit_path = Self::unit_path()?;
+		if let Some(dir) = unit_path.parent() {
+			std::fs::create_dir_all(dir)
+				.map_err(|e| wrap(e, format!("error creating {}", dir.display())))?;
+		}
+		std::fs::write(&unit_path, render_unit(&exe, args))
+			.map_err(|e| wrap(e, format!("error writing {}", unit_path.display())))?;
+
+		systemctl(&["daemon-reload"])?;
+		systemctl(&["enable", SERVICE_NAME])?;
+		// restarted so a re-installed service picks up the new unit
+		systemctl(&["restart", SERVICE_NAME])?;
+		info!(self.log, "Successfully registered service...");
+
+		// without lingering, user services only run while the user is logged in
+		if let Err(e) = run("loginctl", &["enable-linger"]) {
+			warning!(
+				self.log,
+				"The service will only run while you're logged in, as lingering could not be enabled: {}",
+				e
+			);
+		}
+
+		info!(self.log, "Tunnel service successfully started");
+		Ok(())
+	}
+
+	fn run(
+		&self,
+		launcher_paths: LauncherPaths,
+		handle: impl 'static + ServiceContainer,
+	) -> Result<(), AnyError> {
+		run_until_terminated(self.log.clone(), launcher_paths, handle)
+	}
+
+	fn unregister(&self) -> Result<(), AnyError> {
+		let unit_path = Self::unit_path()?;
+		if !unit_path.exists() {
+			return Ok(());
+		}
+
+		systemctl(&["disable", "--now", SERVICE_NAME])?;
+		std::fs::remove_file(&unit_path)
+			.map_err(|e| wrap(e, format!("error removing {}", unit_path.display())))?;
+		systemctl(&["daemon-reload"])?;
+
+		info!(self.log, "Successfully unregistered service");
+		Ok(())
+	}
+
+	fn show_logs(&self, _launcher_paths: &LauncherPaths) -> Result<(), AnyError> {
+		// log lines already have timestamps, so print them as they are
+		run(
+			"journalctl",
+			&[
+				"--user",
+				"--unit",
+				SERVICE_NAME,
+				"--output",
+				"cat",
+				"--follow",
+			],
+		)
+	}
+}
+
+fn systemctl(args: &[&str]) -> Result<(), AnyError> {
+	run("systemctl", &[&["--user"], args].concat())
+}
+
+fn run(command: &str, args: &[&str]) -> Result<(), AnyError> {
+	let status = Command::new(command)
+		.args(args)
+		.status()
+		.map_err(|e| wrap(e, format!("error running {}", command)))?;
+	if !status.success() {
+		return Err(ServiceCommandFailed(format!(
+			"`{} {}` failed with {}",
+			command,
+			args.join(" "),
+			status
+		))
+		.into());
+	}
+
+	Ok(())
+}
+
+/// Creates the unit that runs the executable with the arguments.
+fn render_unit(exe: &Path, args: &[&str]) -> String {
+	let command = std::iter::once(exe.as_os_str())
+		.chain(args.iter().map(OsStr::new))
+		.map(|a| quote_exec_arg(&a.to_string_lossy()))
+		.collect::<Vec<_>>()
+		.join(" ");
+
+	format!(
+		"[Unit]
+Description=Visual Studio Code Tunnel
+StartLimitIntervalSec=0
+
+[Service]
+Type=simple
+ExecStart={}
+Restart=always
+RestartSec=10
+NotifyAccess=main
+
+[Install]
+WantedBy=default.target
+",
+		command
+	)
+}
+
+/// Quotes an argument of `ExecStart`, where specifiers start with '%' and
+/// environment variables are expanded from '$'.
+fn quote_exec_arg(arg: &str) -> String {
+	format!(
+		"\"{}\"",
+		arg.replace('\\', "\\\\")
+			.replace('"', "\\\"")
+			.replace('%', "%%")
+			.replace('$', "$$")
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render_unit() {
+		let unit = render_unit(
+			Path::new("/opt/my code/code"),
+			&[
+				"--cli-data-dir",
+				"/home/me/100%\"",
+				"tunnel",
+				"--name",
+				"$HOME",
+			],
+		);
+		assert!(unit.contains(
+			"ExecStart=\"/opt/my code/code\" \"--cli-data-dir\" \"/home/me/100%%\\\"\" \"tunnel\" \"--name\" \"$$HOME\"\n"
+		));
+		assert!(unit.contains("WantedBy=default.target"));
+	}
+}
@@ -0,0 +1,169 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	ffi::OsStr,
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use crate::{
+	log,
+	state::LauncherPaths,
+	util::errors::{wrap, AnyError, ServiceCommandFailed},
+};
+
+use super::service::{
+	follow_service_log_file, run_until_terminated, tee_service_log_file, ServiceContainer,
+	ServiceManager,
+};
+
+/// Runs the tunnel as a launchd agent of the user, started when they log in
+/// and restarted if it exits. Its logs are written to a file in the data
+/// directory.
+pub struct LaunchdService {
+	log: log::Logger,
+}
+
+const SERVICE_LABEL: &str = "com.visualstudio.code.tunnel";
+
+impl LaunchdService {
+	pub fn new(log: log::Logger) -> Self {
+		Self { log }
+	}
+
+	fn plist_path() -> Result<PathBuf, AnyError> {
+		let home = dirs::home_dir().ok_or_else(|| {
+			ServiceCommandFailed("could not find the user's home directory".to_string())
+		})?;
+		Ok(home
+			.join("Library")
+			.join("LaunchAgents")
+			.join(format!("{}.plist", SERVICE_LABEL)))
+	}
+}
+
+impl ServiceManager for LaunchdService {
+	fn register(&self, exe: PathBuf, args: &[&str]) -> Result<(), AnyError> {
+		let plist_path = Self::plist_path()?;
+		if plist_path.exists() {
+			// unloaded so a re-installed service picks up the new definition
+			launchctl(&["unload", "-w"], &plist_path).ok();
+		} else if let Some(dir) = plist_path.parent() {
+			std::fs::create_dir_all(dir)
+				.map_err(|e| wrap(e, format!("error creating {}", dir.display())))?;
+		}
+
+		std::fs::write(&plist_path, render_plist(&exe, args))
+			.map_err(|e| wrap(e, format!("error writing {}", plist_path.display())))?;
+		info!(self.log, "Successfully registered service...");
+
+		launchctl(&["load", "-w"], &plist_path)?;
+		info!(self.log, "Tunnel service successfully started");
+		Ok(())
+	}
+
+	fn run(
+		&self,
+		launcher_paths: LauncherPaths,
+		handle: impl 'static + ServiceContainer,
+	) -> Result<(), AnyError> {
+		let log = tee_service_log_file(&self.log, &launcher_paths);
+		run_until_terminated(log, launcher_paths, handle)
+	}
+
+	fn unregister(&self) -> Result<(), AnyError> {
+		let plist_path = Self::plist_path()?;
+		if !plist_path.exists() {
+			return Ok(());
+		}
+
+		launchctl(&["unload", "-w"], &plist_path)?;
+		std::fs::remove_file(&plist_path)
+			.map_err(|e| wrap(e, format!("error removing {}", plist_path.display())))?;
+
+		info!(self.log, "Successfully unregistered service");
+		Ok(())
+	}
+
+	fn show_logs(&self, launcher_paths: &LauncherPaths) -> Result<(), AnyError> {
+		follow_service_log_file(launcher_paths)
+	}
+}
+
+fn launchctl(args: &[&str], plist_path: &Path) -> Result<(), AnyError> {
+	let status = Command::new("launchctl")
+		.args(args)
+		.arg(plist_path)
+		.status()
+		.map_err(|e| wrap(e, "error running launchctl"))?;
+	if !status.success() {
+		return Err(ServiceCommandFailed(format!(
+			"`launchctl {} {}` failed with {}",
+			args.join(" "),
+			plist_path.display(),
+			status
+		))
+		.into());
+	}
+
+	Ok(())
+}
+
+/// Creates the property list of the agent that runs the executable with the
+/// arguments.
+fn render_plist(exe: &Path, args: &[&str]) -> String {
+	let arguments = std::iter::once(exe.as_os_str())
+		.chain(args.iter().map(OsStr::new))
+		.map(|a| {
+			format!(
+				"\t\t<string>{}</string>\n",
+				escape_xml(&a.to_string_lossy())
+			)
+		})
+		.collect::<String>();
+
+	format!(
+		r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Label</key>
+	<string>{}</string>
+	<key>ProgramArguments</key>
+	<array>
+{}	</array>
+	<key>RunAtLoad</key>
+	<true/>
+	<key>KeepAlive</key>
+	<true/>
+	<key>ProcessType</key>
+	<string>Background</string>
+</dict>
+</plist>
+"#,
+		SERVICE_LABEL, arguments
+	)
+}
+
+fn escape_xml(s: &str) -> String {
+	s.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render_plist() {
+		let plist = render_plist(Path::new("/Applications/Code.app/code"), &["a&b", "tunnel"]);
+		assert!(plist.contains(
+			"\t<array>\n\t\t<string>/Applications/Code.app/code</string>\n\t\t<string>a&amp;b</string>\n\t\t<string>tunnel</string>\n\t</array>"
+		));
+	}
+}
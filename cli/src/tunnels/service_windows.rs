@@ -22,13 +22,11 @@ use crate::{
 	commands::tunnels::ShutdownSignal,
 	util::errors::{wrap, AnyError, WindowsNeedsElevation},
 };
-use crate::{
-	log::{self, FileLogSink},
-	state::LauncherPaths,
-};
+use crate::{log, state::LauncherPaths};
 
 use super::service::{
-	ServiceContainer, ServiceManager as CliServiceManager, SERVICE_LOG_FILE_NAME,
+	follow_service_log_file, tee_service_log_file, ServiceContainer,
+	ServiceManager as CliServiceManager,
 };
 
 pub struct WindowsService {
@@ -123,16 +121,7 @@ impl CliServiceManager for WindowsService {
 		launcher_paths: LauncherPaths,
 		handle: impl 'static + ServiceContainer,
 	) -> Result<(), AnyError> {
-		let log = match FileLogSink::new(
-			log::Level::Debug,
-			&launcher_paths.root().join(SERVICE_LOG_FILE_NAME),
-		) {
-			Ok(sink) => self.log.tee(sink),
-			Err(e) => {
-				warning!(self.log, "Failed to create service log file: {}", e);
-				self.log.clone()
-			}
-		};
+		let log = tee_service_log_file(&self.log, &launcher_paths);
 
 		// We put the handle into the global "impl" type and then take it out in
 		// my_service_main. This is needed just since we have to have that
@@ -189,6 +178,10 @@ impl CliServiceManager for WindowsService {
 
 		Ok(())
 	}
+
+	fn show_logs(&self, launcher_paths: &LauncherPaths) -> Result<(), AnyError> {
+		follow_service_log_file(launcher_paths)
+	}
 }
 
 struct ServiceImpl {
@@ -0,0 +1,106 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Defaults chosen in `code tunnel setup`, which apply when the tunnel is
+//! served without the equivalent flags, including when it's run as a service.
+//! Organizations can also deploy the file with settings that setup doesn't
+//! ask about, like the metadata sent with requests to the tunnel service.
+
+use serde::{Deserialize, Serialize};
+
+use super::code_server::ExtensionsGallery;
+use super::extension_filter::ExtensionFilter;
+use super::tunnel_service::RequestMetadata;
+use crate::options::TelemetryLevel;
+use crate::state::{LauncherPaths, PersistedState};
+use crate::util::errors::WrappedError;
+
+const SETUP_FILE_NAME: &str = "tunnel_setup.json";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelSetup {
+	/// Ports declared when no `--declare-port` is given, in the same format.
+	#[serde(default)]
+	pub declared_ports: Vec<String>,
+	/// Telemetry level of the server when no `--telemetry-level` is given.
+	#[serde(default)]
+	pub telemetry_level: Option<TelemetryLevel>,
+	/// Filters the extensions installed as the server starts when no
+	/// `--allow-initial-extension` or `--deny-initial-extension` is given.
+	#[serde(default, alias = "extension_policy")]
+	pub extension_filter: ExtensionFilter,
+	/// Extension gallery for the server when no `--extensions-gallery-url` is
+	/// given, such as for a service, which isn't given flags.
+	#[serde(default)]
+	pub extensions_gallery: Option<ExtensionsGallery>,
+	/// Hosts, or hosts and ports, that forwarded ports may go to other than
+	/// loopback, when no `--forward-allow` is given.
+	#[serde(default)]
+	pub forward_allowlist: Vec<String>,
+	/// Metadata added to requests to the tunnel service by every command.
+	#[serde(default)]
+	pub request_metadata: RequestMetadata,
+}
+
+impl TunnelSetup {
+	fn state(paths: &LauncherPaths) -> PersistedState<TunnelSetup> {
+		PersistedState::new(paths.root().join(SETUP_FILE_NAME))
+	}
+
+	/// Loads the saved setup, or the defaults if setup was never run.
+	pub fn load(paths: &LauncherPaths) -> Self {
+		Self::state(paths).load()
+	}
+
+	pub fn save(self, paths: &LauncherPaths) -> Result<(), WrappedError> {
+		Self::state(paths).save(self)
+	}
+
+	/// Gets the forwarding allowlist, given the entries passed as flags.
+	pub fn forward_allowlist<'a>(&'a self, flags: &'a [String]) -> &'a [String] {
+		if flags.is_empty() {
+			&self.forward_allowlist
+		} else {
+			flags
+		}
+	}
+
+	/// Gets the ports to declare, given those passed as flags.
+	pub fn declared_ports<'a>(&'a self, flags: &'a [String]) -> &'a [String] {
+		if flags.is_empty() {
+			&self.declared_ports
+		} else {
+			flags
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_saves_setup_defaults() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		assert_eq!(TunnelSetup::load(&paths), TunnelSetup::default());
+
+		TunnelSetup {
+			declared_ports: vec!["3000".to_string()],
+			telemetry_level: Some(TelemetryLevel::Error),
+			..Default::default()
+		}
+		.save(&paths)
+		.unwrap();
+
+		let setup = TunnelSetup::load(&paths);
+		assert_eq!(setup.telemetry_level, Some(TelemetryLevel::Error));
+		assert_eq!(setup.declared_ports(&[]), ["3000".to_string()]);
+
+		// flags take precedence over what setup saved
+		let flags = ["8080".to_string()];
+		assert_eq!(setup.declared_ports(&flags), flags);
+	}
+}
@@ -0,0 +1,1005 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs::{hard_link, read_to_string, remove_file, write},
+	io::ErrorKind,
+	path::{Path, PathBuf},
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf},
+	sync::{broadcast::error::RecvError, mpsc},
+	time::{interval, sleep, MissedTickBehavior},
+};
+
+use crate::{
+	commands::tunnels::ShutdownSignal,
+	log::{self, BroadcastFrame, BroadcastLogSink, Level},
+	state::LauncherPaths,
+	tunnels::{
+		port_access::PortPrivacy,
+		status::{StatusSink, TunnelStatus},
+		PortForwarding,
+	},
+	util::{
+		async_pipe::{
+			cleanup_socket, get_socket_name, get_socket_rw_stream, listen_socket_rw_stream,
+			AsyncPipe, AsyncPipeListener,
+		},
+		errors::{
+			wrap, AnyError, NoRunningTunnel, PortForwardingFailed, SingletonTakeoverFailed,
+			TunnelAlreadyRunning, TunnelRestartFailed,
+		},
+		machine::process_exists,
+	},
+};
+
+const SINGLETON_LOCK_FILE: &str = "tunnel.lock";
+/// Plain process ID of the running tunnel, for external supervisors.
+const PID_FILE: &str = "tunnel.pid";
+/// `InstanceMetadata` of the running tunnel, as JSON.
+const METADATA_FILE: &str = "tunnel-instance.json";
+
+/// How long to wait for a running tunnel to shut down during a takeover.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Contents of the lock file, used to find the process holding the lock.
+#[derive(Serialize, Deserialize)]
+struct LockFileContents {
+	pid: u32,
+	socket_path: PathBuf,
+}
+
+/// Information about the running tunnel, written to the data directory so that
+/// scripts and supervisors can locate and manage it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceMetadata {
+	pub pid: u32,
+	pub started_at: DateTime<Utc>,
+	pub socket_path: PathBuf,
+	pub tunnel_name: String,
+}
+
+/// Request sent by a client over the singleton socket, as a line of JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "camelCase")]
+enum SingletonRequest {
+	Shutdown,
+	/// Streams log and state frames until the tunnel shuts down.
+	Attach,
+	/// Gets a snapshot of the tunnel's status.
+	Status,
+	/// Forwards a port on the tunnel, responding with its URI.
+	ForwardPort {
+		port: u16,
+		privacy: Option<PortPrivacy>,
+	},
+	/// Stops forwarding a port on the tunnel.
+	UnforwardPort {
+		port: u16,
+	},
+	/// Hands the tunnel over to a new process, started from `exe` if it's
+	/// given.
+	Restart {
+		exe: Option<PathBuf>,
+	},
+	/// Takes the tunnel off the relay, refusing clients until it's resumed.
+	Pause,
+	/// Puts a paused tunnel back on the relay.
+	Resume,
+	/// Tells the tunnel it was renamed, such as with `code tunnel rename`.
+	Renamed {
+		name: String,
+	},
+}
+
+/// Response sent by the singleton to a client, as a line of JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum SingletonResponse {
+	ShutdownAck,
+	Status { status: Box<TunnelStatus> },
+	PortForwarded { uri: String },
+	PortUnforwarded,
+	PortError { message: String },
+	RestartAck,
+	PauseAck,
+	ResumeAck,
+	RenamedAck,
+}
+
+/// Held while this process is the running tunnel for the data directory.
+/// The lock file and socket are removed when it's dropped.
+pub struct SingletonLock {
+	lock_file: PathBuf,
+	socket_path: PathBuf,
+	listener: Option<AsyncPipeListener>,
+	started_at: DateTime<Utc>,
+	wrote_pid: bool,
+}
+
+impl SingletonLock {
+	/// Writes the instance metadata file for the tunnel once it's hosted. It's
+	/// removed, along with the PID file, when the lock is dropped.
+	pub fn write_metadata(&mut self, tunnel_name: &str) -> Result<(), AnyError> {
+		let metadata = InstanceMetadata {
+			pid: std::process::id(),
+			started_at: self.started_at,
+			socket_path: self.socket_path.clone(),
+			tunnel_name: tunnel_name.to_string(),
+		};
+
+		let path = self.lock_file.parent().unwrap().join(METADATA_FILE);
+		write(&path, serde_json::to_string(&metadata).unwrap())
+			.map_err(|e| wrap(e, format!("error writing {}", path.display())))?;
+
+		Ok(())
+	}
+
+	/// Starts handling requests from other CLI instances, such as a shutdown
+	/// request from `code tunnel --force`, `code tunnel attach`,
+	/// `code tunnel status`, `code tunnel watch`, `code tunnel forward`, or
+	/// `code tunnel restart`.
+	pub fn serve(
+		&mut self,
+		log: log::Logger,
+		log_broadcast: BroadcastLogSink,
+		status: StatusSink,
+		port_forwarding: PortForwarding,
+		shutdown_tx: mpsc::Sender<ShutdownSignal>,
+	) {
+		let mut listener = match self.listener.take() {
+			Some(l) => l,
+			None => return,
+		};
+		let root = self.lock_file.parent().unwrap().to_owned();
+
+		tokio::spawn(async move {
+			loop {
+				let pipe = match listener.accept().await {
+					Ok(p) => p,
+					Err(e) => {
+						warning!(log, "error on singleton socket: {}", e);
+						return;
+					}
+				};
+
+				let log = log.clone();
+				let log_broadcast = log_broadcast.clone();
+				let status = status.clone();
+				let port_forwarding = port_forwarding.clone();
+				let shutdown_tx = shutdown_tx.clone();
+				let root = root.clone();
+				tokio::spawn(async move {
+					if let Err(e) = handle_singleton_client(
+						pipe,
+						&root,
+						log_broadcast,
+						status,
+						port_forwarding,
+						shutdown_tx,
+					)
+					.await
+					{
+						debug!(log, "error handling singleton client: {}", e);
+					}
+				});
+			}
+		});
+	}
+}
+
+impl Drop for SingletonLock {
+	fn drop(&mut self) {
+		// metadata goes first, so a process taking over can't see ours as its own
+		if self.wrote_pid {
+			let root = self.lock_file.parent().unwrap();
+			remove_file(root.join(PID_FILE)).ok();
+			remove_file(root.join(METADATA_FILE)).ok();
+		}
+
+		// only remove the lock if it wasn't stolen by another process in the meantime
+		if read_lock_file(&self.lock_file).map(|c| c.pid) == Some(std::process::id()) {
+			remove_file(&self.lock_file).ok();
+		}
+
+		cleanup_socket(&self.socket_path).ok();
+	}
+}
+
+async fn handle_singleton_client(
+	pipe: AsyncPipe,
+	root: &Path,
+	log_broadcast: BroadcastLogSink,
+	status: StatusSink,
+	port_forwarding: PortForwarding,
+	shutdown_tx: mpsc::Sender<ShutdownSignal>,
+) -> Result<(), AnyError> {
+	let (read, mut write) = tokio::io::split(pipe);
+	let mut lines = BufReader::new(read).lines();
+	while let Some(line) = lines
+		.next_line()
+		.await
+		.map_err(|e| wrap(e, "error reading singleton request"))?
+	{
+		let req: SingletonRequest =
+			serde_json::from_str(&line).map_err(|e| wrap(e, "invalid singleton request"))?;
+
+		match req {
+			SingletonRequest::Shutdown => {
+				write_line(&mut write, &SingletonResponse::ShutdownAck).await?;
+				shutdown_tx
+					.send(ShutdownSignal::ShutdownRequested)
+					.await
+					.ok();
+			}
+			SingletonRequest::Restart { exe } => {
+				write_line(&mut write, &SingletonResponse::RestartAck).await?;
+				shutdown_tx
+					.send(ShutdownSignal::RestartRequested(exe))
+					.await
+					.ok();
+			}
+			SingletonRequest::Pause => {
+				write_line(&mut write, &SingletonResponse::PauseAck).await?;
+				shutdown_tx.send(ShutdownSignal::PauseRequested).await.ok();
+			}
+			SingletonRequest::Resume => {
+				write_line(&mut write, &SingletonResponse::ResumeAck).await?;
+				shutdown_tx.send(ShutdownSignal::ResumeRequested).await.ok();
+			}
+			SingletonRequest::Renamed { name } => {
+				rename_in_metadata(root, &name);
+				write_line(&mut write, &SingletonResponse::RenamedAck).await?;
+				shutdown_tx.send(ShutdownSignal::Renamed(name)).await.ok();
+			}
+			SingletonRequest::Status => {
+				let status = Box::new(status.snapshot());
+				write_line(&mut write, &SingletonResponse::Status { status }).await?;
+			}
+			SingletonRequest::ForwardPort { port, privacy } => {
+				let res = match port_forwarding.forward(port, privacy).await {
+					Ok(uri) => SingletonResponse::PortForwarded { uri },
+					Err(e) => SingletonResponse::PortError {
+						message: e.to_string(),
+					},
+				};
+				write_line(&mut write, &res).await?;
+			}
+			SingletonRequest::UnforwardPort { port } => {
+				let res = match port_forwarding.unforward(port).await {
+					Ok(()) => SingletonResponse::PortUnforwarded,
+					Err(e) => SingletonResponse::PortError {
+						message: e.to_string(),
+					},
+				};
+				write_line(&mut write, &res).await?;
+			}
+			SingletonRequest::Attach => {
+				let (state, mut rx) = log_broadcast.subscribe();
+				if let Some(state) = state {
+					write_frame(&mut write, state).await?;
+				}
+
+				loop {
+					match rx.recv().await {
+						Ok(frame) => write_frame(&mut write, frame).await?,
+						Err(RecvError::Lagged(_)) => continue,
+						Err(RecvError::Closed) => return Ok(()),
+					}
+				}
+			}
+		}
+	}
+
+	Ok(())
+}
+
+async fn write_frame<W>(write: &mut W, mut frame: String) -> Result<(), AnyError>
+where
+	W: AsyncWriteExt + Unpin,
+{
+	frame.push('\n');
+	write
+		.write_all(frame.as_bytes())
+		.await
+		.map_err(|e| wrap(e, "error writing to singleton socket"))?;
+	Ok(())
+}
+
+async fn write_line<W, T>(write: &mut W, value: &T) -> Result<(), AnyError>
+where
+	W: AsyncWriteExt + Unpin,
+	T: Serialize,
+{
+	write_frame(write, serde_json::to_string(value).unwrap()).await
+}
+
+fn read_lock_file(path: &Path) -> Option<LockFileContents> {
+	read_to_string(path)
+		.ok()
+		.and_then(|s| serde_json::from_str(&s).ok())
+}
+
+/// Either the acquired lock, or information about the process that holds it.
+enum Acquisition {
+	Acquired(SingletonLock),
+	Held(LockFileContents),
+}
+
+fn try_acquire(paths: &LauncherPaths) -> Result<Acquisition, AnyError> {
+	let lock_file = paths.root().join(SINGLETON_LOCK_FILE);
+	let socket_path = get_socket_name();
+	let listener = listen_socket_rw_stream(&socket_path)?;
+	let contents = LockFileContents {
+		pid: std::process::id(),
+		socket_path: socket_path.clone(),
+	};
+
+	// Write the contents aside and hard link them into place, so that the lock
+	// file is created atomically and is never observed while half-written.
+	let staging = paths
+		.root()
+		.join(format!("{}.{}", SINGLETON_LOCK_FILE, contents.pid));
+	write(&staging, serde_json::to_string(&contents).unwrap())
+		.map_err(|e| wrap(e, format!("error writing {}", staging.display())))?;
+
+	let mut attempts = 0;
+	let linked = loop {
+		attempts += 1;
+		match hard_link(&staging, &lock_file) {
+			Ok(_) => break Ok(()),
+			Err(e) if e.kind() == ErrorKind::AlreadyExists && attempts < 3 => {
+				match read_lock_file(&lock_file) {
+					Some(c) if process_exists(c.pid) => break Err(c),
+					// the owner is gone, or was killed mid-write; the lock is stale
+					_ => {
+						remove_file(&lock_file).ok();
+					}
+				}
+			}
+			Err(e) => {
+				remove_file(&staging).ok();
+				return Err(wrap(e, format!("error creating {}", lock_file.display())).into());
+			}
+		}
+	};
+
+	remove_file(&staging).ok();
+
+	match linked {
+		Ok(_) => Ok(Acquisition::Acquired(SingletonLock {
+			lock_file,
+			socket_path,
+			listener: Some(listener),
+			started_at: Utc::now(),
+			wrote_pid: false,
+		})),
+		Err(c) => {
+			drop(listener);
+			cleanup_socket(&socket_path).ok();
+			Ok(Acquisition::Held(c))
+		}
+	}
+}
+
+async fn request_shutdown(socket_path: &Path) -> Result<(), AnyError> {
+	let pipe = get_socket_rw_stream(socket_path).await?;
+	let (read, mut write) = tokio::io::split(pipe);
+	write_line(&mut write, &SingletonRequest::Shutdown).await?;
+
+	let mut lines = BufReader::new(read).lines();
+	while let Some(line) = lines
+		.next_line()
+		.await
+		.map_err(|e| wrap(e, "error reading from singleton socket"))?
+	{
+		if let Ok(SingletonResponse::ShutdownAck) = serde_json::from_str(&line) {
+			return Ok(());
+		}
+	}
+
+	Err(
+		SingletonTakeoverFailed("the running tunnel did not acknowledge shutdown".to_string())
+			.into(),
+	)
+}
+
+/// Reads the metadata of the tunnel running for the data directory. Returns
+/// None if no tunnel is running, or if the metadata is stale.
+pub fn read_instance_metadata(paths: &LauncherPaths) -> Option<InstanceMetadata> {
+	read_to_string(paths.root().join(METADATA_FILE))
+		.ok()
+		.and_then(|s| serde_json::from_str::<InstanceMetadata>(&s).ok())
+		.filter(|m| process_exists(m.pid))
+}
+
+/// Updates the tunnel name in the instance metadata in the directory, if it
+/// was written.
+fn rename_in_metadata(root: &Path, name: &str) {
+	let path = root.join(METADATA_FILE);
+	let metadata = read_to_string(&path)
+		.ok()
+		.and_then(|s| serde_json::from_str::<InstanceMetadata>(&s).ok());
+	if let Some(mut metadata) = metadata {
+		metadata.tunnel_name = name.to_string();
+		write(&path, serde_json::to_string(&metadata).unwrap()).ok();
+	}
+}
+
+/// Writes the process ID of the tunnel to the PID file in the data directory.
+/// `code tunnel --detach` writes it for the background process as soon as it's
+/// started, and the tunnel writes it again once it holds the singleton lock.
+pub fn write_pid_file(paths: &LauncherPaths, pid: u32) -> Result<(), AnyError> {
+	let path = paths.root().join(PID_FILE);
+	write(&path, pid.to_string())
+		.map_err(|e| wrap(e, format!("error writing {}", path.display())))?;
+	Ok(())
+}
+
+/// Removes PID and metadata files left behind by a tunnel that didn't exit
+/// cleanly. A PID file naming this process was written for it by
+/// `code tunnel --detach`, and is kept. Must only be called while holding the
+/// singleton lock.
+fn remove_stale_metadata(log: &log::Logger, paths: &LauncherPaths) {
+	let pid_file = paths.root().join(PID_FILE);
+	let metadata_file = paths.root().join(METADATA_FILE);
+	let pid = read_to_string(&pid_file).ok();
+	let own_pid = pid
+		.as_ref()
+		.map(|p| p.trim() == std::process::id().to_string())
+		.unwrap_or(false);
+	if (own_pid || !pid_file.exists()) && !metadata_file.exists() {
+		return;
+	}
+
+	match pid {
+		Some(pid) if !own_pid => info!(
+			log,
+			"Removing stale instance files from process {}",
+			pid.trim()
+		),
+		_ => info!(log, "Removing stale instance files"),
+	}
+
+	if !own_pid {
+		remove_file(pid_file).ok();
+	}
+	remove_file(metadata_file).ok();
+}
+
+/// Acquires the singleton lock for the data directory. If another tunnel holds
+/// it and `force` is set, that tunnel is asked to shut down, and the lock is
+/// taken once it has closed its relay connection and exited.
+pub async fn acquire_singleton(
+	log: &log::Logger,
+	paths: &LauncherPaths,
+	force: bool,
+) -> Result<SingletonLock, AnyError> {
+	let mut lock = acquire_lock(log, paths, force).await?;
+	remove_stale_metadata(log, paths);
+	lock.wrote_pid = true;
+	write_pid_file(paths, std::process::id())?;
+	Ok(lock)
+}
+
+async fn acquire_lock(
+	log: &log::Logger,
+	paths: &LauncherPaths,
+	force: bool,
+) -> Result<SingletonLock, AnyError> {
+	let other = match try_acquire(paths)? {
+		Acquisition::Acquired(lock) => return Ok(lock),
+		Acquisition::Held(other) if !force => return Err(TunnelAlreadyRunning(other.pid).into()),
+		Acquisition::Held(other) => other,
+	};
+
+	info!(
+		log,
+		"Asking the running tunnel (process {}) to shut down...", other.pid
+	);
+	request_shutdown(&other.socket_path).await?;
+
+	let started = Instant::now();
+	loop {
+		sleep(Duration::from_millis(250)).await;
+
+		match try_acquire(paths)? {
+			Acquisition::Acquired(lock) => {
+				info!(log, "Took over from process {}", other.pid);
+				return Ok(lock);
+			}
+			Acquisition::Held(c) if c.pid != other.pid => {
+				return Err(TunnelAlreadyRunning(c.pid).into());
+			}
+			Acquisition::Held(_) if started.elapsed() > TAKEOVER_TIMEOUT => {
+				return Err(SingletonTakeoverFailed(format!(
+					"process {} did not shut down within {}s",
+					other.pid,
+					TAKEOVER_TIMEOUT.as_secs()
+				))
+				.into());
+			}
+			Acquisition::Held(_) => {}
+		}
+	}
+}
+
+/// Gets the process ID of the tunnel running for the data directory, if any.
+pub fn running_pid(paths: &LauncherPaths) -> Option<u32> {
+	read_lock_file(&paths.root().join(SINGLETON_LOCK_FILE))
+		.map(|c| c.pid)
+		.filter(|pid| process_exists(*pid))
+}
+
+/// Stream of frames from a running tunnel, one JSON object per line.
+struct AttachedStream {
+	pid: u32,
+	lines: Lines<BufReader<ReadHalf<AsyncPipe>>>,
+}
+
+impl AttachedStream {
+	async fn next_frame(&mut self) -> Result<Option<BroadcastFrame>, AnyError> {
+		while let Some(line) = self
+			.lines
+			.next_line()
+			.await
+			.map_err(|e| wrap(e, "error reading from singleton socket"))?
+		{
+			if let Ok(frame) = serde_json::from_str(&line) {
+				return Ok(Some(frame));
+			}
+		}
+
+		Ok(None)
+	}
+}
+
+/// Connects to the socket of the tunnel running for the data directory.
+async fn connect_running(
+	paths: &LauncherPaths,
+) -> Result<(LockFileContents, ReadHalf<AsyncPipe>, WriteHalf<AsyncPipe>), AnyError> {
+	let running = match read_lock_file(&paths.root().join(SINGLETON_LOCK_FILE)) {
+		Some(c) if process_exists(c.pid) => c,
+		_ => return Err(NoRunningTunnel().into()),
+	};
+
+	let pipe = get_socket_rw_stream(&running.socket_path).await?;
+	let (read, write) = tokio::io::split(pipe);
+	Ok((running, read, write))
+}
+
+async fn connect_attached(paths: &LauncherPaths) -> Result<AttachedStream, AnyError> {
+	let (running, read, mut write) = connect_running(paths).await?;
+	write_line(&mut write, &SingletonRequest::Attach).await?;
+
+	Ok(AttachedStream {
+		pid: running.pid,
+		lines: BufReader::new(read).lines(),
+	})
+}
+
+/// Waits until the running tunnel is listening, returning its name and URI.
+pub async fn wait_for_listening(paths: &LauncherPaths) -> Result<(String, String), AnyError> {
+	let mut stream = connect_attached(paths).await?;
+	while let Some(frame) = stream.next_frame().await? {
+		if let BroadcastFrame::TunnelState {
+			name: Some(name),
+			uri: Some(uri),
+			..
+		} = frame
+		{
+			return Ok((name, uri));
+		}
+	}
+
+	Err(NoRunningTunnel().into())
+}
+
+/// Connects to the tunnel running for the data directory, and writes its log
+/// output and state changes to the given logger until it shuts down.
+pub async fn attach(log: &log::Logger, paths: &LauncherPaths) -> Result<(), AnyError> {
+	let mut stream = connect_attached(paths).await?;
+	log.result(format!(
+		"Attached to the tunnel running in process {}",
+		stream.pid
+	));
+
+	while let Some(frame) = stream.next_frame().await? {
+		match frame {
+			BroadcastFrame::Log { level, message } => {
+				if let Ok(level) = Level::from_str(&level, true) {
+					log.emit(level, &message);
+				}
+			}
+			BroadcastFrame::Result { message } => log.result(message),
+			BroadcastFrame::TunnelState { state, name, uri } => match (name, uri) {
+				(Some(name), Some(uri)) => info!(log, "Tunnel {} is {}: {}", name, state, uri),
+				(Some(name), None) => info!(log, "Tunnel {} is {}", name, state),
+				_ => info!(log, "Tunnel is {}", state),
+			},
+			BroadcastFrame::PortUri { port, uri } => {
+				info!(log, "Port {} is forwarded at {}", port, uri)
+			}
+			BroadcastFrame::Other => {}
+		}
+	}
+
+	log.result("The tunnel has shut down");
+	Ok(())
+}
+
+/// Status of the tunnel running in a process, at a point in time.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceStatus {
+	pub pid: u32,
+	pub timestamp: DateTime<Utc>,
+	#[serde(flatten)]
+	pub status: TunnelStatus,
+}
+
+/// Gets the status of the tunnel running for the data directory.
+pub async fn status(paths: &LauncherPaths) -> Result<InstanceStatus, AnyError> {
+	let (running, read, mut write) = connect_running(paths).await?;
+	write_line(&mut write, &SingletonRequest::Status).await?;
+
+	let mut lines = BufReader::new(read).lines();
+	while let Some(line) = lines
+		.next_line()
+		.await
+		.map_err(|e| wrap(e, "error reading from singleton socket"))?
+	{
+		if let Ok(SingletonResponse::Status { status }) = serde_json::from_str(&line) {
+			return Ok(InstanceStatus {
+				pid: running.pid,
+				timestamp: Utc::now(),
+				status: *status,
+			});
+		}
+	}
+
+	Err(NoRunningTunnel().into())
+}
+
+/// Forwards a port on the tunnel running for the data directory, and gets
+/// the URI it can be reached at. Without a privacy, the tunnel's default
+/// privacy for forwarded ports is used.
+pub async fn forward_port(
+	paths: &LauncherPaths,
+	port: u16,
+	privacy: Option<PortPrivacy>,
+) -> Result<String, AnyError> {
+	match request(paths, SingletonRequest::ForwardPort { port, privacy }).await? {
+		SingletonResponse::PortForwarded { uri } => Ok(uri),
+		_ => Err(NoRunningTunnel().into()),
+	}
+}
+
+/// Stops forwarding a port on the tunnel running for the data directory.
+pub async fn unforward_port(paths: &LauncherPaths, port: u16) -> Result<(), AnyError> {
+	match request(paths, SingletonRequest::UnforwardPort { port }).await? {
+		SingletonResponse::PortUnforwarded => Ok(()),
+		_ => Err(NoRunningTunnel().into()),
+	}
+}
+
+/// Asks the tunnel running for the data directory to hand itself over to a
+/// new process, started from `exe` or otherwise the binary it's running.
+/// Returns the ID of the process that was asked.
+pub async fn request_restart(paths: &LauncherPaths, exe: Option<PathBuf>) -> Result<u32, AnyError> {
+	let pid = running_pid(paths).ok_or(NoRunningTunnel())?;
+	match request(paths, SingletonRequest::Restart { exe }).await? {
+		SingletonResponse::RestartAck => Ok(pid),
+		_ => Err(NoRunningTunnel().into()),
+	}
+}
+
+/// Asks the tunnel running for the data directory to pause hosting. It's
+/// unregistered from the relay, but the process and its servers keep running.
+/// Returns the ID of the process that was asked.
+pub async fn request_pause(paths: &LauncherPaths) -> Result<u32, AnyError> {
+	let pid = running_pid(paths).ok_or(NoRunningTunnel())?;
+	match request(paths, SingletonRequest::Pause).await? {
+		SingletonResponse::PauseAck => Ok(pid),
+		_ => Err(NoRunningTunnel().into()),
+	}
+}
+
+/// Asks the tunnel running for the data directory to resume hosting after
+/// it was paused. Returns the ID of the process that was asked.
+pub async fn request_resume(paths: &LauncherPaths) -> Result<u32, AnyError> {
+	let pid = running_pid(paths).ok_or(NoRunningTunnel())?;
+	match request(paths, SingletonRequest::Resume).await? {
+		SingletonResponse::ResumeAck => Ok(pid),
+		_ => Err(NoRunningTunnel().into()),
+	}
+}
+
+/// Tells the tunnel running for the data directory that it was renamed, so
+/// it's known by the new name without restarting.
+pub async fn notify_renamed(paths: &LauncherPaths, name: &str) -> Result<(), AnyError> {
+	let name = name.to_string();
+	match request(paths, SingletonRequest::Renamed { name }).await? {
+		SingletonResponse::RenamedAck => Ok(()),
+		_ => Err(NoRunningTunnel().into()),
+	}
+}
+
+/// Waits for a process other than `pid` to take the singleton lock, such as
+/// the one a restarting tunnel hands over to, and returns its ID.
+pub async fn wait_for_takeover(
+	paths: &LauncherPaths,
+	pid: u32,
+	max_wait: Duration,
+) -> Result<u32, AnyError> {
+	let started = Instant::now();
+	loop {
+		match running_pid(paths) {
+			Some(p) if p != pid => return Ok(p),
+			_ if started.elapsed() > max_wait => {
+				return Err(TunnelRestartFailed(format!(
+					"no new process took over within {}s",
+					max_wait.as_secs()
+				))
+				.into());
+			}
+			_ => sleep(Duration::from_millis(250)).await,
+		}
+	}
+}
+
+/// Makes a request of the running tunnel and reads its response, mapping
+/// reported errors.
+async fn request(
+	paths: &LauncherPaths,
+	req: SingletonRequest,
+) -> Result<SingletonResponse, AnyError> {
+	let (_, read, mut write) = connect_running(paths).await?;
+	write_line(&mut write, &req).await?;
+
+	let mut lines = BufReader::new(read).lines();
+	while let Some(line) = lines
+		.next_line()
+		.await
+		.map_err(|e| wrap(e, "error reading from singleton socket"))?
+	{
+		match serde_json::from_str(&line) {
+			Ok(SingletonResponse::PortError { message }) => {
+				return Err(PortForwardingFailed(message).into())
+			}
+			Ok(res) => return Ok(res),
+			Err(_) => continue,
+		}
+	}
+
+	Err(NoRunningTunnel().into())
+}
+
+/// Connects to the tunnel running for the data directory, and calls
+/// `on_status` with its status once per `period` until it shuts down.
+pub async fn watch(
+	paths: &LauncherPaths,
+	period: Duration,
+	mut on_status: impl FnMut(InstanceStatus),
+) -> Result<(), AnyError> {
+	let (running, read, mut write) = connect_running(paths).await?;
+	let mut lines = BufReader::new(read).lines();
+	let mut ticks = interval(period);
+	ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+	loop {
+		ticks.tick().await;
+
+		// the tunnel closes the socket when it shuts down
+		if write_line(&mut write, &SingletonRequest::Status)
+			.await
+			.is_err()
+		{
+			return Ok(());
+		}
+
+		let status = loop {
+			let line = match lines.next_line().await {
+				Ok(Some(l)) => l,
+				Ok(None) | Err(_) => return Ok(()),
+			};
+
+			if let Ok(SingletonResponse::Status { status }) = serde_json::from_str(&line) {
+				break *status;
+			}
+		};
+
+		on_status(InstanceStatus {
+			pid: running.pid,
+			timestamp: Utc::now(),
+			status,
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		log::{ProgressFrame, TunnelProgressState},
+		tunnels::{port_forwarder::PortForwardingRec, PortForwardingProcessor},
+	};
+
+	/// Acquires the lock for the directory and serves requests on it.
+	async fn serve(
+		paths: &LauncherPaths,
+		log_broadcast: BroadcastLogSink,
+		forwarding: &PortForwardingProcessor,
+	) -> (SingletonLock, mpsc::Receiver<ShutdownSignal>) {
+		let log = log::Logger::test();
+		let mut lock = acquire_singleton(&log, paths, false).await.unwrap();
+		let (tx, rx) = mpsc::channel(4);
+		lock.serve(
+			log,
+			log_broadcast,
+			StatusSink::new(),
+			forwarding.handle(),
+			tx,
+		);
+		(lock, rx)
+	}
+
+	#[tokio::test]
+	async fn test_takes_over_with_force() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let log = log::Logger::test();
+		let forwarding = PortForwardingProcessor::new();
+		let (lock, mut rx) = serve(&paths, BroadcastLogSink::new(), &forwarding).await;
+		assert_eq!(running_pid(&paths), Some(std::process::id()));
+
+		match acquire_singleton(&log, &paths, false).await {
+			Err(AnyError::TunnelAlreadyRunning(e)) => assert_eq!(e.0, std::process::id()),
+			_ => panic!("expected the lock to be held"),
+		}
+
+		// the running tunnel releases the lock once it's asked to shut down
+		tokio::spawn(async move {
+			if let Some(ShutdownSignal::ShutdownRequested) = rx.recv().await {
+				drop(lock);
+			}
+		});
+
+		let lock = acquire_singleton(&log, &paths, true).await.unwrap();
+		assert_eq!(running_pid(&paths), Some(std::process::id()));
+		drop(lock);
+		assert_eq!(running_pid(&paths), None);
+	}
+
+	#[tokio::test]
+	async fn test_pauses_and_resumes_running_tunnel() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		assert!(matches!(
+			request_pause(&paths).await,
+			Err(AnyError::NoRunningTunnel(_))
+		));
+
+		let forwarding = PortForwardingProcessor::new();
+		let (_lock, mut rx) = serve(&paths, BroadcastLogSink::new(), &forwarding).await;
+
+		assert_eq!(request_pause(&paths).await.unwrap(), std::process::id());
+		assert!(matches!(
+			rx.recv().await,
+			Some(ShutdownSignal::PauseRequested)
+		));
+
+		assert_eq!(request_resume(&paths).await.unwrap(), std::process::id());
+		assert!(matches!(
+			rx.recv().await,
+			Some(ShutdownSignal::ResumeRequested)
+		));
+	}
+
+	#[tokio::test]
+	async fn test_forwards_ports_on_running_tunnel() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let mut forwarding = PortForwardingProcessor::new();
+		let (_lock, _rx) = serve(&paths, BroadcastLogSink::new(), &forwarding).await;
+
+		// stands in for the tunnel, which processes requests as they come
+		tokio::spawn(async move {
+			while let Some(req) = forwarding.recv().await {
+				match req {
+					PortForwardingRec::Forward(0, _, tx) => {
+						tx.send(Err(PortForwardingFailed("bad port".to_string()).into()))
+							.ok();
+					}
+					PortForwardingRec::Forward(port, _, tx) => {
+						tx.send(Ok(format!("https://{}.example.com", port))).ok();
+					}
+					PortForwardingRec::Unforward(_, tx) => {
+						tx.send(Ok(())).ok();
+					}
+				}
+			}
+		});
+
+		let uri = forward_port(&paths, 8080, Some(PortPrivacy::Public))
+			.await
+			.unwrap();
+		assert_eq!(uri, "https://8080.example.com");
+		unforward_port(&paths, 8080).await.unwrap();
+
+		match forward_port(&paths, 0, None).await {
+			Err(AnyError::PortForwardingFailed(e)) => assert!(e.0.contains("bad port")),
+			r => panic!("unexpected result {:?}", r),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_attaches_to_running_tunnel() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let forwarding = PortForwardingProcessor::new();
+		let sink = BroadcastLogSink::new();
+		let (_lock, _rx) = serve(&paths, sink.clone(), &forwarding).await;
+		let log = log::Logger::test().tee(sink);
+
+		log.progress(ProgressFrame::TunnelState {
+			state: TunnelProgressState::Listening,
+			name: Some("my-tunnel"),
+			uri: Some("https://example.com"),
+		});
+		assert_eq!(
+			wait_for_listening(&paths).await.unwrap(),
+			("my-tunnel".to_string(), "https://example.com".to_string())
+		);
+
+		// the last state is sent first, then anything logged after attaching
+		let mut stream = connect_attached(&paths).await.unwrap();
+		assert_eq!(stream.pid, std::process::id());
+		match stream.next_frame().await.unwrap() {
+			Some(BroadcastFrame::TunnelState { state, .. }) => assert_eq!(state, "listening"),
+			f => panic!("unexpected frame {:?}", f),
+		}
+
+		info!(log, "hello");
+		match stream.next_frame().await.unwrap() {
+			Some(BroadcastFrame::Log { level, message }) => {
+				assert_eq!(level, "info");
+				assert_eq!(message, "hello");
+			}
+			f => panic!("unexpected frame {:?}", f),
+		}
+	}
+
+	#[test]
+	fn test_keeps_detached_pid_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let log = log::Logger::test();
+		let pid_file = dir.path().join(PID_FILE);
+		let metadata_file = dir.path().join(METADATA_FILE);
+
+		// written by `--detach` for this process before it took the lock
+		write_pid_file(&paths, std::process::id()).unwrap();
+		write(&metadata_file, "{}").unwrap();
+		remove_stale_metadata(&log, &paths);
+		assert_eq!(
+			read_to_string(&pid_file).unwrap(),
+			std::process::id().to_string()
+		);
+		assert!(!metadata_file.exists());
+
+		// left behind by some other process
+		write_pid_file(&paths, std::process::id() + 1).unwrap();
+		remove_stale_metadata(&log, &paths);
+		assert!(!pid_file.exists());
+	}
+}
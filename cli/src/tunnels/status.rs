@@ -0,0 +1,633 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::log::{Level, LogSink, ProgressFrame, TunnelProgressState};
+use crate::util::sd_notify;
+
+use super::port_metrics::PortMetrics;
+use super::security_events::SecurityEvent;
+
+/// Number of the most recent security events kept in the status.
+const RECENT_SECURITY_EVENTS: usize = 10;
+
+/// Snapshot of the state of a running tunnel, as reported to `code tunnel watch`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelStatus {
+	/// Connection state of the tunnel, or None if it hasn't started connecting.
+	pub state: Option<TunnelProgressState>,
+	pub name: Option<String>,
+	pub uri: Option<String>,
+	/// Forwarded ports, keyed by port number, with the URI each is reachable at.
+	pub ports: BTreeMap<u16, String>,
+	/// Number of clients currently connected to the tunnel.
+	pub clients: usize,
+	pub metrics: TunnelMetrics,
+	/// Status of the tunnel's connection to the relay, once it's hosted.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub connection: Option<ConnectionStatus>,
+	/// Why new client connections are refused, while a data transfer budget
+	/// is used up.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub budget_exceeded: Option<String>,
+	/// Why hosting is paused, while the tunnel is taken off the relay.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub paused: Option<String>,
+	/// Optional subsystems turned off after failing too often, with why.
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub disabled_subsystems: BTreeMap<String, String>,
+	/// Traffic on each port clients have connected to, keyed by port number.
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub port_metrics: BTreeMap<u16, PortMetrics>,
+	/// Most recent security events, oldest first.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub security_events: Vec<SecurityEvent>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelMetrics {
+	pub uptime_secs: u64,
+	/// Number of clients that have connected since the tunnel started.
+	pub total_clients: u64,
+	/// Number of times the tunnel has lost its connection to the relay.
+	pub reconnects: u64,
+	/// Bytes received from clients, counted once their connection closes.
+	pub bytes_received: u64,
+	/// Bytes sent to clients, counted once their connection closes.
+	pub bytes_sent: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionState {
+	Connecting,
+	Connected,
+	Reconnecting,
+	/// Waiting to retry after the connection failed or was lost.
+	Backoff,
+	/// Stopped reconnecting after the retries were used up.
+	Failed,
+	/// Disconnected from the relay after idling, until a client tries to
+	/// connect.
+	Sleeping,
+	Closed,
+}
+
+impl fmt::Display for ConnectionState {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			ConnectionState::Connecting => "connecting",
+			ConnectionState::Connected => "connected",
+			ConnectionState::Reconnecting => "reconnecting",
+			ConnectionState::Backoff => "waiting to reconnect",
+			ConnectionState::Failed => "gave up reconnecting",
+			ConnectionState::Sleeping => "asleep until a client connects",
+			ConnectionState::Closed => "closed",
+		})
+	}
+}
+
+/// Snapshot of an `ActiveTunnel`'s connection to the relay.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStatus {
+	pub state: ConnectionState,
+	/// Most recent error connecting to the relay, kept after reconnecting.
+	pub last_error: Option<String>,
+	/// ID this machine is registered with as a host of the tunnel.
+	pub host_id: Option<String>,
+	pub relay_uri: Option<String>,
+	/// Seconds since the tunnel was started.
+	pub uptime_secs: u64,
+	/// Seconds since the current connection was made, while connected.
+	pub connected_secs: Option<u64>,
+	/// Seconds until the next connection attempt, while backing off.
+	pub retry_in_secs: Option<u64>,
+	/// Number of times the connection was made again after being lost.
+	pub reconnects: u64,
+	/// Number of attempts in a row that failed to connect.
+	#[serde(default)]
+	pub failed_attempts: u32,
+	/// Forwarded ports, other than the control port.
+	pub ports: Vec<u16>,
+}
+
+impl fmt::Display for ConnectionStatus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (self.state, self.connected_secs, self.retry_in_secs) {
+			(ConnectionState::Connected, Some(secs), _) => {
+				write!(f, "connected for {}", format_secs(secs))?
+			}
+			(ConnectionState::Backoff, _, Some(secs)) => write!(
+				f,
+				"reconnecting in {} after {} failed attempt{}",
+				format_secs(secs),
+				self.failed_attempts,
+				if self.failed_attempts == 1 { "" } else { "s" }
+			)?,
+			(ConnectionState::Failed, _, _) => write!(
+				f,
+				"gave up reconnecting after {} failed attempts",
+				self.failed_attempts
+			)?,
+			(state, _, _) => write!(f, "{}", state)?,
+		}
+
+		match (&self.last_error, self.state) {
+			(Some(e), ConnectionState::Backoff | ConnectionState::Failed) => write!(f, ": {}", e),
+			_ => Ok(()),
+		}
+	}
+}
+
+impl TunnelStatus {
+	/// Gets a one-line description of the tunnel's state, such as for a
+	/// supervisor to show.
+	pub fn summary(&self) -> String {
+		let name = self.name.as_deref().unwrap_or("(unnamed)");
+		if let Some(reason) = &self.paused {
+			return format!("Tunnel {} is paused, as {}", name, reason);
+		}
+
+		let summary = match &self.connection {
+			Some(c) if c.state == ConnectionState::Connected => format!(
+				"Tunnel {} is {}, with {} client{} connected",
+				name,
+				c,
+				self.clients,
+				if self.clients == 1 { "" } else { "s" }
+			),
+			Some(c) => format!("Tunnel {} is {}", name, c),
+			None => "Starting the tunnel".to_string(),
+		};
+		match &self.budget_exceeded {
+			Some(message) => format!("{}. {}", summary, message),
+			None => summary,
+		}
+	}
+}
+
+/// Formats a number of seconds like `1h 2m`, `3m 4s`, or `5s`.
+fn format_secs(secs: u64) -> String {
+	match (secs / 3600, secs / 60 % 60, secs % 60) {
+		(0, 0, s) => format!("{}s", s),
+		(0, m, s) => format!("{}m {}s", m, s),
+		(h, m, _) => format!("{}h {}m", h, m),
+	}
+}
+
+/// How often the status reported to a supervisor is brought up to date.
+const SUPERVISOR_STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps the status text of the supervisor the process runs under, such as
+/// systemd, up to date with the tunnel's status, if there is one.
+pub fn report_to_supervisor(status: StatusSink) {
+	if !sd_notify::is_supervised() {
+		return;
+	}
+
+	tokio::spawn(async move {
+		let mut last = String::new();
+		loop {
+			let summary = status.snapshot().summary();
+			if summary != last {
+				sd_notify::notify_status(&summary).ok();
+				last = summary;
+			}
+			tokio::time::sleep(SUPERVISOR_STATUS_INTERVAL).await;
+		}
+	});
+}
+
+struct TrackedConnection {
+	started_at: Instant,
+	state: ConnectionState,
+	last_error: Option<String>,
+	host_id: Option<String>,
+	relay_uri: Option<String>,
+	connected_at: Option<Instant>,
+	retry_at: Option<Instant>,
+	connects: u64,
+	failures: u32,
+	ports: Vec<u16>,
+}
+
+/// Shared state of an `ActiveTunnel`'s connection, updated by the task that
+/// keeps the tunnel connected to the relay.
+#[derive(Clone)]
+pub struct ConnectionTracker(Arc<Mutex<TrackedConnection>>);
+
+impl Default for ConnectionTracker {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl ConnectionTracker {
+	pub fn new() -> Self {
+		Self(Arc::new(Mutex::new(TrackedConnection {
+			started_at: Instant::now(),
+			state: ConnectionState::Connecting,
+			last_error: None,
+			host_id: None,
+			relay_uri: None,
+			connected_at: None,
+			retry_at: None,
+			connects: 0,
+			failures: 0,
+			ports: vec![],
+		})))
+	}
+
+	/// Records that a connection attempt is starting.
+	pub fn connecting(&self) {
+		let mut c = self.0.lock().unwrap();
+		c.state = if c.connects == 0 {
+			ConnectionState::Connecting
+		} else {
+			ConnectionState::Reconnecting
+		};
+		c.connected_at = None;
+		c.retry_at = None;
+	}
+
+	/// Records that the tunnel connected to the relay.
+	pub fn connected(&self, host_id: String, relay_uri: Option<String>) {
+		let mut c = self.0.lock().unwrap();
+		c.state = ConnectionState::Connected;
+		c.host_id = Some(host_id);
+		c.relay_uri = relay_uri;
+		c.connected_at = Some(Instant::now());
+		c.retry_at = None;
+		c.connects += 1;
+		c.failures = 0;
+	}
+
+	/// Records that connecting failed or the connection was lost, and that
+	/// the next attempt is in `retry_in`.
+	pub fn backoff(&self, error: String, retry_in: Duration) {
+		let mut c = self.0.lock().unwrap();
+		c.state = ConnectionState::Backoff;
+		c.last_error = Some(error);
+		c.connected_at = None;
+		c.retry_at = Some(Instant::now() + retry_in);
+		c.failures += 1;
+	}
+
+	/// Records that the tunnel stopped reconnecting after `error`.
+	pub fn gave_up(&self, error: String) {
+		let mut c = self.0.lock().unwrap();
+		c.state = ConnectionState::Failed;
+		c.failures += 1;
+		c.last_error = Some(error);
+		c.connected_at = None;
+		c.retry_at = None;
+	}
+
+	/// Records that the tunnel disconnected from the relay to sleep.
+	pub fn sleeping(&self) {
+		let mut c = self.0.lock().unwrap();
+		c.state = ConnectionState::Sleeping;
+		c.connected_at = None;
+		c.retry_at = None;
+	}
+
+	pub fn closed(&self) {
+		let mut c = self.0.lock().unwrap();
+		c.state = ConnectionState::Closed;
+		c.connected_at = None;
+		c.retry_at = None;
+	}
+
+	pub fn set_ports(&self, ports: Vec<u16>) {
+		self.0.lock().unwrap().ports = ports;
+	}
+
+	/// Gets the current status of the connection.
+	pub fn snapshot(&self) -> ConnectionStatus {
+		let c = self.0.lock().unwrap();
+		let now = Instant::now();
+		let mut ports = c.ports.clone();
+		ports.sort_unstable();
+		ConnectionStatus {
+			state: c.state,
+			last_error: c.last_error.clone(),
+			host_id: c.host_id.clone(),
+			relay_uri: c.relay_uri.clone(),
+			uptime_secs: now.duration_since(c.started_at).as_secs(),
+			connected_secs: c.connected_at.map(|t| now.duration_since(t).as_secs()),
+			retry_in_secs: c
+				.retry_at
+				.map(|t| t.saturating_duration_since(now).as_secs()),
+			reconnects: c.connects.saturating_sub(1),
+			failed_attempts: c.failures,
+			ports,
+		}
+	}
+}
+
+/// Log sink that keeps the status of the tunnel up to date from the progress
+/// it reports, so that it can be given to clients that ask for it.
+#[derive(Clone)]
+pub struct StatusSink {
+	started_at: Instant,
+	status: Arc<Mutex<TunnelStatus>>,
+	connection: Arc<Mutex<Option<ConnectionTracker>>>,
+}
+
+impl Default for StatusSink {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl StatusSink {
+	pub fn new() -> Self {
+		Self {
+			started_at: Instant::now(),
+			status: Arc::new(Mutex::new(TunnelStatus::default())),
+			connection: Arc::new(Mutex::new(None)),
+		}
+	}
+
+	/// Sets the connection of the tunnel being served, whose status is
+	/// included in snapshots.
+	pub fn set_connection(&self, connection: ConnectionTracker) {
+		*self.connection.lock().unwrap() = Some(connection);
+	}
+
+	/// Gets the current status of the tunnel.
+	pub fn snapshot(&self) -> TunnelStatus {
+		let mut status = self.status.lock().unwrap().clone();
+		status.metrics.uptime_secs = self.started_at.elapsed().as_secs();
+		status.connection = self
+			.connection
+			.lock()
+			.unwrap()
+			.as_ref()
+			.map(|c| c.snapshot());
+		status
+	}
+}
+
+impl LogSink for StatusSink {
+	fn write_log(&self, _level: Level, _prefix: &str, _message: &str) {}
+	fn write_result(&self, _message: &str) {}
+
+	fn write_progress(&self, frame: &ProgressFrame) {
+		let mut status = self.status.lock().unwrap();
+		match frame {
+			ProgressFrame::TunnelState { state, name, uri } => {
+				if *state == TunnelProgressState::Reconnecting
+					&& status.state != Some(TunnelProgressState::Reconnecting)
+				{
+					status.metrics.reconnects += 1;
+				}
+
+				status.state = Some(*state);
+				if let Some(name) = name {
+					status.name = Some(name.to_string());
+				}
+				match state {
+					TunnelProgressState::Listening => status.uri = uri.map(str::to_string),
+					TunnelProgressState::Closed => {
+						status.uri = None;
+						status.ports.clear();
+					}
+					_ => {}
+				}
+			}
+			ProgressFrame::PortUri { port, uri } => {
+				status.ports.insert(*port, uri.to_string());
+			}
+			ProgressFrame::PortUnforwarded { port } => {
+				status.ports.remove(port);
+			}
+			ProgressFrame::BudgetExceeded { message } => {
+				status.budget_exceeded = Some(message.to_string());
+			}
+			ProgressFrame::BudgetAvailable => {
+				status.budget_exceeded = None;
+			}
+			ProgressFrame::HostingPaused { reason } => {
+				status.paused = Some(reason.to_string());
+			}
+			ProgressFrame::HostingResumed => {
+				status.paused = None;
+			}
+			ProgressFrame::SubsystemDisabled { subsystem, reason } => {
+				status
+					.disabled_subsystems
+					.insert(subsystem.to_string(), reason.to_string());
+			}
+			ProgressFrame::SecurityEvent { kind, detail } => {
+				if status.security_events.len() == RECENT_SECURITY_EVENTS {
+					status.security_events.remove(0);
+				}
+				status.security_events.push(SecurityEvent {
+					kind: *kind,
+					detail: detail.to_string(),
+					at: chrono::Utc::now(),
+				});
+			}
+			ProgressFrame::ClientConnected => {
+				status.clients += 1;
+				status.metrics.total_clients += 1;
+			}
+			ProgressFrame::ClientDisconnected {
+				bytes_received,
+				bytes_sent,
+				..
+			} => {
+				status.clients = status.clients.saturating_sub(1);
+				status.metrics.bytes_received += bytes_received;
+				status.metrics.bytes_sent += bytes_sent;
+			}
+			ProgressFrame::PortConnectionOpened { port } => {
+				let metrics = status.port_metrics.entry(*port).or_default();
+				metrics.active_connections += 1;
+				metrics.connects += 1;
+			}
+			ProgressFrame::PortConnectionClosed {
+				port,
+				bytes_received,
+				bytes_sent,
+			} => {
+				let metrics = status.port_metrics.entry(*port).or_default();
+				metrics.active_connections = metrics.active_connections.saturating_sub(1);
+				metrics.disconnects += 1;
+				metrics.bytes_received += bytes_received;
+				metrics.bytes_sent += bytes_sent;
+			}
+			_ => {}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn state_frame(state: TunnelProgressState) -> ProgressFrame<'static> {
+		ProgressFrame::TunnelState {
+			state,
+			name: Some("my-machine"),
+			uri: Some("https://vscode.dev/tunnel/my-machine"),
+		}
+	}
+
+	#[test]
+	fn test_tracks_tunnel_status() {
+		let sink = StatusSink::new();
+		sink.write_progress(&state_frame(TunnelProgressState::Listening));
+		sink.write_progress(&ProgressFrame::PortUri {
+			port: 8080,
+			uri: "https://example.com",
+		});
+		sink.write_progress(&ProgressFrame::ClientConnected);
+		sink.write_progress(&ProgressFrame::ClientConnected);
+		sink.write_progress(&ProgressFrame::ClientDisconnected {
+			bytes_received: 10,
+			bytes_sent: 20,
+			duration_ms: 1000,
+		});
+
+		let status = sink.snapshot();
+		assert_eq!(status.state, Some(TunnelProgressState::Listening));
+		assert_eq!(status.name.as_deref(), Some("my-machine"));
+		assert_eq!(
+			status.uri.as_deref(),
+			Some("https://vscode.dev/tunnel/my-machine")
+		);
+		assert_eq!(
+			status.ports.get(&8080).map(String::as_str),
+			Some("https://example.com")
+		);
+		assert_eq!(status.clients, 1);
+		assert_eq!(status.metrics.total_clients, 2);
+		assert_eq!(status.metrics.bytes_received, 10);
+		assert_eq!(status.metrics.bytes_sent, 20);
+
+		sink.write_progress(&ProgressFrame::PortUnforwarded { port: 8080 });
+		assert!(sink.snapshot().ports.is_empty());
+
+		sink.write_progress(&ProgressFrame::BudgetExceeded {
+			message: "budget used up",
+		});
+		assert_eq!(
+			sink.snapshot().budget_exceeded.as_deref(),
+			Some("budget used up")
+		);
+		sink.write_progress(&ProgressFrame::BudgetAvailable);
+		assert_eq!(sink.snapshot().budget_exceeded, None);
+
+		sink.write_progress(&ProgressFrame::HostingPaused {
+			reason: "it was requested with `code tunnel pause`",
+		});
+		assert!(sink.snapshot().summary().contains("is paused"));
+		sink.write_progress(&ProgressFrame::HostingResumed);
+		assert_eq!(sink.snapshot().paused, None);
+	}
+
+	#[test]
+	fn test_counts_reconnects() {
+		let sink = StatusSink::new();
+		for state in [
+			TunnelProgressState::Listening,
+			TunnelProgressState::Reconnecting,
+			TunnelProgressState::Reconnecting,
+			TunnelProgressState::Connected,
+			TunnelProgressState::Reconnecting,
+		] {
+			sink.write_progress(&state_frame(state));
+		}
+
+		assert_eq!(sink.snapshot().metrics.reconnects, 2);
+	}
+
+	#[test]
+	fn test_tracks_connection() {
+		let tracker = ConnectionTracker::new();
+		assert_eq!(tracker.snapshot().state, ConnectionState::Connecting);
+
+		tracker.backoff("relay unavailable".to_string(), Duration::from_secs(60));
+		let status = tracker.snapshot();
+		assert_eq!(status.state, ConnectionState::Backoff);
+		assert!(status.retry_in_secs.unwrap() > 50);
+
+		tracker.connecting();
+		tracker.connected("host1".to_string(), None);
+		tracker.set_ports(vec![8080, 3000]);
+		let status = tracker.snapshot();
+		assert_eq!(status.state, ConnectionState::Connected);
+		assert_eq!(status.last_error.as_deref(), Some("relay unavailable"));
+		assert_eq!(status.host_id.as_deref(), Some("host1"));
+		assert_eq!(status.connected_secs, Some(0));
+		assert_eq!(status.retry_in_secs, None);
+		assert_eq!(status.ports, vec![3000, 8080]);
+		assert_eq!(status.reconnects, 0);
+
+		tracker.connecting();
+		assert_eq!(tracker.snapshot().state, ConnectionState::Reconnecting);
+		tracker.connected("host1".to_string(), None);
+		assert_eq!(tracker.snapshot().reconnects, 1);
+
+		tracker.sleeping();
+		let status = tracker.snapshot();
+		assert_eq!(status.state, ConnectionState::Sleeping);
+		assert_eq!(status.connected_secs, None);
+		tracker.connecting();
+		tracker.connected("host1".to_string(), None);
+
+		let sink = StatusSink::new();
+		assert_eq!(sink.snapshot().connection, None);
+		sink.set_connection(tracker.clone());
+		let connection = sink.snapshot().connection.unwrap();
+		assert_eq!(connection.state, ConnectionState::Connected);
+		assert_eq!(connection.reconnects, 2);
+	}
+
+	#[test]
+	fn test_describes_connection() {
+		let tracker = ConnectionTracker::new();
+		tracker.backoff("relay unavailable".to_string(), Duration::from_secs(90));
+		let status = tracker.snapshot();
+		assert_eq!(status.failed_attempts, 1);
+		let text = status.to_string();
+		assert!(text.starts_with("reconnecting in 1m "), "{}", text);
+		assert!(
+			text.ends_with(" after 1 failed attempt: relay unavailable"),
+			"{}",
+			text
+		);
+
+		tracker.gave_up("relay unavailable".to_string());
+		assert_eq!(
+			tracker.snapshot().to_string(),
+			"gave up reconnecting after 2 failed attempts: relay unavailable"
+		);
+
+		tracker.connected("host1".to_string(), None);
+		let status = TunnelStatus {
+			name: Some("my-machine".to_string()),
+			clients: 1,
+			connection: Some(tracker.snapshot()),
+			..Default::default()
+		};
+		assert_eq!(
+			status.summary(),
+			"Tunnel my-machine is connected for 0s, with 1 client connected"
+		);
+		assert_eq!(format_secs(3725), "1h 2m");
+	}
+}
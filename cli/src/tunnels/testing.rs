@@ -0,0 +1,341 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! A mock of the tunnel service for tests: the emulator, with responses that
+//! are hard to come by otherwise scripted in, like throttling, errors, and
+//! other machines racing for the same name. This covers the logic of
+//! `DevTunnels` that depends on those without reaching the network.
+
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use tunnels::contracts::Tunnel;
+use tunnels::management::{HttpResult, TunnelLocator, TunnelRequestOptions};
+
+use super::emulator::{response_error, EmulatedTunnelService};
+use super::service_limits::ServiceLimits;
+use super::tunnel_service::{ManagementClient, RelayHost, SharedManagementClient};
+
+/// Kind of request made to the management API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Call {
+	List,
+	Get,
+	Create,
+	Update,
+	Delete,
+	DeletePort,
+}
+
+type BeforeCall = Box<dyn FnOnce(&EmulatedTunnelService) + Send>;
+
+enum Step {
+	/// Responds with the status instead of passing the call on.
+	Fail(StatusCode),
+	/// Acts on the service just before the call is passed on to it.
+	Before(BeforeCall),
+}
+
+/// Tunnel service whose next calls of each kind can be scripted. Clones
+/// share the same tunnels and script.
+#[derive(Clone)]
+pub struct MockTunnelService {
+	pub service: EmulatedTunnelService,
+	script: Arc<Mutex<Vec<(Call, Step)>>>,
+}
+
+impl MockTunnelService {
+	pub fn new(service: EmulatedTunnelService) -> Self {
+		MockTunnelService {
+			service,
+			script: Arc::new(Mutex::new(vec![])),
+		}
+	}
+
+	/// Fails the next call of the kind with the status.
+	pub fn fail_next(&self, call: Call, status: StatusCode) {
+		self.script.lock().unwrap().push((call, Step::Fail(status)));
+	}
+
+	/// Runs `f` just before the next call of the kind reaches the service,
+	/// such as to have another machine create a tunnel in between.
+	pub fn before_next(&self, call: Call, f: impl FnOnce(&EmulatedTunnelService) + Send + 'static) {
+		self.script
+			.lock()
+			.unwrap()
+			.push((call, Step::Before(Box::new(f))));
+	}
+
+	/// Runs the first step scripted for the call, if any, returning the
+	/// status to fail it with.
+	fn run(&self, call: Call) -> Result<(), StatusCode> {
+		let step = {
+			let mut script = self.script.lock().unwrap();
+			script
+				.iter()
+				.position(|(c, _)| *c == call)
+				.map(|i| script.remove(i).1)
+		};
+
+		match step {
+			Some(Step::Fail(status)) => Err(status),
+			Some(Step::Before(f)) => {
+				f(&self.service);
+				Ok(())
+			}
+			None => Ok(()),
+		}
+	}
+}
+
+#[async_trait]
+impl ManagementClient for MockTunnelService {
+	async fn list_all_tunnels(&self, options: &TunnelRequestOptions) -> HttpResult<Vec<Tunnel>> {
+		self.run(Call::List).map_err(response_error)?;
+		self.service.list_all_tunnels(options).await
+	}
+
+	async fn get_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.run(Call::Get).map_err(response_error)?;
+		self.service.get_tunnel(locator, options).await
+	}
+
+	async fn create_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.run(Call::Create).map_err(response_error)?;
+		self.service.create_tunnel(tunnel, options).await
+	}
+
+	async fn update_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.run(Call::Update).map_err(response_error)?;
+		self.service.update_tunnel(tunnel, options).await
+	}
+
+	async fn delete_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.run(Call::Delete).map_err(response_error)?;
+		self.service.delete_tunnel(locator, options).await
+	}
+
+	async fn delete_tunnel_port(
+		&self,
+		locator: &TunnelLocator,
+		port_number: u16,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.run(Call::DeletePort).map_err(response_error)?;
+		self.service
+			.delete_tunnel_port(locator, port_number, options)
+			.await
+	}
+
+	async fn delete_tunnel_endpoints(
+		&self,
+		locator: &TunnelLocator,
+		host_id: &str,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.service
+			.delete_tunnel_endpoints(locator, host_id, options)
+			.await
+	}
+
+	async fn get_service_limits(&self) -> HttpResult<Option<ServiceLimits>> {
+		self.service.get_service_limits().await
+	}
+
+	fn with_host_token(&self, _host_token: &str) -> SharedManagementClient {
+		Arc::new(self.clone())
+	}
+
+	fn create_relay_host(&self, locator: TunnelLocator) -> Box<dyn RelayHost> {
+		self.service.create_relay_host(locator)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use chrono::Utc;
+
+	use crate::log;
+	use crate::state::LauncherPaths;
+	use crate::tunnels::dev_tunnels::DevTunnels;
+	use crate::tunnels::port_access::PortPrivacy;
+
+	const LAUNCHER_TAG: &str = "vscode-server-launcher";
+
+	fn make_dev_tunnels(mock: &MockTunnelService, dir: &tempfile::TempDir) -> DevTunnels {
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		DevTunnels::new_with_client(&log::Logger::test(), &paths, Arc::new(mock.clone()))
+	}
+
+	/// Creates a launcher tunnel as another machine would, before now so that
+	/// it claimed its name first.
+	fn add_other_machine(service: &EmulatedTunnelService, name: &str) {
+		service
+			.add_tunnel(&Tunnel {
+				tags: vec![name.to_string(), LAUNCHER_TAG.to_string()],
+				created: Some(Utc::now() - chrono::Duration::hours(1)),
+				..Default::default()
+			})
+			.unwrap();
+	}
+
+	fn names(service: &EmulatedTunnelService) -> Vec<String> {
+		let mut names: Vec<_> = service
+			.tunnels()
+			.into_iter()
+			.map(|t| t.tags[0].clone())
+			.collect();
+		names.sort();
+		names
+	}
+
+	#[tokio::test]
+	async fn test_recycles_tunnel_when_creation_is_throttled() {
+		let dir = tempfile::tempdir().unwrap();
+		let mock = MockTunnelService::new(EmulatedTunnelService::default());
+		add_other_machine(&mock.service, "old-machine");
+		mock.fail_next(Call::Create, StatusCode::TOO_MANY_REQUESTS);
+
+		let mut active = make_dev_tunnels(&mock, &dir)
+			.start_new_launcher_tunnel(Some("new-machine".to_string()), false)
+			.await
+			.unwrap();
+		active.close().await.unwrap();
+
+		assert_eq!(names(&mock.service), vec!["new-machine"]);
+	}
+
+	#[tokio::test]
+	async fn test_fails_when_throttled_with_nothing_to_recycle() {
+		let dir = tempfile::tempdir().unwrap();
+		let mock = MockTunnelService::new(EmulatedTunnelService::default());
+		mock.fail_next(Call::Create, StatusCode::TOO_MANY_REQUESTS);
+
+		let result = make_dev_tunnels(&mock, &dir)
+			.start_new_launcher_tunnel(Some("new-machine".to_string()), false)
+			.await;
+		assert!(result.is_err());
+		assert!(mock.service.tunnels().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_takes_next_name_when_raced_for_it() {
+		let dir = tempfile::tempdir().unwrap();
+		let mock = MockTunnelService::new(EmulatedTunnelService::default());
+		mock.before_next(Call::Create, |s| add_other_machine(s, "my-machine"));
+
+		let mut active = make_dev_tunnels(&mock, &dir)
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		assert_eq!(active.name, "my-machine-2");
+		active.close().await.unwrap();
+
+		// the tunnel that lost the race was deleted
+		assert_eq!(names(&mock.service), vec!["my-machine", "my-machine-2"]);
+	}
+
+	#[tokio::test]
+	async fn test_restores_name_when_rename_is_raced() {
+		let dir = tempfile::tempdir().unwrap();
+		let mock = MockTunnelService::new(EmulatedTunnelService::default());
+		let mut dt = make_dev_tunnels(&mock, &dir);
+		dt.rename_tunnel("first-name").await.unwrap();
+
+		mock.before_next(Call::Update, |s| add_other_machine(s, "second-name"));
+		assert!(dt.rename_tunnel("second-name").await.is_err());
+		assert_eq!(names(&mock.service), vec!["first-name", "second-name"]);
+	}
+
+	#[tokio::test]
+	async fn test_reports_errors_listing_tunnels() {
+		let dir = tempfile::tempdir().unwrap();
+		let mock = MockTunnelService::new(EmulatedTunnelService::default());
+		let mut dt = make_dev_tunnels(&mock, &dir);
+
+		mock.fail_next(Call::List, StatusCode::TOO_MANY_REQUESTS);
+		assert!(dt.list_all_server_tunnels().await.is_err());
+		assert!(dt.list_all_server_tunnels().await.unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_starts_when_pruning_a_stale_port_fails() {
+		let dir = tempfile::tempdir().unwrap();
+		let mock = MockTunnelService::new(EmulatedTunnelService::default());
+		let mut dt = make_dev_tunnels(&mock, &dir);
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		for port in [8080, 8081, 8082] {
+			active
+				.add_port_tcp(port, PortPrivacy::Private)
+				.await
+				.unwrap();
+		}
+		active.close().await.unwrap();
+
+		mock.fail_next(Call::DeletePort, StatusCode::INTERNAL_SERVER_ERROR);
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		active.close().await.unwrap();
+
+		// only the port whose delete failed outright is left
+		assert_eq!(mock.service.tunnels()[0].ports.len(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_forced_removal_cleans_up_when_deletion_fails() {
+		let dir = tempfile::tempdir().unwrap();
+		let mock = MockTunnelService::new(EmulatedTunnelService::default());
+		let mut dt = make_dev_tunnels(&mock, &dir);
+		let mut active = dt
+			.start_new_launcher_tunnel(Some("my-machine".to_string()), false)
+			.await
+			.unwrap();
+		active
+			.add_port_tcp(8080, PortPrivacy::Private)
+			.await
+			.unwrap();
+
+		mock.fail_next(Call::Delete, StatusCode::INTERNAL_SERVER_ERROR);
+		let steps = dt.remove_tunnel_forced().await.unwrap();
+		let failed: Vec<_> = steps
+			.iter()
+			.filter(|s| s.error.is_some())
+			.map(|s| s.description.as_str())
+			.collect();
+		assert_eq!(steps.len(), 3);
+		assert_eq!(failed, vec!["delete tunnel my-machine"]);
+
+		let tunnel = &mock.service.tunnels()[0];
+		assert!(tunnel.endpoints.is_empty());
+		assert!(tunnel.ports.is_empty());
+		// forgotten on this machine even though the tunnel is left
+		assert!(dt.remove_tunnel_forced().await.unwrap().is_empty());
+		active.close().await.unwrap();
+	}
+}
@@ -0,0 +1,468 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tunnels::connections::{RelayHandle, RelayTunnelHost};
+use tunnels::contracts::{Tunnel, TunnelPort, TunnelRelayTunnelEndpoint};
+use tunnels::management::{
+	Authorization, HttpError, HttpResult, TunnelClientBuilder, TunnelLocator,
+	TunnelManagementClient, TunnelRequestOptions,
+};
+
+use crate::constants::{TUNNEL_LIMITS_URL, TUNNEL_SERVICE_USER_AGENT};
+use crate::log;
+use crate::util::errors::{wrap, WrappedError};
+
+use super::port_connection::PortConnection;
+use super::service_limits::ServiceLimits;
+
+pub type SharedManagementClient = Arc<dyn ManagementClient>;
+
+/// The parts of the tunnel service's management API used by `DevTunnels`.
+/// This is implemented for the real service, and by the emulator, which is
+/// built for tests and when the `tunnel-emulator` feature is enabled.
+#[async_trait]
+pub trait ManagementClient: Send + Sync {
+	async fn list_all_tunnels(&self, options: &TunnelRequestOptions) -> HttpResult<Vec<Tunnel>>;
+
+	async fn get_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel>;
+
+	async fn create_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel>;
+
+	async fn update_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel>;
+
+	async fn delete_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()>;
+
+	async fn delete_tunnel_port(
+		&self,
+		locator: &TunnelLocator,
+		port_number: u16,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()>;
+
+	async fn delete_tunnel_endpoints(
+		&self,
+		locator: &TunnelLocator,
+		host_id: &str,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()>;
+
+	/// Gets the service's current limits, or None if it doesn't provide them.
+	async fn get_service_limits(&self) -> HttpResult<Option<ServiceLimits>>;
+
+	/// Gets a client that authenticates using the tunnel's host token.
+	fn with_host_token(&self, host_token: &str) -> SharedManagementClient;
+
+	/// Creates a host that connects the tunnel to its relay.
+	fn create_relay_host(&self, locator: TunnelLocator) -> Box<dyn RelayHost>;
+}
+
+/// Hosts a tunnel on the relay, forwarding ports to this machine.
+#[async_trait]
+pub trait RelayHost: Send {
+	async fn connect(
+		&mut self,
+		access_token: &str,
+	) -> Result<Box<dyn RelayConnection>, WrappedError>;
+
+	async fn add_port(&mut self, port: &TunnelPort) -> Result<(), WrappedError>;
+
+	/// Adds a port whose connections are handled by the caller, rather than
+	/// forwarded to localhost.
+	async fn add_port_raw(
+		&mut self,
+		port: &TunnelPort,
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, WrappedError>;
+
+	async fn remove_port(&mut self, port_number: u16) -> Result<(), WrappedError>;
+
+	async fn unregister(&mut self) -> Result<(), WrappedError>;
+}
+
+/// A live connection from a `RelayHost` to the relay.
+#[async_trait]
+pub trait RelayConnection: Send {
+	fn endpoint(&self) -> &TunnelRelayTunnelEndpoint;
+
+	/// Waits until the connection ends.
+	async fn wait(&mut self) -> Result<(), WrappedError>;
+
+	async fn close(&mut self) -> Result<(), WrappedError>;
+}
+
+/// Header that requests to the tunnel service carry the CLI's correlation ID
+/// in, so they can be matched with its logs.
+const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+/// Metadata an organization attaches to each request to the management API,
+/// such as to attribute traffic through its proxies. It's deployed in the
+/// `request_metadata` of the setup file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestMetadata {
+	/// Appended to the CLI's user agent.
+	#[serde(default)]
+	pub user_agent_suffix: Option<String>,
+	/// Headers added to each request, by name.
+	#[serde(default)]
+	pub headers: BTreeMap<String, String>,
+}
+
+impl RequestMetadata {
+	/// Gets the user agent to send, which is the CLI's own if the suffix
+	/// would make it invalid.
+	pub fn user_agent(&self) -> String {
+		match self.user_agent_suffix.as_deref().map(str::trim) {
+			Some(suffix) if !suffix.is_empty() => {
+				let ua = format!("{} {}", TUNNEL_SERVICE_USER_AGENT.as_str(), suffix);
+				match HeaderValue::from_str(&ua) {
+					Ok(_) => ua,
+					Err(_) => TUNNEL_SERVICE_USER_AGENT.clone(),
+				}
+			}
+			_ => TUNNEL_SERVICE_USER_AGENT.clone(),
+		}
+	}
+
+	/// Gets the headers to add, skipping any that aren't valid HTTP headers.
+	pub fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+		self.headers
+			.iter()
+			.filter_map(|(name, value)| {
+				Some((
+					HeaderName::from_bytes(name.as_bytes()).ok()?,
+					HeaderValue::from_str(value).ok()?,
+				))
+			})
+			.collect()
+	}
+
+	/// Gets the names of headers that are skipped, as they aren't valid.
+	pub fn invalid_headers(&self) -> Vec<&str> {
+		self.headers
+			.iter()
+			.filter(|(name, value)| {
+				HeaderName::from_bytes(name.as_bytes()).is_err()
+					|| HeaderValue::from_str(value).is_err()
+			})
+			.map(|(name, _)| name.as_str())
+			.collect()
+	}
+
+	/// Adds the user agent and headers to a request made outside of the
+	/// management client.
+	pub fn apply(&self, mut req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+		req = req.header(reqwest::header::USER_AGENT, self.user_agent());
+		for (name, value) in self.headers() {
+			req = req.header(name, value);
+		}
+		req
+	}
+}
+
+/// Client for the real tunnel service.
+pub struct ServiceManagementClient {
+	client: TunnelManagementClient,
+	metadata: Arc<RequestMetadata>,
+}
+
+impl ServiceManagementClient {
+	/// Creates a client that adds the metadata's headers to each request. Its
+	/// user agent should be set on the builder.
+	pub fn new(builder: TunnelClientBuilder, metadata: &RequestMetadata) -> Self {
+		ServiceManagementClient {
+			client: builder.into(),
+			metadata: Arc::new(metadata.clone()),
+		}
+	}
+
+	/// Adds the CLI's correlation ID and the metadata's headers to a request.
+	fn options(&self, options: &TunnelRequestOptions) -> TunnelRequestOptions {
+		let mut options = options.clone();
+		if let Ok(value) = HeaderValue::from_str(log::correlation_id()) {
+			options
+				.headers
+				.push((HeaderName::from_static(CORRELATION_ID_HEADER), value));
+		}
+		options.headers.extend(self.metadata.headers());
+		options
+	}
+}
+
+impl From<TunnelClientBuilder> for ServiceManagementClient {
+	fn from(builder: TunnelClientBuilder) -> Self {
+		ServiceManagementClient::new(builder, &RequestMetadata::default())
+	}
+}
+
+#[async_trait]
+impl ManagementClient for ServiceManagementClient {
+	async fn list_all_tunnels(&self, options: &TunnelRequestOptions) -> HttpResult<Vec<Tunnel>> {
+		self.client.list_all_tunnels(&self.options(options)).await
+	}
+
+	async fn get_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.client
+			.get_tunnel(locator, &self.options(options))
+			.await
+	}
+
+	async fn create_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.client
+			.create_tunnel(tunnel, &self.options(options))
+			.await
+	}
+
+	async fn update_tunnel(
+		&self,
+		tunnel: &Tunnel,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<Tunnel> {
+		self.client
+			.update_tunnel(tunnel, &self.options(options))
+			.await
+	}
+
+	async fn delete_tunnel(
+		&self,
+		locator: &TunnelLocator,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.client
+			.delete_tunnel(locator, &self.options(options))
+			.await
+			.map(|_| ())
+	}
+
+	async fn delete_tunnel_port(
+		&self,
+		locator: &TunnelLocator,
+		port_number: u16,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.client
+			.delete_tunnel_port(locator, port_number, &self.options(options))
+			.await
+			.map(|_| ())
+	}
+
+	async fn delete_tunnel_endpoints(
+		&self,
+		locator: &TunnelLocator,
+		host_id: &str,
+		options: &TunnelRequestOptions,
+	) -> HttpResult<()> {
+		self.client
+			.delete_tunnel_endpoints(locator, host_id, None, &self.options(options))
+			.await
+			.map(|_| ())
+	}
+
+	async fn get_service_limits(&self) -> HttpResult<Option<ServiceLimits>> {
+		let url = match TUNNEL_LIMITS_URL.as_deref() {
+			Some(u) => u,
+			None => return Ok(None),
+		};
+
+		let limits = self
+			.metadata
+			.apply(reqwest::Client::new().get(url))
+			.header(CORRELATION_ID_HEADER, log::correlation_id())
+			.send()
+			.await
+			.and_then(|r| r.error_for_status())
+			.map_err(HttpError::ConnectionError)?
+			.json::<ServiceLimits>()
+			.await
+			.map_err(HttpError::ConnectionError)?;
+
+		Ok(Some(limits))
+	}
+
+	fn with_host_token(&self, host_token: &str) -> SharedManagementClient {
+		let mut builder = self.client.build();
+		builder.authorization(Authorization::Tunnel(host_token.to_string()));
+		Arc::new(ServiceManagementClient {
+			client: builder.into(),
+			metadata: self.metadata.clone(),
+		})
+	}
+
+	fn create_relay_host(&self, locator: TunnelLocator) -> Box<dyn RelayHost> {
+		Box::new(ServiceRelayHost(RelayTunnelHost::new(
+			locator,
+			self.client.clone(),
+		)))
+	}
+}
+
+struct ServiceRelayHost(RelayTunnelHost);
+
+#[async_trait]
+impl RelayHost for ServiceRelayHost {
+	async fn connect(
+		&mut self,
+		access_token: &str,
+	) -> Result<Box<dyn RelayConnection>, WrappedError> {
+		let handle = self
+			.0
+			.connect(access_token)
+			.await
+			.map_err(|e| wrap(e, "error connecting to tunnel"))?;
+		Ok(Box::new(ServiceRelayConnection(handle)))
+	}
+
+	async fn add_port(&mut self, port: &TunnelPort) -> Result<(), WrappedError> {
+		self.0
+			.add_port(port)
+			.await
+			.map_err(|e| wrap(e, "error adding port to relay"))
+	}
+
+	async fn add_port_raw(
+		&mut self,
+		port: &TunnelPort,
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, WrappedError> {
+		let mut relayed = self
+			.0
+			.add_port_raw(port)
+			.await
+			.map_err(|e| wrap(e, "error adding port to relay"))?;
+
+		let (tx, rx) = mpsc::unbounded_channel();
+		tokio::spawn(async move {
+			while let Some(conn) = relayed.recv().await {
+				if tx.send(PortConnection::from(conn)).is_err() {
+					return;
+				}
+			}
+		});
+		Ok(rx)
+	}
+
+	async fn remove_port(&mut self, port_number: u16) -> Result<(), WrappedError> {
+		self.0
+			.remove_port(port_number)
+			.await
+			.map_err(|e| wrap(e, "error remove port from relay"))
+	}
+
+	async fn unregister(&mut self) -> Result<(), WrappedError> {
+		self.0
+			.unregister()
+			.await
+			.map_err(|e| wrap(e, "error unregistering relay"))
+	}
+}
+
+struct ServiceRelayConnection(RelayHandle);
+
+#[async_trait]
+impl RelayConnection for ServiceRelayConnection {
+	fn endpoint(&self) -> &TunnelRelayTunnelEndpoint {
+		self.0.endpoint()
+	}
+
+	async fn wait(&mut self) -> Result<(), WrappedError> {
+		// error is mapped like this prevent it being used across an await,
+		// which Rust dislikes since there's a non-sendable dyn Error in there
+		(&mut self.0)
+			.await
+			.map_err(|e| wrap(e, "error from tunnel connection"))
+	}
+
+	async fn close(&mut self) -> Result<(), WrappedError> {
+		self.0
+			.close()
+			.await
+			.map_err(|e| wrap(e, "error closing tunnel connection"))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_request_metadata() {
+		let metadata: RequestMetadata = serde_json::from_str(
+			r#"{"user_agent_suffix":"contoso-it/1.0","headers":{"x-org-id":"contoso","bad header":"x"}}"#,
+		)
+		.unwrap();
+
+		assert!(metadata.user_agent().ends_with(" contoso-it/1.0"));
+		assert_eq!(
+			metadata.headers(),
+			vec![(
+				HeaderName::from_static("x-org-id"),
+				HeaderValue::from_static("contoso")
+			)]
+		);
+		assert_eq!(metadata.invalid_headers(), vec!["bad header"]);
+	}
+
+	#[test]
+	fn test_request_metadata_ignores_invalid_user_agent() {
+		let metadata = RequestMetadata {
+			user_agent_suffix: Some("line\nbreak".to_string()),
+			..Default::default()
+		};
+		assert_eq!(metadata.user_agent(), *TUNNEL_SERVICE_USER_AGENT);
+	}
+
+	#[test]
+	fn test_adds_correlation_id_to_requests() {
+		let metadata: RequestMetadata =
+			serde_json::from_str(r#"{"headers":{"x-org-id":"contoso"}}"#).unwrap();
+		let client = ServiceManagementClient::new(
+			tunnels::management::new_tunnel_management(&TUNNEL_SERVICE_USER_AGENT),
+			&metadata,
+		);
+
+		let options = client.options(tunnels::management::NO_REQUEST_OPTIONS);
+		assert_eq!(
+			options.headers,
+			vec![
+				(
+					HeaderName::from_static(CORRELATION_ID_HEADER),
+					HeaderValue::from_str(log::correlation_id()).unwrap()
+				),
+				(
+					HeaderName::from_static("x-org-id"),
+					HeaderValue::from_static("contoso")
+				),
+			]
+		);
+	}
+}
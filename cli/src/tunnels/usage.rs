@@ -0,0 +1,203 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! History of the client sessions served by the tunnel, so that the cost of
+//! a shared tunnel host can be attributed to the teams using it. Sessions are
+//! appended as lines of JSON to a file for the month they started in.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::log::{Level, LogSink, ProgressFrame, TunnelProgressState};
+use crate::state::LauncherPaths;
+use crate::util::errors::InvalidUsageMonth;
+
+const USAGE_DIR_NAME: &str = "usage";
+
+/// A single client session on the tunnel.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionRecord {
+	pub tunnel_name: String,
+	pub started_at: DateTime<Utc>,
+	pub ended_at: DateTime<Utc>,
+	pub bytes_received: u64,
+	pub bytes_sent: u64,
+}
+
+impl SessionRecord {
+	pub fn duration_secs(&self) -> i64 {
+		(self.ended_at - self.started_at).num_seconds()
+	}
+}
+
+/// Month that sessions are grouped by, given as `YYYY-MM`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsageMonth(NaiveDate);
+
+impl UsageMonth {
+	pub fn parse(s: &str) -> Result<Self, InvalidUsageMonth> {
+		NaiveDate::parse_from_str(&format!("{}-01", s.trim()), "%Y-%m-%d")
+			.map(UsageMonth)
+			.map_err(|_| InvalidUsageMonth(s.to_string()))
+	}
+
+	pub fn of(time: &DateTime<Utc>) -> Self {
+		UsageMonth(time.date_naive().with_day0(0).unwrap())
+	}
+
+	fn file_name(&self) -> String {
+		format!("{}.jsonl", self.0.format("%Y-%m"))
+	}
+}
+
+fn usage_dir(paths: &LauncherPaths) -> PathBuf {
+	paths.root().join(USAGE_DIR_NAME)
+}
+
+/// Reads the sessions that started in the month, oldest first. Lines that
+/// can't be read, such as one cut short by a crash, are skipped.
+pub fn read_sessions(paths: &LauncherPaths, month: UsageMonth) -> io::Result<Vec<SessionRecord>> {
+	read_sessions_from(&usage_dir(paths).join(month.file_name()))
+}
+
+fn read_sessions_from(file: &Path) -> io::Result<Vec<SessionRecord>> {
+	let file = match fs::File::open(file) {
+		Ok(f) => f,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+		Err(e) => return Err(e),
+	};
+
+	let mut sessions = vec![];
+	for line in BufReader::new(file).lines() {
+		if let Ok(session) = serde_json::from_str::<SessionRecord>(&line?) {
+			sessions.push(session);
+		}
+	}
+
+	sessions.sort_by_key(|s| s.started_at);
+	Ok(sessions)
+}
+
+/// Log sink that records each client session as it ends.
+#[derive(Clone)]
+pub struct UsageSink {
+	dir: PathBuf,
+	tunnel_name: Arc<Mutex<Option<String>>>,
+}
+
+impl UsageSink {
+	pub fn new(paths: &LauncherPaths) -> Self {
+		Self {
+			dir: usage_dir(paths),
+			tunnel_name: Arc::new(Mutex::new(None)),
+		}
+	}
+
+	fn record(&self, session: &SessionRecord) -> io::Result<()> {
+		fs::create_dir_all(&self.dir)?;
+		let mut line = serde_json::to_vec(session)?;
+		line.push(b'\n');
+
+		OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(
+				self.dir
+					.join(UsageMonth::of(&session.started_at).file_name()),
+			)?
+			.write_all(&line)
+	}
+}
+
+impl LogSink for UsageSink {
+	fn write_log(&self, _level: Level, _prefix: &str, _message: &str) {}
+	fn write_result(&self, _message: &str) {}
+
+	fn write_progress(&self, frame: &ProgressFrame) {
+		match frame {
+			ProgressFrame::TunnelState {
+				state: TunnelProgressState::Listening,
+				name: Some(name),
+				..
+			} => {
+				*self.tunnel_name.lock().unwrap() = Some(name.to_string());
+			}
+			ProgressFrame::ClientDisconnected {
+				bytes_received,
+				bytes_sent,
+				duration_ms,
+			} => {
+				let ended_at = Utc::now();
+				let duration = chrono::Duration::from_std(Duration::from_millis(*duration_ms))
+					.unwrap_or_else(|_| chrono::Duration::zero());
+				let session = SessionRecord {
+					tunnel_name: self.tunnel_name.lock().unwrap().clone().unwrap_or_default(),
+					started_at: ended_at - duration,
+					ended_at,
+					bytes_received: *bytes_received,
+					bytes_sent: *bytes_sent,
+				};
+
+				// ignore any errors, not much we can do if recording fails...
+				self.record(&session).ok();
+			}
+			_ => {}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_month() {
+		let month = UsageMonth::parse("2024-05").unwrap();
+		assert_eq!(month.file_name(), "2024-05.jsonl");
+		assert_eq!(
+			UsageMonth::of(&"2024-05-31T23:59:59Z".parse().unwrap()),
+			month
+		);
+
+		for invalid in ["", "2024", "2024-13", "May 2024"] {
+			assert!(UsageMonth::parse(invalid).is_err(), "{}", invalid);
+		}
+	}
+
+	#[test]
+	fn test_records_sessions_by_month() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let sink = UsageSink::new(&paths);
+
+		sink.write_progress(&ProgressFrame::TunnelState {
+			state: TunnelProgressState::Listening,
+			name: Some("my-machine"),
+			uri: None,
+		});
+		sink.write_progress(&ProgressFrame::ClientDisconnected {
+			bytes_received: 10,
+			bytes_sent: 20,
+			duration_ms: 90_000,
+		});
+
+		let sessions = read_sessions(&paths, UsageMonth::of(&Utc::now())).unwrap();
+		assert_eq!(sessions.len(), 1);
+		assert_eq!(sessions[0].tunnel_name, "my-machine");
+		assert_eq!(sessions[0].duration_secs(), 90);
+		assert_eq!(sessions[0].bytes_received, 10);
+		assert_eq!(sessions[0].bytes_sent, 20);
+
+		let other = UsageMonth::parse("2000-01").unwrap();
+		assert!(read_sessions(&paths, other).unwrap().is_empty());
+	}
+}
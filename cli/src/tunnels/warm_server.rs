@@ -0,0 +1,199 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! A server kept running while no clients are connected, so that the first
+//! client to attach finds it already started rather than waiting for it to
+//! start. Clients find it the same way they find a server another client
+//! started, so it's only used by clients of the same release. It's restarted
+//! now and then while idle, to pick up new releases and keep its memory in
+//! check.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+
+use crate::log;
+use crate::options::Quality;
+use crate::state::LauncherPaths;
+use crate::update_service::Platform;
+use crate::util::errors::{AnyError, MismatchedLaunchModeError};
+use crate::util::machine::process_tree_memory;
+
+use super::code_server::{
+	AnyCodeServer, CodeServerArgs, ServerBuilder, ServerParamsRaw, SocketCodeServer,
+};
+
+/// How often the warm server is checked on.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct WarmServerOptions {
+	/// Quality whose latest release is kept running.
+	pub quality: Quality,
+	/// How long the server runs before it's restarted, once it's idle.
+	pub recycle_after: Duration,
+	/// Memory, in MB, past which the server is restarted, once it's idle.
+	pub max_memory_mb: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RecycleReason {
+	Aged,
+	OverMemory(u64),
+}
+
+impl fmt::Display for RecycleReason {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			RecycleReason::Aged => write!(f, "it's been running for a while"),
+			RecycleReason::OverMemory(mb) => write!(f, "it's using {} MB of memory", mb),
+		}
+	}
+}
+
+impl WarmServerOptions {
+	/// Gets why an idle server that's been running for `age` and uses
+	/// `memory_kb` should be restarted, if it should be.
+	fn recycle_reason(&self, age: Duration, memory_kb: u64) -> Option<RecycleReason> {
+		let memory_mb = memory_kb / 1024;
+		if matches!(self.max_memory_mb, Some(max) if memory_mb > max) {
+			return Some(RecycleReason::OverMemory(memory_mb));
+		}
+
+		(age >= self.recycle_after).then_some(RecycleReason::Aged)
+	}
+}
+
+/// Keeps a server running until it's dropped, when the server is stopped.
+pub struct WarmServer {
+	_stop_tx: oneshot::Sender<()>,
+}
+
+impl WarmServer {
+	/// Starts keeping a server running in the background. It's only started
+	/// or restarted while `active_clients` is zero.
+	pub fn start(
+		log: log::Logger,
+		launcher_paths: LauncherPaths,
+		code_server_args: CodeServerArgs,
+		platform: Platform,
+		options: WarmServerOptions,
+		active_clients: Arc<AtomicUsize>,
+	) -> WarmServer {
+		let (stop_tx, mut stop_rx) = oneshot::channel();
+		let log = log.prefixed("[warm server]");
+
+		tokio::spawn(async move {
+			let mut server: Option<(SocketCodeServer, Instant)> = None;
+			let mut check = tokio::time::interval(CHECK_INTERVAL);
+
+			loop {
+				tokio::select! {
+					_ = &mut stop_rx => break,
+					_ = check.tick() => {},
+				}
+
+				if active_clients.load(Ordering::SeqCst) > 0 {
+					continue;
+				}
+
+				if let Some((mut s, started_at)) = server.take() {
+					match s.origin.running_pid() {
+						None => info!(log, "Server exited, starting it again"),
+						Some(pid) => match options.recycle_reason(
+							started_at.elapsed(),
+							process_tree_memory(pid).unwrap_or_default(),
+						) {
+							Some(reason) => {
+								info!(log, "Restarting the server, as {}", reason);
+								s.origin.kill().await;
+							}
+							None => server = Some((s, started_at)),
+						},
+					}
+				}
+
+				if server.is_none() {
+					match start_server(&log, &launcher_paths, &code_server_args, platform, &options)
+						.await
+					{
+						Ok(s) => server = Some((s, Instant::now())),
+						Err(e) => warning!(log, "Error starting the server: {}", e),
+					}
+				}
+			}
+
+			if let Some((mut s, _)) = server {
+				debug!(log, "Stopping the server");
+				s.origin.kill().await;
+			}
+		});
+
+		WarmServer { _stop_tx: stop_tx }
+	}
+}
+
+/// Starts the latest release of the quality, or gets it if it's running.
+async fn start_server(
+	log: &log::Logger,
+	launcher_paths: &LauncherPaths,
+	code_server_args: &CodeServerArgs,
+	platform: Platform,
+	options: &WarmServerOptions,
+) -> Result<SocketCodeServer, AnyError> {
+	let resolved = ServerParamsRaw {
+		commit_id: None,
+		quality: options.quality,
+		code_server_args: code_server_args.clone(),
+		headless: true,
+		platform,
+	}
+	.resolve(log)
+	.await?;
+
+	let sb = ServerBuilder::new(log, &resolved, launcher_paths).keep_alive();
+	match sb.get_running().await? {
+		Some(AnyCodeServer::Socket(s)) => Ok(s),
+		Some(_) => Err(MismatchedLaunchModeError().into()),
+		None => {
+			sb.setup().await?;
+			sb.listen_on_default_socket().await
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_recycle_reason() {
+		let options = WarmServerOptions {
+			quality: Quality::Stable,
+			recycle_after: Duration::from_secs(3600),
+			max_memory_mb: Some(500),
+		};
+		let minute = Duration::from_secs(60);
+
+		assert_eq!(options.recycle_reason(minute, 400 * 1024), None);
+		assert_eq!(
+			options.recycle_reason(minute, 600 * 1024),
+			Some(RecycleReason::OverMemory(600))
+		);
+		assert_eq!(
+			options.recycle_reason(Duration::from_secs(7200), 400 * 1024),
+			Some(RecycleReason::Aged)
+		);
+
+		let options = WarmServerOptions {
+			max_memory_mb: None,
+			..options
+		};
+		assert_eq!(options.recycle_reason(minute, 10 * 1024 * 1024), None);
+	}
+}
@@ -0,0 +1,50 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! The web UI served by `code serve-web`, which can be published through a
+//! forwarded tunnel port. The server only knows the address it listens on, so
+//! the URL it prints has to be moved onto the port's public URI, keeping the
+//! server's base path and connection token.
+
+use reqwest::Url;
+
+use crate::util::errors::{wrap, AnyError};
+
+/// Gets the URL the web UI at `local` is reachable at through the forwarded
+/// port with the public URI `port_uri`.
+pub fn public_url(local: &Url, port_uri: &str) -> Result<Url, AnyError> {
+	let mut url = Url::parse(port_uri).map_err(|e| wrap(e, "invalid forwarded port URI"))?;
+	let path = format!("{}{}", url.path().trim_end_matches('/'), local.path());
+	url.set_path(&path);
+	url.set_query(local.query());
+	url.set_fragment(local.fragment());
+	Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_moves_url_onto_port_uri() {
+		let local = Url::parse("http://127.0.0.1:8000/code/?tkn=abc").unwrap();
+		assert_eq!(
+			public_url(&local, "https://xyz-8000.usw2.devtunnels.ms/")
+				.unwrap()
+				.as_str(),
+			"https://xyz-8000.usw2.devtunnels.ms/code/?tkn=abc"
+		);
+
+		let local = Url::parse("http://localhost:8000/").unwrap();
+		assert_eq!(
+			public_url(&local, "https://tunnels.example.com/xyz/8000")
+				.unwrap()
+				.as_str(),
+			"https://tunnels.example.com/xyz/8000/"
+		);
+
+		assert!(public_url(&local, "not a uri").is_err());
+	}
+}
@@ -9,11 +9,14 @@ use serde::Deserialize;
 
 use crate::{
 	constants::VSCODE_CLI_UPDATE_ENDPOINT,
-	debug, log, options, spanf,
+	debug, log,
+	options::{self, ServerArch, ServerOs},
+	spanf,
 	util::{
 		errors::{
 			AnyError, StatusError, UnsupportedPlatformError, UpdatesNotConfigured, WrappedError,
 		},
+		http,
 		io::ReportCopyProgress,
 	},
 };
@@ -81,14 +84,14 @@ impl UpdateService {
 		let response = spanf!(
 			self.log,
 			self.log.span("server.version.resolve"),
-			self.client.get(download_url).send()
+			self.client.get(&download_url).send()
 		)?;
 
 		if !response.status().is_success() {
 			return Err(StatusError::from_res(response).await?.into());
 		}
 
-		let res = response.json::<UpdateServerVersion>().await?;
+		let res = http::read_json::<UpdateServerVersion>(&download_url, response).await?;
 		debug!(self.log, "Resolved version {} to {}", version, res.version);
 
 		Ok(Release {
@@ -122,14 +125,14 @@ impl UpdateService {
 		let response = spanf!(
 			self.log,
 			self.log.span("server.version.resolve"),
-			self.client.get(download_url).send()
+			self.client.get(&download_url).send()
 		)?;
 
 		if !response.status().is_success() {
 			return Err(StatusError::from_res(response).await?.into());
 		}
 
-		let res = response.json::<UpdateServerVersion>().await?;
+		let res = http::read_json::<UpdateServerVersion>(&download_url, response).await?;
 		debug!(self.log, "Resolved quality {} to {}", quality, res.version);
 
 		Ok(Release {
@@ -168,6 +171,26 @@ impl UpdateService {
 
 		Ok(response)
 	}
+
+	/// Gets the detached Minisign signature for a download, which is published
+	/// next to the file that the download stream was redirected to.
+	pub async fn get_download_signature(
+		&self,
+		download: &reqwest::Response,
+	) -> Result<String, AnyError> {
+		let signature_url = format!("{}.minisig", download.url());
+		let response = spanf!(
+			self.log,
+			self.log.span("update.signature"),
+			self.client.get(&signature_url).send()
+		)?;
+
+		if !response.status().is_success() {
+			return Err(StatusError::from_res(response).await?.into());
+		}
+
+		Ok(response.text().await?)
+	}
 }
 
 pub fn unzip_downloaded_release<T>(
@@ -216,19 +239,23 @@ pub enum Platform {
 	LinuxX64,
 	LinuxARM64,
 	LinuxARM32,
+	LinuxX64Legacy,
+	LinuxARM64Legacy,
+	LinuxARM32Legacy,
+	LinuxRISCV64,
 	DarwinX64,
 	DarwinARM64,
 	WindowsX64,
 	WindowsX86,
-	WindowsARM64
+	WindowsARM64,
 }
 
 impl Platform {
 	pub fn archive(&self) -> Option<String> {
 		match self {
-			Platform::LinuxX64 => Some("linux-x64".to_owned()),
-			Platform::LinuxARM64 => Some("linux-arm64".to_owned()),
-			Platform::LinuxARM32 => Some("linux-armhf".to_owned()),
+			Platform::LinuxX64 | Platform::LinuxX64Legacy => Some("linux-x64".to_owned()),
+			Platform::LinuxARM64 | Platform::LinuxARM64Legacy => Some("linux-arm64".to_owned()),
+			Platform::LinuxARM32 | Platform::LinuxARM32Legacy => Some("linux-armhf".to_owned()),
 			Platform::DarwinX64 => Some("darwin".to_owned()),
 			Platform::DarwinARM64 => Some("darwin-arm64".to_owned()),
 			Platform::WindowsX64 => Some("win32-x64-archive".to_owned()),
@@ -244,6 +271,10 @@ impl Platform {
 			Platform::LinuxX64 => "server-linux-x64",
 			Platform::LinuxARM64 => "server-linux-arm64",
 			Platform::LinuxARM32 => "server-linux-armhf",
+			Platform::LinuxX64Legacy => "server-linux-legacy-x64",
+			Platform::LinuxARM64Legacy => "server-linux-legacy-arm64",
+			Platform::LinuxARM32Legacy => "server-linux-legacy-armhf",
+			Platform::LinuxRISCV64 => "server-linux-riscv64",
 			Platform::DarwinX64 => "server-darwin",
 			Platform::DarwinARM64 => "server-darwin-arm64",
 			Platform::WindowsX64 => "server-win32-x64",
@@ -257,9 +288,10 @@ impl Platform {
 		match self {
 			Platform::LinuxAlpineARM64 => "cli-alpine-arm64",
 			Platform::LinuxAlpineX64 => "cli-alpine-x64",
-			Platform::LinuxX64 => "cli-linux-x64",
-			Platform::LinuxARM64 => "cli-linux-arm64",
-			Platform::LinuxARM32 => "cli-linux-armhf",
+			Platform::LinuxX64 | Platform::LinuxX64Legacy => "cli-linux-x64",
+			Platform::LinuxARM64 | Platform::LinuxARM64Legacy => "cli-linux-arm64",
+			Platform::LinuxARM32 | Platform::LinuxARM32Legacy => "cli-linux-armhf",
+			Platform::LinuxRISCV64 => "cli-linux-riscv64",
 			Platform::DarwinX64 => "cli-darwin-x64",
 			Platform::DarwinARM64 => "cli-darwin-arm64",
 			Platform::WindowsARM64 => "cli-win32-arm64",
@@ -288,10 +320,12 @@ impl Platform {
 			Some(Platform::LinuxAlpineARM64)
 		} else if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
 			Some(Platform::LinuxX64)
-		} else if cfg!(all(target_os = "linux", target_arch = "armhf")) {
+		} else if cfg!(all(target_os = "linux", target_arch = "arm")) {
 			Some(Platform::LinuxARM32)
 		} else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
 			Some(Platform::LinuxARM64)
+		} else if cfg!(all(target_os = "linux", target_arch = "riscv64")) {
+			Some(Platform::LinuxRISCV64)
 		} else if cfg!(all(target_os = "macos", target_arch = "x86_64")) {
 			Some(Platform::DarwinX64)
 		} else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
@@ -300,8 +334,45 @@ impl Platform {
 			Some(Platform::WindowsX64)
 		} else if cfg!(all(target_os = "windows", target_arch = "x86")) {
 			Some(Platform::WindowsX86)
+		} else if cfg!(all(target_os = "windows", target_arch = "aarch64")) {
+			Some(Platform::WindowsARM64)
 		} else {
 			None
 		}
 	}
+
+	/// Gets the build of this platform for older glibc versions, if it has one.
+	pub fn legacy(self) -> Platform {
+		match self {
+			Platform::LinuxX64 => Platform::LinuxX64Legacy,
+			Platform::LinuxARM64 => Platform::LinuxARM64Legacy,
+			Platform::LinuxARM32 => Platform::LinuxARM32Legacy,
+			p => p,
+		}
+	}
+
+	pub fn is_legacy(&self) -> bool {
+		matches!(
+			self,
+			Platform::LinuxX64Legacy | Platform::LinuxARM64Legacy | Platform::LinuxARM32Legacy
+		)
+	}
+
+	/// Gets the platform for the OS and architecture, if there are builds for it.
+	pub fn from_parts(os: ServerOs, arch: ServerArch) -> Option<Platform> {
+		match (os, arch) {
+			(ServerOs::Linux, ServerArch::X64) => Some(Platform::LinuxX64),
+			(ServerOs::Linux, ServerArch::Arm64) => Some(Platform::LinuxARM64),
+			(ServerOs::Linux, ServerArch::Armhf) => Some(Platform::LinuxARM32),
+			(ServerOs::Linux, ServerArch::Riscv64) => Some(Platform::LinuxRISCV64),
+			(ServerOs::Alpine, ServerArch::X64) => Some(Platform::LinuxAlpineX64),
+			(ServerOs::Alpine, ServerArch::Arm64) => Some(Platform::LinuxAlpineARM64),
+			(ServerOs::Darwin, ServerArch::X64) => Some(Platform::DarwinX64),
+			(ServerOs::Darwin, ServerArch::Arm64) => Some(Platform::DarwinARM64),
+			(ServerOs::Win32, ServerArch::X64) => Some(Platform::WindowsX64),
+			(ServerOs::Win32, ServerArch::X86) => Some(Platform::WindowsX86),
+			(ServerOs::Win32, ServerArch::Arm64) => Some(Platform::WindowsARM64),
+			_ => None,
+		}
+	}
 }
@@ -5,13 +5,17 @@
 
 mod is_integrated;
 
+pub mod async_pipe;
 pub mod command;
 pub mod errors;
 pub mod http;
 pub mod input;
 pub mod io;
 pub mod machine;
+pub mod os_permissions;
+pub mod power;
 pub mod prereqs;
+pub mod sd_notify;
 pub mod sync;
 pub use is_integrated::*;
 
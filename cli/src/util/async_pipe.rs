@@ -0,0 +1,193 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use super::errors::{wrap, WrappedError};
+
+// On unix-y platforms, the "pipe" is a Unix domain socket in the temp dir.
+#[cfg(not(windows))]
+mod unix_impl {
+	use std::path::Path;
+
+	use tokio::net::{UnixListener, UnixStream};
+
+	use crate::util::errors::{wrap, WrappedError};
+
+	pub type AsyncPipe = UnixStream;
+
+	pub async fn get_socket_rw_stream(path: &Path) -> Result<AsyncPipe, WrappedError> {
+		UnixStream::connect(path)
+			.await
+			.map_err(|e| wrap(e, format!("error connecting to {}", path.display())))
+	}
+
+	pub struct AsyncPipeListener(UnixListener);
+
+	impl AsyncPipeListener {
+		pub async fn accept(&mut self) -> Result<AsyncPipe, WrappedError> {
+			self.0
+				.accept()
+				.await
+				.map(|(s, _)| s)
+				.map_err(|e| wrap(e, "error accepting connection"))
+		}
+	}
+
+	pub fn listen_socket_rw_stream(path: &Path) -> Result<AsyncPipeListener, WrappedError> {
+		// a socket left over from a crashed process would otherwise fail the bind
+		std::fs::remove_file(path).ok();
+		UnixListener::bind(path)
+			.map(AsyncPipeListener)
+			.map_err(|e| wrap(e, format!("error listening on {}", path.display())))
+	}
+}
+
+// On Windows, the "pipe" is a named pipe. Servers need to create a new
+// instance of the pipe for each client that connects.
+#[cfg(windows)]
+mod win_impl {
+	use std::{
+		io,
+		path::{Path, PathBuf},
+		pin::Pin,
+		task::{Context, Poll},
+		time::Duration,
+	};
+
+	use tokio::{
+		io::{AsyncRead, AsyncWrite, ReadBuf},
+		net::windows::named_pipe::{
+			ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+		},
+		time::sleep,
+	};
+
+	use crate::util::errors::{wrap, WrappedError};
+
+	/// winerror.h ERROR_PIPE_BUSY: all instances of the pipe are in use.
+	const ERROR_PIPE_BUSY: i32 = 231;
+
+	pub enum AsyncPipe {
+		PipeClient(NamedPipeClient),
+		PipeServer(NamedPipeServer),
+	}
+
+	impl AsyncRead for AsyncPipe {
+		fn poll_read(
+			self: Pin<&mut Self>,
+			cx: &mut Context<'_>,
+			buf: &mut ReadBuf<'_>,
+		) -> Poll<io::Result<()>> {
+			match self.get_mut() {
+				AsyncPipe::PipeClient(c) => Pin::new(c).poll_read(cx, buf),
+				AsyncPipe::PipeServer(s) => Pin::new(s).poll_read(cx, buf),
+			}
+		}
+	}
+
+	impl AsyncWrite for AsyncPipe {
+		fn poll_write(
+			self: Pin<&mut Self>,
+			cx: &mut Context<'_>,
+			buf: &[u8],
+		) -> Poll<io::Result<usize>> {
+			match self.get_mut() {
+				AsyncPipe::PipeClient(c) => Pin::new(c).poll_write(cx, buf),
+				AsyncPipe::PipeServer(s) => Pin::new(s).poll_write(cx, buf),
+			}
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+			match self.get_mut() {
+				AsyncPipe::PipeClient(c) => Pin::new(c).poll_flush(cx),
+				AsyncPipe::PipeServer(s) => Pin::new(s).poll_flush(cx),
+			}
+		}
+
+		fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+			match self.get_mut() {
+				AsyncPipe::PipeClient(c) => Pin::new(c).poll_shutdown(cx),
+				AsyncPipe::PipeServer(s) => Pin::new(s).poll_shutdown(cx),
+			}
+		}
+	}
+
+	pub async fn get_socket_rw_stream(path: &Path) -> Result<AsyncPipe, WrappedError> {
+		let client = loop {
+			match ClientOptions::new().open(path) {
+				Ok(client) => break client,
+				Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {}
+				Err(e) => return Err(wrap(e, format!("error connecting to {}", path.display()))),
+			}
+
+			sleep(Duration::from_millis(50)).await;
+		};
+
+		Ok(AsyncPipe::PipeClient(client))
+	}
+
+	pub struct AsyncPipeListener {
+		path: PathBuf,
+		server: NamedPipeServer,
+	}
+
+	impl AsyncPipeListener {
+		pub async fn accept(&mut self) -> Result<AsyncPipe, WrappedError> {
+			self.server
+				.connect()
+				.await
+				.map_err(|e| wrap(e, "error accepting connection"))?;
+
+			let next = ServerOptions::new()
+				.create(&self.path)
+				.map_err(|e| wrap(e, format!("error listening on {}", self.path.display())))?;
+
+			Ok(AsyncPipe::PipeServer(std::mem::replace(
+				&mut self.server,
+				next,
+			)))
+		}
+	}
+
+	pub fn listen_socket_rw_stream(path: &Path) -> Result<AsyncPipeListener, WrappedError> {
+		let server = ServerOptions::new()
+			.first_pipe_instance(true)
+			.create(path)
+			.map_err(|e| wrap(e, format!("error listening on {}", path.display())))?;
+
+		Ok(AsyncPipeListener {
+			path: path.to_owned(),
+			server,
+		})
+	}
+}
+
+#[cfg(not(windows))]
+pub use unix_impl::*;
+#[cfg(windows)]
+pub use win_impl::*;
+
+/// Gets a random, unused name for a local socket or named pipe.
+pub fn get_socket_name() -> PathBuf {
+	let id = Uuid::new_v4().to_simple().to_string();
+	if cfg!(windows) {
+		PathBuf::from(format!(r"\\.\pipe\vscode-cli-{}", id))
+	} else {
+		std::env::temp_dir().join(format!("vscode-cli-{}.sock", id))
+	}
+}
+
+/// Removes any file left behind by a listener at the given path.
+pub fn cleanup_socket(path: &Path) -> Result<(), WrappedError> {
+	if cfg!(windows) || !path.exists() {
+		return Ok(());
+	}
+
+	std::fs::remove_file(path)
+		.map_err(|e| wrap(e, format!("error removing socket {}", path.display())))
+}
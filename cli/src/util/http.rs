@@ -2,18 +2,21 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use crate::util::errors::{self, WrappedError};
+use crate::util::errors::{self, AnyError, OperationCancelled};
 use futures::stream::TryStreamExt;
+use serde::de::DeserializeOwned;
 use tokio::fs;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 
 use super::io::{copy_async_progress, ReportCopyProgress};
 
+/// Downloads the response into the file. If the user presses Ctrl+C during
+/// the download, the partial file is removed and `OperationCancelled` is returned.
 pub async fn download_into_file<T>(
 	filename: &std::path::Path,
 	progress: T,
 	res: reqwest::Response,
-) -> Result<fs::File, WrappedError>
+) -> Result<fs::File, AnyError>
 where
 	T: ReportCopyProgress,
 {
@@ -28,9 +31,40 @@ where
 		.into_async_read()
 		.compat();
 
-	copy_async_progress(progress, &mut read, &mut file, content_length)
-		.await
-		.map_err(|e| errors::wrap(e, "failed to download file"))?;
+	tokio::select! {
+		r = copy_async_progress(progress, &mut read, &mut file, content_length) => {
+			r.map_err(|e| errors::wrap(e, "failed to download file"))?;
+		},
+		Ok(()) = tokio::signal::ctrl_c() => {
+			drop(file);
+			fs::remove_file(filename).await.ok();
+			return Err(OperationCancelled().into());
+		}
+	}
 
 	Ok(file)
 }
+
+/// Reads a JSON body from a successful response to a request to the
+/// `requested_url`. If it's instead a page from a captive portal, an error
+/// explaining that is returned.
+pub async fn read_json<T: DeserializeOwned>(
+	requested_url: &str,
+	res: reqwest::Response,
+) -> Result<T, AnyError> {
+	let status_code = res.status().as_u16();
+	let url = res.url().to_string();
+	let body = res
+		.text()
+		.await
+		.map_err(|e| errors::wrap(e, format!("failed to read response from {}", url)))?;
+
+	if let Some(e) =
+		errors::get_interception_error(status_code, &url, Some(requested_url), Some(&body))
+	{
+		return Err(e);
+	}
+
+	serde_json::from_str(&body)
+		.map_err(|e| errors::wrap(e, format!("failed to parse response from {}", url)).into())
+}
@@ -5,9 +5,12 @@
 use crate::util::errors::wrap;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
 use indicatif::ProgressBar;
-use std::fmt::Display;
+use std::{fmt::Display, io};
 
-use super::{errors::WrappedError, io::ReportCopyProgress};
+use super::{
+	errors::{AnyError, OperationCancelled},
+	io::ReportCopyProgress,
+};
 
 /// Wrapper around indicatif::ProgressBar that implements ReportCopyProgress.
 pub struct ProgressBarReporter {
@@ -38,15 +41,27 @@ impl ReportCopyProgress for ProgressBarReporter {
 	}
 }
 
-pub fn prompt_yn(text: &str) -> Result<bool, WrappedError> {
+/// Maps an error from a dialoguer prompt. Ctrl+C is reported by the terminal
+/// as an interrupted read, in which case we restore the cursor (which some
+/// prompts hide) and report the operation as cancelled.
+fn map_prompt_error(e: io::Error, message: &str) -> AnyError {
+	if e.kind() == io::ErrorKind::Interrupted {
+		eprint!("\x1b[?25h");
+		return OperationCancelled().into();
+	}
+
+	wrap(e, message).into()
+}
+
+pub fn prompt_yn(text: &str) -> Result<bool, AnyError> {
 	Confirm::with_theme(&ColorfulTheme::default())
 		.with_prompt(text)
 		.default(true)
 		.interact()
-		.map_err(|e| wrap(e, "Failed to read confirm input"))
+		.map_err(|e| map_prompt_error(e, "Failed to read confirm input"))
 }
 
-pub fn prompt_options<T>(text: &str, options: &[T]) -> Result<T, WrappedError>
+pub fn prompt_options<T>(text: &str, options: &[T]) -> Result<T, AnyError>
 where
 	T: Display + Copy,
 {
@@ -55,15 +70,29 @@ where
 		.items(options)
 		.default(0)
 		.interact()
-		.map_err(|e| wrap(e, "Failed to read select input"))?;
+		.map_err(|e| map_prompt_error(e, "Failed to read select input"))?;
 
 	Ok(options[chosen])
 }
 
-pub fn prompt_placeholder(question: &str, placeholder: &str) -> Result<String, WrappedError> {
+pub fn prompt_placeholder(question: &str, placeholder: &str) -> Result<String, AnyError> {
 	Input::with_theme(&ColorfulTheme::default())
 		.with_prompt(question)
 		.default(placeholder.to_string())
 		.interact_text()
-		.map_err(|e| wrap(e, "Failed to read confirm input"))
+		.map_err(|e| map_prompt_error(e, "Failed to read confirm input"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_maps_interrupted_prompt_to_cancelled() {
+		let err = map_prompt_error(io::Error::from(io::ErrorKind::Interrupted), "failed");
+		assert!(matches!(err, AnyError::OperationCancelled(_)));
+
+		let err = map_prompt_error(io::Error::from(io::ErrorKind::BrokenPipe), "failed");
+		assert!(matches!(err, AnyError::WrappedError(_)));
+	}
 }
@@ -3,7 +3,7 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::path::Path;
+use std::{collections::HashSet, path::Path, time::Duration};
 use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
 
 pub fn process_at_path_exists(pid: u32, name: &Path) -> bool {
@@ -49,3 +49,94 @@ pub fn find_running_process(name: &Path) -> Option<u32> {
 	}
 	None
 }
+
+/// Gets the memory used by the process and all of its descendants, in KB, or
+/// None if it's not running.
+pub fn process_tree_memory(pid: u32) -> Option<u64> {
+	let mut sys = System::new();
+	sys.refresh_processes();
+	let root = Pid::from_u32(pid);
+	sys.process(root)?;
+
+	let descends_from_root = |mut pid: Pid| loop {
+		if pid == root {
+			return true;
+		}
+		match sys.process(pid).and_then(|p| p.parent()) {
+			Some(parent) if parent != pid => pid = parent,
+			_ => return false,
+		}
+	};
+
+	Some(
+		sys.processes()
+			.iter()
+			.filter(|(pid, _)| descends_from_root(**pid))
+			.map(|(_, p)| p.memory())
+			.sum(),
+	)
+}
+
+/// Gets the IDs of the processes descended from any of the roots, not
+/// including the roots themselves.
+pub fn descendant_pids(roots: &[u32]) -> HashSet<u32> {
+	let mut sys = System::new();
+	sys.refresh_processes();
+	let roots: HashSet<Pid> = roots.iter().map(|p| Pid::from_u32(*p)).collect();
+
+	let has_root_ancestor = |pid: Pid| {
+		let mut pid = pid;
+		loop {
+			match sys.process(pid).and_then(|p| p.parent()) {
+				Some(parent) if roots.contains(&parent) => return true,
+				Some(parent) if parent != pid => pid = parent,
+				_ => return false,
+			}
+		}
+	};
+
+	sys.processes()
+		.keys()
+		.filter(|pid| !roots.contains(pid) && has_root_ancestor(**pid))
+		.map(|pid| pid.as_u32())
+		.collect()
+}
+
+/// Polls at the given interval until the process is no longer running.
+pub async fn wait_until_process_exits(pid: u32, poll_interval: Duration) {
+	let mut s = System::new();
+	let pid = Pid::from_u32(pid);
+	while s.refresh_process(pid) {
+		tokio::time::sleep(poll_interval).await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_waits_until_process_exits() {
+		let running = tokio::time::timeout(
+			Duration::from_millis(200),
+			wait_until_process_exits(std::process::id(), Duration::from_millis(10)),
+		)
+		.await;
+		assert!(running.is_err());
+
+		let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+			.arg("--list")
+			.stdout(std::process::Stdio::null())
+			.spawn()
+			.unwrap();
+		let pid = child.id();
+		child.wait().unwrap();
+
+		tokio::time::timeout(
+			Duration::from_secs(5),
+			wait_until_process_exits(pid, Duration::from_millis(10)),
+		)
+		.await
+		.expect("expected to see the process exit");
+	}
+}
@@ -0,0 +1,152 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Permissions the OS may withhold from the CLI, like Windows Firewall rules
+//! and macOS privacy settings. Without them, hosting fails in ways that only
+//! show as generic OS errors, so they're checked up front by `code tunnel
+//! doctor` and on installing the service, along with how to grant them.
+
+use std::io;
+use std::path::Path;
+
+/// Whether the OS grants a permission.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PermissionStatus {
+	Granted,
+	Missing,
+	/// The OS doesn't say, such as for permissions it only asks for on use.
+	Unknown,
+}
+
+/// Outcome of checking a permission.
+#[derive(Clone, Debug)]
+pub struct PermissionCheck {
+	pub name: &'static str,
+	pub status: PermissionStatus,
+	/// What the permission's needed for, and how to grant it.
+	pub remedy: String,
+}
+
+/// Name of the Windows Firewall rule that lets clients on the network
+/// connect to the CLI, such as to a local relay.
+#[cfg(windows)]
+pub const FIREWALL_RULE_NAME: &str = "VS Code Tunnel";
+
+/// Checks the permissions the CLI at `exe` needs on this platform.
+#[cfg(windows)]
+pub async fn check_permissions(exe: &Path) -> Vec<PermissionCheck> {
+	use super::command::capture_command;
+
+	// netsh exits with an error if no rule has the name
+	let rule = format!("name={}", FIREWALL_RULE_NAME);
+	let status = match capture_command(
+		"netsh",
+		["advfirewall", "firewall", "show", "rule", rule.as_str()],
+	)
+	.await
+	{
+		Ok(o) if o.status.success() => PermissionStatus::Granted,
+		Ok(_) => PermissionStatus::Missing,
+		Err(_) => PermissionStatus::Unknown,
+	};
+
+	vec![PermissionCheck {
+		name: "Windows Firewall rule",
+		status,
+		remedy: format!(
+			"Needed for clients on the network to connect directly, such as with --local-port. To add it, run as an administrator: netsh advfirewall firewall add rule name=\"{}\" dir=in action=allow program=\"{}\" enable=yes",
+			FIREWALL_RULE_NAME,
+			exe.display()
+		),
+	}]
+}
+
+/// Checks the permissions the CLI at `exe` needs on this platform.
+#[cfg(target_os = "macos")]
+pub async fn check_permissions(exe: &Path) -> Vec<PermissionCheck> {
+	// the privacy database can only be read with full disk access
+	let tcc_db = dirs::home_dir()
+		.unwrap_or_default()
+		.join("Library/Application Support/com.apple.TCC/TCC.db");
+	let full_disk_access = match std::fs::File::open(tcc_db) {
+		Ok(_) => PermissionStatus::Granted,
+		Err(e) if e.kind() == io::ErrorKind::PermissionDenied => PermissionStatus::Missing,
+		Err(_) => PermissionStatus::Unknown,
+	};
+
+	vec![
+		PermissionCheck {
+			name: "Full Disk Access",
+			status: full_disk_access,
+			remedy: format!(
+				"Needed for clients to open files in protected folders, like Desktop and Documents, when running as a service. Add {} under System Settings > Privacy & Security > Full Disk Access.",
+				exe.display()
+			),
+		},
+		PermissionCheck {
+			name: "Local Network",
+			status: PermissionStatus::Unknown,
+			remedy: format!(
+				"Needed to forward ports to other machines on the network, which otherwise fails with 'No route to host'. macOS asks on first use, or allow {} under System Settings > Privacy & Security > Local Network.",
+				exe.display()
+			),
+		},
+	]
+}
+
+/// Checks the permissions the CLI at `exe` needs on this platform.
+#[cfg(not(any(target_os = "macos", windows)))]
+pub async fn check_permissions(_exe: &Path) -> Vec<PermissionCheck> {
+	vec![]
+}
+
+/// Explains an error connecting to a forwarded port, if it's likely to be
+/// caused by a missing permission.
+pub fn explain_connect_error(e: &io::Error) -> Option<&'static str> {
+	// EHOSTUNREACH, which macOS returns for hosts on the local network if
+	// the CLI isn't allowed to reach them
+	#[cfg(target_os = "macos")]
+	if e.raw_os_error() == Some(65) {
+		return Some("the CLI may need to be allowed under System Settings > Privacy & Security > Local Network");
+	}
+
+	// WSAEACCES, returned when a firewall or security policy blocks the socket
+	#[cfg(windows)]
+	if e.raw_os_error() == Some(10013) {
+		return Some(
+			"a firewall or security policy may be blocking the CLI, see `code tunnel doctor`",
+		);
+	}
+
+	let _ = e;
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_checks_platform_permissions() {
+		let checks = check_permissions(Path::new("/usr/bin/code")).await;
+		if cfg!(any(target_os = "macos", windows)) {
+			assert!(!checks.is_empty());
+			assert!(checks.iter().all(|c| c.remedy.contains("code")));
+		} else {
+			assert!(checks.is_empty());
+		}
+	}
+
+	#[test]
+	fn test_explains_permission_errors() {
+		let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+		assert_eq!(explain_connect_error(&refused), None);
+
+		#[cfg(target_os = "macos")]
+		assert!(explain_connect_error(&io::Error::from_raw_os_error(65)).is_some());
+		#[cfg(windows)]
+		assert!(explain_connect_error(&io::Error::from_raw_os_error(10013)).is_some());
+	}
+}
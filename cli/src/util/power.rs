@@ -0,0 +1,199 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Whether the machine is running on battery or on a metered connection, so
+//! that hosting can be paused to save power and data on laptops. There's no
+//! portable notification of changes, so the state is polled.
+
+use std::time::Duration;
+
+/// Power and network conditions of the machine.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PowerState {
+	pub on_battery: bool,
+	pub metered: bool,
+}
+
+/// Conditions under which hosting is paused.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PowerPolicy {
+	pub pause_on_battery: bool,
+	pub pause_on_metered: bool,
+}
+
+impl PowerPolicy {
+	pub fn is_enabled(&self) -> bool {
+		self.pause_on_battery || self.pause_on_metered
+	}
+
+	/// Gets why hosting should be paused in the given state, or None if it
+	/// shouldn't be.
+	pub fn pause_reason(&self, state: &PowerState) -> Option<&'static str> {
+		if self.pause_on_battery && state.on_battery {
+			Some("the machine is on battery power")
+		} else if self.pause_on_metered && state.metered {
+			Some("the machine is on a metered connection")
+		} else {
+			None
+		}
+	}
+}
+
+/// Polls the machine's power state at the given interval until `predicate`
+/// is true for it, and returns that state.
+pub async fn wait_for_power_state(
+	poll_interval: Duration,
+	mut predicate: impl FnMut(&PowerState) -> bool,
+) -> PowerState {
+	loop {
+		let state = get_power_state().await;
+		if predicate(&state) {
+			return state;
+		}
+
+		tokio::time::sleep(poll_interval).await;
+	}
+}
+
+/// Gets the machine's current power state. Conditions that can't be detected
+/// are reported as not applying.
+#[cfg(target_os = "linux")]
+pub async fn get_power_state() -> PowerState {
+	use super::command::capture_command;
+
+	let on_battery = std::fs::read_dir("/sys/class/power_supply")
+		.map(|entries| {
+			entries.filter_map(|e| e.ok()).any(|e| {
+				let read = |f: &str| std::fs::read_to_string(e.path().join(f)).unwrap_or_default();
+				read("type").trim() == "Battery" && read("status").trim() == "Discharging"
+			})
+		})
+		.unwrap_or(false);
+
+	let metered = capture_command(
+		"busctl",
+		[
+			"get-property",
+			"org.freedesktop.NetworkManager",
+			"/org/freedesktop/NetworkManager",
+			"org.freedesktop.NetworkManager",
+			"Metered",
+		],
+	)
+	.await
+	.map(|o| o.status.success() && is_nm_metered(&String::from_utf8_lossy(&o.stdout)))
+	.unwrap_or(false);
+
+	PowerState {
+		on_battery,
+		metered,
+	}
+}
+
+/// Gets the machine's current power state. Conditions that can't be detected
+/// are reported as not applying.
+#[cfg(target_os = "macos")]
+pub async fn get_power_state() -> PowerState {
+	use super::command::capture_command;
+
+	let on_battery = capture_command("pmset", ["-g", "batt"])
+		.await
+		.map(|o| String::from_utf8_lossy(&o.stdout).contains("'Battery Power'"))
+		.unwrap_or(false);
+
+	// macOS doesn't expose whether a connection is metered outside of its
+	// network framework
+	PowerState {
+		on_battery,
+		metered: false,
+	}
+}
+
+/// Gets the machine's current power state. Conditions that can't be detected
+/// are reported as not applying.
+#[cfg(windows)]
+pub async fn get_power_state() -> PowerState {
+	use super::command::capture_command;
+
+	const SCRIPT: &str = "(Get-CimInstance Win32_Battery).BatteryStatus; [Windows.Networking.Connectivity.NetworkInformation,Windows.Networking.Connectivity,ContentType=WindowsRuntime]::GetInternetConnectionProfile().GetConnectionCost().NetworkCostType";
+
+	let output = match capture_command(
+		"powershell",
+		["-NoProfile", "-NonInteractive", "-Command", SCRIPT],
+	)
+	.await
+	{
+		Ok(o) => String::from_utf8_lossy(&o.stdout).to_string(),
+		Err(_) => return PowerState::default(),
+	};
+
+	// a BatteryStatus of 1 means the battery is discharging, and "Fixed" or
+	// "Variable" network costs mean the connection is metered
+	let mut on_battery = false;
+	let mut metered = false;
+	for line in output.lines().map(str::trim) {
+		match line {
+			"1" => on_battery = true,
+			"Fixed" | "Variable" => metered = true,
+			_ => {}
+		}
+	}
+
+	PowerState {
+		on_battery,
+		metered,
+	}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub async fn get_power_state() -> PowerState {
+	PowerState::default()
+}
+
+/// Parses NetworkManager's `Metered` property, as printed by busctl. It's one
+/// of unknown (0), yes (1), no (2), guess-yes (3), or guess-no (4).
+#[cfg(target_os = "linux")]
+fn is_nm_metered(busctl_output: &str) -> bool {
+	matches!(
+		busctl_output.trim().strip_prefix("u "),
+		Some("1") | Some("3")
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_pause_reason() {
+		let policy = PowerPolicy {
+			pause_on_battery: true,
+			pause_on_metered: false,
+		};
+		assert!(policy.is_enabled());
+		assert!(policy
+			.pause_reason(&PowerState {
+				on_battery: true,
+				metered: false,
+			})
+			.is_some());
+		assert_eq!(
+			policy.pause_reason(&PowerState {
+				on_battery: false,
+				metered: true,
+			}),
+			None
+		);
+		assert!(!PowerPolicy::default().is_enabled());
+
+		#[cfg(target_os = "linux")]
+		{
+			assert!(is_nm_metered("u 1\n"));
+			assert!(is_nm_metered("u 3"));
+			assert!(!is_nm_metered("u 4"));
+			assert!(!is_nm_metered(""));
+		}
+	}
+}
@@ -5,6 +5,7 @@
 use std::cmp::Ordering;
 
 use super::command::capture_command;
+use crate::options::{Libc, ServerArch, ServerOs};
 use crate::update_service::Platform;
 use crate::util::errors::SetupError;
 use lazy_static::lazy_static;
@@ -20,64 +21,104 @@ lazy_static! {
 	static ref LIBSTD_CXX_VERSION_RE: BinRegex =
 		BinRegex::new(r"GLIBCXX_([0-9]+)\.([0-9]+)(?:\.([0-9]+))?").unwrap();
 	static ref MIN_CXX_VERSION: SimpleSemver = SimpleSemver::new(3, 4, 18);
-	static ref MIN_LDD_VERSION: SimpleSemver = SimpleSemver::new(2, 17, 0);
+	static ref GETCONF_GLIBC_VERSION_RE: BinRegex =
+		BinRegex::new(r"^glibc ([0-9]+)\.([0-9]+)").unwrap();
+	static ref MIN_LDD_VERSION: SimpleSemver = SimpleSemver::new(2, 28, 0);
+	static ref MIN_LEGACY_LDD_VERSION: SimpleSemver = SimpleSemver::new(2, 17, 0);
 }
 
-pub struct PreReqChecker {}
-
-impl Default for PreReqChecker {
-	fn default() -> Self {
-		Self::new()
-	}
+#[derive(Default)]
+pub struct PreReqChecker {
+	os: Option<ServerOs>,
+	arch: Option<ServerArch>,
+	libc: Option<Libc>,
+	use_legacy_server: bool,
 }
 
 impl PreReqChecker {
 	pub fn new() -> PreReqChecker {
-		PreReqChecker {}
+		PreReqChecker::default()
+	}
+
+	/// Uses the given OS and architecture, where set, instead of detecting them.
+	pub fn with_overrides(mut self, os: Option<ServerOs>, arch: Option<ServerArch>) -> Self {
+		self.os = os;
+		self.arch = arch;
+		self
+	}
+
+	/// Uses the given libc instead of detecting it, and whether to use the
+	/// server build for older glibc versions. Only applies on Linux.
+	pub fn with_libc_overrides(mut self, libc: Option<Libc>, use_legacy_server: bool) -> Self {
+		self.libc = libc;
+		self.use_legacy_server = use_legacy_server;
+		self
 	}
 
 	#[cfg(not(target_os = "linux"))]
 	pub async fn verify(&self) -> Result<Platform, AnyError> {
-		Platform::env_default().ok_or_else(|| {
-			SetupError("VS Code it not supported on this platform".to_owned()).into()
-		})
+		let os = match self.os {
+			Some(os) => os,
+			None if cfg!(target_os = "macos") => ServerOs::Darwin,
+			None if cfg!(windows) => ServerOs::Win32,
+			None => return Err(crate::util::errors::UnsupportedPlatformError().into()),
+		};
+
+		let arch = match self.arch {
+			Some(a) => a,
+			None => detect_host_arch().await?,
+		};
+
+		platform_from_parts(os, arch)
 	}
 
 	#[cfg(target_os = "linux")]
 	pub async fn verify(&self) -> Result<Platform, AnyError> {
-		let (gnu_a, gnu_b, or_musl) = tokio::join!(
-			check_glibc_version(),
-			check_glibcxx_version(),
-			check_musl_interpreter()
-		);
+		let arch = match self.arch {
+			Some(a) => a,
+			None => detect_host_arch().await?,
+		};
 
-		if gnu_a.is_ok() && gnu_b.is_ok() {
-			return Ok(if cfg!(target_arch = "x86_64") {
-				Platform::LinuxX64
-			} else if cfg!(target_arch = "armhf") {
-				Platform::LinuxARM32
-			} else {
-				Platform::LinuxARM64
-			});
-		}
+		let os = self.os.or(match self.libc {
+			Some(Libc::Glibc) => Some(ServerOs::Linux),
+			Some(Libc::Musl) => Some(ServerOs::Alpine),
+			None => None,
+		});
 
-		if or_musl.is_ok() {
-			return Ok(if cfg!(target_arch = "x86_64") {
-				Platform::LinuxAlpineX64
-			} else {
-				Platform::LinuxAlpineARM64
-			});
+		if let Some(os) = os {
+			return self.finish(platform_from_parts(os, arch)?);
 		}
 
+		let (glibc, glibcxx, or_musl) = tokio::join!(
+			get_glibc_version(),
+			check_glibcxx_version(),
+			check_musl_interpreter(arch)
+		);
+
 		let mut errors: Vec<String> = vec![];
-		if let Err(e) = gnu_a {
-			errors.push(e);
-		} else if let Err(e) = gnu_b {
-			errors.push(e);
+		match (glibc, glibcxx) {
+			// Machines on older glibc can still run the legacy server
+			(Some(v), Ok(())) if v.gte(&MIN_LDD_VERSION) => {
+				return self.finish(platform_from_parts(ServerOs::Linux, arch)?);
+			}
+			(Some(v), Ok(())) if v.gte(&MIN_LEGACY_LDD_VERSION) => {
+				return Ok(platform_from_parts(ServerOs::Linux, arch)?.legacy());
+			}
+			(Some(v), _) if !v.gte(&MIN_LEGACY_LDD_VERSION) => errors.push(format!(
+				"find GLIBC >= {} (but found {} instead) for GNU environments",
+				*MIN_LEGACY_LDD_VERSION, v
+			)),
+			// glibc's version could not be read, so rely on libstdc++ and musl
+			(None, Ok(())) if or_musl.is_err() => {
+				return self.finish(platform_from_parts(ServerOs::Linux, arch)?);
+			}
+			(_, Err(e)) => errors.push(e),
+			_ => {}
 		}
 
-		if let Err(e) = or_musl {
-			errors.push(e);
+		match or_musl {
+			Ok(()) => return self.finish(platform_from_parts(ServerOs::Alpine, arch)?),
+			Err(e) => errors.push(e),
 		}
 
 		let bullets = errors
@@ -87,49 +128,100 @@ impl PreReqChecker {
 			.join("\n");
 
 		Err(AnyError::from(SetupError(format!(
-			"This machine not meet VS Code Server's prerequisites, expected either...\n{}",
+			"This machine not meet VS Code Server's prerequisites, expected either...\n{}\nUse --libc or --use-legacy-server to choose a server build yourself.",
 			bullets,
 		))))
 	}
+
+	#[allow(dead_code)]
+	fn finish(&self, platform: Platform) -> Result<Platform, AnyError> {
+		Ok(if self.use_legacy_server {
+			platform.legacy()
+		} else {
+			platform
+		})
+	}
 }
 
-#[allow(dead_code)]
-async fn check_musl_interpreter() -> Result<(), String> {
-	const MUSL_PATH: &str = if cfg!(target_platform = "aarch64") {
-		"/lib/ld-musl-aarch64.so.1"
+fn platform_from_parts(os: ServerOs, arch: ServerArch) -> Result<Platform, AnyError> {
+	Platform::from_parts(os, arch).ok_or_else(|| {
+		SetupError(format!(
+			"VS Code Server is not available for {} on {}. Use --server-platform and --server-arch to choose a different build.",
+			os, arch
+		))
+		.into()
+	})
+}
+
+/// Gets the architecture of the machine. This can differ from the one the CLI
+/// was built for if it's running under emulation, like Rosetta on macOS or x64
+/// emulation on Windows on ARM. On Linux the CLI's own architecture is used,
+/// since a 64-bit kernel can report `aarch64` under a 32-bit armhf userland.
+pub async fn detect_host_arch() -> Result<ServerArch, AnyError> {
+	let detected = if cfg!(target_os = "macos") {
+		capture_command("sysctl", ["-n", "hw.optional.arm64"])
+			.await
+			.ok()
+			.filter(|o| o.stdout.starts_with(b"1"))
+			.map(|_| ServerArch::Arm64)
+	} else if cfg!(target_os = "windows") {
+		std::env::var("PROCESSOR_ARCHITEW6432")
+			.or_else(|_| std::env::var("PROCESSOR_ARCHITECTURE"))
+			.ok()
+			.and_then(|a| ServerArch::from_machine_name(&a))
 	} else {
-		"/lib/ld-musl-x86_64.so.1"
+		None
+	};
+
+	detected.or_else(ServerArch::build_default).ok_or_else(|| {
+		SetupError(
+			"Could not detect this machine's architecture, use --server-arch to set it".to_owned(),
+		)
+		.into()
+	})
+}
+
+#[allow(dead_code)]
+async fn check_musl_interpreter(arch: ServerArch) -> Result<(), String> {
+	const ALPINE_RELEASE_PATH: &str = "/etc/alpine-release";
+
+	let musl_path = match arch {
+		ServerArch::Arm64 => "/lib/ld-musl-aarch64.so.1",
+		ServerArch::Armhf => "/lib/ld-musl-armhf.so.1",
+		ServerArch::Riscv64 => "/lib/ld-musl-riscv64.so.1",
+		ServerArch::X86 => "/lib/ld-musl-i386.so.1",
+		ServerArch::X64 => "/lib/ld-musl-x86_64.so.1",
 	};
 
-	if fs::metadata(MUSL_PATH).await.is_err() {
+	if fs::metadata(musl_path).await.is_err() && fs::metadata(ALPINE_RELEASE_PATH).await.is_err() {
 		return Err(format!(
 			"find {}, which is required to run the VS Code Server in musl environments",
-			MUSL_PATH
+			musl_path
 		));
 	}
 
 	Ok(())
 }
 
+/// Gets the version of glibc on the machine, or None if it's not installed or
+/// could not be read. `getconf` is tried first since, unlike `ldd`, it's part
+/// of glibc itself and is not shadowed by musl's `ldd` on mixed systems.
 #[allow(dead_code)]
-async fn check_glibc_version() -> Result<(), String> {
-	let ldd_version = capture_command("ldd", ["--version"])
+async fn get_glibc_version() -> Option<SimpleSemver> {
+	if let Some(v) = capture_command("getconf", ["GNU_LIBC_VERSION"])
 		.await
 		.ok()
-		.and_then(|o| extract_ldd_version(&o.stdout));
-
-	if let Some(v) = ldd_version {
-		return if v.gte(&MIN_LDD_VERSION) {
-			Ok(())
-		} else {
-			Err(format!(
-				"find GLIBC >= 2.17 (but found {} instead) for GNU environments",
-				v
-			))
-		};
+		.filter(|o| o.status.success())
+		.and_then(|o| extract_getconf_glibc_version(&o.stdout))
+	{
+		return Some(v);
 	}
 
-	Ok(())
+	capture_command("ldd", ["--version"])
+		.await
+		.ok()
+		.filter(|o| o.status.success())
+		.and_then(|o| extract_ldd_version(&o.stdout))
 }
 
 #[allow(dead_code)]
@@ -171,7 +263,7 @@ fn check_for_sufficient_glibcxx_versions(contents: Vec<u8>) -> Result<(), String
 		})
 		.collect();
 
-	if !all_versions.iter().any(|v| MIN_CXX_VERSION.gte(v)) {
+	if !all_versions.iter().any(|v| v.gte(&MIN_CXX_VERSION)) {
 		return Err(format!(
 			"find GLIBCXX >= 3.4.18 (but found {} instead) for GNU environments",
 			all_versions
@@ -185,6 +277,16 @@ fn check_for_sufficient_glibcxx_versions(contents: Vec<u8>) -> Result<(), String
 	Ok(())
 }
 
+fn extract_getconf_glibc_version(output: &[u8]) -> Option<SimpleSemver> {
+	GETCONF_GLIBC_VERSION_RE
+		.captures(output)
+		.map(|m| SimpleSemver {
+			major: m.get(1).map_or(0, |s| u32_from_bytes(s.as_bytes())),
+			minor: m.get(2).map_or(0, |s| u32_from_bytes(s.as_bytes())),
+			patch: 0,
+		})
+}
+
 fn extract_ldd_version(output: &[u8]) -> Option<SimpleSemver> {
 	LDD_VERSION_RE.captures(output).map(|m| SimpleSemver {
 		major: m.get(1).map_or(0, |s| u32_from_bytes(s.as_bytes())),
@@ -251,6 +353,51 @@ impl SimpleSemver {
 mod tests {
 	use super::*;
 
+	async fn verify(os: ServerOs, arch: ServerArch) -> Result<Platform, AnyError> {
+		PreReqChecker::new()
+			.with_overrides(Some(os), Some(arch))
+			.verify()
+			.await
+	}
+
+	#[tokio::test]
+	async fn test_verifies_with_overrides() {
+		assert!(matches!(
+			verify(ServerOs::Linux, ServerArch::Riscv64).await,
+			Ok(Platform::LinuxRISCV64)
+		));
+		assert!(matches!(
+			verify(ServerOs::Alpine, ServerArch::Arm64).await,
+			Ok(Platform::LinuxAlpineARM64)
+		));
+		assert!(matches!(
+			verify(ServerOs::Darwin, ServerArch::Arm64).await,
+			Ok(Platform::DarwinARM64)
+		));
+
+		let err = verify(ServerOs::Darwin, ServerArch::Riscv64)
+			.await
+			.unwrap_err();
+		assert!(err.to_string().contains("--server-platform"));
+	}
+
+	#[test]
+	fn test_parses_machine_arch_names() {
+		assert_eq!(
+			ServerArch::from_machine_name("AMD64"),
+			Some(ServerArch::X64)
+		);
+		assert_eq!(
+			ServerArch::from_machine_name("aarch64"),
+			Some(ServerArch::Arm64)
+		);
+		assert_eq!(
+			ServerArch::from_machine_name("armv7l"),
+			Some(ServerArch::Armhf)
+		);
+		assert_eq!(ServerArch::from_machine_name("mips"), None);
+	}
+
 	#[test]
 	fn test_extract_libstd_from_ldconfig() {
 		let actual = "
@@ -298,4 +445,13 @@ mod tests {
 			Some(SimpleSemver::new(2, 31, 0)),
 		);
 	}
+
+	#[test]
+	fn test_extract_getconf_glibc_version() {
+		assert_eq!(
+			extract_getconf_glibc_version(b"glibc 2.17\n"),
+			Some(SimpleSemver::new(2, 17, 0)),
+		);
+		assert_eq!(extract_getconf_glibc_version(b"NPTL 2.17\n"), None);
+	}
 }
@@ -0,0 +1,76 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Reporting state to systemd through `$NOTIFY_SOCKET`, so the supervisor of
+//! a tunnel run as a service shows what it's doing, such as in
+//! `systemctl status`. Outside of systemd, this does nothing.
+
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+/// Gets whether the process was started by a supervisor that takes
+/// notifications.
+pub fn is_supervised() -> bool {
+	cfg!(target_os = "linux") && std::env::var_os(NOTIFY_SOCKET_ENV).is_some()
+}
+
+/// Sets the human-readable status text shown by the supervisor.
+pub fn notify_status(text: &str) -> std::io::Result<()> {
+	// the protocol is newline-separated, so keep the text to one line
+	notify(&format!("STATUS={}", text.replace('\n', " ")))
+}
+
+#[cfg(target_os = "linux")]
+fn notify(message: &str) -> std::io::Result<()> {
+	match std::env::var_os(NOTIFY_SOCKET_ENV) {
+		Some(path) => send(&path.to_string_lossy(), message),
+		None => Ok(()),
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn send(path: &str, message: &str) -> std::io::Result<()> {
+	use std::os::linux::net::SocketAddrExt;
+	use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+	// a leading '@' is a socket in the abstract namespace
+	let addr = match path.strip_prefix('@') {
+		Some(name) => SocketAddr::from_abstract_name(name.as_bytes())?,
+		None => SocketAddr::from_pathname(path)?,
+	};
+
+	let socket = UnixDatagram::unbound()?;
+	socket.send_to_addr(message.as_bytes(), &addr)?;
+	Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify(_message: &str) -> std::io::Result<()> {
+	Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use super::*;
+	use std::os::linux::net::SocketAddrExt;
+	use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+	#[test]
+	fn test_sends_to_socket() {
+		let path = std::env::temp_dir().join(format!("sd-notify-test-{}", std::process::id()));
+		let receiver = UnixDatagram::bind(&path).unwrap();
+		send(&path.to_string_lossy(), "STATUS=Connected").unwrap();
+		let mut buf = [0u8; 64];
+		let n = receiver.recv(&mut buf).unwrap();
+		assert_eq!(&buf[..n], b"STATUS=Connected");
+		std::fs::remove_file(&path).ok();
+
+		let name = format!("sd-notify-test-{}", std::process::id());
+		let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+		let receiver = UnixDatagram::bind_addr(&addr).unwrap();
+		send(&format!("@{}", name), "READY=1").unwrap();
+		let n = receiver.recv(&mut buf).unwrap();
+		assert_eq!(&buf[..n], b"READY=1");
+	}
+}
@@ -2,11 +2,15 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
+use std::future::Future;
+
 use tokio::sync::watch::{
 	self,
 	error::{RecvError, SendError},
 };
 
+use super::errors::{AnyError, OperationCancelled};
+
 #[derive(Clone)]
 pub struct Barrier<T>(watch::Receiver<Option<T>>)
 where
@@ -47,6 +51,18 @@ where
 	(Barrier(closed_rx), BarrierOpener(closed_tx))
 }
 
+/// Runs the future to completion, unless the user presses Ctrl+C first, in
+/// which case the future is dropped and an `OperationCancelled` is returned.
+pub async fn cancellable<F, T>(fut: F) -> Result<T, AnyError>
+where
+	F: Future<Output = Result<T, AnyError>>,
+{
+	tokio::select! {
+		r = fut => r,
+		Ok(()) = tokio::signal::ctrl_c() => Err(OperationCancelled().into()),
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -84,4 +100,12 @@ mod tests {
 		assert!(rx1.await.unwrap() == 42);
 		assert!(rx2.await.unwrap() == 42);
 	}
+
+	#[tokio::test]
+	async fn test_cancellable_passes_through_result() {
+		assert_eq!(cancellable(async { Ok(42) }).await.unwrap(), 42);
+
+		let err = cancellable(async { Err::<u32, _>(OperationCancelled().into()) }).await;
+		assert!(matches!(err, Err(AnyError::OperationCancelled(_))));
+	}
 }